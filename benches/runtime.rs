@@ -0,0 +1,247 @@
+//! Compares the cost of common operations under the `DeterministicRuntime` against the
+//! `SingleThreadedRuntime`, so regressions in the simulation layer show up as a widening gap.
+//!
+//! Each group benchmarks the same code, written against `Environment`, once per runtime. The
+//! polls group also runs the `DeterministicRuntime` without keeping events, which is the cost of
+//! simulation when nothing inspects the event log.
+//!
+//! Run with `cargo bench`.
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use futures::{future, Future, Poll};
+use simulation::{
+    deterministic::{DeterministicRuntime, EventRetention, FaultConfig},
+    singlethread::SingleThreadedRuntime,
+    spawn_with_result, Environment, TcpListener,
+};
+use std::{net, pin::Pin, task::Context, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Number of tasks spawned per iteration of the spawn benchmark.
+const TASKS: u64 = 100;
+
+/// Number of delays registered per iteration of the timer benchmark.
+const DELAYS: u64 = 100;
+
+/// Number of times each task yields per iteration of the poll benchmark.
+const YIELDS: u64 = 100;
+
+/// Size of the message echoed per iteration of the message benchmark.
+const MESSAGE: usize = 64;
+
+/// A runtime the benchmarks can be run on.
+trait Runtime {
+    type Env: Environment;
+    const NAME: &'static str;
+    fn build() -> Self;
+    fn env(&self) -> Self::Env;
+    fn block_on<F: Future>(&mut self, future: F) -> F::Output;
+    /// Address to bind listeners to.
+    fn listen_addr() -> net::SocketAddr;
+}
+
+impl Runtime for DeterministicRuntime {
+    type Env = simulation::deterministic::DeterministicRuntimeHandle;
+    const NAME: &'static str = "deterministic";
+    fn build() -> Self {
+        DeterministicRuntime::new_with_seed(1).unwrap()
+    }
+    fn env(&self) -> Self::Env {
+        self.handle().new_cluster(FaultConfig::disabled())
+    }
+    fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        DeterministicRuntime::block_on(self, future)
+    }
+    fn listen_addr() -> net::SocketAddr {
+        "127.0.0.1:9092".parse().unwrap()
+    }
+}
+
+/// A `DeterministicRuntime` which keeps no events.
+struct Unrecorded(DeterministicRuntime);
+
+impl Runtime for Unrecorded {
+    type Env = simulation::deterministic::DeterministicRuntimeHandle;
+    const NAME: &'static str = "deterministic-unrecorded";
+    fn build() -> Self {
+        let runtime = DeterministicRuntime::builder()
+            .seed(1)
+            .event_retention(EventRetention::Disabled)
+            .build()
+            .unwrap();
+        Unrecorded(runtime)
+    }
+    fn env(&self) -> Self::Env {
+        self.0.env()
+    }
+    fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        self.0.block_on(future)
+    }
+    fn listen_addr() -> net::SocketAddr {
+        DeterministicRuntime::listen_addr()
+    }
+}
+
+impl Runtime for SingleThreadedRuntime {
+    type Env = simulation::singlethread::SingleThreadedRuntimeHandle;
+    const NAME: &'static str = "singlethread";
+    fn build() -> Self {
+        SingleThreadedRuntime::new().unwrap()
+    }
+    fn env(&self) -> Self::Env {
+        self.handle()
+    }
+    fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        SingleThreadedRuntime::block_on(self, future)
+    }
+    fn listen_addr() -> net::SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+}
+
+/// Spawns `TASKS` empty tasks and waits for all of them.
+fn spawn<R: Runtime>(group: &mut BenchmarkGroup<'_, criterion::measurement::WallTime>) {
+    let mut runtime = R::build();
+    let env = runtime.env();
+    group.bench_function(R::NAME, |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let tasks = (0..TASKS).map(|_| spawn_with_result(&env, async {}));
+                future::join_all(tasks).await;
+            })
+        })
+    });
+}
+
+/// Future which wakes itself and returns `Pending` `remaining` times before completing.
+struct Yield {
+    remaining: u64,
+}
+
+impl Future for Yield {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.remaining == 0 {
+            return Poll::Ready(());
+        }
+        self.remaining -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Spawns a task which yields `YIELDS` times, so it is polled once per yield.
+fn polls<R: Runtime>(group: &mut BenchmarkGroup<'_, criterion::measurement::WallTime>) {
+    let mut runtime = R::build();
+    let env = runtime.env();
+    group.bench_function(R::NAME, |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let task = spawn_with_result(&env, Yield { remaining: YIELDS });
+                task.await;
+            })
+        })
+    });
+}
+
+/// Registers `DELAYS` delays and drops them before they fire.
+fn timers<R: Runtime>(group: &mut BenchmarkGroup<'_, criterion::measurement::WallTime>) {
+    let mut runtime = R::build();
+    let env = runtime.env();
+    group.bench_function(R::NAME, |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut delays = Vec::with_capacity(DELAYS as usize);
+                for i in 0..DELAYS {
+                    let mut delay = env.delay_from(Duration::from_secs(60 + i));
+                    // Poll once so the delay is registered with the timer.
+                    assert!(futures::poll!(&mut delay).is_pending());
+                    delays.push(delay);
+                }
+            })
+        })
+    });
+}
+
+/// Connects to a listener and accepts the connection.
+fn connect<R: Runtime>(group: &mut BenchmarkGroup<'_, criterion::measurement::WallTime>) {
+    let mut runtime = R::build();
+    let env = runtime.env();
+    let mut listener = runtime.block_on(env.bind(R::listen_addr())).unwrap();
+    let addr = listener.local_addr().unwrap();
+    group.bench_function(R::NAME, |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let (client, server) = future::join(env.connect(addr), listener.accept()).await;
+                // Close the accepted side first, so the client's ephemeral port is not held.
+                drop(server.unwrap());
+                drop(client.unwrap());
+            })
+        })
+    });
+}
+
+/// Sends a message over an established connection and waits for it to be echoed back.
+fn message<R: Runtime>(group: &mut BenchmarkGroup<'_, criterion::measurement::WallTime>) {
+    let mut runtime = R::build();
+    let env = runtime.env();
+    let mut listener = runtime.block_on(env.bind(R::listen_addr())).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, server) = runtime.block_on(future::join(env.connect(addr), listener.accept()));
+    let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+    env.spawn(async move {
+        let _listener = listener;
+        let mut buf = [0; MESSAGE];
+        while server.read_exact(&mut buf).await.is_ok() {
+            if server.write_all(&buf).await.is_err() {
+                return;
+            }
+        }
+    });
+    group.bench_function(R::NAME, |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut buf = [1; MESSAGE];
+                client.write_all(&buf).await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+            })
+        })
+    });
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("spawn");
+    group.throughput(Throughput::Elements(TASKS));
+    spawn::<DeterministicRuntime>(&mut group);
+    spawn::<SingleThreadedRuntime>(&mut group);
+    group.finish();
+
+    let mut group = c.benchmark_group("polls");
+    group.throughput(Throughput::Elements(YIELDS));
+    polls::<DeterministicRuntime>(&mut group);
+    polls::<Unrecorded>(&mut group);
+    polls::<SingleThreadedRuntime>(&mut group);
+    group.finish();
+
+    let mut group = c.benchmark_group("timers");
+    group.throughput(Throughput::Elements(DELAYS));
+    timers::<DeterministicRuntime>(&mut group);
+    timers::<SingleThreadedRuntime>(&mut group);
+    group.finish();
+
+    let mut group = c.benchmark_group("connect");
+    group.throughput(Throughput::Elements(1));
+    // Keep the number of real connections made well below the ephemeral port range.
+    group.measurement_time(Duration::from_secs(1));
+    connect::<DeterministicRuntime>(&mut group);
+    connect::<SingleThreadedRuntime>(&mut group);
+    group.finish();
+
+    let mut group = c.benchmark_group("message");
+    group.throughput(Throughput::Bytes(MESSAGE as u64));
+    message::<DeterministicRuntime>(&mut group);
+    message::<SingleThreadedRuntime>(&mut group);
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! Measures how the in-memory network scales with the number of connections.
+//!
+//! Each round binds a listener, connects one simulated client per connection from its own task
+//! and accepts every connection, then reports the wall time taken. Connections are left open
+//! until the end of the round, so the registry of the network holds every connection.
+//!
+//! Run with `cargo run --release --example connect_throughput`.
+use futures::stream::{FuturesUnordered, StreamExt};
+use simulation::{
+    deterministic::{DeterministicRuntime, FaultConfig},
+    Environment, TcpListener,
+};
+use std::{
+    net,
+    time::{Duration, Instant},
+};
+
+/// Makes and accepts `connections` connections, returning the wall time taken.
+fn connect(connections: usize) -> Duration {
+    let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+    let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+    let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+    let start = Instant::now();
+    runtime.block_on(async move {
+        let mut listener = handle.bind(addr).await.unwrap();
+        let (tx, rx) = futures::channel::oneshot::channel();
+        handle.spawn(async move {
+            let mut accepted = Vec::with_capacity(connections);
+            while accepted.len() < connections {
+                accepted.push(listener.accept().await.unwrap());
+            }
+            let _ = tx.send(accepted);
+        });
+        let mut clients = FuturesUnordered::new();
+        for _ in 0..connections {
+            let client = handle.clone();
+            let (tx, rx) = futures::channel::oneshot::channel();
+            handle.spawn(async move {
+                let _ = tx.send(client.connect(addr).await.unwrap());
+            });
+            clients.push(rx);
+        }
+        let clients: Vec<_> = clients.collect().await;
+        let accepted = rx.await.unwrap();
+        assert_eq!(clients.len(), accepted.len());
+    });
+    start.elapsed()
+}
+
+fn main() {
+    for &connections in &[1_000, 10_000, 100_000] {
+        let elapsed = connect(connections);
+        let per_sec = connections as f64 / elapsed.as_secs_f64();
+        println!(
+            "{:>7} connections in {:>8.1?} ({:.0} connections/s)",
+            connections, elapsed, per_sec
+        );
+    }
+}
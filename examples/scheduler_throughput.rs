@@ -0,0 +1,63 @@
+//! Measures how the deterministic executor scales with the number of concurrent tasks.
+//!
+//! Each round spawns one task per simulated client, each of which wakes itself `WAKES` times,
+//! and reports the wall time taken to run every task to completion. Tasks are scheduled through
+//! an intrusive ready queue and counted without looking them up, so the cost per wake should
+//! stay flat as the number of tasks grows.
+//!
+//! Run with `cargo run --release --example scheduler_throughput`.
+use futures::Poll;
+use simulation::deterministic::DeterministicRuntime;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+/// Number of times each task wakes itself before completing.
+const WAKES: usize = 10;
+
+/// Future which wakes its task and yields once before completing.
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Runs `tasks` tasks to completion, returning the wall time taken.
+fn schedule(tasks: usize) -> Duration {
+    let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+    for _ in 0..tasks {
+        runtime.spawn(async {
+            for _ in 0..WAKES {
+                YieldNow { yielded: false }.await;
+            }
+        });
+    }
+    let start = Instant::now();
+    runtime.run().unwrap();
+    start.elapsed()
+}
+
+fn main() {
+    for &tasks in &[1_000, 10_000, 100_000] {
+        let elapsed = schedule(tasks);
+        let polls = tasks * (WAKES + 1);
+        let per_sec = polls as f64 / elapsed.as_secs_f64();
+        println!(
+            "{:>7} tasks polled {} times in {:>8.1?} ({:.0} polls/s)",
+            tasks, polls, elapsed, per_sec
+        );
+    }
+}
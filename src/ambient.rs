@@ -0,0 +1,212 @@
+//! The environment of the task currently executing, and free functions which use it.
+//!
+//! Every runtime installs its handle as the ambient environment while it is running, and tasks
+//! spawned through a handle run with that handle as their ambient environment. A task spawned
+//! through the handle of a simulated cluster therefore connects and binds within that cluster.
+//! The free functions `spawn`, `connect`, `delay_for` and friends use the ambient environment,
+//! so code can be written in the style of Tokio without threading an `Environment` through
+//! every function.
+use crate::{
+    dynamic::{DynEnvironment, TcpListenerObj, TcpStreamObj},
+    Environment, Error, ToSocketAddrs,
+};
+use futures::Poll;
+use pin_project::pin_project;
+use std::{cell::RefCell, future::Future, io, net, pin::Pin, task::Context, time};
+
+thread_local! {
+    static CURRENT: RefCell<Option<DynEnvironment>> = const { RefCell::new(None) };
+}
+
+/// Guard restoring the previously installed environment when dropped.
+#[derive(Debug)]
+pub(crate) struct DefaultGuard {
+    prev: Option<DynEnvironment>,
+}
+
+impl Drop for DefaultGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|current| *current.borrow_mut() = prev);
+    }
+}
+
+/// Installs `env` as the ambient environment of this thread.
+pub(crate) fn set_default<E: Environment + Sync>(env: &E) -> DefaultGuard {
+    let env = DynEnvironment::new(env.clone());
+    let prev = CURRENT.with(|current| current.borrow_mut().replace(env));
+    DefaultGuard { prev }
+}
+
+/// Returns the ambient environment, if any.
+pub fn current() -> Option<DynEnvironment> {
+    with_current(|env| env.cloned())
+}
+
+/// Calls `f` with the ambient environment, if any, without cloning it.
+pub(crate) fn with_current<F, R>(f: F) -> R
+where
+    F: FnOnce(Option<&DynEnvironment>) -> R,
+{
+    CURRENT.with(|current| f(current.borrow().as_ref()))
+}
+
+fn expect_current() -> DynEnvironment {
+    current().expect("no ambient environment, must be called from within a runtime")
+}
+
+/// Runs `future` with `env` as its ambient environment.
+pub fn scope<E, F>(env: E, future: F) -> Scoped<F>
+where
+    E: Environment + Sync,
+    F: Future,
+{
+    Scoped {
+        env: DynEnvironment::new(env),
+        future,
+    }
+}
+
+/// Future returned by `scope`.
+#[pin_project]
+#[derive(Debug)]
+pub struct Scoped<F> {
+    env: DynEnvironment,
+    #[pin]
+    future: F,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let prev = CURRENT.with(|current| current.replace(Some(this.env.clone())));
+        let result = this.future.poll(cx);
+        CURRENT.with(|current| *current.borrow_mut() = prev);
+        result
+    }
+}
+
+/// Spawns `future` onto the ambient environment.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    expect_current().spawn(future)
+}
+
+/// Spawns `future` onto the ambient environment, returning `Error::Spawn` if its executor has
+/// shut down.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn try_spawn<F>(future: F) -> Result<(), Error>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    expect_current().try_spawn(future)
+}
+
+/// Returns the time now according to the ambient environment.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn now() -> time::Instant {
+    expect_current().now()
+}
+
+/// Returns a future which completes at `deadline` according to the ambient environment.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn delay(deadline: time::Instant) -> tokio_timer::Delay {
+    expect_current().delay(deadline)
+}
+
+/// Returns a future which completes after `duration` has elapsed in the ambient environment.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn delay_for(duration: time::Duration) -> tokio_timer::Delay {
+    expect_current().delay_from(duration)
+}
+
+/// Requires `future` to complete before `duration` has elapsed in the ambient environment.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn timeout<F>(duration: time::Duration, future: F) -> crate::Timeout<F> {
+    expect_current().timeout(future, duration)
+}
+
+/// Binds a listener to `addr` in the ambient environment.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub async fn bind<A: Into<net::SocketAddr>>(addr: A) -> io::Result<Box<dyn TcpListenerObj>> {
+    let env = expect_current();
+    env.bind(addr.into()).await
+}
+
+/// Opens a connection to one of the candidate addresses `addr` in the ambient environment.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Box<dyn TcpStreamObj>> {
+    let env = expect_current();
+    env.connect(addr.to_socket_addrs()?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, connect, delay_for, now, spawn};
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        Environment, TcpListener,
+    };
+    use std::{net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that tasks spawned through a cluster's handle use that cluster as their ambient
+    /// environment.
+    fn cluster_scope() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let cluster = handle.new_cluster(FaultConfig::disabled());
+        runtime.block_on(async move {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let (tx, rx) = futures::channel::oneshot::channel();
+            cluster.spawn(async move {
+                let mut listener = bind(addr).await.unwrap();
+                spawn(async move {
+                    let mut socket = connect(addr).await.unwrap();
+                    let mut buf = [0; 4];
+                    socket.read_exact(&mut buf).await.unwrap();
+                    tx.send(buf).unwrap();
+                });
+                let (mut socket, _) = listener.accept().await.unwrap();
+                delay_for(time::Duration::from_secs(10)).await;
+                socket.write_all(b"pong").await.unwrap();
+                let _ = socket.read(&mut [0; 1]).await;
+            });
+            let start = now();
+            assert_eq!(&rx.await.unwrap(), b"pong");
+            assert!(now() - start >= time::Duration::from_secs(10));
+            // the listener is bound in the cluster, not the root network.
+            assert!(connect(addr).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,103 @@
+//! Antithesis style assertions for simulation tests.
+//!
+//! `always!` behaves like `assert!`, failing the current run immediately. `sometimes!`
+//! records whether a condition was ever observed to be true. Across a seed sweep, labels which
+//! were never satisfied indicate that the injected faults are not exercising the states the
+//! test author cares about.
+use std::{cell::RefCell, collections::BTreeMap, sync};
+
+/// Observations of a single `sometimes!` label.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Observations {
+    /// Number of times the assertion was evaluated.
+    pub evaluated: u64,
+    /// Number of times the condition held when evaluated.
+    pub satisfied: u64,
+}
+
+/// Record of every `sometimes!` assertion evaluated during a run.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    inner: sync::Arc<sync::Mutex<BTreeMap<&'static str, Observations>>>,
+}
+
+impl Coverage {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    fn record(&self, label: &'static str, condition: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        let observations = lock.entry(label).or_default();
+        observations.evaluated += 1;
+        if condition {
+            observations.satisfied += 1;
+        }
+    }
+
+    /// Returns a snapshot of the observations for each label.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, Observations> {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Coverage>> = const { RefCell::new(None) };
+}
+
+/// Guard restoring the previously installed `Coverage` when dropped.
+#[derive(Debug)]
+pub(crate) struct DefaultGuard {
+    prev: Option<Coverage>,
+}
+
+impl Drop for DefaultGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|current| *current.borrow_mut() = prev);
+    }
+}
+
+/// Installs `coverage` as the destination for assertions made on this thread.
+pub(crate) fn set_default(coverage: &Coverage) -> DefaultGuard {
+    let prev = CURRENT.with(|current| current.borrow_mut().replace(coverage.clone()));
+    DefaultGuard { prev }
+}
+
+#[doc(hidden)]
+pub fn __sometimes(condition: bool, label: &'static str) {
+    CURRENT.with(|current| {
+        if let Some(coverage) = current.borrow().as_ref() {
+            coverage.record(label, condition);
+        }
+    })
+}
+
+/// Asserts that `cond` holds every time it is evaluated, failing the run immediately otherwise.
+///
+/// ```rust
+/// simulation::always!(1 + 1 == 2);
+/// simulation::always!(1 + 1 == 2, "arithmetic works");
+/// ```
+#[macro_export]
+macro_rules! always {
+    ($cond:expr) => {
+        $crate::always!($cond, stringify!($cond))
+    };
+    ($cond:expr, $label:expr) => {
+        if !$cond {
+            panic!("always! assertion `{}` failed", $label)
+        }
+    };
+}
+
+/// Records whether `cond` held under `label`. A label which is never satisfied across a seed
+/// sweep is reported by [`SweepReport::unreached`].
+///
+/// [`SweepReport::unreached`]: crate::deterministic::SweepReport::unreached
+#[macro_export]
+macro_rules! sometimes {
+    ($cond:expr, $label:expr) => {
+        $crate::assertions::__sometimes($cond, $label)
+    };
+}
@@ -0,0 +1,284 @@
+//! An `Environment` backed by async-std, available with the `async-std` feature.
+//!
+//! Tasks are spawned onto async-std's executor and network operations use `async_std::net`,
+//! so applications built on async-std rather than Tokio can be written generic over
+//! `Environment`, tested under the `DeterministicRuntime` and run in production with the
+//! `AsyncStdRuntime`. Streams are adapted to the `AsyncRead` and `AsyncWrite` traits of Tokio
+//! used by `Environment`. async-std does not provide the `tokio_timer` futures returned by
+//! `delay` and `timeout`, so the runtime drives a Tokio timer on a thread of its own.
+use crate::Error;
+use futures::{stream::BoxStream, Future, Poll, StreamExt};
+use std::{
+    io, net,
+    pin::Pin,
+    sync::{
+        self,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::Context,
+    thread, time,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_executor::park::{Park, ParkThread, Unpark, UnparkThread};
+use tokio_timer::{timer, Timer};
+
+#[derive(Debug, Clone)]
+pub struct AsyncStdRuntimeHandle {
+    timer_handle: timer::Handle,
+}
+
+impl crate::Environment for AsyncStdRuntimeHandle {
+    type TcpStream = TcpStream;
+    type TcpListener = TcpListener;
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        async_std::task::spawn(crate::ambient::scope(self.clone(), future));
+        Ok(())
+    }
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+    fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay {
+        self.timer_handle.delay(deadline)
+    }
+    fn timeout<T>(&self, value: T, timeout: time::Duration) -> crate::Timeout<T> {
+        crate::Timeout::new(self.timer_handle.timeout(value, timeout))
+    }
+    fn rng(&self) -> crate::RngHandle {
+        crate::RngHandle::from_entropy()
+    }
+    fn rng_for(&self, _name: &str) -> crate::RngHandle {
+        crate::RngHandle::from_entropy()
+    }
+    async fn bind<A>(&self, addr: A) -> Result<Self::TcpListener, io::Error>
+    where
+        A: Into<net::SocketAddr> + Send + Sync,
+    {
+        // the listener is bound through std so that a handle to the socket is kept for its TTL,
+        // which async-std does not expose.
+        let socket = net::TcpListener::bind(addr.into())?;
+        let inner = async_std::net::TcpListener::from(socket.try_clone()?);
+        Ok(TcpListener { inner, socket })
+    }
+    async fn connect<A>(&self, addr: A) -> Result<Self::TcpStream, io::Error>
+    where
+        A: crate::ToSocketAddrs + Send + Sync,
+    {
+        let addrs = addr.to_socket_addrs()?;
+        crate::connect::connect_any(self, addrs, |addr| async move {
+            let inner = async_std::net::TcpStream::connect(addr).await?;
+            Ok(TcpStream { inner })
+        })
+        .await
+    }
+}
+
+/// A connection of an `AsyncStdRuntime`.
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: async_std::net::TcpStream,
+}
+
+impl TcpStream {
+    /// Returns the async-std stream.
+    pub fn into_inner(self) -> async_std::net::TcpStream {
+        self.inner
+    }
+}
+
+impl crate::TcpStream for TcpStream {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown(net::Shutdown::Both)
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        async_std::io::Read::poll_read(Pin::new(&mut self.inner), cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        async_std::io::Write::poll_write(Pin::new(&mut self.inner), cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        async_std::io::Write::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        async_std::io::Write::poll_close(Pin::new(&mut self.inner), cx)
+    }
+}
+
+/// A listener of an `AsyncStdRuntime`.
+#[derive(Debug)]
+pub struct TcpListener {
+    inner: async_std::net::TcpListener,
+    /// The same socket as `inner`, through which its TTL is read and set.
+    socket: net::TcpListener,
+}
+
+impl crate::TcpListener for TcpListener {
+    type Stream = TcpStream;
+    type Incoming = BoxStream<'static, io::Result<Self::Stream>>;
+    async fn accept(&mut self) -> Result<(Self::Stream, net::SocketAddr), io::Error> {
+        let (inner, addr) = self.inner.accept().await?;
+        Ok((TcpStream { inner }, addr))
+    }
+    fn incoming(self) -> Self::Incoming {
+        futures::stream::unfold(self, |mut listener| async move {
+            let accepted = crate::TcpListener::accept(&mut listener).await;
+            Some((accepted.map(|(stream, _)| stream), listener))
+        })
+        .boxed()
+    }
+    fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.inner.local_addr()
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        self.socket.ttl()
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.socket.set_ttl(ttl)
+    }
+}
+
+/// A Tokio timer turned by a thread of its own, stopped when dropped.
+#[derive(Debug)]
+struct TimerThread {
+    handle: timer::Handle,
+    unpark: UnparkThread,
+    shutdown: sync::Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TimerThread {
+    fn new() -> Result<Self, Error> {
+        let shutdown = sync::Arc::new(AtomicBool::new(false));
+        let stopped = shutdown.clone();
+        let (tx, rx) = sync::mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("simulation-timer".to_string())
+            .spawn(move || {
+                // the timer parks the thread it is created on, so it is created on this one.
+                let mut timer = Timer::new(ParkThread::new());
+                let _ = tx.send((timer.handle(), timer.unpark()));
+                while !stopped.load(Ordering::SeqCst) {
+                    timer.turn(None).expect("failed to turn timer");
+                }
+            })
+            .map_err(|source| Error::RuntimeBuild { source })?;
+        let (handle, unpark) = rx.recv().expect("timer thread exited");
+        Ok(Self {
+            handle,
+            unpark,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for TimerThread {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.unpark.unpark();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A runtime executing tasks on async-std's executor.
+#[derive(Debug)]
+pub struct AsyncStdRuntime {
+    timer: TimerThread,
+}
+
+impl AsyncStdRuntime {
+    pub fn new() -> Result<Self, Error> {
+        Ok(AsyncStdRuntime {
+            timer: TimerThread::new()?,
+        })
+    }
+
+    pub fn handle(&self) -> AsyncStdRuntimeHandle {
+        AsyncStdRuntimeHandle {
+            timer_handle: self.timer.handle.clone(),
+        }
+    }
+
+    pub fn spawn<F>(&mut self, future: F) -> &mut Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        async_std::task::spawn(crate::ambient::scope(self.handle(), future));
+        self
+    }
+
+    /// Runs `f` to completion on the current thread. Timers stop once the runtime is dropped,
+    /// failing the delays of tasks which are still running.
+    pub fn block_on<F>(&mut self, f: F) -> F::Output
+    where
+        F: Future,
+    {
+        let _ambient = crate::ambient::set_default(&self.handle());
+        async_std::task::block_on(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Environment, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that tasks spawned on async-std can use timers and the real network.
+    fn echo() {
+        let mut runtime = AsyncStdRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            listener.set_ttl(42).unwrap();
+            assert_eq!(listener.ttl().unwrap(), 42);
+            let env = handle.clone();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                env.delay_from(time::Duration::from_millis(10)).await;
+                let mut buf = [0; 4];
+                socket.read_exact(&mut buf).await.unwrap();
+                socket.write_all(&buf).await.unwrap();
+            });
+            let start = handle.now();
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            assert!(handle.now() - start >= time::Duration::from_millis(10));
+            let timeout = handle.timeout(
+                futures::future::pending::<()>(),
+                time::Duration::from_millis(5),
+            );
+            assert!(timeout.await.is_err());
+        });
+    }
+}
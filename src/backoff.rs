@@ -0,0 +1,182 @@
+//! Exponential backoff with jitter.
+//!
+//! Jitter is drawn from `Environment::rng` and delays are awaited with `Environment::delay`,
+//! so under the `DeterministicRuntime` retry timing is repeatable for a seed and long backoffs
+//! complete instantly in simulated time.
+use crate::{Environment, RngHandle};
+use serde::{Deserialize, Serialize};
+use std::{future::Future, time};
+
+/// Configuration for a `Backoff`. Fields missing when a policy is deserialized take their
+/// default values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackoffPolicy {
+    /// The delay before the first retry.
+    pub initial: time::Duration,
+    /// The largest delay between retries, before jitter is applied.
+    pub max: time::Duration,
+    /// Factor the delay is multiplied by after each attempt.
+    pub multiplier: f64,
+    /// Fraction of each delay which is randomized, 0..1. A delay `d` is drawn from
+    /// `d * (1 - jitter)..d`.
+    pub jitter: f64,
+    /// The number of retries after which to give up, or `None` to retry forever.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial: time::Duration::from_millis(100),
+            max: time::Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_retries: None,
+        }
+    }
+}
+
+/// Tracks the retries of an operation, returned by `Environment::backoff`.
+#[derive(Debug)]
+pub struct Backoff<E> {
+    env: E,
+    policy: BackoffPolicy,
+    rng: RngHandle,
+    retries: u32,
+}
+
+impl<E: Environment> Backoff<E> {
+    pub(crate) fn new(env: E, policy: BackoffPolicy) -> Self {
+        let rng = env.rng();
+        Self {
+            env,
+            policy,
+            rng,
+            retries: 0,
+        }
+    }
+
+    /// Returns the number of retries made so far.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Resets the backoff after a successful attempt.
+    pub fn reset(&mut self) {
+        self.retries = 0;
+    }
+
+    /// Returns the delay before the next retry, or `None` if the retries are exhausted.
+    pub fn next_delay(&mut self) -> Option<time::Duration> {
+        if self
+            .policy
+            .max_retries
+            .is_some_and(|max| self.retries >= max)
+        {
+            return None;
+        }
+        let exponent = self.policy.multiplier.powi(self.retries as i32);
+        let base =
+            (self.policy.initial.as_secs_f64() * exponent).min(self.policy.max.as_secs_f64());
+        let jitter = self.policy.jitter.clamp(0.0, 1.0);
+        let scale = if jitter > 0.0 {
+            self.rng.gen_range(1.0 - jitter..1.0)
+        } else {
+            1.0
+        };
+        self.retries += 1;
+        Some(time::Duration::from_secs_f64(base * scale))
+    }
+
+    /// Waits for the next delay, returning false without waiting if the retries are exhausted.
+    pub async fn wait(&mut self) -> bool {
+        match self.next_delay() {
+            Some(delay) => {
+                self.env.delay_from(delay).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs `op` until it succeeds, waiting between attempts. Returns the last error if the
+    /// retries are exhausted.
+    pub async fn retry<F, U, T, Err>(&mut self, mut op: F) -> Result<T, Err>
+    where
+        F: FnMut() -> U,
+        U: Future<Output = Result<T, Err>>,
+    {
+        loop {
+            match op().await {
+                Ok(value) => {
+                    self.reset();
+                    return Ok(value);
+                }
+                Err(err) => {
+                    if !self.wait().await {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::time::Duration;
+
+    fn delays(seed: u64) -> Vec<Duration> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let policy = BackoffPolicy {
+                max_retries: Some(8),
+                ..BackoffPolicy::default()
+            };
+            let mut backoff = handle.backoff(policy);
+            std::iter::from_fn(|| backoff.next_delay()).collect()
+        })
+    }
+
+    #[test]
+    /// Test that delays grow exponentially up to the maximum, with jitter drawn from the seed.
+    fn seeded_jitter() {
+        assert_eq!(delays(1), delays(1));
+        assert_ne!(delays(1), delays(2));
+        let delays = delays(1);
+        assert_eq!(delays.len(), 8);
+        for (retry, delay) in delays.iter().enumerate() {
+            let base =
+                Duration::from_millis(100 * 2u64.pow(retry as u32)).min(Duration::from_secs(10));
+            assert!(*delay <= base && *delay >= base / 2);
+        }
+    }
+
+    #[test]
+    /// Test that retries wait in simulated time and give up once exhausted.
+    fn retry() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let policy = BackoffPolicy {
+                max_retries: Some(3),
+                ..BackoffPolicy::default()
+            };
+            let start = handle.now();
+            let mut attempts = 0;
+            let result: Result<(), u32> = handle
+                .backoff(policy)
+                .retry(|| {
+                    attempts += 1;
+                    futures::future::ready(Err(attempts))
+                })
+                .await;
+            assert_eq!(result, Err(4));
+            assert!(handle.now() - start >= Duration::from_millis(350));
+        });
+    }
+}
@@ -0,0 +1,177 @@
+//! Cooperative fault points, in the style of the `BUGGIFY` macro of FoundationDB.
+//!
+//! `buggify!` marks a point where code may take a rarely exercised path, such as returning an
+//! error early, shrinking a buffer or sleeping before a retry. Under the
+//! `DeterministicRuntime`, each point is enabled for the run with probability
+//! `FaultConfig::buggify_prob` the first time it is evaluated, and an enabled point fires with
+//! probability `FaultConfig::buggify_fire_prob` each time it is evaluated. Outside of the
+//! simulation a point never fires.
+//!
+//! The points enabled and fired during a run are returned by
+//! `DeterministicRuntimeHandle::buggify_points`, included in failure reports and aggregated
+//! across the seeds of a sweep, so points which are never exercised can be spotted.
+use crate::RngHandle;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, sync};
+
+/// Activity of a single `buggify!` point during a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuggifyPoint {
+    /// Whether the point was enabled for the run.
+    pub enabled: bool,
+    /// Number of times the point was evaluated.
+    pub evaluated: u64,
+    /// Number of times the point fired.
+    pub fired: u64,
+}
+
+struct Point {
+    stats: BuggifyPoint,
+    /// Stream deciding whether the point fires, so points do not affect one another.
+    rng: RngHandle,
+}
+
+/// Record of every `buggify!` point evaluated during a run, deciding whether each fires.
+#[derive(Clone)]
+pub(crate) struct Buggify {
+    enable_prob: f64,
+    fire_prob: f64,
+    rng: RngHandle,
+    points: sync::Arc<sync::Mutex<BTreeMap<&'static str, Point>>>,
+}
+
+impl fmt::Debug for Buggify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buggify")
+            .field("enable_prob", &self.enable_prob)
+            .field("fire_prob", &self.fire_prob)
+            .field("points", &self.snapshot())
+            .finish()
+    }
+}
+
+impl Buggify {
+    /// Returns a record enabling points with probability `enable_prob` and firing enabled
+    /// points with probability `fire_prob`, drawing from streams forked from `rng`.
+    pub(crate) fn new(rng: RngHandle, enable_prob: f64, fire_prob: f64) -> Self {
+        Self {
+            enable_prob,
+            fire_prob,
+            rng,
+            points: Default::default(),
+        }
+    }
+
+    /// Evaluates the point `label`, returning whether it fires.
+    pub(crate) fn evaluate(&self, label: &'static str) -> bool {
+        let mut lock = self.points.lock().unwrap();
+        let point = lock.entry(label).or_insert_with(|| {
+            let mut rng = self.rng.fork(label);
+            let enabled = rng.gen::<f64>() < self.enable_prob;
+            Point {
+                stats: BuggifyPoint {
+                    enabled,
+                    ..BuggifyPoint::default()
+                },
+                rng,
+            }
+        });
+        point.stats.evaluated += 1;
+        let fired = point.stats.enabled && point.rng.gen::<f64>() < self.fire_prob;
+        if fired {
+            point.stats.fired += 1;
+        }
+        fired
+    }
+
+    /// Returns a snapshot of the activity of each point.
+    pub(crate) fn snapshot(&self) -> BTreeMap<&'static str, BuggifyPoint> {
+        let lock = self.points.lock().unwrap();
+        lock.iter()
+            .map(|(label, point)| (*label, point.stats))
+            .collect()
+    }
+}
+
+#[doc(hidden)]
+pub fn __buggify(label: &'static str) -> bool {
+    crate::deterministic::context::current().is_some_and(|handle| handle.buggify_point(label))
+}
+
+/// Returns `true` if the fault point `label` fires, letting code take a rarely exercised path
+/// under simulation. Always returns `false` outside of a `DeterministicRuntime`.
+///
+/// ```rust
+/// fn batch_size() -> usize {
+///     if simulation::buggify!("tiny batches") {
+///         1
+///     } else {
+///         1024
+///     }
+/// }
+/// assert_eq!(batch_size(), 1024);
+/// ```
+#[macro_export]
+macro_rules! buggify {
+    ($label:expr) => {
+        $crate::buggify::__buggify($label)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{DeterministicRuntime, FaultConfig};
+
+    fn points(seed: u64, config: FaultConfig) -> Vec<(bool, u64, u64)> {
+        let mut runtime = DeterministicRuntime::builder()
+            .seed(seed)
+            .fault_config(config)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            for _ in 0..100 {
+                crate::buggify!("a");
+                crate::buggify!("b");
+            }
+        });
+        let points = runtime.handle().buggify_points();
+        points
+            .values()
+            .map(|point| (point.enabled, point.evaluated, point.fired))
+            .collect()
+    }
+
+    #[test]
+    /// Test that only enabled points fire, that a seed always makes the same decisions and
+    /// that disabled faults disable every point.
+    fn enabled_and_fired() {
+        let config = FaultConfig {
+            buggify_prob: 0.5,
+            buggify_fire_prob: 0.5,
+            ..FaultConfig::default()
+        };
+        let mut enabled = 0;
+        for seed in 0..16 {
+            let run = points(seed, config.clone());
+            assert_eq!(run, points(seed, config.clone()));
+            for &(point_enabled, evaluated, fired) in &run {
+                assert_eq!(evaluated, 100);
+                if point_enabled {
+                    enabled += 1;
+                    assert!(fired > 0 && fired < 100);
+                } else {
+                    assert_eq!(fired, 0);
+                }
+            }
+        }
+        assert!(enabled > 0 && enabled < 32);
+        for seed in 0..16 {
+            let run = points(seed, FaultConfig::disabled());
+            assert!(run
+                .iter()
+                .all(|&(enabled, _, fired)| !enabled && fired == 0));
+        }
+        assert!(!crate::buggify!("outside"));
+    }
+}
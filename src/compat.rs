@@ -0,0 +1,253 @@
+//! Drop-in replacements for Tokio types, routed through the ambient runtime.
+//!
+//! Threading an `Environment` through a large codebase is a big change. Instead, imports of
+//! `tokio::net::{TcpListener, TcpStream}`, `tokio::timer::{delay, delay_for, Timeout}` and
+//! `tokio::spawn` can be replaced with the items of this module. They use the ambient
+//! environment, see `ambient`, so within a `DeterministicRuntime` they use the simulated
+//! network, clock and executor of the cluster the task was spawned in. Outside of any runtime
+//! they call through to Tokio.
+//!
+//! Unlike Tokio, addresses must be given as a `SocketAddr`, as there is no DNS in the
+//! simulation.
+use crate::{
+    ambient,
+    deterministic::{DeterministicRuntimeHandle, Listener, MemoryStream},
+    Environment,
+};
+use futures::Poll;
+use std::{fmt, future::Future, io, net, pin::Pin, task::Context, time};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Returns the simulated runtime of the ambient environment, if any.
+fn simulated() -> Option<DeterministicRuntimeHandle> {
+    ambient::with_current(|env| env?.downcast_ref::<DeterministicRuntimeHandle>().cloned())
+}
+
+/// Spawns `future` onto the current runtime.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match ambient::current() {
+        Some(env) => env.spawn(future),
+        None => {
+            tokio::spawn(future);
+        }
+    }
+}
+
+/// Returns the current time according to the current runtime.
+pub fn now() -> time::Instant {
+    match ambient::current() {
+        Some(env) => env.now(),
+        None => tokio_timer::clock::now(),
+    }
+}
+
+/// Returns a future which completes at `deadline`.
+pub fn delay(deadline: time::Instant) -> tokio_timer::Delay {
+    match ambient::current() {
+        Some(env) => env.delay(deadline),
+        None => tokio_timer::delay(deadline),
+    }
+}
+
+/// Returns a future which completes after `duration` has elapsed.
+pub fn delay_for(duration: time::Duration) -> tokio_timer::Delay {
+    delay(now() + duration)
+}
+
+/// Requires `future` to complete before `duration` has elapsed.
+pub fn timeout<F: Future>(duration: time::Duration, future: F) -> crate::Timeout<F> {
+    match ambient::current() {
+        Some(env) => env.timeout(future, duration),
+        None => crate::Timeout::new(tokio_timer::Timeout::new(future, duration)),
+    }
+}
+
+/// A TCP connection, to either the simulated network or a real socket.
+#[derive(Debug)]
+pub enum TcpStream {
+    Simulated(MemoryStream),
+    Tokio(tokio::net::TcpStream),
+}
+
+impl TcpStream {
+    /// Opens a connection to `addr`.
+    pub async fn connect<A: Into<net::SocketAddr>>(addr: A) -> io::Result<TcpStream> {
+        let addr = addr.into();
+        match simulated() {
+            Some(handle) => Ok(TcpStream::Simulated(handle.connect(addr).await?)),
+            None => Ok(TcpStream::Tokio(
+                tokio::net::TcpStream::connect(addr).await?,
+            )),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        match self {
+            TcpStream::Simulated(stream) => Ok(stream.local_addr()),
+            TcpStream::Tokio(stream) => stream.local_addr(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        match self {
+            TcpStream::Simulated(stream) => Ok(stream.peer_addr()),
+            TcpStream::Tokio(stream) => stream.peer_addr(),
+        }
+    }
+}
+
+impl crate::TcpStream for TcpStream {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            TcpStream::Simulated(stream) => crate::TcpStream::shutdown(stream),
+            TcpStream::Tokio(stream) => crate::TcpStream::shutdown(stream),
+        }
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TcpStream::Simulated(stream) => Pin::new(stream).poll_read(cx, buf),
+            TcpStream::Tokio(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TcpStream::Simulated(stream) => Pin::new(stream).poll_write(cx, buf),
+            TcpStream::Tokio(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TcpStream::Simulated(stream) => Pin::new(stream).poll_flush(cx),
+            TcpStream::Tokio(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TcpStream::Simulated(stream) => Pin::new(stream).poll_shutdown(cx),
+            TcpStream::Tokio(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A TCP listener, bound in either the simulated network or on a real socket.
+pub enum TcpListener {
+    Simulated(Listener),
+    Tokio(tokio::net::TcpListener),
+}
+
+impl fmt::Debug for TcpListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpListener::Simulated(listener) => f
+                .debug_tuple("Simulated")
+                .field(&crate::TcpListener::local_addr(listener).ok())
+                .finish(),
+            TcpListener::Tokio(listener) => f.debug_tuple("Tokio").field(listener).finish(),
+        }
+    }
+}
+
+impl TcpListener {
+    /// Binds a listener to `addr`.
+    pub async fn bind<A: Into<net::SocketAddr>>(addr: A) -> io::Result<TcpListener> {
+        let addr = addr.into();
+        match simulated() {
+            Some(handle) => Ok(TcpListener::Simulated(handle.bind(addr).await?)),
+            None => Ok(TcpListener::Tokio(
+                tokio::net::TcpListener::bind(addr).await?,
+            )),
+        }
+    }
+
+    /// Accepts a new connection.
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, net::SocketAddr)> {
+        match self {
+            TcpListener::Simulated(listener) => {
+                let (stream, addr) = crate::TcpListener::accept(listener).await?;
+                Ok((TcpStream::Simulated(stream), addr))
+            }
+            TcpListener::Tokio(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((TcpStream::Tokio(stream), addr))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        match self {
+            TcpListener::Simulated(listener) => crate::TcpListener::local_addr(listener),
+            TcpListener::Tokio(listener) => listener.local_addr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that the compat types use the simulated network and clock within a runtime.
+    fn simulated() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = TcpListener::bind(addr).await.unwrap();
+            assert!(matches!(listener, TcpListener::Simulated(_)));
+            spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                delay_for(time::Duration::from_secs(60)).await;
+                socket.write_all(b"pong").await.unwrap();
+                let _ = socket.read(&mut [0; 1]).await;
+            });
+            let start = now();
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            let mut buf = [0; 4];
+            let result = timeout(time::Duration::from_secs(120), socket.read_exact(&mut buf)).await;
+            assert!(result.is_ok());
+            assert!(handle.now() - start >= time::Duration::from_secs(60));
+        });
+    }
+
+    #[test]
+    /// Test that the compat types use the cluster a task was spawned in.
+    fn cluster() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let cluster = runtime
+            .handle()
+            .new_cluster(crate::deterministic::FaultConfig::disabled());
+        let (tx, rx) = futures::channel::oneshot::channel();
+        cluster.spawn(async move {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let listener = TcpListener::bind(addr).await.unwrap();
+            let _ = tx.send(listener.local_addr().unwrap());
+        });
+        let addr = runtime.block_on(rx).unwrap();
+        assert_eq!(addr, net::SocketAddr::new(cluster.local_ip(), 9092));
+    }
+}
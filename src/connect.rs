@@ -0,0 +1,226 @@
+//! Connecting to one of several candidate addresses.
+//!
+//! `Environment::connect` accepts any `ToSocketAddrs`, and tries each candidate address in the
+//! style of Happy Eyeballs (RFC 8305). Addresses are interleaved by family, and a new attempt is
+//! started whenever the previous attempt fails or has not completed within
+//! `CONNECTION_ATTEMPT_DELAY`. The first attempt to succeed is used, and the others are
+//! dropped. Under the `DeterministicRuntime` each address is connected through its own seeded
+//! fault stream, so whether an address is slow or refuses the connection is reproducible.
+use crate::Environment;
+use futures::{
+    future::{self, Either},
+    stream::{FuturesUnordered, StreamExt},
+    Future,
+};
+use std::{io, net, time};
+
+/// Delay after which a new connection attempt is started while earlier attempts are pending.
+pub const CONNECTION_ATTEMPT_DELAY: time::Duration = time::Duration::from_millis(250);
+
+/// Types which can be converted to candidate addresses to connect to. Unlike
+/// `std::net::ToSocketAddrs`, hostnames are not resolved, as there is no DNS in the simulation.
+pub trait ToSocketAddrs {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>>;
+}
+
+impl ToSocketAddrs for net::SocketAddr {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        Ok(vec![*self])
+    }
+}
+
+impl ToSocketAddrs for net::SocketAddrV4 {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        Ok(vec![(*self).into()])
+    }
+}
+
+impl ToSocketAddrs for net::SocketAddrV6 {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        Ok(vec![(*self).into()])
+    }
+}
+
+impl<I: Into<net::IpAddr> + Copy> ToSocketAddrs for (I, u16) {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        Ok(vec![net::SocketAddr::new(self.0.into(), self.1)])
+    }
+}
+
+impl ToSocketAddrs for str {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        let addr = self.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a socket address", self),
+            )
+        })?;
+        Ok(vec![addr])
+    }
+}
+
+impl ToSocketAddrs for String {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        self.as_str().to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for [net::SocketAddr] {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        Ok(self.to_vec())
+    }
+}
+
+impl<const N: usize> ToSocketAddrs for [net::SocketAddr; N] {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        Ok(self.to_vec())
+    }
+}
+
+impl ToSocketAddrs for Vec<net::SocketAddr> {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        Ok(self.clone())
+    }
+}
+
+impl<T: ToSocketAddrs + ?Sized> ToSocketAddrs for &T {
+    fn to_socket_addrs(&self) -> io::Result<Vec<net::SocketAddr>> {
+        (**self).to_socket_addrs()
+    }
+}
+
+/// Orders `addrs` so that address families alternate, starting with the family of the first
+/// address.
+fn interleave(addrs: Vec<net::SocketAddr>) -> Vec<net::SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut ordered = vec![];
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Connects to the first of `addrs` to accept a connection made with `connect`, starting
+/// attempts in turn as described in the module documentation.
+pub(crate) async fn connect_any<E, F, U, S>(
+    env: &E,
+    addrs: Vec<net::SocketAddr>,
+    connect: F,
+) -> io::Result<S>
+where
+    E: Environment,
+    F: Fn(net::SocketAddr) -> U,
+    U: Future<Output = io::Result<S>>,
+{
+    let mut addrs = interleave(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(connect(addr)),
+                None => {
+                    return Err(last_err.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+                    }))
+                }
+            }
+        }
+        let completed = if addrs.peek().is_some() {
+            let delay = env.delay_from(CONNECTION_ATTEMPT_DELAY);
+            match future::select(attempts.next(), delay).await {
+                Either::Left((completed, _)) => completed,
+                Either::Right(_) => None,
+            }
+        } else {
+            attempts.next().await
+        };
+        match completed {
+            Some(Ok(stream)) => return Ok(stream),
+            Some(Err(err)) => last_err = Some(err),
+            None => {}
+        }
+        if let Some(addr) = addrs.next() {
+            attempts.push(connect(addr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        TcpListener,
+    };
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    /// Test that a new attempt is started when an earlier attempt stalls or fails.
+    fn fallback() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let stalled: net::SocketAddr = "[::1]:1".parse().unwrap();
+            let refused: net::SocketAddr = "10.0.0.1:2".parse().unwrap();
+            let open: net::SocketAddr = "[::1]:3".parse().unwrap();
+            let start = handle.now();
+            let connect = |addr: net::SocketAddr| {
+                let handle = handle.clone();
+                async move {
+                    match addr.port() {
+                        1 => future::pending().await,
+                        2 => Err(io::ErrorKind::ConnectionRefused.into()),
+                        _ => Ok((addr, handle.now())),
+                    }
+                }
+            };
+            let (addr, at) = connect_any(&handle, vec![stalled, open, refused], connect)
+                .await
+                .unwrap();
+            assert_eq!(addr, open);
+            // the refused IPv4 address is tried second, then the open address immediately.
+            assert_eq!(at - start, CONNECTION_ATTEMPT_DELAY);
+
+            let err = connect_any(&handle, vec![refused], connect)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let err = connect_any(&handle, vec![], connect).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    /// Test that `Environment::connect` falls back to a candidate address with a listener.
+    fn candidates() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        runtime.block_on(async {
+            let mut listener = handle.bind(([127, 0, 0, 1], 9092)).await.unwrap();
+            handle.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    let _ = socket.read(&mut [0; 1]).await;
+                }
+            });
+            let candidates = [
+                "127.0.0.1:9091".parse().unwrap(),
+                "127.0.0.1:9092".parse().unwrap(),
+            ];
+            let stream = handle.connect(candidates).await.unwrap();
+            assert_eq!(stream.peer_addr().port(), 9092);
+            let stream = handle.connect("127.0.0.1:9092").await.unwrap();
+            assert_eq!(stream.peer_addr().port(), 9092);
+        });
+    }
+}
@@ -0,0 +1,272 @@
+//! Audit trail of the draws made from the random streams of a run.
+//!
+//! A refactor which draws from a stream in a different order, or from a task whose scheduling
+//! changed, alters every decision made after it, which shows up much later as a seed no longer
+//! reproducing its run. With `Builder::audit_rng`, every draw is recorded along with the stream
+//! it was drawn from, and `diff_draws` compares the draws of two runs stream by stream, pointing
+//! at the first draw of each stream which differs.
+//!
+//! ```
+//! # use simulation::{deterministic::{self, DeterministicRuntime}, Environment};
+//! # use rand::Rng;
+//! let run = |draws: usize| {
+//!     let mut runtime = DeterministicRuntime::builder().audit_rng().build().unwrap();
+//!     let handle = runtime.handle();
+//!     runtime.block_on(async {
+//!         let mut rng = handle.rng_for("backoff");
+//!         for _ in 0..draws {
+//!             rng.gen::<u64>();
+//!         }
+//!     });
+//!     runtime.handle().rng_draws()
+//! };
+//! let divergences = deterministic::diff_draws(&run(3), &run(2));
+//! assert_eq!(divergences.len(), 1);
+//! assert_eq!(divergences[0].stream, r#"("component", "backoff")"#);
+//! assert_eq!(divergences[0].index, 2);
+//! ```
+use super::{rng::stable_hash, task, TaskId};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync};
+
+/// A value drawn from a random stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RngDraw {
+    /// Key the stream was derived from, such as the component name passed to
+    /// `Environment::rng_for` or the callsite of a primitive.
+    pub stream: String,
+    /// Number of draws previously made from the stream.
+    pub index: u64,
+    /// The value drawn. Filled byte buffers are recorded as a hash of their contents.
+    pub value: u64,
+    /// Task which made the draw, if it was made by a task.
+    pub task: Option<TaskId>,
+}
+
+impl fmt::Display for RngDraw {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.value)?;
+        match self.task {
+            Some(task) => write!(f, " by task {}", task.0),
+            None => write!(f, " outside of any task"),
+        }
+    }
+}
+
+/// The first draw of a stream which differs between two runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrawDivergence {
+    pub stream: String,
+    /// Number of draws from the stream which were the same in both runs.
+    pub index: u64,
+    /// The draw made by the first run, or `None` if it made fewer draws from the stream.
+    pub before: Option<RngDraw>,
+    /// The draw made by the second run, or `None` if it made fewer draws from the stream.
+    pub after: Option<RngDraw>,
+}
+
+impl fmt::Display for DrawDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let draw = |draw: &Option<RngDraw>| match draw {
+            Some(draw) => draw.to_string(),
+            None => "no draw".to_string(),
+        };
+        write!(
+            f,
+            "stream {} diverges at draw {}: {} before, {} after",
+            self.stream,
+            self.index,
+            draw(&self.before),
+            draw(&self.after)
+        )
+    }
+}
+
+/// Compares the draws recorded by two runs, returning the first differing draw of each stream
+/// on which they disagree, ordered by how early the divergence happened in either run. Draws
+/// are the same if the same value was drawn by the same task. Returns an empty list if every
+/// stream produced the same draws in both runs.
+pub fn diff_draws(before: &[RngDraw], after: &[RngDraw]) -> Vec<DrawDivergence> {
+    /// Groups draws by stream, along with their position in the run.
+    fn streams(draws: &[RngDraw]) -> HashMap<&str, Vec<(usize, &RngDraw)>> {
+        let mut streams = HashMap::<_, Vec<_>>::new();
+        for (position, draw) in draws.iter().enumerate() {
+            streams
+                .entry(draw.stream.as_str())
+                .or_default()
+                .push((position, draw));
+        }
+        streams
+    }
+    let (before, after) = (streams(before), streams(after));
+    let empty = vec![];
+    let mut names: Vec<_> = before.keys().chain(after.keys()).copied().collect();
+    names.sort();
+    names.dedup();
+    let mut divergences = vec![];
+    for name in names {
+        let (a, b) = (
+            before.get(name).unwrap_or(&empty),
+            after.get(name).unwrap_or(&empty),
+        );
+        let same = |(x, y): (&(usize, &RngDraw), &(usize, &RngDraw))| {
+            (x.1.value, x.1.task) == (y.1.value, y.1.task)
+        };
+        let index = a
+            .iter()
+            .zip(b.iter())
+            .take_while(|&pair| same(pair))
+            .count();
+        let (x, y) = (a.get(index), b.get(index));
+        if x.is_none() && y.is_none() {
+            continue;
+        }
+        let position = x.iter().chain(y.iter()).map(|draw| draw.0).min();
+        let divergence = DrawDivergence {
+            stream: name.to_string(),
+            index: index as u64,
+            before: x.map(|draw| draw.1.clone()),
+            after: y.map(|draw| draw.1.clone()),
+        };
+        divergences.push((position, divergence));
+    }
+    divergences.sort_by_key(|(position, _)| *position);
+    divergences
+        .into_iter()
+        .map(|(_, divergence)| divergence)
+        .collect()
+}
+
+/// Shared log of the draws made from the streams of a runtime.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RngAudit {
+    draws: sync::Arc<sync::Mutex<Vec<RngDraw>>>,
+}
+
+impl RngAudit {
+    /// Wraps the generator of the stream described by `stream`, recording its draws.
+    pub(crate) fn record(
+        &self,
+        rng: Box<dyn RngCore + Send>,
+        stream: &dyn fmt::Debug,
+    ) -> Box<dyn RngCore + Send> {
+        Box::new(AuditedRng {
+            rng,
+            stream: format!("{:?}", stream),
+            draws: 0,
+            audit: self.clone(),
+        })
+    }
+
+    pub(crate) fn draws(&self) -> Vec<RngDraw> {
+        self.draws.lock().unwrap().clone()
+    }
+}
+
+struct AuditedRng {
+    rng: Box<dyn RngCore + Send>,
+    stream: String,
+    draws: u64,
+    audit: RngAudit,
+}
+
+impl AuditedRng {
+    fn push(&mut self, value: u64) {
+        let draw = RngDraw {
+            stream: self.stream.clone(),
+            index: self.draws,
+            value,
+            task: task::current(),
+        };
+        self.draws += 1;
+        self.audit.draws.lock().unwrap().push(draw);
+    }
+}
+
+impl RngCore for AuditedRng {
+    fn next_u32(&mut self) -> u32 {
+        let value = self.rng.next_u32();
+        self.push(u64::from(value));
+        value
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let value = self.rng.next_u64();
+        self.push(value);
+        value
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest);
+        self.push(stable_hash(dest));
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)?;
+        self.push(stable_hash(dest));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use rand::Rng;
+
+    /// Runs two tasks drawing from a shared stream, the first every 5 milliseconds and the
+    /// second every `wait` milliseconds, returning the draws recorded.
+    fn run(wait: u64) -> Vec<RngDraw> {
+        let mut runtime = DeterministicRuntime::builder().audit_rng().build().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let mut tasks = vec![];
+            for (i, wait) in [5, wait].iter().enumerate() {
+                let env = handle.clone();
+                let wait = std::time::Duration::from_millis(*wait);
+                tasks.push(crate::spawn_with_result(&handle, async move {
+                    let mut shared = env.rng_for("shared");
+                    let mut own = env.rng_for(&format!("own {}", i));
+                    for _ in 0..3 {
+                        env.delay_from(wait).await;
+                        shared.gen::<u32>();
+                        own.gen::<u64>();
+                    }
+                }));
+            }
+            futures::future::join_all(tasks).await;
+        });
+        runtime.handle().rng_draws()
+    }
+
+    #[test]
+    /// Test that draws are recorded with their stream and task, and that runs of the same seed
+    /// have no divergences.
+    fn record() {
+        let draws = run(20);
+        assert_eq!(draws.len(), 12);
+        let own: Vec<_> = draws
+            .iter()
+            .filter(|draw| draw.stream == r#"("component", "own 1")"#)
+            .map(|draw| (draw.index, draw.task))
+            .collect();
+        let task = Some(TaskId(2));
+        assert_eq!(own, vec![(0, task), (1, task), (2, task)]);
+        assert!(diff_draws(&draws, &run(20)).is_empty());
+    }
+
+    #[test]
+    /// Test that reordering the draws made from a stream is reported at the first draw which
+    /// differs, while streams drawn from in the same order are not reported.
+    fn diff() {
+        let divergences = diff_draws(&run(20), &run(7));
+        assert_eq!(divergences.len(), 1, "{:?}", divergences);
+        let divergence = &divergences[0];
+        assert_eq!(divergence.stream, r#"("component", "shared")"#);
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.before.as_ref().unwrap().task, Some(TaskId(1)));
+        assert_eq!(divergence.after.as_ref().unwrap().task, Some(TaskId(2)));
+        assert!(divergence.to_string().contains("diverges at draw 1"));
+    }
+}
@@ -0,0 +1,166 @@
+//! Fault bisection for localizing the root cause of a failing seed.
+//!
+//! A failing seed is re-run with subsets of its injected faults, using delta debugging to
+//! find a minimal set of faults which still causes the failure.
+use super::{DeterministicRuntime, FaultId, FaultRecord};
+use std::panic;
+
+/// Result of bisecting the faults of a failing seed.
+#[derive(Debug, Clone)]
+pub struct Bisection {
+    pub seed: u64,
+    /// Every fault injected by the original failing run.
+    pub injected: Vec<FaultRecord>,
+    /// A minimal set of faults which is sufficient to cause the failure. Empty if the test
+    /// fails without any faults being injected.
+    pub minimal: Vec<FaultRecord>,
+    /// Number of times the test was run while bisecting.
+    pub runs: usize,
+}
+
+/// Runs `test` with `seed`, allowing only `allowed` faults if provided. Returns the faults
+/// injected if the test failed.
+fn run_with<F>(seed: u64, allowed: Option<&[FaultId]>, test: &F) -> Option<Vec<FaultRecord>>
+where
+    F: Fn(&mut DeterministicRuntime),
+{
+    let mut runtime = DeterministicRuntime::new_with_seed(seed).expect("failed to build runtime");
+    if let Some(allowed) = allowed {
+        runtime.allow_only_faults(allowed.iter().cloned());
+    }
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| test(&mut runtime)));
+    match result {
+        Ok(()) => None,
+        Err(_) => Some(runtime.faults()),
+    }
+}
+
+/// Finds a minimal set of the faults injected by `seed` which causes `test` to fail.
+///
+/// Returns `None` if `test` does not fail with `seed`. Suppressing a fault can change the
+/// execution of the remainder of the run, so the result is a best effort localization.
+pub fn bisect_faults<F>(seed: u64, test: F) -> Option<Bisection>
+where
+    F: Fn(&mut DeterministicRuntime),
+{
+    let injected = run_with(seed, None, &test)?;
+    let mut runs = 1;
+    let mut fails = |allowed: &[FaultId]| {
+        runs += 1;
+        run_with(seed, Some(allowed), &test)
+    };
+    if let Some(minimal) = fails(&[]) {
+        return Some(Bisection {
+            seed,
+            injected,
+            minimal,
+            runs,
+        });
+    }
+
+    // ddmin, see "Simplifying and Isolating Failure-Inducing Input" by Zeller and Hildebrandt.
+    let mut candidate: Vec<FaultId> = injected.iter().map(|fault| fault.id).collect();
+    let mut minimal = injected.clone();
+    let mut granularity = 2;
+    while candidate.len() >= 2 {
+        let chunk_size = candidate.len().div_ceil(granularity);
+        let chunks: Vec<Vec<FaultId>> = candidate.chunks(chunk_size).map(|c| c.to_vec()).collect();
+        let mut reduced = false;
+        for chunk in &chunks {
+            if let Some(faults) = fails(chunk) {
+                candidate = chunk.clone();
+                minimal = faults;
+                granularity = 2;
+                reduced = true;
+                break;
+            }
+        }
+        if !reduced && granularity > 2 {
+            for chunk in &chunks {
+                let complement: Vec<FaultId> = candidate
+                    .iter()
+                    .filter(|id| !chunk.contains(id))
+                    .cloned()
+                    .collect();
+                if let Some(faults) = fails(&complement) {
+                    candidate = complement;
+                    minimal = faults;
+                    granularity -= 1;
+                    reduced = true;
+                    break;
+                }
+            }
+        }
+        if !reduced {
+            if granularity >= candidate.len() {
+                break;
+            }
+            granularity = std::cmp::min(granularity * 2, candidate.len());
+        }
+    }
+    Some(Bisection {
+        seed,
+        injected,
+        minimal,
+        runs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::FaultKind;
+    use crate::{Environment, TcpListener};
+    use futures::{SinkExt, StreamExt};
+    use std::net;
+    use tokio::codec::{Framed, LinesCodec};
+
+    /// Exchanges messages with an echo server, failing if the connection is disrupted.
+    fn echo(runtime: &mut DeterministicRuntime) {
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    let mut transport = Framed::new(socket, LinesCodec::new());
+                    while let Some(Ok(line)) = transport.next().await {
+                        if transport.send(line).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+            let socket = handle.connect(addr).await.unwrap();
+            let mut transport = Framed::new(socket, LinesCodec::new());
+            for idx in 0..100usize {
+                transport.send(idx.to_string()).await.unwrap();
+                let line = transport.next().await.unwrap().unwrap();
+                assert_eq!(line, idx.to_string());
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a failure caused by a disconnect is reduced to fewer faults which still
+    /// include the disconnect.
+    fn localizes_disconnect() {
+        let seed = (0..100)
+            .find(|seed| run_with(*seed, None, &echo).is_some())
+            .expect("expected a seed to fail");
+        let bisection = bisect_faults(seed, echo).unwrap();
+        assert!(bisection.minimal.len() < bisection.injected.len());
+        assert!(bisection
+            .minimal
+            .iter()
+            .any(|fault| fault.kind == FaultKind::Disconnect));
+        let allowed: Vec<FaultId> = bisection.minimal.iter().map(|f| f.id).collect();
+        assert!(run_with(seed, Some(&allowed), &echo).is_some());
+    }
+
+    #[test]
+    /// Test that passing seeds are not bisected.
+    fn passing_seed() {
+        assert!(bisect_faults(0, |_| {}).is_none());
+    }
+}
@@ -0,0 +1,599 @@
+//! Configuration of a `DeterministicRuntime`.
+use super::{
+    assertions, buggify, event, fault, invariant, network, rng, task, DeterministicRuntime,
+    DeterministicRuntimeHandle, EventRetention, FaultConfig, Linger, LoggedEvent, RngAlgorithm,
+    Seed, SimObserver, SmallRngAlgorithm, Time, Watermarks,
+};
+use crate::Error;
+use std::{collections::HashMap, fmt, ops, path, sync, time};
+
+/// Observer shared by every runtime built by a builder.
+type Observer = sync::Arc<sync::Mutex<dyn FnMut(&LoggedEvent) + Send>>;
+
+/// Builds a `DeterministicRuntime`, configuring its seed, faults, network topology, RNG, time
+/// and observers in one place.
+///
+/// ```
+/// # use simulation::deterministic::{DeterministicRuntime, FaultConfig};
+/// # use std::time::Duration;
+/// let runtime = DeterministicRuntime::builder()
+///     .seed(42)
+///     .fault_config(FaultConfig::disabled())
+///     .cluster("east", FaultConfig::default())
+///     .cluster("west", FaultConfig::default())
+///     .link("east", "west", FaultConfig::default())
+///     .max_sim_time(Duration::from_secs(600))
+///     .build()
+///     .unwrap();
+/// assert!(runtime.cluster("east").is_some());
+/// ```
+#[derive(Clone)]
+pub struct Builder {
+    seed: u64,
+    algorithm: rng::Algorithm,
+    audit_rng: bool,
+    fault_config: FaultConfig,
+    clusters: Vec<(String, FaultConfig)>,
+    links: Vec<(String, String, FaultConfig)>,
+    max_sim_time: Option<time::Duration>,
+    max_events: Option<u64>,
+    event_retention: EventRetention,
+    memory_budget: Option<u64>,
+    observers: Vec<Observer>,
+    failure_report: Option<path::PathBuf>,
+    fail_on_leaks: bool,
+    blocking_threshold: Option<time::Duration>,
+    fail_on_blocking: bool,
+    watermarks: Watermarks,
+    ephemeral_ports: ops::RangeInclusive<u16>,
+    randomize_ephemeral_ports: bool,
+    linger: Linger,
+    start_time: Option<time::SystemTime>,
+    announce_seed: bool,
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("seed", &self.seed)
+            .field("algorithm", &self.algorithm.name())
+            .field("audit_rng", &self.audit_rng)
+            .field("fault_config", &self.fault_config)
+            .field("clusters", &self.clusters)
+            .field("links", &self.links)
+            .field("max_sim_time", &self.max_sim_time)
+            .field("max_events", &self.max_events)
+            .field("event_retention", &self.event_retention)
+            .field("memory_budget", &self.memory_budget)
+            .field("observers", &self.observers.len())
+            .field("failure_report", &self.failure_report)
+            .field("fail_on_leaks", &self.fail_on_leaks)
+            .field("blocking_threshold", &self.blocking_threshold)
+            .field("fail_on_blocking", &self.fail_on_blocking)
+            .field("watermarks", &self.watermarks)
+            .field("ephemeral_ports", &self.ephemeral_ports)
+            .field("randomize_ephemeral_ports", &self.randomize_ephemeral_ports)
+            .field("linger", &self.linger)
+            .field("start_time", &self.start_time)
+            .field("announce_seed", &self.announce_seed)
+            .finish()
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            algorithm: rng::Algorithm::new(SmallRngAlgorithm),
+            audit_rng: false,
+            fault_config: FaultConfig::default(),
+            clusters: vec![],
+            links: vec![],
+            max_sim_time: None,
+            max_events: None,
+            event_retention: EventRetention::All,
+            memory_budget: None,
+            observers: vec![],
+            failure_report: None,
+            fail_on_leaks: false,
+            blocking_threshold: None,
+            fail_on_blocking: false,
+            watermarks: Watermarks::default(),
+            ephemeral_ports: network::EPHEMERAL_PORTS,
+            randomize_ephemeral_ports: false,
+            linger: Linger::default(),
+            start_time: None,
+            announce_seed: true,
+        }
+    }
+}
+
+impl Builder {
+    /// Returns a builder for a runtime with seed 0, the default `FaultConfig`, the
+    /// `SmallRngAlgorithm` and no limit on simulated time.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the seed driving scheduling and fault injection.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sets the seed from its decimal, hexadecimal or word form, as parsed by `Seed`, such as
+    /// one copied from the output of a failed run. Fails with `Error::InvalidSeed` if `seed` is
+    /// in none of these forms.
+    pub fn seed_str(self, seed: &str) -> Result<Self, Error> {
+        let seed: Seed = seed.parse()?;
+        Ok(self.seed(seed.0))
+    }
+
+    /// Sets the algorithm generating the random streams of the runtime. Changing the algorithm
+    /// changes the run produced by a seed.
+    pub fn rng<A: RngAlgorithm>(self, algorithm: A) -> Self {
+        self.algorithm(rng::Algorithm::new(algorithm))
+    }
+
+    pub(crate) fn algorithm(mut self, algorithm: rng::Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Records every value drawn from the random streams of the runtime, returned by
+    /// `DeterministicRuntimeHandle::rng_draws`. Comparing the draws of two runs with
+    /// `diff_draws` finds where a seed stopped reproducing its run.
+    pub fn audit_rng(mut self) -> Self {
+        self.audit_rng = true;
+        self
+    }
+
+    /// Sets the faults injected into the network of the runtime's handle.
+    pub fn fault_config(mut self, config: FaultConfig) -> Self {
+        self.fault_config = config;
+        self
+    }
+
+    /// Adds a cluster named `name` with its own address space, injecting faults according to
+    /// `config`. A handle to the cluster, whose hostname is `name`, is returned by
+    /// `DeterministicRuntime::cluster`.
+    pub fn cluster(mut self, name: &str, config: FaultConfig) -> Self {
+        self.clusters.push((name.to_string(), config));
+        self
+    }
+
+    /// Allows connections made from the cluster `from` to reach listeners bound in the cluster
+    /// `to`, injecting faults according to `config`. Links are one way.
+    pub fn link(mut self, from: &str, to: &str, config: FaultConfig) -> Self {
+        self.links.push((from.to_string(), to.to_string(), config));
+        self
+    }
+
+    /// Sets the amount of simulated time which may elapse before the runtime panics, to catch
+    /// runs which never complete.
+    pub fn max_sim_time(mut self, limit: time::Duration) -> Self {
+        self.max_sim_time = Some(limit);
+        self
+    }
+
+    /// Sets the number of events which may be recorded before the runtime panics, describing
+    /// which tasks were polled the most. Unlike `max_sim_time`, this catches runs which spin
+    /// without time advancing, bounding the real time taken by any seed of a sweep.
+    pub fn max_events(mut self, limit: u64) -> Self {
+        self.max_events = Some(limit);
+        self
+    }
+
+    /// Sets which recorded events the runtime keeps, see `EventRetention`. Every event is kept
+    /// by default, so long soak runs should keep only the most recent events or none.
+    pub fn event_retention(mut self, retention: EventRetention) -> Self {
+        self.event_retention = retention;
+        self
+    }
+
+    /// Sets the number of bytes each host may hold in simulated connection buffers and message
+    /// queues before the runtime panics, catching simulations which buffer data without bound.
+    /// The memory held by each host is reported by `RunStats::memory` either way.
+    pub fn memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Adds an observer which is called with every event recorded by the runtime. Clones of the
+    /// builder share the observer, so it sees the events of every runtime built from them.
+    pub fn observer<F>(mut self, observer: F) -> Self
+    where
+        F: FnMut(&LoggedEvent) + Send + 'static,
+    {
+        self.observers
+            .push(sync::Arc::new(sync::Mutex::new(observer)));
+        self
+    }
+
+    /// Adds a `SimObserver`, whose callbacks are called with every event recorded by the
+    /// runtime.
+    pub fn sim_observer<O: SimObserver>(self, mut observer: O) -> Self {
+        self.observer(move |logged: &LoggedEvent| observer.on_event(logged))
+    }
+
+    /// Sets a file which the panic message and `FailureContext` of a failed run are written to,
+    /// in addition to stderr.
+    pub fn failure_report<P: Into<path::PathBuf>>(mut self, path: P) -> Self {
+        self.failure_report = Some(path.into());
+        self
+    }
+
+    /// Fails the run when a host leaves listeners or connections open, rather than logging
+    /// them. Hosts are checked once the future passed to `DeterministicRuntimeHandle::run_host`
+    /// completes, and every cluster is checked at the end of `DeterministicRuntime::run`.
+    pub fn fail_on_leaks(mut self) -> Self {
+        self.fail_on_leaks = true;
+        self
+    }
+
+    /// Reports tasks whose poll holds the executor thread for longer than `threshold` of real
+    /// time, such as by calling `std::thread::sleep`, doing synchronous IO or computing for a
+    /// long time. Such polls take no simulated time, so they hide latency from the simulation
+    /// and make a run depend on the speed of the machine. Reported polls are logged and recorded
+    /// as a `SimEvent::TaskBlocked`. No polls are reported unless a threshold is set.
+    pub fn blocking_threshold(mut self, threshold: time::Duration) -> Self {
+        self.blocking_threshold = Some(threshold);
+        self
+    }
+
+    /// Fails the run when a poll exceeds the `blocking_threshold`, rather than logging it.
+    pub fn fail_on_blocking(mut self) -> Self {
+        self.fail_on_blocking = true;
+        self
+    }
+
+    /// Sets the depths at which queues of the runtime raise a `SimEvent::WatermarkExceeded`.
+    pub fn watermarks(mut self, watermarks: Watermarks) -> Self {
+        self.watermarks = watermarks;
+        self
+    }
+
+    /// Sets the range of ports assigned to the client ends of connections made from each
+    /// cluster, defaulting to `49152..=65535`. Each open connection holds its port until the
+    /// client end is dropped, so a cluster with every port held fails to connect with
+    /// `AddrNotAvailable`, exercising connection pooling and reuse.
+    pub fn ephemeral_ports(mut self, range: ops::RangeInclusive<u16>) -> Self {
+        self.ephemeral_ports = range;
+        self
+    }
+
+    /// Draws the port assigned to the client end of each connection from the seed, as
+    /// operating systems randomize ephemeral ports, rather than assigning ports in order. The
+    /// same seed assigns the same ports, so servers keying state on the peer address of a
+    /// connection see the same addresses every time a run is repeated.
+    pub fn randomize_ephemeral_ports(mut self) -> Self {
+        self.randomize_ephemeral_ports = true;
+        self
+    }
+
+    /// Sets what happens to data the peer has not read when a connection end is dropped,
+    /// defaulting to `Linger::Flush`. Ends may override it with `MemoryStream::set_linger`.
+    pub fn linger(mut self, linger: Linger) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    /// Sets the wall clock time returned by `Environment::system_time` at the start of the run,
+    /// defaulting to 2020-01-01T00:00:00Z so runs do not depend on when they are made.
+    pub fn start_time(mut self, start_time: time::SystemTime) -> Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    /// Sets whether the seed is written to stderr the first time the runtime runs, so a failed
+    /// run can be reproduced from its output. On by default, it may be turned off by harnesses
+    /// which run many seeds and report the failing ones themselves.
+    pub fn announce_seed(mut self, announce: bool) -> Self {
+        self.announce_seed = announce;
+        self
+    }
+
+    /// Builds the runtime. Fails with `Error::UnknownCluster` if a link refers to a cluster
+    /// which was not added.
+    pub fn build(self) -> Result<DeterministicRuntime, Error> {
+        self.build_seed(self.seed)
+    }
+
+    /// Builds a runtime for `seed` like `build`, keeping the builder to build runtimes for
+    /// further seeds, as each worker of a `Sweep` does. Observers are shared by every runtime
+    /// built, seeing the events of each in turn.
+    pub(crate) fn build_seed(&self, seed: u64) -> Result<DeterministicRuntime, Error> {
+        let Builder {
+            seed: _,
+            algorithm,
+            audit_rng,
+            fault_config,
+            clusters,
+            links,
+            max_sim_time,
+            max_events,
+            event_retention,
+            memory_budget,
+            observers,
+            failure_report,
+            fail_on_leaks,
+            blocking_threshold,
+            fail_on_blocking,
+            watermarks,
+            ephemeral_ports,
+            randomize_ephemeral_ports,
+            linger,
+            start_time,
+            announce_seed,
+        } = self.clone();
+        let reactor =
+            tokio_net::driver::Reactor::new().map_err(|source| Error::RuntimeBuild { source })?;
+        let reactor_handle = reactor.handle();
+        let time = Time::new();
+        time.set_limit(max_sim_time);
+        if let Some(start_time) = start_time {
+            time.set_epoch(start_time);
+        }
+        let events = event::EventLog::new(time.clone_now(), event_retention);
+        for observer in observers {
+            events.add_observer(move |logged: &LoggedEvent| (observer.lock().unwrap())(logged));
+        }
+        let tasks = task::Tasks::new(events.clone(), time.clone_now());
+        tasks.set_blocking_threshold(blocking_threshold, fail_on_blocking);
+        tasks.set_max_events(max_events);
+        let reactor = time.wrap_park(reactor, events.clone());
+        let invariants = invariant::Invariants::new();
+        let reactor = invariants.wrap_park(reactor, seed, time.clone());
+        let timer = tokio_timer::Timer::new_with_now(reactor, time.clone_now());
+        let timer_handle = timer.handle();
+        let clock = tokio_timer::clock::Clock::new_with_now(time.clone_now());
+        let algorithm = if audit_rng {
+            algorithm.audited()
+        } else {
+            algorithm
+        };
+        let entropy = rng::Entropy::new(seed, algorithm.clone());
+        let buggify = buggify::Buggify::new(
+            entropy.component("buggify"),
+            fault_config.buggify_prob,
+            fault_config.buggify_fire_prob,
+        );
+        let fault_injector = fault::FaultInjector::new(
+            fault_config,
+            seed,
+            algorithm.clone(),
+            timer_handle.clone(),
+            time.clone_now(),
+            events.clone(),
+        );
+        let fault_injector_handle = fault_injector.handle();
+        let network =
+            network::Network::new_with_park(timer, fault_injector_handle.clone(), events.clone());
+        let network_handle = network.handle();
+        network_handle.set_fail_on_leaks(fail_on_leaks);
+        network_handle.set_ephemeral_ports(ephemeral_ports);
+        if randomize_ephemeral_ports {
+            network_handle.randomize_ephemeral_ports(seed, algorithm.clone());
+        }
+        network_handle.set_linger(linger);
+        network_handle.set_memory_budget(memory_budget);
+        let executor = tokio_executor::current_thread::CurrentThread::new_with_park(network);
+        let handle = DeterministicRuntimeHandle {
+            seed,
+            reactor: reactor_handle.clone(),
+            time,
+            timer: timer_handle.clone(),
+            fault_injector: fault_injector_handle,
+            network: network_handle,
+            executor: executor.handle(),
+            invariants,
+            events,
+            tasks,
+            entropy,
+            buggify,
+            hostname: "localhost".to_string(),
+            watermarks: sync::Arc::new(watermarks),
+        };
+        let clusters: HashMap<_, _> = clusters
+            .into_iter()
+            .map(|(name, config)| {
+                let cluster = handle.new_cluster(config).with_hostname(&name);
+                (name, cluster)
+            })
+            .collect();
+        for (from, to, config) in links {
+            let cluster = |name: &str| {
+                clusters.get(name).ok_or_else(|| Error::UnknownCluster {
+                    name: name.to_string(),
+                })
+            };
+            cluster(&from)?.link(cluster(&to)?, config);
+        }
+        Ok(DeterministicRuntime {
+            executor,
+            handle,
+            reactor_handle,
+            timer_handle,
+            clock,
+            coverage: assertions::Coverage::new(),
+            clusters,
+            failure_report,
+            announced: !announce_seed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{EventCategory, SimEvent},
+        Environment,
+    };
+    use std::{net, time::Duration};
+
+    #[test]
+    /// Test that clusters are linked and observers see every event of the built runtime.
+    fn topology() {
+        let bound = sync::Arc::new(sync::Mutex::new(vec![]));
+        let observed = sync::Arc::clone(&bound);
+        let mut runtime = DeterministicRuntime::builder()
+            .seed(3)
+            .cluster("east", FaultConfig::disabled())
+            .cluster("west", FaultConfig::disabled())
+            .link("east", "west", FaultConfig::disabled())
+            .observer(move |logged| {
+                if let SimEvent::ListenerBound { addr } = logged.event {
+                    observed.lock().unwrap().push(addr);
+                }
+            })
+            .build()
+            .unwrap();
+        let east = runtime.cluster("east").unwrap();
+        let west = runtime.cluster("west").unwrap();
+        assert!(runtime.cluster("north").is_none());
+        runtime.block_on(async {
+            let addr = net::SocketAddr::new(west.local_ip(), 9092);
+            let _listener = west.bind(addr).await.unwrap();
+            assert!(east.connect(addr).await.is_ok());
+            assert!(west
+                .new_cluster(FaultConfig::disabled())
+                .connect(addr)
+                .await
+                .is_err());
+        });
+        assert_eq!(bound.lock().unwrap().len(), 1);
+
+        match DeterministicRuntime::builder()
+            .link("east", "west", FaultConfig::disabled())
+            .build()
+        {
+            Err(Error::UnknownCluster { name }) => assert_eq!(name, "east"),
+            other => panic!("expected an unknown cluster, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[derive(Default)]
+    struct Counts {
+        tasks: u64,
+        time: u64,
+        network: u64,
+    }
+
+    struct Counter(sync::Arc<sync::Mutex<Counts>>);
+
+    impl SimObserver for Counter {
+        fn on_task(&mut self, _logged: &LoggedEvent) {
+            self.0.lock().unwrap().tasks += 1;
+        }
+
+        fn on_time(&mut self, _logged: &LoggedEvent) {
+            self.0.lock().unwrap().time += 1;
+        }
+
+        fn on_network(&mut self, _logged: &LoggedEvent) {
+            self.0.lock().unwrap().network += 1;
+        }
+    }
+
+    #[test]
+    /// Test that a `SimObserver` has events dispatched to the callback of their category.
+    fn sim_observer() {
+        let counts = sync::Arc::new(sync::Mutex::new(Counts::default()));
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .sim_observer(Counter(sync::Arc::clone(&counts)))
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let _listener = handle.bind(addr).await.unwrap();
+            handle.delay_from(Duration::from_secs(1)).await;
+        });
+        let counts = counts.lock().unwrap();
+        let events = runtime.handle().events();
+        let count = |category| {
+            events
+                .iter()
+                .filter(|logged| logged.event.category() == category)
+                .count() as u64
+        };
+        assert_eq!(counts.tasks, count(EventCategory::Task));
+        assert_eq!(counts.time, count(EventCategory::Time));
+        assert_eq!(counts.network, count(EventCategory::Network));
+        assert!(counts.tasks >= 2 && counts.time >= 1);
+    }
+
+    /// Runs a second of delays under `retention`, returning the number of events observed
+    /// and the events kept.
+    fn retained(retention: EventRetention) -> (u64, Vec<LoggedEvent>) {
+        let observed = sync::Arc::new(sync::Mutex::new(0));
+        let counter = sync::Arc::clone(&observed);
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .event_retention(retention)
+            .observer(move |_| *counter.lock().unwrap() += 1)
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            for _ in 0..10 {
+                handle.delay_from(Duration::from_millis(100)).await;
+            }
+        });
+        let observed = *observed.lock().unwrap();
+        (observed, runtime.handle().events())
+    }
+
+    #[test]
+    /// Test that a bounded retention keeps the most recent events, which are still observed.
+    fn event_retention() {
+        let (observed, all) = retained(EventRetention::All);
+        assert_eq!(all.len() as u64, observed);
+        let (observed, last) = retained(EventRetention::Last(5));
+        assert_eq!(last.len(), 5);
+        assert_eq!(last[..], all[all.len() - 5..]);
+        assert_eq!(last[4].index, observed - 1);
+        let (observed, none) = retained(EventRetention::Disabled);
+        assert!(none.is_empty());
+        assert_eq!(observed, all.len() as u64);
+    }
+
+    #[test]
+    /// Test that polls which are not recorded, as nothing would see them, still take their
+    /// sequence numbers.
+    fn unobserved_polls() {
+        let recorded = |retention| {
+            let mut runtime = DeterministicRuntime::builder()
+                .fault_config(FaultConfig::disabled())
+                .event_retention(retention)
+                .build()
+                .unwrap();
+            let handle = runtime.handle();
+            runtime.block_on(async {
+                for _ in 0..10 {
+                    handle.delay_from(Duration::from_millis(100)).await;
+                }
+            });
+            runtime.handle().events.len()
+        };
+        assert_eq!(
+            recorded(EventRetention::Disabled),
+            recorded(EventRetention::All)
+        );
+    }
+
+    #[test]
+    /// Test that the seed is announced unless turned off, and that a builder reused for
+    /// several seeds builds runtimes with the seed given.
+    fn announce_seed() {
+        let builder = DeterministicRuntime::builder().seed(5);
+        assert!(!builder.clone().build().unwrap().announced);
+        let quiet = builder.announce_seed(false);
+        assert!(quiet.clone().build().unwrap().announced);
+        let runtime = quiet.build_seed(9).unwrap();
+        assert!(runtime.announced);
+        assert_eq!(runtime.handle().seed(), 9);
+    }
+}
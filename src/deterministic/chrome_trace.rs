@@ -0,0 +1,114 @@
+//! Export of the event log in the Chrome trace event format.
+//!
+//! The exported JSON can be opened with `chrome://tracing` or the Perfetto UI to inspect a run
+//! on a timeline. Each task is shown as a thread spanning from when it was spawned to when it
+//! completed, with its polls marked within it. Faults, network operations and other events are
+//! shown as instants on a separate runtime thread. Timestamps are in simulated time.
+use super::{LoggedEvent, SimEvent, TaskId};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+
+/// Thread id of events which do not belong to a task.
+const RUNTIME_TID: u64 = 0;
+
+fn task_tid(task: TaskId) -> u64 {
+    task.0 + 1
+}
+
+/// Splits `event` into its variant name and fields.
+fn name_and_args(event: &SimEvent) -> (String, Value) {
+    match serde_json::to_value(event).expect("events are serializable") {
+        Value::Object(map) => {
+            let (name, args) = map.into_iter().next().expect("event variant missing");
+            (name, args)
+        }
+        Value::String(name) => (name, Value::Null),
+        other => (format!("{:?}", other), Value::Null),
+    }
+}
+
+/// Returns the trace events for `events`.
+pub(crate) fn trace_events(events: &[LoggedEvent]) -> Value {
+    let mut trace = vec![json!({
+        "ph": "M",
+        "name": "thread_name",
+        "pid": 1,
+        "tid": RUNTIME_TID,
+        "args": {"name": "runtime"},
+    })];
+    let mut tasks = BTreeSet::new();
+    for logged in events {
+        let ts = logged.elapsed.as_micros() as u64;
+        let (name, mut args) = name_and_args(&logged.event);
+        if let Value::Object(map) = &mut args {
+            map.insert("index".to_string(), json!(logged.index));
+        }
+        let event = match &logged.event {
+            SimEvent::TaskSpawned { task } => {
+                tasks.insert(*task);
+                json!({"ph": "B", "name": format!("task {}", task.0), "pid": 1,
+                       "tid": task_tid(*task), "ts": ts, "args": args})
+            }
+            SimEvent::TaskCompleted { task } => {
+                json!({"ph": "E", "pid": 1, "tid": task_tid(*task), "ts": ts})
+            }
+            SimEvent::TaskPolled { task } => {
+                json!({"ph": "i", "s": "t", "name": name, "pid": 1,
+                       "tid": task_tid(*task), "ts": ts, "args": args})
+            }
+            SimEvent::FaultInjected(_) => {
+                json!({"ph": "i", "s": "g", "cat": "fault", "name": name, "pid": 1,
+                       "tid": RUNTIME_TID, "ts": ts, "args": args})
+            }
+            _ => json!({"ph": "i", "s": "t", "name": name, "pid": 1,
+                        "tid": RUNTIME_TID, "ts": ts, "args": args}),
+        };
+        trace.push(event);
+    }
+    for task in tasks {
+        trace.push(json!({
+            "ph": "M",
+            "name": "thread_name",
+            "pid": 1,
+            "tid": task_tid(task),
+            "args": {"name": format!("task {}", task.0)},
+        }));
+    }
+    json!({"traceEvents": trace, "displayTimeUnit": "ms"})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that task lifetimes are exported as spans on their own thread.
+    fn task_spans() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let env = handle.clone();
+            crate::spawn_with_result(&handle, async move {
+                env.delay_from(Duration::from_millis(10)).await;
+            })
+            .await;
+        });
+        let trace = trace_events(&runtime.handle().events());
+        let events = trace["traceEvents"].as_array().unwrap();
+        let begin = events
+            .iter()
+            .find(|event| event["ph"] == "B")
+            .expect("task span missing");
+        let tid = &begin["tid"];
+        let end = events
+            .iter()
+            .find(|event| event["ph"] == "E" && &event["tid"] == tid)
+            .expect("task span not ended");
+        assert!(end["ts"].as_u64().unwrap() >= 10_000);
+        assert!(events
+            .iter()
+            .any(|event| event["name"] == "TaskPolled" && &event["tid"] == tid));
+    }
+}
@@ -0,0 +1,81 @@
+//! The runtime currently executing on this thread.
+//!
+//! Primitives which are not created through a handle, such as channels and locks, use this to
+//! find the runtime they are running under, from the ambient environment of the thread.
+use super::{
+    event::{LoggedEvent, SimEvent},
+    rng::SimRng,
+    task, DeterministicRuntimeHandle, FaultKind, QueueKind, TaskId,
+};
+use std::{fmt, ops, panic::Location, time};
+
+/// Returns a handle to the runtime executing on this thread, if any. This is the ambient
+/// environment if it is a `DeterministicRuntimeHandle`, so a task spawned through the handle
+/// of a cluster sees the handle of that cluster.
+pub(crate) fn current() -> Option<DeterministicRuntimeHandle> {
+    crate::ambient::with_current(|env| env?.downcast_ref::<DeterministicRuntimeHandle>().cloned())
+}
+
+/// Returns an RNG for a primitive created at `location`, derived from the seed of the runtime
+/// executing on this thread. Returns `None` outside of a `DeterministicRuntime`.
+pub(crate) fn callsite_rng(location: &'static Location<'static>) -> Option<SimRng> {
+    current().map(|handle| handle.entropy.callsite(location))
+}
+
+/// Records `event` in the log of the runtime executing on this thread, if any.
+pub(crate) fn record(event: SimEvent) -> Option<LoggedEvent> {
+    current().map(|handle| handle.events.record(event))
+}
+
+/// Calls `f` with the metrics recorded so far by the runtime executing on this thread, if any.
+#[cfg(feature = "metrics")]
+pub(crate) fn with_metrics<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&crate::metrics::Metrics) -> R,
+{
+    current().map(|handle| handle.events.with_metrics(f))
+}
+
+/// Returns the id of the task currently being polled on this thread.
+pub(crate) fn current_task() -> Option<TaskId> {
+    task::current()
+}
+
+/// Returns a stable id for a primitive created at `location`, or `None` outside of a
+/// `DeterministicRuntime`.
+pub(crate) fn callsite_id(location: &'static Location<'static>) -> Option<u64> {
+    current().map(|handle| handle.entropy.callsite_id(location))
+}
+
+/// Decides whether to inject a fault of `kind` into the primitive identified by `callsite`.
+/// Faults are never injected outside of a `DeterministicRuntime`.
+pub(crate) fn fault(callsite: u64, probability: f64, kind: FaultKind) -> bool {
+    current().is_some_and(|handle| {
+        handle
+            .fault_injector
+            .primitive_fault(callsite, probability, kind)
+    })
+}
+
+/// Returns a delay to inject into the primitive identified by `callsite`, if any.
+pub(crate) fn delay_fault(
+    callsite: u64,
+    probability: f64,
+    range: ops::Range<time::Duration>,
+    kind: FaultKind,
+) -> Option<tokio_timer::Delay> {
+    current().and_then(|handle| {
+        handle
+            .fault_injector
+            .primitive_delay(callsite, probability, range, kind)
+    })
+}
+
+/// Checks the growth of `queue`, identified by `name`, from `before` to `after` against the
+/// watermarks of the runtime executing on this thread, if any. Must not be called while
+/// holding a lock on the queue, as exceeding a watermark may panic.
+pub(crate) fn watermark(queue: QueueKind, name: &dyn fmt::Display, before: usize, after: usize) {
+    if let Some(handle) = current() {
+        handle.watermarks.check(queue, name, before, after);
+    }
+}
@@ -0,0 +1,186 @@
+//! Replay debugger for failing seeds.
+//!
+//! A failing seed is first run to completion to find how many events occur before the
+//! failure. The seed is then replayed, stopping at a breakpoint a number of events before the
+//! failure and stepping event by event from there, with access to the event log, the live
+//! tasks and the state of the network.
+use super::{
+    DeterministicRuntime, DeterministicRuntimeHandle, LoggedEvent, NetworkState, TaskInfo,
+};
+use std::panic;
+
+/// Returned from a step callback to control the debugger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepAction {
+    /// Stop at the next event.
+    Continue,
+    /// Stop stepping, running the remainder of the test without interruption.
+    Detach,
+}
+
+/// The state of the runtime when stopped at an event.
+#[derive(Debug)]
+pub struct Step {
+    event: LoggedEvent,
+    failure_point: u64,
+    handle: DeterministicRuntimeHandle,
+}
+
+impl Step {
+    /// The event the debugger is stopped at.
+    pub fn event(&self) -> &LoggedEvent {
+        &self.event
+    }
+
+    /// Number of events which remain before the failure.
+    pub fn remaining(&self) -> u64 {
+        self.failure_point.saturating_sub(self.event.index + 1)
+    }
+
+    /// Every event up to and including the current one.
+    pub fn history(&self) -> Vec<LoggedEvent> {
+        self.handle.events()
+    }
+
+    /// Tasks which have been spawned but not yet completed.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.handle.tasks()
+    }
+
+    /// The listeners and connections of the in-memory network.
+    pub fn network(&self) -> NetworkState {
+        self.handle.network_state()
+    }
+}
+
+/// Replays a failing seed event by event.
+#[derive(Debug)]
+pub struct Debugger<F> {
+    seed: u64,
+    test: F,
+}
+
+impl<F> Debugger<F>
+where
+    F: Fn(&mut DeterministicRuntime),
+{
+    pub fn new(seed: u64, test: F) -> Self {
+        Self { seed, test }
+    }
+
+    /// Runs the test once, returning the number of events which occurred before it failed.
+    /// Returns `None` if the test passes.
+    pub fn failure_point(&self) -> Option<u64> {
+        let mut runtime = self.runtime();
+        let handle = runtime.handle();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| (self.test)(&mut runtime)));
+        result.err().map(|_| handle.events.len())
+    }
+
+    /// Replays the test, calling `step` for each of the last `before_failure` events which
+    /// occur before the failure. Returns `false` if the test does not fail.
+    pub fn replay<S>(&self, before_failure: u64, mut step: S) -> bool
+    where
+        S: FnMut(&Step) -> StepAction + Send + 'static,
+    {
+        let failure_point = match self.failure_point() {
+            Some(failure_point) => failure_point,
+            None => return false,
+        };
+        let breakpoint = failure_point.saturating_sub(before_failure);
+        let mut runtime = self.runtime();
+        let handle = runtime.handle();
+        let hook_handle = handle.clone();
+        let mut detached = false;
+        handle.events.set_hook(breakpoint, move |event| {
+            if detached {
+                return;
+            }
+            let current = Step {
+                event: event.clone(),
+                failure_point,
+                handle: hook_handle.clone(),
+            };
+            detached = step(&current) == StepAction::Detach;
+        });
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| (self.test)(&mut runtime)));
+        handle.events.clear_hook();
+        result.is_err()
+    }
+
+    fn runtime(&self) -> DeterministicRuntime {
+        DeterministicRuntime::new_with_seed(self.seed).expect("failed to build runtime")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::SimEvent;
+    use crate::{Environment, TcpListener};
+    use std::{
+        net,
+        sync::{Arc, Mutex},
+    };
+
+    /// Accepts a single connection and then fails.
+    fn accept_then_fail(runtime: &mut DeterministicRuntime) {
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let client = handle.clone();
+            handle.spawn(async move {
+                let _ = client.connect(addr).await;
+            });
+            let _ = listener.accept().await.unwrap();
+            panic!("failed after accepting");
+        })
+    }
+
+    #[test]
+    /// Test that the debugger stops at each of the events preceding the failure.
+    fn steps_to_failure() {
+        let debugger = Debugger::new(0, accept_then_fail);
+        let failure_point = debugger.failure_point().unwrap();
+        let steps = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&steps);
+        assert!(debugger.replay(failure_point, move |step| {
+            assert_eq!(step.history().len() as u64, step.event().index + 1);
+            let connections = step.network().connections.values().sum::<usize>();
+            recorded
+                .lock()
+                .unwrap()
+                .push((step.event().clone(), step.remaining(), connections));
+            StepAction::Continue
+        }));
+        let steps = steps.lock().unwrap();
+        let indices: Vec<u64> = steps.iter().map(|(event, ..)| event.index).collect();
+        assert_eq!(indices, (0..failure_point).collect::<Vec<_>>());
+        assert_eq!(steps.last().unwrap().1, 0);
+        assert!(steps.iter().any(|(event, _, connections)| {
+            matches!(event.event, SimEvent::ConnectionOpened { .. }) && *connections == 1
+        }));
+    }
+
+    #[test]
+    /// Test that detaching stops the debugger from being called.
+    fn detach() {
+        let debugger = Debugger::new(0, accept_then_fail);
+        let calls = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&calls);
+        assert!(debugger.replay(3, move |_| {
+            *counted.lock().unwrap() += 1;
+            StepAction::Detach
+        }));
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    /// Test that passing tests are not replayed.
+    fn passing() {
+        let debugger = Debugger::new(0, |_| {});
+        assert_eq!(debugger.failure_point(), None);
+        assert!(!debugger.replay(1, |_| StepAction::Continue));
+    }
+}
@@ -0,0 +1,185 @@
+//! Configuration of a `DeterministicRuntime` read from environment variables.
+use super::{rng, FaultConfig, Seed};
+use crate::Error;
+use std::{fmt, fs, path, time};
+
+/// The configuration chosen by `DeterministicRuntime::from_env`.
+#[derive(Debug)]
+pub(crate) struct EnvConfig {
+    pub(crate) seed: u64,
+    pub(crate) fault_profile: String,
+    pub(crate) fault_config: FaultConfig,
+    pub(crate) max_sim_time: Option<time::Duration>,
+    pub(crate) algorithm: rng::Algorithm,
+    pub(crate) failure_report: Option<path::PathBuf>,
+}
+
+impl fmt::Display for EnvConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SIM_SEED={} SIM_FAULT_PROFILE={} SIM_MAX_SIM_TIME={} SIM_RNG={}",
+            self.seed,
+            self.fault_profile,
+            self.max_sim_time
+                .map_or_else(|| "none".to_string(), |limit| format!("{:?}", limit)),
+            self.algorithm.name()
+        )?;
+        if let Some(path) = &self.failure_report {
+            write!(f, " SIM_FAILURE_REPORT={}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(name: &str, value: &str) -> Error {
+    Error::InvalidEnvVar {
+        name: name.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// Parses a duration such as `30s`, `500ms`, `2m` or `1h`. A bare number is in seconds.
+fn parse_duration(value: &str) -> Option<time::Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: f64 = amount.parse().ok()?;
+    let seconds = match unit.trim() {
+        "" | "s" => amount,
+        "ms" => amount / 1000.0,
+        "m" => amount * 60.0,
+        "h" => amount * 3600.0,
+        _ => return None,
+    };
+    time::Duration::try_from_secs_f64(seconds).ok()
+}
+
+fn fault_config(profile: &str) -> Result<FaultConfig, Error> {
+    match profile {
+        "default" => Ok(FaultConfig::default()),
+        "disabled" | "none" => Ok(FaultConfig::disabled()),
+        path if path.ends_with(".json") => {
+            let json = fs::read_to_string(path).map_err(|source| Error::Io { source })?;
+            serde_json::from_str(&json).map_err(|source| Error::Serialization { source })
+        }
+        _ => Err(invalid("SIM_FAULT_PROFILE", profile)),
+    }
+}
+
+impl EnvConfig {
+    pub(crate) fn from_env() -> Result<Self, Error> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Reads the configuration with `var`, using defaults for variables which are not set.
+    pub(crate) fn from_vars<V>(var: V) -> Result<Self, Error>
+    where
+        V: Fn(&str) -> Option<String>,
+    {
+        let seed = match var("SIM_SEED") {
+            Some(value) => value
+                .parse::<Seed>()
+                .map_err(|_| invalid("SIM_SEED", &value))?
+                .into(),
+            None => rand::random(),
+        };
+        let fault_profile = var("SIM_FAULT_PROFILE").unwrap_or_else(|| "default".to_string());
+        let fault_config = fault_config(&fault_profile)?;
+        let max_sim_time = match var("SIM_MAX_SIM_TIME") {
+            Some(value) => Some(
+                parse_duration(value.trim()).ok_or_else(|| invalid("SIM_MAX_SIM_TIME", &value))?,
+            ),
+            None => None,
+        };
+        let algorithm = match var("SIM_RNG") {
+            Some(value) => {
+                rng::algorithm_by_name(value.trim()).ok_or_else(|| invalid("SIM_RNG", &value))?
+            }
+            None => rng::algorithm_by_name("small_rng").unwrap(),
+        };
+        Ok(EnvConfig {
+            seed,
+            fault_profile,
+            fault_config,
+            max_sim_time,
+            algorithm,
+            failure_report: var("SIM_FAILURE_REPORT").map(path::PathBuf::from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::collections::HashMap;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<EnvConfig, Error> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        EnvConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    /// Test that variables are parsed, falling back to defaults when they are not set.
+    fn vars() {
+        let config = from_vars(&[
+            ("SIM_SEED", "42"),
+            ("SIM_FAULT_PROFILE", "disabled"),
+            ("SIM_MAX_SIM_TIME", "1.5s"),
+            ("SIM_RNG", "chacha20"),
+        ])
+        .unwrap();
+        assert_eq!(config.seed, 42);
+        assert_eq!(config.fault_config, FaultConfig::disabled());
+        assert_eq!(config.max_sim_time, Some(time::Duration::from_millis(1500)));
+        assert_eq!(
+            config.to_string(),
+            "SIM_SEED=42 SIM_FAULT_PROFILE=disabled SIM_MAX_SIM_TIME=1.5s SIM_RNG=chacha20"
+        );
+
+        for value in &["0x2a", "camel", " \"42\" "] {
+            assert_eq!(from_vars(&[("SIM_SEED", value)]).unwrap().seed, 42);
+        }
+
+        let config = from_vars(&[]).unwrap();
+        assert_eq!(config.fault_config, FaultConfig::default());
+        assert_eq!(config.max_sim_time, None);
+        assert_eq!(config.algorithm.name(), "small_rng");
+
+        for (name, value) in &[
+            ("SIM_SEED", "abc"),
+            ("SIM_FAULT_PROFILE", "chaos"),
+            ("SIM_MAX_SIM_TIME", "10 fortnights"),
+            ("SIM_MAX_SIM_TIME", "1e30h"),
+            ("SIM_MAX_SIM_TIME", "100000000000000000000000h"),
+            ("SIM_MAX_SIM_TIME", "inf"),
+            ("SIM_MAX_SIM_TIME", "nan"),
+            ("SIM_RNG", "xorshift"),
+        ] {
+            match from_vars(&[(name, value)]) {
+                Err(Error::InvalidEnvVar { name: invalid, .. }) => assert_eq!(&invalid, name),
+                other => panic!("expected an invalid {}, got {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "simulated time limit of 10s exceeded")]
+    /// Test that the runtime panics once the simulated time limit has elapsed.
+    fn max_sim_time() {
+        let mut runtime = DeterministicRuntime::builder()
+            .max_sim_time(time::Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            handle.delay_from(time::Duration::from_secs(60)).await;
+        });
+    }
+}
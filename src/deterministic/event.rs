@@ -0,0 +1,474 @@
+//! Log of events occurring during a simulation.
+//!
+//! Every task poll, advance of time, injected fault and network operation is assigned a
+//! sequence number. Given the same seed, a run will produce the same sequence of events,
+//! which allows a run to be stopped at a particular point when it is replayed.
+use super::{task::TaskId, ConnectionId, FaultRecord, RunStats};
+use futures::{channel::mpsc, Poll, Stream};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, net,
+    pin::Pin,
+    sync::{
+        self,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::Context,
+    time,
+};
+
+/// An event which occurred during a simulation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SimEvent {
+    TaskSpawned {
+        task: TaskId,
+    },
+    TaskPolled {
+        task: TaskId,
+    },
+    TaskCompleted {
+        task: TaskId,
+    },
+    /// A poll of `task` held the executor thread for `wall` of real time, past the threshold
+    /// set with `Builder::blocking_threshold`.
+    TaskBlocked {
+        task: TaskId,
+        wall: time::Duration,
+    },
+    /// Simulated time was advanced by `by`, to the deadline of the next pending timer, firing
+    /// every timer with that deadline.
+    TimeAdvanced {
+        by: time::Duration,
+    },
+    FaultInjected(FaultRecord),
+    ListenerBound {
+        addr: net::SocketAddr,
+    },
+    ListenerClosed {
+        addr: net::SocketAddr,
+    },
+    ConnectionOpened {
+        client: net::SocketAddr,
+        server: net::SocketAddr,
+        /// Id of the connection, zero in traces written before ids were recorded.
+        #[serde(default)]
+        connection: ConnectionId,
+    },
+    /// The end of a connection at `from`, connected to `to`, was dropped.
+    ConnectionClosed {
+        from: net::SocketAddr,
+        to: net::SocketAddr,
+        #[serde(default)]
+        connection: ConnectionId,
+    },
+    /// `bytes` were written to a connection from `from` to `to`.
+    BytesWritten {
+        from: net::SocketAddr,
+        to: net::SocketAddr,
+        bytes: usize,
+        #[serde(default)]
+        connection: ConnectionId,
+    },
+    /// A record was logged through `logger::SimLogger` by `task`, within the scope of `host`.
+    Log {
+        host: Option<String>,
+        task: Option<TaskId>,
+        level: String,
+        target: String,
+        message: String,
+    },
+    /// A metric was recorded through the `metrics` module.
+    Metric {
+        name: String,
+        kind: crate::metrics::MetricKind,
+        value: f64,
+    },
+    /// `permits` were acquired from a semaphore by `task`.
+    PermitsAcquired {
+        task: Option<TaskId>,
+        permits: usize,
+    },
+    /// `permits` were released back to a semaphore by `task`.
+    PermitsReleased {
+        task: Option<TaskId>,
+        permits: usize,
+    },
+    /// A barrier was released once `waiters` tasks reached it, `leader` being chosen as the
+    /// leader.
+    BarrierReleased {
+        leader: Option<TaskId>,
+        waiters: usize,
+    },
+    /// The queue identified by `name` grew to `depth`, past its configured `watermark`.
+    WatermarkExceeded {
+        queue: super::QueueKind,
+        name: String,
+        depth: usize,
+        watermark: usize,
+    },
+}
+
+/// Broad kind of a `SimEvent`, used to dispatch events to the callbacks of a `SimObserver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EventCategory {
+    /// Tasks being spawned, polled and completed.
+    Task,
+    /// Simulated time advancing.
+    Time,
+    /// Faults being injected.
+    Fault,
+    /// Listeners and connections being opened, written to and closed.
+    Network,
+    /// Records logged through `logger::SimLogger`.
+    Log,
+    /// Metrics recorded through the `metrics` module.
+    Metric,
+    /// Semaphores and barriers coordinating tasks.
+    Sync,
+    /// Queues growing past their watermarks.
+    Watermark,
+}
+
+impl SimEvent {
+    pub fn category(&self) -> EventCategory {
+        match self {
+            SimEvent::TaskSpawned { .. }
+            | SimEvent::TaskPolled { .. }
+            | SimEvent::TaskCompleted { .. }
+            | SimEvent::TaskBlocked { .. } => EventCategory::Task,
+            SimEvent::TimeAdvanced { .. } => EventCategory::Time,
+            SimEvent::FaultInjected(_) => EventCategory::Fault,
+            SimEvent::ListenerBound { .. }
+            | SimEvent::ListenerClosed { .. }
+            | SimEvent::ConnectionOpened { .. }
+            | SimEvent::ConnectionClosed { .. }
+            | SimEvent::BytesWritten { .. } => EventCategory::Network,
+            SimEvent::Log { .. } => EventCategory::Log,
+            SimEvent::Metric { .. } => EventCategory::Metric,
+            SimEvent::PermitsAcquired { .. }
+            | SimEvent::PermitsReleased { .. }
+            | SimEvent::BarrierReleased { .. } => EventCategory::Sync,
+            SimEvent::WatermarkExceeded { .. } => EventCategory::Watermark,
+        }
+    }
+}
+
+/// Consumer of the events of a runtime, registered with `Builder::sim_observer`.
+///
+/// Every event is passed to `on_event`, which by default calls the callback for the category
+/// of the event. Each callback does nothing by default, so an observer only implements the
+/// callbacks for the categories it is interested in. Observers are called synchronously as
+/// events are recorded, so a checker may panic to fail the run at the offending event.
+pub trait SimObserver: Send + 'static {
+    fn on_event(&mut self, logged: &LoggedEvent) {
+        match logged.event.category() {
+            EventCategory::Task => self.on_task(logged),
+            EventCategory::Time => self.on_time(logged),
+            EventCategory::Fault => self.on_fault(logged),
+            EventCategory::Network => self.on_network(logged),
+            EventCategory::Log => self.on_log(logged),
+            EventCategory::Metric => self.on_metric(logged),
+            EventCategory::Sync => self.on_sync(logged),
+            EventCategory::Watermark => self.on_watermark(logged),
+        }
+    }
+
+    fn on_task(&mut self, _logged: &LoggedEvent) {}
+
+    fn on_time(&mut self, _logged: &LoggedEvent) {}
+
+    fn on_fault(&mut self, _logged: &LoggedEvent) {}
+
+    fn on_network(&mut self, _logged: &LoggedEvent) {}
+
+    fn on_log(&mut self, _logged: &LoggedEvent) {}
+
+    fn on_metric(&mut self, _logged: &LoggedEvent) {}
+
+    fn on_sync(&mut self, _logged: &LoggedEvent) {}
+
+    fn on_watermark(&mut self, _logged: &LoggedEvent) {}
+}
+
+/// A `SimEvent` along with its position in the log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    /// Sequence number of this event, starting from 0.
+    pub index: u64,
+    /// Simulated time since the start of the run at which the event occurred.
+    pub elapsed: time::Duration,
+    pub event: SimEvent,
+}
+
+/// Stream of every event recorded by a runtime, from the start of the run, returned by
+/// `DeterministicRuntimeHandle::event_stream`. The stream ends once the runtime and its handles
+/// are dropped.
+#[derive(Debug)]
+pub struct EventStream {
+    rx: mpsc::UnboundedReceiver<LoggedEvent>,
+}
+
+impl Stream for EventStream {
+    type Item = LoggedEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<LoggedEvent>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// Which recorded events a runtime keeps in memory, set with `Builder::event_retention`.
+///
+/// Observers, event streams and the debugger see every event whatever the retention, and
+/// events keep their sequence numbers. The stats, metrics and event budget of the run are
+/// counted as events are recorded, so they also cover every event. Only the history returned
+/// by `DeterministicRuntimeHandle::events`, `logs`, `logs_by_host` and `connection_timeline`,
+/// and the traces and reports built from it, are limited to the kept events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventRetention {
+    /// Every event is kept, the default.
+    #[default]
+    All,
+    /// Only the most recent events are kept, bounding the memory of long runs.
+    Last(usize),
+    /// No events are kept.
+    Disabled,
+}
+
+type Hook = Box<dyn FnMut(&LoggedEvent) + Send>;
+
+/// Counts of every event recorded by a log, kept whatever its retention.
+#[derive(Default)]
+struct Totals {
+    categories: BTreeMap<EventCategory, u64>,
+    /// Activity counted by `RunStats`, without the times and memory of the run.
+    stats: RunStats,
+    metrics: crate::metrics::Metrics,
+}
+
+impl Totals {
+    fn count(&mut self, logged: &LoggedEvent) {
+        *self.categories.entry(logged.event.category()).or_insert(0) += 1;
+        self.stats.count(&logged.event);
+        self.metrics.count(logged);
+    }
+}
+
+struct Inner {
+    events: VecDeque<LoggedEvent>,
+    retention: EventRetention,
+    totals: Totals,
+    /// Hook called for every event with an index of at least `hook_from`.
+    hook: Option<(u64, Hook)>,
+    /// Observers called for every event.
+    observers: Vec<Hook>,
+}
+
+impl Inner {
+    /// Returns `true` if a recorded event would be kept, or passed to an observer or hook.
+    fn observed(&self) -> bool {
+        self.retention != EventRetention::Disabled
+            || self.hook.is_some()
+            || !self.observers.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    /// Number of events recorded or skipped, including those no longer kept.
+    recorded: AtomicU64,
+    /// Number of polls skipped, which are not counted in the totals of the log.
+    skipped: AtomicU64,
+}
+
+/// Shared handle to the event log of a runtime.
+#[derive(Clone)]
+pub(crate) struct EventLog {
+    now: super::time::Now,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    counters: sync::Arc<Counters>,
+    /// Whether recorded events are kept, or passed to an observer or hook, updated whenever
+    /// one is added or removed so that `skip` can be checked without taking the lock.
+    observed: sync::Arc<AtomicBool>,
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        f.debug_struct("EventLog")
+            .field("events", &lock.events.len())
+            .finish()
+    }
+}
+
+impl EventLog {
+    pub(crate) fn new(now: super::time::Now, retention: EventRetention) -> Self {
+        let inner = Inner {
+            events: VecDeque::new(),
+            retention,
+            totals: Totals::default(),
+            hook: None,
+            observers: vec![],
+        };
+        Self {
+            now,
+            observed: sync::Arc::new(AtomicBool::new(inner.observed())),
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+            counters: Default::default(),
+        }
+    }
+
+    /// Appends `event` to the log, calling the observers and the installed hook if any.
+    pub(crate) fn record(&self, event: SimEvent) -> LoggedEvent {
+        let (logged, hook, mut observers) = {
+            let mut lock = self.inner.lock().unwrap();
+            let logged = LoggedEvent {
+                index: self.counters.recorded.fetch_add(1, Ordering::Relaxed),
+                elapsed: self.now.elapsed(),
+                event,
+            };
+            lock.totals.count(&logged);
+            match lock.retention {
+                EventRetention::All => lock.events.push_back(logged.clone()),
+                EventRetention::Last(limit) => {
+                    if lock.events.len() >= limit {
+                        lock.events.pop_front();
+                    }
+                    if limit > 0 {
+                        lock.events.push_back(logged.clone());
+                    }
+                }
+                EventRetention::Disabled => {}
+            }
+            let hook = match lock.hook.take() {
+                Some((from, hook)) if logged.index >= from => Some((from, hook)),
+                hook => {
+                    lock.hook = hook;
+                    None
+                }
+            };
+            let observers = std::mem::take(&mut lock.observers);
+            (logged, hook, observers)
+        };
+        // observers and the hook are called without holding the lock, so they are free to
+        // inspect the log.
+        for observer in &mut observers {
+            observer(&logged);
+        }
+        if !observers.is_empty() {
+            let mut lock = self.inner.lock().unwrap();
+            observers.append(&mut lock.observers);
+            lock.observers = observers;
+            // the hook may have been cleared while the observers were taken out of the log.
+            self.observed.store(true, Ordering::Relaxed);
+        }
+        if let Some((from, mut hook)) = hook {
+            hook(&logged);
+            let mut lock = self.inner.lock().unwrap();
+            if lock.hook.is_none() {
+                lock.hook = Some((from, hook));
+            }
+        }
+        logged
+    }
+
+    /// Assigns the next sequence number to a `TaskPolled` event without recording it,
+    /// returning `true`, if nothing would see the event: the log keeps no events and has no
+    /// observer or hook. Polls are skipped this way to avoid the cost of the lock, while
+    /// keeping sequence numbers, stats and the event budget the same as when they are
+    /// recorded.
+    pub(crate) fn skip_poll(&self) -> bool {
+        if self.observed.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.counters.recorded.fetch_add(1, Ordering::Relaxed);
+        self.counters.skipped.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Returns the number of events recorded so far, including those skipped or no longer
+    /// kept.
+    pub(crate) fn len(&self) -> u64 {
+        self.counters.recorded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of events of each category recorded so far, including those
+    /// skipped or no longer kept.
+    pub(crate) fn categories(&self) -> BTreeMap<EventCategory, u64> {
+        let mut categories = self.inner.lock().unwrap().totals.categories.clone();
+        let skipped = self.counters.skipped.load(Ordering::Relaxed);
+        if skipped > 0 {
+            *categories.entry(EventCategory::Task).or_insert(0) += skipped;
+        }
+        categories
+    }
+
+    /// Returns the activity counted by `RunStats` of every event recorded so far, leaving the
+    /// times and memory of the run to the caller.
+    pub(crate) fn stats(&self) -> RunStats {
+        let mut stats = self.inner.lock().unwrap().totals.stats.clone();
+        stats.polls += self.counters.skipped.load(Ordering::Relaxed);
+        stats
+    }
+
+    /// Returns every metric recorded so far.
+    pub(crate) fn metrics(&self) -> crate::metrics::Metrics {
+        self.with_metrics(Clone::clone)
+    }
+
+    /// Calls `f` with every metric recorded so far, without copying them.
+    pub(crate) fn with_metrics<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&crate::metrics::Metrics) -> R,
+    {
+        f(&self.inner.lock().unwrap().totals.metrics)
+    }
+
+    /// Returns the events recorded so far which are kept by the retention of the log.
+    pub(crate) fn events(&self) -> Vec<LoggedEvent> {
+        self.inner.lock().unwrap().events.iter().cloned().collect()
+    }
+
+    /// Installs a hook which is called for every event with an index of at least `from`.
+    pub(crate) fn set_hook<F>(&self, from: u64, hook: F)
+    where
+        F: FnMut(&LoggedEvent) + Send + 'static,
+    {
+        let mut lock = self.inner.lock().unwrap();
+        lock.hook = Some((from, Box::new(hook)));
+        self.observed.store(true, Ordering::Relaxed);
+    }
+
+    /// Adds an observer which is called for every event recorded from now on.
+    pub(crate) fn add_observer<F>(&self, observer: F)
+    where
+        F: FnMut(&LoggedEvent) + Send + 'static,
+    {
+        let mut lock = self.inner.lock().unwrap();
+        lock.observers.push(Box::new(observer));
+        self.observed.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns a stream of the events recorded so far, followed by every event recorded from now
+    /// on.
+    pub(crate) fn subscribe(&self) -> EventStream {
+        let (tx, rx) = mpsc::unbounded();
+        let mut lock = self.inner.lock().unwrap();
+        for logged in &lock.events {
+            let _ = tx.unbounded_send(logged.clone());
+        }
+        lock.observers.push(Box::new(move |logged| {
+            if !tx.is_closed() {
+                let _ = tx.unbounded_send(logged.clone());
+            }
+        }));
+        self.observed.store(true, Ordering::Relaxed);
+        EventStream { rx }
+    }
+
+    /// Removes the installed hook.
+    pub(crate) fn clear_hook(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.hook.take();
+        self.observed.store(lock.observed(), Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,160 @@
+//! Context describing the state of a simulation when it failed.
+use super::{FaultRecord, LoggedEvent, TaskId, TaskInfo};
+use crate::buggify::BuggifyPoint;
+use std::{collections::BTreeMap, fmt, time};
+
+/// The number of most recently injected faults included in a `FailureContext`.
+pub(crate) const RECENT_FAULTS: usize = 5;
+
+/// The number of most recently recorded events included in a `FailureContext`.
+pub(crate) const RECENT_EVENTS: usize = 20;
+
+/// The state of a `DeterministicRuntime` at the point a run failed. Along with the panic
+/// message, this is enough to start reproducing the failure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailureContext {
+    pub seed: u64,
+    /// Name of the algorithm generating the random streams of the runtime.
+    pub rng: String,
+    /// Simulated time since the start of the run at which the run failed.
+    pub elapsed: time::Duration,
+    /// The host set with `logger::with_host` for the task which panicked, if any.
+    pub host: Option<String>,
+    /// The task which panicked, or `None` if the panic did not occur while polling a task.
+    pub task: Option<TaskId>,
+    /// The most recently injected faults, oldest first.
+    pub recent_faults: Vec<FaultRecord>,
+    /// The most recently recorded events, oldest first.
+    pub recent_events: Vec<LoggedEvent>,
+    /// Tasks which had not completed when the run failed.
+    pub pending_tasks: Vec<TaskInfo>,
+    /// The `buggify!` points evaluated during the run.
+    pub buggify: BTreeMap<&'static str, BuggifyPoint>,
+}
+
+impl fmt::Display for FailureContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "seed {} ({}), simulated time {:?}",
+            self.seed, self.rng, self.elapsed
+        )?;
+        if let Some(host) = &self.host {
+            write!(f, ", host {}", host)?;
+        }
+        if let Some(task) = self.task {
+            write!(f, ", task {}", task.0)?;
+        }
+        if !self.recent_faults.is_empty() {
+            write!(f, "\nrecent faults:")?;
+            for fault in &self.recent_faults {
+                write!(
+                    f,
+                    "\n  {:?} {:?} (fault {})",
+                    fault.elapsed, fault.kind, fault.id.0
+                )?;
+            }
+        }
+        if !self.recent_events.is_empty() {
+            write!(f, "\nrecent events:")?;
+            for logged in &self.recent_events {
+                write!(
+                    f,
+                    "\n  {:?} #{} {:?}",
+                    logged.elapsed, logged.index, logged.event
+                )?;
+            }
+        }
+        if !self.pending_tasks.is_empty() {
+            write!(f, "\npending tasks:")?;
+            for task in &self.pending_tasks {
+                write!(
+                    f,
+                    "\n  task {} spawned at {:?}, polled {} times",
+                    task.id.0, task.spawned_at, task.polls
+                )?;
+            }
+        }
+        if !self.buggify.is_empty() {
+            write!(f, "\nbuggify points:")?;
+            for (label, point) in &self.buggify {
+                if point.enabled {
+                    write!(
+                        f,
+                        "\n  {:?} enabled, fired {} of {} times",
+                        label, point.fired, point.evaluated
+                    )?;
+                } else {
+                    write!(f, "\n  {:?} disabled", label)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, logger, Environment, Error};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that a panicking task is surfaced as an error with the seed, time, host and task.
+    fn panicked_task() {
+        let mut runtime = DeterministicRuntime::new_with_seed(9).unwrap();
+        let handle = runtime.handle();
+        let result = runtime.try_block_on(async {
+            let env = handle.clone();
+            handle.spawn(logger::with_host("node-2", async move {
+                env.delay_from(Duration::from_secs(2)).await;
+                panic!("lost quorum");
+            }));
+            handle.delay_from(Duration::from_secs(10)).await;
+        });
+        let (message, context) = match result {
+            Err(Error::Panicked { message, context }) => (message, context),
+            other => panic!("expected a panic, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(message, "lost quorum");
+        assert_eq!(context.seed, 9);
+        assert_eq!(context.elapsed, Duration::from_secs(2));
+        assert_eq!(context.host.as_deref(), Some("node-2"));
+        assert_eq!(context.task, Some(TaskId(1)));
+        assert!(!context.recent_events.is_empty() && context.recent_events.len() <= RECENT_EVENTS);
+        let error = Error::Panicked { message, context };
+        assert!(error
+            .to_string()
+            .starts_with("simulation panicked: lost quorum\nseed 9 (small_rng), simulated time 2s, host node-2, task 1"));
+    }
+
+    #[test]
+    /// Test that a failed run is written to the file set with `Builder::failure_report`.
+    fn report_file() {
+        let path = std::env::temp_dir().join(format!(
+            "simulation-failure-report-{}.txt",
+            std::process::id()
+        ));
+        let mut runtime = DeterministicRuntime::builder()
+            .seed(4)
+            .failure_report(&path)
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        assert!(runtime
+            .try_block_on(async move {
+                let env = handle.clone();
+                handle.spawn(async move { env.delay_from(Duration::from_secs(60)).await });
+                handle.delay_from(Duration::from_secs(1)).await;
+                crate::buggify!("before split");
+                panic!("split brain");
+            })
+            .is_err());
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(report.starts_with("simulation panicked: split brain\nseed 4"));
+        assert!(report.contains("\nrecent events:\n"));
+        assert!(report.contains("\npending tasks:\n  task 1 spawned at 0ns"));
+        assert!(report.contains("\nbuggify points:\n  \"before split\" "));
+    }
+}
@@ -1,11 +1,19 @@
 //! Fault injection controller.
-use rand::{rngs, Rng};
-use std::{ops, sync, time};
+use super::event::{EventLog, SimEvent};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net, ops, sync, time,
+};
 use tokio_timer::clock::Now;
 
 /// Configuration for various fauilts which can be injected into the mock network.
-#[derive(Debug, Clone)]
-pub struct Config {
+///
+/// Fields missing when a configuration is deserialized take their default values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultConfig {
     /// The range of duration for which a delay for a new connection can be injected.
     pub listener_connection_delay: ops::Range<time::Duration>,
     /// The probability of a new connection delay being injected, 0..1.
@@ -20,10 +28,66 @@ pub struct Config {
     pub socket_write_delay_prob: f64,
 
     pub disconnect_prob: f64,
+
+    /// The range of duration for which the client port of a connection closed by its client
+    /// remains unusable for new connections to the same server, as in the TIME_WAIT state.
+    pub time_wait: ops::Range<time::Duration>,
+    /// The probability of a closed connection holding its client port in TIME_WAIT, 0..1.
+    pub time_wait_prob: f64,
+
+    /// The probability of a message sent on a `MessageChannel` being lost, 0..1.
+    pub message_drop_prob: f64,
+    /// The range of duration for which the delivery of a message can be delayed.
+    pub message_delay: ops::Range<time::Duration>,
+    /// The probability of the delivery of a message being delayed, 0..1.
+    pub message_delay_prob: f64,
+    /// The probability of a message being delivered twice, 0..1.
+    pub message_duplicate_prob: f64,
+    /// The number of messages sent after a reordered message, due at the same instant, which
+    /// can be delivered before it. Wider windows reorder messages more aggressively.
+    pub message_reorder_window: u32,
+    /// The probability of a message being reordered within `message_reorder_window`, 0..1.
+    pub message_reorder_prob: f64,
+
+    /// The probability of a read of the wall clock of a host finding it has skipped or repeated
+    /// a second, as around a leap second, 0..1. Anomalies persist, shifting every later read.
+    pub clock_anomaly_prob: f64,
+    /// Whether a repeated second moves the wall clock backwards. Otherwise the clock holds
+    /// still for a second, so successive reads never decrease.
+    pub clock_backwards: bool,
+    /// The range of duration by which the wall clock of a host can be ahead of or behind
+    /// simulated time, drawn once for each host when its clock is first read.
+    pub clock_skew: ops::Range<time::Duration>,
+    /// The probability of the wall clock of a host being skewed, 0..1.
+    pub clock_skew_prob: f64,
+
+    /// The range of duration by which a timer can fire early or late.
+    pub timer_skew: ops::Range<time::Duration>,
+    /// The probability of a timer firing early or late, 0..1.
+    pub timer_skew_prob: f64,
+
+    /// The range of duration for which the address of a listener remains in use once its host
+    /// is restarted, failing attempts to bind it again with `AddrInUse`.
+    pub rebind_delay: ops::Range<time::Duration>,
+    /// The probability of the address of a restarted host's listener remaining in use, 0..1.
+    pub rebind_delay_prob: f64,
+
+    /// The range of duration for which every task of a host can be paused, as by a long garbage
+    /// collection or the migration of a virtual machine.
+    pub host_pause: ops::Range<time::Duration>,
+    /// The probability of pausing a host which is not already paused each time the executor
+    /// parks, 0..1.
+    pub host_pause_prob: f64,
+
+    /// The probability of a `buggify!` point being enabled for a run, decided when it is first
+    /// evaluated, 0..1.
+    pub buggify_prob: f64,
+    /// The probability of an enabled `buggify!` point firing each time it is evaluated, 0..1.
+    pub buggify_fire_prob: f64,
 }
 
-impl Config {
-    pub(crate) fn new() -> Self {
+impl Default for FaultConfig {
+    fn default() -> Self {
         Self {
             listener_connection_delay: time::Duration::from_millis(0)
                 ..time::Duration::from_millis(10000),
@@ -33,141 +97,838 @@ impl Config {
             socket_write_delay: time::Duration::from_millis(0)..time::Duration::from_millis(5000),
             socket_write_delay_prob: 0.10,
             disconnect_prob: 0.01,
+            time_wait: time::Duration::from_secs(30)..time::Duration::from_secs(60),
+            time_wait_prob: 0.0,
+            message_drop_prob: 0.01,
+            message_delay: time::Duration::from_millis(0)..time::Duration::from_millis(1000),
+            message_delay_prob: 0.10,
+            message_duplicate_prob: 0.01,
+            message_reorder_window: 4,
+            message_reorder_prob: 0.0,
+            clock_anomaly_prob: 0.0,
+            clock_backwards: false,
+            clock_skew: time::Duration::from_millis(0)..time::Duration::from_millis(500),
+            clock_skew_prob: 0.0,
+            timer_skew: time::Duration::from_millis(0)..time::Duration::from_millis(10),
+            timer_skew_prob: 0.0,
+            rebind_delay: time::Duration::from_secs(1)..time::Duration::from_secs(10),
+            rebind_delay_prob: 0.0,
+            host_pause: time::Duration::from_secs(1)..time::Duration::from_secs(30),
+            host_pause_prob: 0.0,
+            buggify_prob: 0.25,
+            buggify_fire_prob: 0.25,
+        }
+    }
+}
+
+impl FaultConfig {
+    /// Returns a configuration which never injects faults.
+    pub fn disabled() -> Self {
+        Self {
+            listener_connection_delay_prob: 0.0,
+            socket_read_delay_prob: 0.0,
+            socket_write_delay_prob: 0.0,
+            disconnect_prob: 0.0,
+            time_wait_prob: 0.0,
+            message_drop_prob: 0.0,
+            message_delay_prob: 0.0,
+            message_duplicate_prob: 0.0,
+            message_reorder_prob: 0.0,
+            clock_anomaly_prob: 0.0,
+            clock_skew_prob: 0.0,
+            timer_skew_prob: 0.0,
+            rebind_delay_prob: 0.0,
+            host_pause_prob: 0.0,
+            buggify_prob: 0.0,
+            ..Self::default()
+        }
+    }
+}
+
+/// The type of a fault injected by the `FaultInjector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FaultKind {
+    /// Delay accepting a new connection.
+    ListenerDelay,
+    /// Delay a read from a socket.
+    SocketReadDelay,
+    /// Delay a write to a socket.
+    SocketWriteDelay,
+    /// Disconnect an established connection.
+    Disconnect,
+    /// Drop a notification sent before any task was waiting for it.
+    LostWakeup,
+    /// Delay adding a value to a bounded queue.
+    EnqueueDelay,
+    /// Report a bounded queue as full when it has capacity.
+    SpuriousFull,
+    /// Hold the client port of a closed connection, failing reconnects which would reuse it.
+    TimeWait,
+    /// Lose a message sent on a `MessageChannel`.
+    MessageDrop,
+    /// Delay the delivery of a message.
+    MessageDelay,
+    /// Deliver a message twice.
+    MessageDuplicate,
+    /// Deliver a message after messages sent later.
+    MessageReorder,
+    /// Advance the wall clock of a host by an extra second.
+    ClockSkip,
+    /// Repeat a second of the wall clock of a host.
+    ClockRepeat,
+    /// Set the wall clock of a host ahead of or behind those of other hosts.
+    ClockSkew,
+    /// Fire a timer before its deadline.
+    TimerEarly,
+    /// Fire a timer after its deadline.
+    TimerLate,
+    /// Keep the address of a restarted host's listener in use.
+    RebindDelay,
+    /// Pause every task of a host.
+    HostPause,
+}
+
+/// Identifies a fault by the stream it was drawn from and its position within that stream.
+/// Ids remain the same when a run is repeated with other faults suppressed, or when
+/// unrelated activity is added to a test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct FaultId(pub u64);
+
+/// Record of a fault which was injected during a run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaultRecord {
+    pub id: FaultId,
+    pub kind: FaultKind,
+    /// Simulated time since the start of the run at which the fault was injected.
+    pub elapsed: time::Duration,
+    /// Client and server addresses of the connection the fault was injected into, if any.
+    #[serde(default)]
+    pub connection: Option<(net::SocketAddr, net::SocketAddr)>,
+}
+
+/// Identifies an independent stream of fault decisions. Each stream draws from its own RNG
+/// derived from the seed and the key, so the faults it produces do not depend on how often
+/// other streams are consulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum StreamKey {
+    /// Connections accepted by the listener bound to `port`.
+    Listener { port: u16 },
+    /// Reads of one side of the `connection`th connection made to `port`.
+    Socket {
+        port: u16,
+        connection: u64,
+        server: bool,
+    },
+    /// Writes of one side of the `connection`th connection made to `port`.
+    SocketWrite {
+        port: u16,
+        connection: u64,
+        server: bool,
+    },
+    /// Disconnects of connections made to `port`.
+    Disconnect { port: u16 },
+    /// A synchronization primitive, identified by the callsite it was created at.
+    Primitive { callsite: u64 },
+    /// Client ports held after closing connections made to `port`.
+    TimeWait { port: u16 },
+    /// Faults of `kind` injected into messages sent to `port`.
+    Message { port: u16, kind: FaultKind },
+    /// Reads of the wall clock.
+    Clock,
+    /// The skew of the wall clock.
+    ClockSkew,
+    /// Timers created by `Environment::delay` and `Environment::timeout`.
+    Timer,
+    /// Addresses of listeners held once their host is restarted.
+    Rebind { port: u16 },
+    /// Pauses of the hosts of a cluster.
+    HostPause,
+}
+
+/// Faults injected into a message sent on a `MessageChannel`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct MessageFaults {
+    pub(crate) drop: bool,
+    pub(crate) delay: Option<time::Duration>,
+    pub(crate) duplicate: bool,
+    /// The number of later messages which can overtake the message.
+    pub(crate) reorder: u32,
+}
+
+/// An anomaly of the wall clock of a host, injected when it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClockAnomaly {
+    /// The clock skips a second.
+    Skip,
+    /// The clock repeats a second, moving backwards if `backwards` is set.
+    Repeat { backwards: bool },
+}
+
+/// Skew of the wall clock of a host from simulated time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClockSkew {
+    Behind(time::Duration),
+    Ahead(time::Duration),
+}
+
+impl ClockSkew {
+    /// Moves the wall clock time `wall` by the skew.
+    pub(crate) fn apply(self, wall: time::SystemTime) -> time::SystemTime {
+        match self {
+            ClockSkew::Behind(skew) => wall.checked_sub(skew).unwrap_or(wall),
+            ClockSkew::Ahead(skew) => wall + skew,
+        }
+    }
+}
+
+/// Skew injected into a timer, moving its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimerSkew {
+    Early(time::Duration),
+    Late(time::Duration),
+}
+
+impl TimerSkew {
+    /// Moves `deadline` by the skew, firing no earlier than `now`.
+    pub(crate) fn apply(self, deadline: time::Instant, now: time::Instant) -> time::Instant {
+        match self {
+            TimerSkew::Early(skew) => deadline
+                .checked_sub(skew)
+                .map_or(now, |deadline| deadline.max(now)),
+            TimerSkew::Late(skew) => deadline + skew,
         }
     }
 }
 
+#[derive(Debug)]
+struct Stream {
+    rng: super::rng::SimRng,
+    /// Number of fault decisions drawn from this stream.
+    draws: u64,
+    /// Number of decisions remaining before the next fault, or `None` if it has not been
+    /// sampled since the last fault.
+    quiet: Option<u64>,
+}
+
+impl Stream {
+    /// Returns the number of decisions remaining before the next fault, sampling it from the
+    /// geometric distribution of fault arrivals if needed. Sampling the gap between faults
+    /// takes a single draw from the RNG, rather than one per decision.
+    fn quiet(&mut self, probability: f64) -> &mut u64 {
+        if self.quiet.is_none() {
+            let quiet = if probability <= 0.0 {
+                u64::MAX
+            } else if probability >= 1.0 {
+                0
+            } else {
+                // `1 - gen` lies in (0, 1], so the logarithm is finite.
+                let u: f64 = 1.0 - self.rng.gen::<f64>();
+                (u.ln() / (1.0 - probability).ln()).floor() as u64
+            };
+            self.quiet = Some(quiet);
+        }
+        self.quiet.as_mut().unwrap()
+    }
+}
+
+/// Restricts which faults are injected, allowing a run to be repeated with a subset of its
+/// faults.
+#[derive(Debug, Clone)]
+enum Filter {
+    All,
+    Only(HashSet<FaultId>),
+}
+
 #[derive(Debug)]
 enum State {
     Real {
         timer_handle: tokio_timer::timer::Handle,
         now: super::time::Now,
-        rng: rngs::SmallRng,
+        seed: u64,
+        algorithm: super::rng::Algorithm,
+        /// Streams keyed by the scope of the handle drawing from them along with their key.
+        streams: HashMap<(u64, StreamKey), Stream>,
+        filter: Filter,
+        records: Vec<FaultRecord>,
+        events: EventLog,
     },
     Noop,
 }
 
 impl State {
-    fn should_fault(&mut self, probability: f64) -> bool {
-        match self {
-            State::Real { rng, .. } => rng.gen_bool(probability),
-            State::Noop => false,
-        }
-    }
-
-    fn new_delay(&mut self, range: ops::Range<time::Duration>) -> tokio_timer::Delay {
+    /// Decides whether to inject a fault with the provided probability, drawing from the stream
+    /// identified by `key`. Returns the stream along with the id of the fault if it should be
+    /// injected, or `None` if no fault was drawn. Injected faults are then passed to `record`.
+    fn should_fault(
+        &mut self,
+        key: (u64, StreamKey),
+        probability: f64,
+    ) -> Option<(&mut Stream, Option<FaultId>)> {
         match self {
             State::Real {
-                timer_handle,
-                now,
-                rng,
+                seed,
+                algorithm,
+                streams,
+                filter,
+                ..
             } => {
-                let now = now.now();
-                let duration = rng.gen_range(range.start, range.end);
-                timer_handle.delay(now + duration)
+                let seed = *seed;
+                let stream = streams.entry(key).or_insert_with(|| Stream {
+                    rng: super::rng::derive(algorithm, seed, &key),
+                    draws: 0,
+                    quiet: None,
+                });
+                let draw = stream.draws;
+                stream.draws += 1;
+                let quiet = stream.quiet(probability);
+                if *quiet > 0 {
+                    *quiet -= 1;
+                    return None;
+                }
+                stream.quiet = None;
+                let id = FaultId(super::rng::stable_hash(&(key, draw)));
+                if let Filter::Only(allowed) = filter {
+                    if !allowed.contains(&id) {
+                        return Some((stream, None));
+                    }
+                }
+                Some((stream, Some(id)))
             }
-            State::Noop => unreachable!(),
+            State::Noop => None,
         }
     }
 
+    /// Records that the fault `id` of `kind` was injected into `connection`.
+    fn record(
+        &mut self,
+        id: FaultId,
+        kind: FaultKind,
+        connection: Option<(net::SocketAddr, net::SocketAddr)>,
+    ) {
+        if let State::Real {
+            now,
+            records,
+            events,
+            ..
+        } = self
+        {
+            let record = FaultRecord {
+                id,
+                kind,
+                elapsed: now.elapsed(),
+                connection,
+            };
+            records.push(record.clone());
+            events.record(SimEvent::FaultInjected(record));
+        }
+    }
+
+    /// Draws a delay from `range` if a fault should be injected, recording the fault as
+    /// injected into `connection`.
     fn maybe_new_delay(
         &mut self,
+        key: (u64, StreamKey),
         probability: f64,
         range: ops::Range<time::Duration>,
+        kind: FaultKind,
+        connection: Option<(net::SocketAddr, net::SocketAddr)>,
     ) -> Option<tokio_timer::Delay> {
-        if self.should_fault(probability) {
-            let delay = self.new_delay(range);
-            Some(delay)
-        } else {
-            None
+        let (stream, id) = self.should_fault(key, probability)?;
+        // suppressed faults still draw a delay, keeping the remaining draws stable.
+        let duration = stream.rng.gen_range(range.start, range.end);
+        self.record(id?, kind, connection);
+        match self {
+            State::Real {
+                timer_handle, now, ..
+            } => Some(timer_handle.delay(now.now() + duration)),
+            State::Noop => None,
         }
     }
 
-    fn random_idx(&mut self, probability: f64, range: ops::Range<usize>) -> Option<usize> {
-        if self.should_fault(probability) {
-            match self {
-                State::Real { rng, .. } => Some(rng.gen_range(range.start, range.end)),
-                State::Noop => None,
+    /// Skips the decisions remaining before the next fault drawn from the stream identified
+    /// by `key`, returning how many were skipped. The caller can then treat that many
+    /// decisions as faultless without consulting the injector.
+    fn skip_quiet(&mut self, key: (u64, StreamKey), probability: f64) -> u64 {
+        match self {
+            State::Real {
+                seed,
+                algorithm,
+                streams,
+                ..
+            } => {
+                let seed = *seed;
+                let stream = streams.entry(key).or_insert_with(|| Stream {
+                    rng: super::rng::derive(algorithm, seed, &key),
+                    draws: 0,
+                    quiet: None,
+                });
+                let quiet = std::mem::replace(stream.quiet(probability), 0);
+                stream.draws = stream.draws.saturating_add(quiet);
+                quiet
             }
-        } else {
-            None
+            State::Noop => u64::MAX,
         }
     }
+
+    /// Draws an index from `range` if a fault should be injected, recording the fault as
+    /// injected into the connection returned by `connection` for the index.
+    fn random_idx<C>(
+        &mut self,
+        key: (u64, StreamKey),
+        probability: f64,
+        range: ops::Range<usize>,
+        kind: FaultKind,
+        connection: C,
+    ) -> Option<usize>
+    where
+        C: FnOnce(usize) -> Option<(net::SocketAddr, net::SocketAddr)>,
+    {
+        let (stream, id) = self.should_fault(key, probability)?;
+        let idx = stream.rng.gen_range(range.start, range.end);
+        self.record(id?, kind, connection(idx));
+        Some(idx)
+    }
 }
 
 #[derive(Debug)]
 pub struct FaultInjector {
-    config: Config,
+    config: FaultConfig,
     inner: sync::Arc<sync::Mutex<State>>,
 }
 
 impl FaultInjector {
     pub(crate) fn new_noop() -> Self {
         FaultInjector {
-            config: Config::new(),
+            config: FaultConfig::default(),
             inner: sync::Arc::new(sync::Mutex::new(State::Noop)),
         }
     }
     pub(crate) fn new(
+        config: FaultConfig,
         seed: u64,
+        algorithm: super::rng::Algorithm,
         timer_handle: tokio_timer::timer::Handle,
         now: super::time::Now,
+        events: EventLog,
     ) -> FaultInjector {
         let state = State::Real {
             timer_handle,
             now,
-            rng: rand::SeedableRng::seed_from_u64(seed),
+            seed,
+            algorithm,
+            streams: HashMap::new(),
+            filter: Filter::All,
+            records: vec![],
+            events,
         };
         let state = sync::Arc::new(sync::Mutex::new(state));
         FaultInjector {
-            config: Config::new(),
+            config,
             inner: state,
         }
     }
     pub(crate) fn handle(&self) -> FaultInjectorHandle {
-        FaultInjectorHandle::new(self.config.clone(), sync::Arc::clone(&self.inner))
+        FaultInjectorHandle::new(self.config.clone(), 0, sync::Arc::clone(&self.inner))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct FaultInjectorHandle {
-    config: Config,
+    config: FaultConfig,
+    /// Distinguishes the streams of handles sharing the same `FaultInjector`, such as those of
+    /// separate clusters.
+    scope: u64,
     inner: sync::Arc<sync::Mutex<State>>,
 }
 
 impl FaultInjectorHandle {
-    fn new(config: Config, inner: sync::Arc<sync::Mutex<State>>) -> Self {
-        Self { config, inner }
+    fn new(config: FaultConfig, scope: u64, inner: sync::Arc<sync::Mutex<State>>) -> Self {
+        Self {
+            config,
+            scope,
+            inner,
+        }
     }
 
-    pub(crate) fn listener_delay(&self) -> Option<tokio_timer::Delay> {
+    /// Returns the faults this handle injects.
+    pub(crate) fn config(&self) -> &FaultConfig {
+        &self.config
+    }
+
+    /// Returns a handle sharing the seed and records of this handle, injecting faults according
+    /// to `config` from streams which are independent of those of other scopes.
+    pub(crate) fn scoped(&self, scope: u64, config: FaultConfig) -> Self {
+        Self::new(config, scope, sync::Arc::clone(&self.inner))
+    }
+
+    pub(crate) fn listener_delay(&self, key: StreamKey) -> Option<tokio_timer::Delay> {
         self.inner.lock().unwrap().maybe_new_delay(
+            (self.scope, key),
             self.config.listener_connection_delay_prob,
             self.config.listener_connection_delay.clone(),
+            FaultKind::ListenerDelay,
+            None,
         )
     }
 
-    pub(crate) fn socket_read_delay(&self) -> Option<tokio_timer::Delay> {
-        self.inner.lock().unwrap().maybe_new_delay(
+    /// Decides whether to delay the next read of the socket identified by `key`, of the
+    /// connection between the client and server addresses `connection`. Also returns the number
+    /// of following reads which will not be delayed, which the socket may skip without
+    /// consulting the injector.
+    pub(crate) fn socket_read_delay(
+        &self,
+        key: StreamKey,
+        connection: (net::SocketAddr, net::SocketAddr),
+    ) -> (Option<tokio_timer::Delay>, u64) {
+        let mut lock = self.inner.lock().unwrap();
+        let delay = lock.maybe_new_delay(
+            (self.scope, key),
             self.config.socket_read_delay_prob,
             self.config.socket_read_delay.clone(),
-        )
+            FaultKind::SocketReadDelay,
+            Some(connection),
+        );
+        let quiet = lock.skip_quiet((self.scope, key), self.config.socket_read_delay_prob);
+        (delay, quiet)
     }
 
-    pub(crate) fn socket_write_delay(&self) -> Option<tokio_timer::Delay> {
-        self.inner.lock().unwrap().maybe_new_delay(
+    /// Decides whether to delay the next write of the socket identified by `key`, as
+    /// `socket_read_delay` does for reads.
+    pub(crate) fn socket_write_delay(
+        &self,
+        key: StreamKey,
+        connection: (net::SocketAddr, net::SocketAddr),
+    ) -> (Option<tokio_timer::Delay>, u64) {
+        let mut lock = self.inner.lock().unwrap();
+        let delay = lock.maybe_new_delay(
+            (self.scope, key),
             self.config.socket_write_delay_prob,
             self.config.socket_write_delay.clone(),
-        )
+            FaultKind::SocketWriteDelay,
+            Some(connection),
+        );
+        let quiet = lock.skip_quiet((self.scope, key), self.config.socket_write_delay_prob);
+        (delay, quiet)
     }
 
-    pub(crate) fn pick_rand_connection_disconnect(
+    /// Decides whether to disconnect one of the connections in `range`, returning its index.
+    /// `connection` returns the addresses of the connection at an index.
+    pub(crate) fn pick_rand_connection_disconnect<C>(
         &self,
+        key: StreamKey,
         range: ops::Range<usize>,
-    ) -> Option<usize> {
-        self.inner
-            .lock()
-            .unwrap()
-            .random_idx(self.config.disconnect_prob, range)
+        connection: C,
+    ) -> Option<usize>
+    where
+        C: FnOnce(usize) -> Option<(net::SocketAddr, net::SocketAddr)>,
+    {
+        self.inner.lock().unwrap().random_idx(
+            (self.scope, key),
+            self.config.disconnect_prob,
+            range,
+            FaultKind::Disconnect,
+            connection,
+        )
+    }
+
+    /// Decides whether the client port of `connection`, closed by its client, is held in
+    /// TIME_WAIT, returning the instant at which it may be reused.
+    pub(crate) fn time_wait(
+        &self,
+        connection: (net::SocketAddr, net::SocketAddr),
+    ) -> Option<time::Instant> {
+        let mut lock = self.inner.lock().unwrap();
+        let key = StreamKey::TimeWait {
+            port: connection.1.port(),
+        };
+        let range = self.config.time_wait.clone();
+        let (stream, id) = lock.should_fault((self.scope, key), self.config.time_wait_prob)?;
+        let duration = stream.rng.gen_range(range.start, range.end);
+        lock.record(id?, FaultKind::TimeWait, Some(connection));
+        match &*lock {
+            State::Real { now, .. } => Some(now.now() + duration),
+            State::Noop => None,
+        }
+    }
+
+    /// Decides whether to pause the tasks of a host, returning how long they are paused for.
+    pub(crate) fn host_pause(&self) -> Option<time::Duration> {
+        let mut lock = self.inner.lock().unwrap();
+        let range = self.config.host_pause.clone();
+        let key = (self.scope, StreamKey::HostPause);
+        let (stream, id) = lock.should_fault(key, self.config.host_pause_prob)?;
+        let duration = stream.rng.gen_range(range.start, range.end);
+        lock.record(id?, FaultKind::HostPause, None);
+        Some(duration)
+    }
+
+    /// Decides whether the address `addr` of a listener of a restarted host remains in use,
+    /// returning the instant at which it may be bound again.
+    pub(crate) fn rebind_delay(&self, addr: net::SocketAddr) -> Option<time::Instant> {
+        let mut lock = self.inner.lock().unwrap();
+        let key = StreamKey::Rebind { port: addr.port() };
+        let range = self.config.rebind_delay.clone();
+        let (stream, id) = lock.should_fault((self.scope, key), self.config.rebind_delay_prob)?;
+        let duration = stream.rng.gen_range(range.start, range.end);
+        lock.record(id?, FaultKind::RebindDelay, None);
+        match &*lock {
+            State::Real { now, .. } => Some(now.now() + duration),
+            State::Noop => None,
+        }
+    }
+
+    /// Decides which faults to inject into a message sent from and to the addresses of
+    /// `connection`. Every kind of fault is drawn for each message, so the faults of later
+    /// messages do not depend on whether this one was lost, but those of a lost message are
+    /// not recorded.
+    pub(crate) fn message_faults(
+        &self,
+        connection: (net::SocketAddr, net::SocketAddr),
+    ) -> MessageFaults {
+        let mut lock = self.inner.lock().unwrap();
+        let port = connection.1.port();
+        let key = |kind| (self.scope, StreamKey::Message { port, kind });
+        let drop = lock
+            .should_fault(key(FaultKind::MessageDrop), self.config.message_drop_prob)
+            .and_then(|(_, id)| id);
+        let range = self.config.message_delay.clone();
+        let delay = lock
+            .should_fault(key(FaultKind::MessageDelay), self.config.message_delay_prob)
+            .and_then(|(stream, id)| {
+                let duration = stream.rng.gen_range(range.start, range.end);
+                Some((id?, duration))
+            });
+        let duplicate = lock
+            .should_fault(
+                key(FaultKind::MessageDuplicate),
+                self.config.message_duplicate_prob,
+            )
+            .and_then(|(_, id)| id);
+        let window = self.config.message_reorder_window;
+        let reorder = lock
+            .should_fault(
+                key(FaultKind::MessageReorder),
+                self.config.message_reorder_prob,
+            )
+            .filter(|_| window > 0)
+            .and_then(|(stream, id)| Some((id?, stream.rng.gen_range(1, window + 1))));
+        if let Some(id) = drop {
+            lock.record(id, FaultKind::MessageDrop, Some(connection));
+            return MessageFaults {
+                drop: true,
+                ..MessageFaults::default()
+            };
+        }
+        if let Some((id, _)) = delay {
+            lock.record(id, FaultKind::MessageDelay, Some(connection));
+        }
+        if let Some(id) = duplicate {
+            lock.record(id, FaultKind::MessageDuplicate, Some(connection));
+        }
+        if let Some((id, _)) = reorder {
+            lock.record(id, FaultKind::MessageReorder, Some(connection));
+        }
+        MessageFaults {
+            drop: false,
+            delay: delay.map(|(_, duration)| duration),
+            duplicate: duplicate.is_some(),
+            reorder: reorder.map_or(0, |(_, positions)| positions),
+        }
+    }
+
+    /// Decides whether to inject an anomaly into a read of the wall clock.
+    pub(crate) fn clock_anomaly(&self) -> Option<ClockAnomaly> {
+        let mut lock = self.inner.lock().unwrap();
+        let key = (self.scope, StreamKey::Clock);
+        let (stream, id) = lock.should_fault(key, self.config.clock_anomaly_prob)?;
+        let skip = stream.rng.gen_bool(0.5);
+        let (kind, anomaly) = if skip {
+            (FaultKind::ClockSkip, ClockAnomaly::Skip)
+        } else {
+            let backwards = self.config.clock_backwards;
+            (FaultKind::ClockRepeat, ClockAnomaly::Repeat { backwards })
+        };
+        lock.record(id?, kind, None);
+        Some(anomaly)
+    }
+
+    /// Decides whether to skew the wall clock of a host.
+    pub(crate) fn clock_skew(&self) -> Option<ClockSkew> {
+        let mut lock = self.inner.lock().unwrap();
+        let key = (self.scope, StreamKey::ClockSkew);
+        let range = self.config.clock_skew.clone();
+        let (stream, id) = lock.should_fault(key, self.config.clock_skew_prob)?;
+        let skew = stream.rng.gen_range(range.start, range.end);
+        let skew = if stream.rng.gen_bool(0.5) {
+            ClockSkew::Behind(skew)
+        } else {
+            ClockSkew::Ahead(skew)
+        };
+        lock.record(id?, FaultKind::ClockSkew, None);
+        Some(skew)
+    }
+
+    /// Decides whether to skew a timer being created.
+    pub(crate) fn timer_skew(&self) -> Option<TimerSkew> {
+        let mut lock = self.inner.lock().unwrap();
+        let key = (self.scope, StreamKey::Timer);
+        let range = self.config.timer_skew.clone();
+        let (stream, id) = lock.should_fault(key, self.config.timer_skew_prob)?;
+        let skew = stream.rng.gen_range(range.start, range.end);
+        let early = stream.rng.gen_bool(0.5);
+        let (kind, skew) = if early {
+            (FaultKind::TimerEarly, TimerSkew::Early(skew))
+        } else {
+            (FaultKind::TimerLate, TimerSkew::Late(skew))
+        };
+        lock.record(id?, kind, None);
+        Some(skew)
+    }
+
+    /// Decides whether to inject a fault of `kind` into the synchronization primitive
+    /// identified by `callsite`.
+    pub(crate) fn primitive_fault(&self, callsite: u64, probability: f64, kind: FaultKind) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        let id = lock
+            .should_fault((self.scope, StreamKey::Primitive { callsite }), probability)
+            .and_then(|(_, id)| id);
+        if let Some(id) = id {
+            lock.record(id, kind, None);
+        }
+        id.is_some()
+    }
+
+    pub(crate) fn primitive_delay(
+        &self,
+        callsite: u64,
+        probability: f64,
+        range: ops::Range<time::Duration>,
+        kind: FaultKind,
+    ) -> Option<tokio_timer::Delay> {
+        self.inner.lock().unwrap().maybe_new_delay(
+            (self.scope, StreamKey::Primitive { callsite }),
+            probability,
+            range,
+            kind,
+            None,
+        )
+    }
+
+    /// Returns a record of every fault injected so far.
+    pub(crate) fn records(&self) -> Vec<FaultRecord> {
+        match &*self.inner.lock().unwrap() {
+            State::Real { records, .. } => records.clone(),
+            State::Noop => vec![],
+        }
+    }
+
+    /// Restricts fault injection to the faults identified by `allowed`. All other faults are
+    /// drawn from the RNG as usual but are not injected.
+    pub(crate) fn allow_only(&self, allowed: HashSet<FaultId>) {
+        if let State::Real { filter, .. } = &mut *self.inner.lock().unwrap() {
+            *filter = Filter::Only(allowed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    fn socket(connection: u64) -> StreamKey {
+        StreamKey::Socket {
+            port: 9092,
+            connection,
+            server: true,
+        }
+    }
+
+    #[test]
+    /// Test that drawing from one stream does not change the faults of another.
+    fn independent_streams() {
+        let draw = |runtime: &DeterministicRuntime, key| {
+            let mut delays = vec![];
+            while delays.len() < 200 {
+                let addrs = (
+                    "127.0.0.1:49152".parse().unwrap(),
+                    "127.0.0.1:9092".parse().unwrap(),
+                );
+                let (delay, quiet) = runtime.handle.fault_injector.socket_read_delay(key, addrs);
+                delays.push(delay.is_some());
+                delays.extend((0..quiet.min(200)).map(|_| false));
+            }
+            delays.truncate(200);
+            delays
+        };
+        let first = DeterministicRuntime::new_with_seed(3).unwrap();
+        let second = DeterministicRuntime::new_with_seed(3).unwrap();
+        draw(&second, socket(1));
+        assert_eq!(draw(&first, socket(0)), draw(&second, socket(0)));
+
+        let injected: HashSet<FaultId> = first.faults().iter().map(|f| f.id).collect();
+        let reinjected = second
+            .faults()
+            .iter()
+            .filter(|f| injected.contains(&f.id))
+            .count();
+        assert!(!injected.is_empty());
+        assert_eq!(reinjected, injected.len());
+    }
+
+    #[test]
+    /// Test that configurations round trip through JSON, with missing fields defaulted.
+    fn serde_config() {
+        let config = FaultConfig {
+            disconnect_prob: 0.5,
+            ..FaultConfig::disabled()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<FaultConfig>(&json).unwrap(), config);
+        let partial: FaultConfig = serde_json::from_str(r#"{"disconnect_prob": 0.25}"#).unwrap();
+        assert_eq!(
+            partial,
+            FaultConfig {
+                disconnect_prob: 0.25,
+                ..FaultConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    /// Test that skewed timers fire early or late within the configured bound, rounded to the
+    /// resolution of the timer, as recorded.
+    fn timer_skew() {
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig {
+                timer_skew: time::Duration::from_millis(1)..time::Duration::from_millis(10),
+                timer_skew_prob: 0.5,
+                ..FaultConfig::disabled()
+            })
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let period = time::Duration::from_millis(100);
+        let fired = runtime.block_on(async {
+            let mut fired = vec![];
+            for _ in 0..50 {
+                let start = handle.now();
+                crate::Environment::delay_from(&handle, period).await;
+                fired.push(handle.now() - start);
+            }
+            fired
+        });
+        let count = |kind| runtime.faults().iter().filter(|f| f.kind == kind).count();
+        let early = fired.iter().filter(|elapsed| **elapsed < period).count();
+        let late = fired.iter().filter(|elapsed| **elapsed > period).count();
+        assert!(early > 0 && late > 0);
+        assert_eq!(
+            (early, late),
+            (count(FaultKind::TimerEarly), count(FaultKind::TimerLate))
+        );
+        let bound = time::Duration::from_millis(10);
+        assert!(fired
+            .iter()
+            .all(|elapsed| *elapsed >= period - bound && *elapsed <= period + bound));
     }
 }
@@ -0,0 +1,162 @@
+//! Invariants checked whenever the runtime reaches quiescence.
+//!
+//! The deterministic runtime is quiescent when no task can make progress without time being
+//! advanced. Checking invariants at these points catches transient bad states which an
+//! assertion at the end of a test would miss.
+use futures::{future::BoxFuture, FutureExt};
+use std::{fmt, sync, task, time};
+
+struct Invariant {
+    name: String,
+    check: Box<dyn FnMut() -> BoxFuture<'static, Result<(), String>> + Send>,
+}
+
+/// Set of invariants registered with a runtime.
+#[derive(Clone, Default)]
+pub(crate) struct Invariants {
+    inner: sync::Arc<sync::Mutex<Vec<Invariant>>>,
+}
+
+impl fmt::Debug for Invariants {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        f.debug_list()
+            .entries(lock.iter().map(|invariant| &invariant.name))
+            .finish()
+    }
+}
+
+impl Invariants {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn register<F, U>(&self, name: &str, mut check: F)
+    where
+        F: FnMut() -> U + Send + 'static,
+        U: std::future::Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.inner.lock().unwrap().push(Invariant {
+            name: String::from(name),
+            check: Box::new(move || check().boxed()),
+        })
+    }
+
+    /// Evaluates every invariant, returning the name and error of the first violation.
+    ///
+    /// Invariants are polled exactly once. An invariant which cannot complete without
+    /// waiting is skipped for this quiescent point.
+    fn check(&self) -> Result<(), (String, String)> {
+        let mut lock = self.inner.lock().unwrap();
+        let mut cx = task::Context::from_waker(futures::task::noop_waker_ref());
+        for invariant in lock.iter_mut() {
+            if let task::Poll::Ready(Err(e)) = (invariant.check)().poll_unpin(&mut cx) {
+                return Err((invariant.name.clone(), e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Wrap the provided `Park` instance in a new `Park` which checks invariants before
+    /// parking.
+    ///
+    /// [`Park`]:[tokio_executor::park::Park]
+    pub(crate) fn wrap_park<P>(&self, park: P, seed: u64, time: super::Time) -> Park<P> {
+        Park {
+            invariants: self.clone(),
+            seed,
+            time,
+            inner_park: park,
+        }
+    }
+}
+
+/// `Park` implementation which checks invariants when the executor is idle and
+/// about to advance time.
+#[derive(Debug)]
+pub(crate) struct Park<P> {
+    invariants: Invariants,
+    seed: u64,
+    time: super::Time,
+    inner_park: P,
+}
+
+impl<P> Park<P> {
+    fn check(&self) {
+        if let Err((name, e)) = self.invariants.check() {
+            panic!(
+                "invariant `{}` violated at seed {}, simulated time {:?}: {}",
+                name,
+                self.seed,
+                self.time.elapsed(),
+                e
+            );
+        }
+    }
+}
+
+impl<P> tokio_executor::park::Park for Park<P>
+where
+    P: tokio_executor::park::Park,
+{
+    type Unpark = P::Unpark;
+    type Error = P::Error;
+    fn unpark(&self) -> Self::Unpark {
+        self.inner_park.unpark()
+    }
+    fn park(&mut self) -> Result<(), Self::Error> {
+        self.check();
+        self.inner_park.park()
+    }
+    fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
+        if duration > time::Duration::from_millis(0) {
+            self.check();
+        }
+        self.inner_park.park_timeout(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::{
+        panic,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    /// Test that a transient violation of an invariant fails the run, even though the state
+    /// is restored before the test completes.
+    fn transient_violation() {
+        let mut runtime = DeterministicRuntime::new_with_seed(7).unwrap();
+        let handle = runtime.handle();
+        let balance = Arc::new(AtomicUsize::new(10));
+        let check_balance = Arc::clone(&balance);
+        handle.register_invariant("balance is at least ten", move || {
+            let balance = check_balance.load(Ordering::SeqCst);
+            async move {
+                if balance < 10 {
+                    return Err(format!("balance was {}", balance));
+                }
+                Ok(())
+            }
+        });
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            runtime.block_on(async {
+                balance.store(5, Ordering::SeqCst);
+                handle.delay_from(Duration::from_secs(1)).await;
+                balance.store(10, Ordering::SeqCst);
+            })
+        }));
+        let message = crate::deterministic::sweep::panic_message(&*result.unwrap_err());
+        assert_eq!(
+            message,
+            "invariant `balance is at least ten` violated at seed 7, simulated time 0ns: balance was 5"
+        );
+    }
+}
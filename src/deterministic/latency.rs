@@ -0,0 +1,161 @@
+//! Latencies observed on a real network, used to calibrate the delays of the simulated one.
+//!
+//! A `LatencyProfile` is recorded by running against real dependencies with the
+//! `SingleThreadedRuntime`, see `SingleThreadedRuntime::record_latencies`, and saved as an
+//! artifact. `LatencyProfile::apply` then replaces the delays of a `FaultConfig` with ones drawn
+//! from the observations, so the timing of the simulated network follows that of the
+//! deployment it was recorded in.
+use super::{report::Artifact, FaultConfig};
+use serde::{Deserialize, Serialize};
+use std::{ops, time};
+
+/// Latencies of the operations observed on a real network.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LatencyProfile {
+    /// Time taken to establish each connection.
+    pub connect: Vec<time::Duration>,
+    /// Time each read waited for data, zero for reads which found data ready.
+    pub read: Vec<time::Duration>,
+    /// Time each write waited for buffer space, zero for writes which completed at once.
+    pub write: Vec<time::Duration>,
+}
+
+impl Artifact for LatencyProfile {
+    const KIND: &'static str = "latency_profile";
+}
+
+impl LatencyProfile {
+    /// Returns `config` with the listener, read and write delays replaced by the observations
+    /// of this profile. Each kind of operation is delayed with the probability that an observed
+    /// operation waited, by a duration between the shortest wait and the 99th percentile. Kinds
+    /// of operation without observations keep the delays of `config`.
+    pub fn apply(&self, config: FaultConfig) -> FaultConfig {
+        let mut config = config;
+        if let Some((range, prob)) = distribution(&self.connect) {
+            config.listener_connection_delay = range;
+            config.listener_connection_delay_prob = prob;
+        }
+        if let Some((range, prob)) = distribution(&self.read) {
+            config.socket_read_delay = range;
+            config.socket_read_delay_prob = prob;
+        }
+        if let Some((range, prob)) = distribution(&self.write) {
+            config.socket_write_delay = range;
+            config.socket_write_delay_prob = prob;
+        }
+        config
+    }
+}
+
+/// Returns the range of the waits among `samples`, from the shortest to the 99th percentile,
+/// along with the fraction of samples which waited.
+fn distribution(samples: &[time::Duration]) -> Option<(ops::Range<time::Duration>, f64)> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut waits: Vec<_> = samples
+        .iter()
+        .copied()
+        .filter(|sample| *sample > time::Duration::from_secs(0))
+        .collect();
+    let prob = waits.len() as f64 / samples.len() as f64;
+    if waits.is_empty() {
+        let zero = time::Duration::from_secs(0);
+        return Some((zero..zero + time::Duration::from_nanos(1), prob));
+    }
+    waits.sort();
+    let low = waits[0];
+    let high = waits[(waits.len() - 1) * 99 / 100];
+    // ranges are sampled excluding their end, which must lie past the start.
+    Some((low..high.max(low) + time::Duration::from_nanos(1), prob))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultKind},
+        Environment, TcpListener,
+    };
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn millis(values: &[u64]) -> Vec<time::Duration> {
+        values
+            .iter()
+            .map(|value| time::Duration::from_millis(*value))
+            .collect()
+    }
+
+    #[test]
+    fn applies_observed_waits() {
+        let profile = LatencyProfile {
+            connect: millis(&[10, 20, 30, 40]),
+            read: millis(&[0, 0, 0, 5]),
+            write: vec![],
+        };
+        let config = profile.apply(FaultConfig::disabled());
+        assert_eq!(config.listener_connection_delay_prob, 1.0);
+        assert_eq!(
+            config.listener_connection_delay.start,
+            time::Duration::from_millis(10)
+        );
+        assert!(config.listener_connection_delay.end > time::Duration::from_millis(30));
+        assert_eq!(config.socket_read_delay_prob, 0.25);
+        assert_eq!(config.socket_write_delay_prob, 0.0);
+        assert_eq!(
+            config.socket_write_delay,
+            FaultConfig::disabled().socket_write_delay
+        );
+    }
+
+    #[test]
+    fn round_trips_as_artifact() {
+        let profile = LatencyProfile {
+            connect: millis(&[1, 2]),
+            read: millis(&[3]),
+            write: millis(&[0]),
+        };
+        let json = profile.to_json().unwrap();
+        assert_eq!(LatencyProfile::from_json(&json).unwrap(), profile);
+    }
+
+    /// Writes ten messages over a connection of a cluster with `config`, returning the
+    /// simulated time taken and the kinds of faults injected.
+    fn exchange(config: FaultConfig) -> (time::Duration, Vec<FaultKind>) {
+        let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+        let handle = runtime.handle().new_cluster(config);
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let elapsed = runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+            let start = handle.now();
+            for _ in 0..10 {
+                client.write_all(b"hello").await.unwrap();
+                let mut buf = [0; 5];
+                server.read_exact(&mut buf).await.unwrap();
+            }
+            handle.now() - start
+        });
+        let kinds = runtime.faults().iter().map(|fault| fault.kind).collect();
+        (elapsed, kinds)
+    }
+
+    #[test]
+    /// Test that a profile observing only slow writes delays writes, and not reads.
+    fn delays_writes() {
+        let profile = LatencyProfile {
+            write: millis(&[50, 50]),
+            ..LatencyProfile::default()
+        };
+        let (baseline, faults) = exchange(FaultConfig::disabled());
+        assert!(faults.is_empty());
+        let (elapsed, faults) = exchange(profile.apply(FaultConfig::disabled()));
+        assert!(elapsed >= baseline + time::Duration::from_millis(250));
+        assert!(!faults.is_empty());
+        assert!(faults
+            .iter()
+            .all(|kind| *kind == FaultKind::SocketWriteDelay));
+    }
+}
@@ -0,0 +1,181 @@
+//! Accounting of the memory held by the simulated network on behalf of each host.
+//!
+//! Bytes written to a connection are charged to the host which reads them until they are read,
+//! and messages sent to a `MessageChannel` to the host the channel is bound in until they are
+//! received. Totals are approximate: buffers are counted by the bytes they hold rather than
+//! their capacity, and messages by the size of their type. A simulation which keeps sending
+//! to a peer which never reads shows up as a host whose usage keeps growing, and fails once it
+//! exceeds the budget set with `Builder::memory_budget`. Disks are not simulated, so nothing
+//! is charged for storage.
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    self,
+    atomic::{AtomicU64, Ordering},
+};
+
+/// Memory held by the simulated buffers and queues of a host, reported by `RunStats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub host: String,
+    /// Bytes held when the stats were taken.
+    pub used: u64,
+    /// Most bytes held at any point of the run.
+    pub peak: u64,
+}
+
+/// Returns the name of the `index`th cluster in reports.
+pub(crate) fn host_name(index: usize) -> String {
+    match index {
+        0 => "localhost".to_string(),
+        index => format!("cluster-{}", index),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    used: AtomicU64,
+    peak: AtomicU64,
+}
+
+/// Memory held on behalf of one host. Clones charge the same host.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HostMemory {
+    index: usize,
+    counters: sync::Arc<Counters>,
+    budget: MemoryBudget,
+}
+
+impl HostMemory {
+    /// Charges `bytes` to the host.
+    pub(crate) fn allocate(&self, bytes: usize) {
+        let bytes = bytes as u64;
+        let used = self.counters.used.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        self.counters.peak.fetch_max(used, Ordering::SeqCst);
+        self.budget.charge(self.index, used);
+    }
+
+    /// Releases `bytes` previously charged to the host.
+    pub(crate) fn free(&self, bytes: usize) {
+        self.counters.used.fetch_sub(bytes as u64, Ordering::SeqCst);
+    }
+
+    pub(crate) fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            host: host_name(self.index),
+            used: self.counters.used.load(Ordering::SeqCst),
+            peak: self.counters.peak.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Budget {
+    /// Bytes each host may hold, or `None` if hosts are not limited.
+    limit: Option<u64>,
+    /// The first host found holding more than `limit`, and how much it held.
+    exceeded: Option<(usize, u64)>,
+}
+
+/// The memory budget shared by the hosts of a runtime.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryBudget {
+    inner: sync::Arc<sync::Mutex<Budget>>,
+}
+
+impl MemoryBudget {
+    /// Sets the bytes each host may hold before the run fails.
+    pub(crate) fn set_limit(&self, limit: Option<u64>) {
+        self.inner.lock().unwrap().limit = limit;
+    }
+
+    /// Returns the memory of the `index`th cluster, charged against this budget.
+    pub(crate) fn host(&self, index: usize) -> HostMemory {
+        HostMemory {
+            index,
+            counters: Default::default(),
+            budget: self.clone(),
+        }
+    }
+
+    fn charge(&self, index: usize, used: u64) {
+        let mut lock = self.inner.lock().unwrap();
+        match lock.limit {
+            Some(limit) if used > limit && lock.exceeded.is_none() => {
+                lock.exceeded = Some((index, used));
+            }
+            _ => {}
+        }
+    }
+
+    /// Fails the run if a host held more memory than the budget allows. Checked as the
+    /// executor parks rather than as memory is charged, so no lock of the network is held.
+    pub(crate) fn check(&self) {
+        let lock = self.inner.lock().unwrap();
+        if let (Some(limit), Some((index, used))) = (lock.limit, lock.exceeded) {
+            drop(lock);
+            panic!(
+                "memory budget of {} bytes exceeded by {}, which held {} bytes in simulated \
+                 buffers and queues",
+                limit,
+                host_name(index),
+                used
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        Environment, TcpListener,
+    };
+    use std::{net, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn runtime(budget: Option<u64>) -> DeterministicRuntime {
+        let builder = DeterministicRuntime::builder().fault_config(FaultConfig::disabled());
+        match budget {
+            Some(budget) => builder.memory_budget(budget),
+            None => builder,
+        }
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    /// Test that unread bytes are charged to the reading host until they are read.
+    fn charges_unread_bytes() {
+        let mut runtime = runtime(None);
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+            client.write_all(&[0; 1000]).await.unwrap();
+            assert_eq!(handle.stats().memory[0].used, 1000);
+            let mut buf = [0; 1000];
+            server.read_exact(&mut buf).await.unwrap();
+        });
+        let memory = &runtime.handle().stats().memory[0];
+        assert_eq!((&memory.host[..], memory.used), ("localhost", 0));
+        assert_eq!(memory.peak, 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "memory budget of 100 bytes exceeded by localhost")]
+    /// Test that holding more memory than the budget fails the run.
+    fn budget() {
+        let mut runtime = runtime(Some(100));
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (mut client, _server) = (client.unwrap(), server.unwrap());
+            client.write_all(&[0; 1000]).await.unwrap();
+            handle.delay_from(Duration::from_secs(1)).await;
+        });
+    }
+}
@@ -0,0 +1,422 @@
+//! Typed channels exchanging whole messages between hosts.
+//!
+//! Protocols which are specified in terms of messages rather than byte streams can be tested
+//! without framing them over a connection. Each `MessageChannel` is bound to an address, and
+//! messages sent to it are routed like connections, within its cluster or over a link. As with
+//! UDP, messages may be lost, delayed past later messages, reordered among messages sent with
+//! them or delivered twice, as decided by the `FaultConfig` of the route.
+//!
+//! Messages can be received along with the wall clock times they were sent and received at,
+//! read from the clocks of the sending and receiving hosts. When `FaultConfig::clock_skew`
+//! sets those clocks apart, a message can appear to be received before it was sent.
+use super::{memory::HostMemory, network, DeterministicRuntimeHandle};
+use crate::Environment;
+use futures::{FutureExt, Poll};
+use std::{
+    collections::BTreeMap,
+    fmt, io, mem, net, num, sync,
+    task::{Context, Waker},
+    time::{Instant, SystemTime},
+};
+
+/// Messages sent to a channel which have not been received, ordered by when they are due.
+#[derive(Debug)]
+struct Queue<T> {
+    /// Messages by the instant they are delivered at, then their position in the order of
+    /// delivery, then the order they were sent in. The position of a message is twice the
+    /// order it was sent in, so that a message overtaken by `n` later messages is positioned
+    /// just after the `n`th of them.
+    messages: BTreeMap<(Instant, u64, u64), Sent<T>>,
+    sent: u64,
+    receiver: Option<Waker>,
+}
+
+/// A message waiting in a queue, with its sender and the time it was sent at.
+#[derive(Debug)]
+struct Sent<T> {
+    from: net::SocketAddr,
+    at: SystemTime,
+    message: T,
+}
+
+/// A message received on a `MessageChannel`, with the wall clock times it was sent and
+/// received at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timestamped<T> {
+    /// The address of the channel the message was sent from.
+    pub from: net::SocketAddr,
+    /// The message itself.
+    pub message: T,
+    /// The wall clock time of the sending host when the message was sent.
+    pub sent: SystemTime,
+    /// The wall clock time of the receiving host when the message was received, which may
+    /// precede `sent` if the clocks of the hosts are skewed.
+    pub received: SystemTime,
+}
+
+#[derive(Debug)]
+struct Mailbox<T> {
+    queue: sync::Mutex<Queue<T>>,
+    /// Memory of the host the channel is bound in, charged with the queued messages.
+    memory: HostMemory,
+}
+
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        let queued = self.queue.lock().unwrap().messages.len();
+        self.memory.free(queued * mem::size_of::<Sent<T>>());
+    }
+}
+
+impl<T> Mailbox<T> {
+    fn push(&self, at: Instant, reorder: u32, message: Sent<T>) {
+        let mut queue = self.queue.lock().unwrap();
+        let sent = queue.sent;
+        queue.sent += 1;
+        let position = if reorder == 0 {
+            2 * sent
+        } else {
+            2 * (sent + u64::from(reorder)) + 1
+        };
+        queue.messages.insert((at, position, sent), message);
+        self.memory.allocate(mem::size_of::<Sent<T>>());
+        if let Some(receiver) = queue.receiver.take() {
+            receiver.wake();
+        }
+    }
+}
+
+/// Channel sending and receiving messages of type `T` to and from channels bound on other
+/// hosts, created with `DeterministicRuntimeHandle::bind_messages`.
+///
+/// ```
+/// # use simulation::deterministic::DeterministicRuntime;
+/// let mut runtime = DeterministicRuntime::new().unwrap();
+/// let handle = runtime.handle();
+/// runtime.block_on(async {
+///     let mut server = handle.bind_messages::<String>("127.0.0.1:7000".parse()?)?;
+///     let client = handle.bind_messages::<String>("127.0.0.1:7001".parse()?)?;
+///     client.send_to("hello".to_string(), server.local_addr())?;
+///     let (from, message) = server.recv().await;
+///     assert_eq!((from, &message[..]), (client.local_addr(), "hello"));
+///     Ok::<_, Box<dyn std::error::Error>>(())
+/// })
+/// .unwrap();
+/// ```
+pub struct MessageChannel<T> {
+    handle: DeterministicRuntimeHandle,
+    port: num::NonZeroU16,
+    /// Address the channel is bound to, on the hosts of its cluster.
+    addr: net::SocketAddr,
+    mailbox: sync::Arc<Mailbox<T>>,
+    /// Timer for the earliest message which is not yet due.
+    delay: Option<tokio_timer::Delay>,
+}
+
+impl<T> fmt::Debug for MessageChannel<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageChannel")
+            .field("local_addr", &self.local_addr())
+            .finish()
+    }
+}
+
+impl<T: Clone + Send + 'static> MessageChannel<T> {
+    pub(crate) fn bind(
+        handle: &DeterministicRuntimeHandle,
+        addr: net::SocketAddr,
+    ) -> Result<Self, io::Error> {
+        let port = num::NonZeroU16::new(addr.port())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mailbox = sync::Arc::new(Mailbox {
+            queue: sync::Mutex::new(Queue {
+                messages: BTreeMap::new(),
+                sent: 0,
+                receiver: None,
+            }),
+            memory: handle.network.memory(),
+        });
+        handle
+            .network
+            .bind_mailbox(port, sync::Arc::clone(&mailbox) as network::Mailbox)?;
+        Ok(MessageChannel {
+            handle: handle.clone(),
+            port,
+            addr: net::SocketAddr::new(handle.network.ip(), port.get()),
+            mailbox,
+            delay: None,
+        })
+    }
+
+    /// Sends `message` to the channel bound to `addr`. Messages sent to an address without a
+    /// channel are lost, as are those lost to an injected fault. Fails with `InvalidInput` if
+    /// the channel bound to `addr` carries messages of another type.
+    pub fn send_to(&self, message: T, addr: net::SocketAddr) -> Result<(), io::Error> {
+        let (mailbox, to, fault_injector) = match num::NonZeroU16::new(addr.port())
+            .and_then(|port| self.handle.network.route_message(addr, port))
+        {
+            Some(route) => route,
+            None => return Ok(()),
+        };
+        let mailbox = mailbox
+            .downcast::<Mailbox<T>>()
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let from = self.local_addr();
+        let faults = fault_injector.message_faults((from, to));
+        if faults.drop {
+            return Ok(());
+        }
+        let at = self.handle.now() + faults.delay.unwrap_or_default();
+        let sent = self.handle.system_time();
+        if faults.duplicate {
+            let duplicate = Sent {
+                from,
+                at: sent,
+                message: message.clone(),
+            };
+            mailbox.push(at, faults.reorder, duplicate);
+        }
+        let message = Sent {
+            from,
+            at: sent,
+            message,
+        };
+        mailbox.push(at, faults.reorder, message);
+        Ok(())
+    }
+
+    /// Polls for the next message which is due, along with the address of its sender.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<(net::SocketAddr, T)> {
+        self.poll_recv_timestamped(cx)
+            .map(|received| (received.from, received.message))
+    }
+
+    /// Polls for the next message which is due, along with its sender and the times it was
+    /// sent and received at.
+    pub fn poll_recv_timestamped(&mut self, cx: &mut Context<'_>) -> Poll<Timestamped<T>> {
+        loop {
+            let next = {
+                let mut queue = self.mailbox.queue.lock().unwrap();
+                let now = self.handle.now();
+                match queue.messages.keys().next().copied() {
+                    Some(key @ (at, _, _)) if at <= now => {
+                        self.delay = None;
+                        let sent = queue.messages.remove(&key).unwrap();
+                        drop(queue);
+                        self.mailbox.memory.free(mem::size_of::<Sent<T>>());
+                        return Poll::Ready(Timestamped {
+                            from: sent.from,
+                            message: sent.message,
+                            sent: sent.at,
+                            received: self.handle.system_time(),
+                        });
+                    }
+                    next => {
+                        queue.receiver = Some(cx.waker().clone());
+                        next
+                    }
+                }
+            };
+            let at = match next {
+                Some((at, _, _)) => at,
+                None => return Poll::Pending,
+            };
+            match &mut self.delay {
+                Some(delay) if delay.deadline() == at => {}
+                _ => self.delay = Some(self.handle.delay(at)),
+            }
+            futures::ready!(self.delay.as_mut().unwrap().poll_unpin(cx));
+        }
+    }
+
+    /// Receives the next message which is due, along with the address of its sender.
+    pub async fn recv(&mut self) -> (net::SocketAddr, T) {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Receives the next message which is due, along with its sender and the times it was
+    /// sent and received at.
+    pub async fn recv_timestamped(&mut self) -> Timestamped<T> {
+        futures::future::poll_fn(|cx| self.poll_recv_timestamped(cx)).await
+    }
+}
+
+impl<T> MessageChannel<T> {
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+}
+
+impl<T> Drop for MessageChannel<T> {
+    fn drop(&mut self) {
+        self.handle.network.unbind_mailbox(self.port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, FaultConfig, FaultKind};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that messages are delivered between linked clusters, and that lost, delayed and
+    /// duplicated messages are recorded as faults of the route.
+    fn faults() {
+        let mut runtime = DeterministicRuntime::new_with_seed(3).unwrap();
+        let handle = runtime.handle();
+        let east = handle.new_cluster(FaultConfig::disabled());
+        let west = handle.new_cluster(FaultConfig::disabled());
+        east.link(
+            &west,
+            FaultConfig {
+                message_drop_prob: 0.2,
+                message_delay_prob: 0.2,
+                message_delay: Duration::from_millis(10)..Duration::from_millis(50),
+                message_duplicate_prob: 0.2,
+                ..FaultConfig::disabled()
+            },
+        );
+        let addr: net::SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let received = runtime.block_on(async {
+            let mut server = west.bind_messages::<u32>(addr).unwrap();
+            let client = east
+                .bind_messages::<u32>("127.0.0.1:7001".parse().unwrap())
+                .unwrap();
+            for i in 0..100 {
+                client.send_to(i, addr).unwrap();
+            }
+            let mut received = vec![];
+            while let Ok((from, i)) = east.timeout(server.recv(), Duration::from_secs(1)).await {
+                assert_eq!(from, client.local_addr());
+                received.push(i);
+            }
+            received
+        });
+        let faults = runtime.faults();
+        let count = |kind| faults.iter().filter(|f| f.kind == kind).count();
+        let (dropped, delayed, duplicated) = (
+            count(FaultKind::MessageDrop),
+            count(FaultKind::MessageDelay),
+            count(FaultKind::MessageDuplicate),
+        );
+        assert!(dropped > 0 && delayed > 0 && duplicated > 0);
+        assert_eq!(received.len(), 100 - dropped + duplicated);
+        let mut sorted = received.clone();
+        sorted.sort();
+        assert_ne!(received, sorted, "delayed messages should be reordered");
+    }
+
+    #[test]
+    /// Test that messages to an unbound address are lost, while sending to a channel of
+    /// another type fails.
+    fn unreachable() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let channel = handle
+                .bind_messages::<u32>("127.0.0.1:7000".parse().unwrap())
+                .unwrap();
+            let _other = handle
+                .bind_messages::<String>("127.0.0.1:7001".parse().unwrap())
+                .unwrap();
+            channel
+                .send_to(1, "127.0.0.1:7002".parse().unwrap())
+                .unwrap();
+            let err = channel
+                .send_to(1, "127.0.0.1:7001".parse().unwrap())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let err = handle
+                .bind_messages::<u32>("127.0.0.1:7000".parse().unwrap())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        });
+    }
+
+    #[test]
+    /// Test that reordered messages are delivered after at most `message_reorder_window`
+    /// messages sent after them.
+    fn reorder_window() {
+        fn received(window: u32) -> (Vec<usize>, usize) {
+            let mut runtime = DeterministicRuntime::builder()
+                .seed(5)
+                .fault_config(FaultConfig {
+                    message_reorder_prob: 0.3,
+                    message_reorder_window: window,
+                    ..FaultConfig::disabled()
+                })
+                .build()
+                .unwrap();
+            let handle = runtime.handle();
+            let addr: net::SocketAddr = "127.0.0.1:7000".parse().unwrap();
+            let received = runtime.block_on(async {
+                let mut server = handle.bind_messages::<usize>(addr).unwrap();
+                let client = handle
+                    .bind_messages::<usize>("127.0.0.1:7001".parse().unwrap())
+                    .unwrap();
+                for i in 0..100 {
+                    client.send_to(i, addr).unwrap();
+                }
+                let mut received = vec![];
+                for _ in 0..100 {
+                    received.push(server.recv().await.1);
+                }
+                received
+            });
+            let reordered = runtime
+                .faults()
+                .iter()
+                .filter(|f| f.kind == FaultKind::MessageReorder)
+                .count();
+            (received, reordered)
+        }
+        for &window in &[1, 8] {
+            let (received, reordered) = received(window);
+            assert!(reordered > 0);
+            let mut sorted = received.clone();
+            sorted.sort();
+            assert_eq!(sorted, (0..100).collect::<Vec<_>>());
+            assert_ne!(received, sorted);
+            for (position, message) in received.iter().enumerate() {
+                assert!(position <= message + window as usize);
+            }
+        }
+        let (received, reordered) = received(0);
+        assert_eq!(reordered, 0);
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    /// Test that messages carry the times they were sent and received at, read from the
+    /// skewed clocks of their hosts.
+    fn timestamps() {
+        let mut runtime = DeterministicRuntime::new_with_seed(2).unwrap();
+        let handle = runtime.handle();
+        let skewed = FaultConfig {
+            clock_skew_prob: 1.0,
+            clock_skew: Duration::from_secs(1)..Duration::from_secs(5),
+            ..FaultConfig::disabled()
+        };
+        let east = handle.new_cluster(skewed.clone());
+        let west = handle.new_cluster(skewed);
+        east.link(&west, FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        runtime.block_on(async {
+            let mut server = west.bind_messages::<u32>(addr).unwrap();
+            let client = east
+                .bind_messages::<u32>("127.0.0.1:7001".parse().unwrap())
+                .unwrap();
+            let sent = east.system_time();
+            client.send_to(1, addr).unwrap();
+            let received = server.recv_timestamped().await;
+            assert_eq!((received.from, received.message), (client.local_addr(), 1));
+            assert_eq!(received.sent, sent);
+            assert_eq!(received.received, west.system_time());
+            assert_ne!(received.sent, received.received);
+        });
+        let faults = runtime.faults();
+        let skews = faults.iter().filter(|f| f.kind == FaultKind::ClockSkew);
+        assert_eq!(skews.count(), 2);
+    }
+}
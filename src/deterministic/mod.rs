@@ -4,72 +4,405 @@
 //! testing for all.
 //!
 
-use crate::Error;
-use async_trait::async_trait;
+use crate::{
+    assertions,
+    buggify::{self, BuggifyPoint},
+    Error,
+};
 use futures::Future;
 use std::{
-    io, net,
+    collections::{BTreeMap, HashMap},
+    io, net, panic,
     time::{Duration, Instant},
 };
 
+mod audit;
+mod bisect;
+mod builder;
+mod chrome_trace;
+pub(crate) mod context;
+mod debugger;
+mod env;
+mod event;
+mod failure;
 mod fault;
-pub use fault::{FaultInjector, FaultInjectorHandle};
+pub use audit::{diff_draws, DrawDivergence, RngDraw};
+pub use bisect::{bisect_faults, Bisection};
+pub use builder::Builder;
+pub use debugger::{Debugger, Step, StepAction};
+pub use event::{EventCategory, EventRetention, EventStream, LoggedEvent, SimEvent, SimObserver};
+pub use failure::FailureContext;
+pub use fault::{FaultConfig, FaultId, FaultInjector, FaultInjectorHandle, FaultKind, FaultRecord};
+mod invariant;
+mod latency;
+mod memory;
+mod message;
 mod network;
+mod pause;
+mod report;
+pub(crate) mod rng;
+mod seed;
+mod sequence;
+mod stats;
+mod sweep;
+mod task;
 mod time;
-pub use network::{ClientConnection, Listener, MemoryStream, ServerConnection};
-pub(crate) use time::Time;
+mod timeline;
+mod topology;
+mod watermark;
+pub use latency::LatencyProfile;
+pub use memory::MemoryStats;
+pub use message::{MessageChannel, Timestamped};
+pub use network::{
+    ClientConnection, Connect, ConnectionId, Linger, Listener, MemoryStream, NetworkState,
+    OpenResources, ServerConnection,
+};
+pub use report::{Artifact, FailureReport, FaultSchedule, Trace, FORMAT_VERSION};
+pub use rng::{ChaChaAlgorithm, RngAlgorithm, SmallRngAlgorithm};
+pub use seed::Seed;
+pub use sequence::DiagramFormat;
+pub use stats::{PhaseStats, RunStats};
+pub use sweep::{
+    BuggifyCoverage, CoverageSummary, FailureSummary, LabelCoverage, SeedFailure, SeedResult,
+    Sweep, SweepProgress, SweepReport, SweepSummary,
+};
+pub use task::{Blocker, TaskDump, TaskId, TaskInfo};
+pub use time::FreezeTime;
+pub(crate) use time::{Time, WallClock};
+pub use timeline::{ConnectionEvent, ConnectionTimeline, TimelineEntry};
+pub use watermark::{QueueKind, Watermarks};
 
 #[derive(Debug, Clone)]
 pub struct DeterministicRuntimeHandle {
+    seed: u64,
     reactor: tokio_net::driver::Handle,
     time: Time,
     timer: tokio_timer::timer::Handle,
     fault_injector: FaultInjectorHandle,
     network: network::NetworkHandle,
     executor: tokio_executor::current_thread::Handle,
+    invariants: invariant::Invariants,
+    events: event::EventLog,
+    tasks: task::Tasks,
+    entropy: rng::Entropy,
+    buggify: buggify::Buggify,
+    hostname: String,
+    watermarks: std::sync::Arc<Watermarks>,
 }
 
 impl DeterministicRuntimeHandle {
     pub fn now(&self) -> Instant {
         self.time.now()
     }
+
+    /// Returns the seed used to drive fault injection for this runtime.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the name of the algorithm generating the random streams of this runtime.
+    pub fn rng_algorithm(&self) -> &'static str {
+        self.entropy.algorithm().name()
+    }
+
+    /// Returns the `buggify!` points evaluated so far, with whether each was enabled for the
+    /// run and how many times it fired.
+    pub fn buggify_points(&self) -> BTreeMap<&'static str, BuggifyPoint> {
+        self.buggify.snapshot()
+    }
+
+    /// Evaluates the `buggify!` point `label`, returning whether it fires.
+    pub(crate) fn buggify_point(&self, label: &'static str) -> bool {
+        self.buggify.evaluate(label)
+    }
+
+    /// Returns every draw made from the random streams of the runtime so far, if they are
+    /// recorded with `Builder::audit_rng`.
+    pub fn rng_draws(&self) -> Vec<RngDraw> {
+        self.entropy.algorithm().draws()
+    }
+
+    /// Registers an invariant which is checked whenever the runtime is idle and about to
+    /// advance time. The run panics with the seed and simulated time if `check` resolves
+    /// to an error.
+    ///
+    /// The future returned by `check` is polled once, invariants which are not ready
+    /// immediately are skipped.
+    pub fn register_invariant<F, U>(&self, name: &str, check: F)
+    where
+        F: FnMut() -> U + Send + 'static,
+        U: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.invariants.register(name, check)
+    }
+
+    /// Returns every event recorded so far, or the most recent ones if the runtime was built
+    /// with a bounded `EventRetention`.
+    pub fn events(&self) -> Vec<LoggedEvent> {
+        self.events.events()
+    }
+
+    /// Spawns `future` as a task named `name`, which identifies it in task dumps.
+    pub fn spawn_named<F>(&self, name: &str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let future = pause::Pausable::new(self.clone(), future);
+        let task = self.tasks.track_named(
+            Some(name.to_string()),
+            crate::ambient::scope(self.clone(), future),
+        );
+        self.executor.spawn(task).expect("failed to spawn task");
+    }
+
+    /// Records the current task as waiting on a timer firing at `deadline`. Timers are only
+    /// seen as they are created, the task keeps the timer as its blocker until the deadline.
+    fn wait_until(&self, deadline: Instant) {
+        let elapsed = self.time.elapsed();
+        let now = self.time.now();
+        task::set_blocker(Blocker::Timer {
+            deadline: elapsed + deadline.saturating_duration_since(now),
+        });
+    }
+
+    /// Returns the events so far of the connection between `a` and `b`, which may be given in
+    /// either order, such as the local and peer address of either end. Only the events kept by
+    /// `Builder::event_retention` are included.
+    pub fn connection_timeline(
+        &self,
+        a: net::SocketAddr,
+        b: net::SocketAddr,
+    ) -> ConnectionTimeline {
+        ConnectionTimeline::from_events(&self.events.events(), a, b)
+    }
+
+    /// Returns statistics summarizing the activity of the run so far.
+    pub fn stats(&self) -> RunStats {
+        RunStats {
+            simulated: self.time.elapsed(),
+            wall: self.time.wall_elapsed(),
+            phases: self.time.phases(),
+            memory: self.network.memory_stats(),
+            ..self.events.stats()
+        }
+    }
+
+    /// Ends the current phase of the run, if any, and starts a phase named `name`. The
+    /// simulated and wall time spent in each phase is reported by `stats`, exposing phases
+    /// which simulate slower than real time.
+    pub fn phase(&self, name: &str) {
+        self.time.start_phase(name);
+    }
+
+    /// Returns a stream of every event recorded so far, followed by events as they are recorded.
+    pub fn event_stream(&self) -> EventStream {
+        self.events.subscribe()
+    }
+
+    /// Returns the records captured by `logger::SimLogger`, prefixed with the simulated time,
+    /// host and task they were logged by. Only records among the events kept by
+    /// `Builder::event_retention` are returned.
+    pub fn logs(&self) -> Vec<String> {
+        self.events()
+            .iter()
+            .filter_map(crate::logger::format_line)
+            .collect()
+    }
+
+    /// Returns the records captured by `logger::SimLogger`, formatted as by `logs` and split
+    /// into the log of each host. Like `logs`, only covers the kept events.
+    pub fn logs_by_host(&self) -> BTreeMap<String, Vec<String>> {
+        crate::logger::lines_by_host(&self.events())
+    }
+
+    /// Returns the metrics recorded through the `metrics` module so far.
+    pub fn metrics(&self) -> crate::metrics::Metrics {
+        self.events.metrics()
+    }
+
+    /// Returns every task which has been spawned but not yet completed.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.tasks.live()
+    }
+
+    /// Returns a snapshot of the in-memory network of this cluster.
+    pub fn network_state(&self) -> NetworkState {
+        self.network.state()
+    }
+
+    /// Returns the listeners and connection ends of this cluster which have not been dropped.
+    pub fn open_resources(&self) -> OpenResources {
+        self.network.open_resources(&self.hostname)
+    }
+
+    /// Simulates the host of this cluster being killed and restarted. Its listeners stop
+    /// accepting, failing pending and later accepts, and the connections they accepted are
+    /// disconnected. The addresses of the listeners remain in use for a delay drawn from
+    /// `FaultConfig::rebind_delay`, so a restarted host binding its old addresses sees
+    /// `AddrInUse` until the delay has elapsed.
+    ///
+    /// Tasks of the host are not cancelled, the caller is expected to stop them before starting
+    /// the host again.
+    pub fn restart_host(&self) {
+        self.network.restart()
+    }
+
+    /// Pauses every task spawned in this cluster for `duration`, while other clusters keep
+    /// running, as a long garbage collection or the migration of a virtual machine would.
+    /// Pauses are also injected at random by `FaultConfig::host_pause`.
+    pub fn pause_host(&self, duration: Duration) {
+        self.network.pause(duration)
+    }
+
+    /// Runs `future` as the root future of this host. Once it completes, any listener or
+    /// connection of the cluster which is still open is reported as a leak, failing the run if
+    /// the runtime was built with `Builder::fail_on_leaks`, and logged otherwise.
+    pub async fn run_host<F: Future>(&self, future: F) -> F::Output {
+        let output = future.await;
+        self.report_leaks(self.open_resources());
+        output
+    }
+
+    fn report_leaks(&self, resources: OpenResources) {
+        if resources.is_empty() {
+            return;
+        }
+        if self.network.fail_on_leaks() {
+            panic!("{}", resources);
+        }
+        log::warn!("{}", resources);
+    }
+
+    /// Returns a future connecting to the listener bound to `addr`. Unlike
+    /// `Environment::connect`, the future is nameable and can be driven with
+    /// `Connect::poll_connect` from a manual `Future` implementation.
+    pub fn connect_addr(&self, addr: net::SocketAddr) -> Connect {
+        self.network.connect(addr)
+    }
+
+    /// Binds a channel exchanging messages of type `T` to `addr` in this cluster. Message
+    /// channels have their own ports, which may also be bound by a listener.
+    pub fn bind_messages<T: Clone + Send + 'static>(
+        &self,
+        addr: net::SocketAddr,
+    ) -> Result<MessageChannel<T>, io::Error> {
+        MessageChannel::bind(self, addr)
+    }
+
+    /// Returns a handle to a new simulated cluster with its own address space, injecting faults
+    /// according to `config`. The cluster shares the executor and clock of this runtime, but
+    /// its listeners cannot be reached from other clusters unless they are linked.
+    ///
+    /// The `n`th cluster created is identified by the hostname `cluster-n` and the address
+    /// `10.0.0.n`.
+    pub fn new_cluster(&self, config: FaultConfig) -> DeterministicRuntimeHandle {
+        let network = self.network.new_cluster(config);
+        DeterministicRuntimeHandle {
+            hostname: format!("cluster-{}", network.cluster_index()),
+            network,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a handle to the same cluster which reports `hostname` as its hostname.
+    pub fn with_hostname(&self, hostname: &str) -> DeterministicRuntimeHandle {
+        DeterministicRuntimeHandle {
+            hostname: hostname.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Moves `deadline` if timer skew is injected into the timer being created.
+    fn skew_timer(&self, deadline: Instant) -> Instant {
+        match self.network.timer_skew() {
+            Some(skew) => skew.apply(deadline, self.now()),
+            None => deadline,
+        }
+    }
+
+    /// Allows connections made from this cluster to reach listeners bound in the cluster of
+    /// `other`, injecting faults according to `config`. Links are one way, a link must also be
+    /// made from `other` for it to reach this cluster. The listeners of `other` are reached at
+    /// its `local_ip`, or at a loopback address when no listener of this cluster is bound to the
+    /// port.
+    pub fn link(&self, other: &DeterministicRuntimeHandle, config: FaultConfig) {
+        self.network.link(&other.network, config)
+    }
 }
 
-#[async_trait]
 impl crate::Environment for DeterministicRuntimeHandle {
     type TcpStream = network::ClientConnection;
     type TcpListener = network::Listener;
-    fn spawn<F>(&self, future: F)
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
     where
         F: Future<Output = ()> + Send + 'static,
     {
-        self.executor.spawn(future).expect("failed to spawn");
+        let future = pause::Pausable::new(self.clone(), future);
+        let task = self
+            .tasks
+            .track(crate::ambient::scope(self.clone(), future));
+        self.executor
+            .spawn(task)
+            .map_err(|source| Error::Spawn { source })
     }
     fn now(&self) -> Instant {
         self.time.now()
     }
+    /// Returns the wall clock of the cluster, which is simulated time since the start time of
+    /// the runtime unless clock anomalies were injected by its `FaultConfig`.
+    fn system_time(&self) -> std::time::SystemTime {
+        self.network.system_time(self.time.system_time())
+    }
+    /// Returns a delay completing at `deadline`, or slightly before or after it if timer skew
+    /// was injected by the `FaultConfig` of the cluster.
     fn delay(&self, deadline: Instant) -> tokio_timer::Delay {
+        let deadline = self.skew_timer(deadline);
+        self.wait_until(deadline);
         self.timer.delay(deadline)
     }
-    fn timeout<T>(&self, value: T, timeout: Duration) -> tokio_timer::Timeout<T> {
-        self.timer.timeout(value, timeout)
+    fn timeout<T>(&self, value: T, timeout: Duration) -> crate::Timeout<T> {
+        let now = self.now();
+        let deadline = self.skew_timer(now + timeout);
+        self.wait_until(deadline);
+        crate::Timeout::new(self.timer.timeout(value, deadline - now))
+    }
+    fn rng(&self) -> crate::RngHandle {
+        let stream = task::current().and_then(|task| self.tasks.stream(task));
+        self.entropy.task(stream)
+    }
+    fn rng_for(&self, name: &str) -> crate::RngHandle {
+        self.entropy.component(name)
+    }
+    /// Returns `localhost` for the root cluster, otherwise the name of the cluster.
+    fn hostname(&self) -> String {
+        self.hostname.clone()
+    }
+    /// Returns `127.0.0.1` for the root cluster, and `10.0.0.n` for the `n`th cluster created.
+    fn local_ip(&self) -> net::IpAddr {
+        network::cluster_ip(self.network.cluster_index())
     }
-    async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
+    fn bind<A>(&self, addr: A) -> impl Future<Output = io::Result<Self::TcpListener>> + Send
     where
         A: Into<net::SocketAddr> + Send + Sync,
     {
-        self.network.bind(addr.into())
+        futures::future::ready(self.network.bind(addr.into()))
     }
     async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
     where
-        A: Into<net::SocketAddr> + Send + Sync,
+        A: crate::ToSocketAddrs + Send + Sync,
     {
-        self.network.connect(addr.into()).await
+        let addrs = addr.to_socket_addrs()?;
+        crate::connect::connect_any(self, addrs, |addr| self.network.connect(addr)).await
     }
 }
 
 type Executor = tokio_executor::current_thread::CurrentThread<
-    network::Network<tokio_timer::timer::Timer<time::Park<tokio_net::driver::Reactor>, time::Now>>,
+    network::Network<
+        tokio_timer::timer::Timer<
+            invariant::Park<time::Park<tokio_net::driver::Reactor>>,
+            time::Now,
+        >,
+    >,
 >;
 
 pub struct DeterministicRuntime {
@@ -78,66 +411,311 @@ pub struct DeterministicRuntime {
     reactor_handle: tokio_net::driver::Handle,
     timer_handle: tokio_timer::timer::Handle,
     clock: tokio_timer::clock::Clock,
+    coverage: assertions::Coverage,
+    clusters: HashMap<String, DeterministicRuntimeHandle>,
+    /// File the failure of a run is written to.
+    failure_report: Option<std::path::PathBuf>,
+    /// Whether the seed was echoed as the runtime started running, or is not to be echoed, see
+    /// `Builder::announce_seed`.
+    announced: bool,
 }
 
 impl DeterministicRuntime {
+    /// Returns a builder for configuring every aspect of a runtime in one place.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
     pub fn new() -> Result<Self, Error> {
-        DeterministicRuntime::new_with_seed(0)
+        DeterministicRuntime::builder().build()
     }
     pub fn new_with_seed(seed: u64) -> Result<Self, Error> {
-        let reactor =
-            tokio_net::driver::Reactor::new().map_err(|source| Error::RuntimeBuild { source })?;
-        let reactor_handle = reactor.handle();
-        let time = Time::new();
-        let reactor = time.wrap_park(reactor);
-        let timer = tokio_timer::Timer::new_with_now(reactor, time.clone_now());
-        let timer_handle = timer.handle();
-        let clock = tokio_timer::clock::Clock::new_with_now(time.clone_now());
-        let fault_injector =
-            fault::FaultInjector::new(seed, timer_handle.clone(), time.clone_now());
-        let fault_injector_handle = fault_injector.handle();
-        let network = network::Network::new_with_park(timer, fault_injector_handle.clone());
-        let network_handle = network.handle();
-        let executor = tokio_executor::current_thread::CurrentThread::new_with_park(network);
-        let handle = DeterministicRuntimeHandle {
-            reactor: reactor_handle.clone(),
-            time,
-            timer: timer_handle.clone(),
-            fault_injector: fault_injector_handle,
-            network: network_handle,
-            executor: executor.handle(),
-        };
-        Ok(DeterministicRuntime {
-            executor,
-            handle,
-            reactor_handle,
-            timer_handle,
-            clock,
-        })
+        DeterministicRuntime::builder().seed(seed).build()
+    }
+
+    /// Returns a runtime drawing faults and scheduling decisions from `algorithm`. Changing the
+    /// algorithm changes the run produced by a seed.
+    pub fn new_with_rng<A: RngAlgorithm>(seed: u64, algorithm: A) -> Result<Self, Error> {
+        DeterministicRuntime::builder()
+            .seed(seed)
+            .rng(algorithm)
+            .build()
+    }
+
+    /// Returns a runtime configured from environment variables, so CI can sweep seeds and a
+    /// failure can be reproduced without changing code:
+    ///
+    /// * `SIM_SEED`, the seed in any form parsed by `Seed`. A random seed is generated if it is
+    ///   not set.
+    /// * `SIM_FAULT_PROFILE`, `default`, `disabled`, or the path of a JSON `FaultConfig`.
+    /// * `SIM_MAX_SIM_TIME`, such as `30s` or `500ms`. Once more simulated time than this has
+    ///   elapsed, the runtime panics.
+    /// * `SIM_RNG`, `small_rng` or `chacha20`.
+    /// * `SIM_FAILURE_REPORT`, the path of a file the `FailureContext` of a failed run is
+    ///   written to.
+    ///
+    /// The chosen values are logged at `Info` level.
+    pub fn from_env() -> Result<Self, Error> {
+        let config = env::EnvConfig::from_env()?;
+        log::info!("{}", config);
+        let mut builder = DeterministicRuntime::builder()
+            .seed(config.seed)
+            .algorithm(config.algorithm)
+            .fault_config(config.fault_config);
+        if let Some(limit) = config.max_sim_time {
+            builder = builder.max_sim_time(limit);
+        }
+        if let Some(path) = config.failure_report {
+            builder = builder.failure_report(path);
+        }
+        builder.build()
     }
 
     pub fn handle(&self) -> DeterministicRuntimeHandle {
         self.handle.clone()
     }
 
+    /// Returns a handle to the cluster named `name` by `Builder::cluster`.
+    pub fn cluster(&self, name: &str) -> Option<DeterministicRuntimeHandle> {
+        self.clusters.get(name).cloned()
+    }
+
+    /// Returns a record of every fault injected so far.
+    pub fn faults(&self) -> Vec<FaultRecord> {
+        self.handle.fault_injector.records()
+    }
+
+    /// Restricts fault injection to the faults identified by `allowed`. Fault decisions are
+    /// still drawn from the seeded RNG, so the remaining faults keep their identity as long
+    /// as the execution does not diverge.
+    pub fn allow_only_faults<I>(&mut self, allowed: I)
+    where
+        I: IntoIterator<Item = FaultId>,
+    {
+        self.handle
+            .fault_injector
+            .allow_only(allowed.into_iter().collect())
+    }
+
+    /// Returns the `sometimes!` assertions recorded while running this runtime.
+    pub fn coverage(&self) -> &assertions::Coverage {
+        &self.coverage
+    }
+
     pub fn spawn<F>(&mut self, future: F) -> &mut Self
     where
         F: Future<Output = ()> + 'static,
     {
-        self.executor.spawn(future);
+        let task = self.handle.tasks.track(future);
+        self.executor.spawn(task);
         self
     }
 
+    /// Runs every spawned task to completion. Returns `Error::Deadlock` listing the remaining
+    /// tasks if they are all waiting and no timer is pending to wake them. As with `block_on`,
+    /// tasks can not be woken from another OS thread.
+    ///
+    /// Once every task has completed, listeners and connections which are still open are
+    /// reported as leaks, returning `Error::ResourceLeak` if the runtime was built with
+    /// `Builder::fail_on_leaks`, and logged otherwise.
     pub fn run(&mut self) -> Result<(), Error> {
-        self.enter(|executor| executor.run())
-            .map_err(|source| Error::CurrentThreadRun { source })
+        self.run_tasks()?;
+        let resources = self.open_resources();
+        if resources.is_empty() {
+            Ok(())
+        } else if self.handle.network.fail_on_leaks() {
+            Err(Error::ResourceLeak { resources })
+        } else {
+            for leaked in resources {
+                log::warn!("{}", leaked);
+            }
+            Ok(())
+        }
+    }
+
+    /// Returns the listeners and connection ends of each cluster which have not been dropped,
+    /// omitting clusters with nothing open.
+    pub fn open_resources(&self) -> Vec<OpenResources> {
+        let names: HashMap<_, _> = self
+            .clusters
+            .iter()
+            .map(|(name, cluster)| (cluster.network.cluster_index(), name.clone()))
+            .collect();
+        self.handle
+            .network
+            .open_resources_by_cluster()
+            .into_iter()
+            .map(|(index, resources)| OpenResources {
+                host: match (index, names.get(&index)) {
+                    (_, Some(name)) => name.clone(),
+                    (0, None) => self.handle.hostname.clone(),
+                    (index, None) => format!("cluster-{}", index),
+                },
+                ..resources
+            })
+            .collect()
+    }
+
+    /// Renders the clusters of the runtime, the links between them and the clusters which can
+    /// not reach each other as a Graphviz DOT digraph, as of the current simulated time.
+    pub fn topology_dot(&self) -> String {
+        let mut names: HashMap<_, _> = self
+            .clusters
+            .iter()
+            .map(|(name, cluster)| (cluster.network.cluster_index(), name.clone()))
+            .collect();
+        names
+            .entry(self.handle.network.cluster_index())
+            .or_insert_with(|| self.handle.hostname.clone());
+        topology::render(
+            &self.handle.network.topology(),
+            &names,
+            self.handle.time.elapsed(),
+        )
+    }
+
+    fn run_tasks(&mut self) -> Result<(), Error> {
+        self.announce();
+        let time = self.handle.time.clone();
+        let tasks = self.handle.tasks.clone();
+        time.take_deadlocked();
+        self.enter(|executor| loop {
+            if executor.is_idle() {
+                return Ok(());
+            }
+            let turn = executor
+                .turn(None)
+                .map_err(|source| Error::CurrentThreadTurn { source })?;
+            if time.take_deadlocked() && !turn.has_polled() {
+                return Err(Error::Deadlock {
+                    tasks: tasks.live(),
+                });
+            }
+        })
     }
 
+    /// Runs `f` to completion. If the run panics, the `FailureContext` of the runtime is
+    /// written to stderr, and to the file set with `Builder::failure_report`, before the panic
+    /// is resumed.
+    ///
+    /// The runtime never blocks the thread waiting for a wakeup, so it can not be woken from
+    /// another OS thread. Once every task is waiting and no timer is pending, the run panics
+    /// with a deadlock, even if a thread outside of the runtime would later have woken `f`.
     pub fn block_on<F>(&mut self, f: F) -> F::Output
     where
         F: Future,
     {
-        self.enter(|executor| executor.block_on(f))
+        match self.catch_block_on(f) {
+            Ok(output) => output,
+            Err(payload) => {
+                self.write_failure_report(&sweep::panic_message(&*payload));
+                eprintln!("simulation failed at {}", self.failure_context());
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Runs `f` to completion, returning `Error::Panicked` with the `FailureContext` of the
+    /// runtime if the run panics. The failure is also written to the file set with
+    /// `Builder::failure_report`.
+    pub fn try_block_on<F>(&mut self, f: F) -> Result<F::Output, Error>
+    where
+        F: Future,
+    {
+        self.catch_block_on(f).map_err(|payload| {
+            let message = sweep::panic_message(&*payload);
+            self.write_failure_report(&message);
+            Error::Panicked {
+                message,
+                context: Box::new(self.failure_context()),
+            }
+        })
+    }
+
+    fn catch_block_on<F>(&mut self, f: F) -> std::thread::Result<F::Output>
+    where
+        F: Future,
+    {
+        self.announce();
+        let task = self.handle.tasks.track(f);
+        let time = self.handle.time.clone();
+        time.take_deadlocked();
+        let mut task = Box::pin(task);
+        let task = futures::future::poll_fn(move |cx| {
+            let poll = task.as_mut().poll(cx);
+            if poll.is_pending() && time.take_deadlocked() {
+                panic!("deadlock, every task is waiting and no timer is pending");
+            }
+            poll
+        });
+        panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.enter(|executor| executor.block_on(task))
+        }))
+    }
+
+    /// Writes the seed in each of its forms to stderr the first time the runtime runs, so a
+    /// run can be reproduced from its output whichever form is copied. Skipped if the runtime
+    /// was built with `Builder::announce_seed(false)`.
+    fn announce(&mut self) {
+        if !self.announced {
+            self.announced = true;
+            let seed = Seed(self.handle.seed);
+            eprintln!(
+                "simulation seed {} ({}, {})",
+                seed,
+                seed.to_hex(),
+                seed.to_words()
+            );
+        }
+    }
+
+    /// Returns every live task, along with when it was last polled and what it is waiting on,
+    /// to diagnose runs which hang.
+    pub fn task_dump(&self) -> TaskDump {
+        TaskDump {
+            elapsed: self.handle.time.elapsed(),
+            tasks: self.handle.tasks(),
+        }
+    }
+
+    /// Returns the state of the runtime, including the task which panicked if any.
+    pub fn failure_context(&self) -> FailureContext {
+        let (task, host) = match self.handle.tasks.panicked() {
+            Some((task, host)) => (Some(task), host),
+            None => (None, None),
+        };
+        let mut recent_faults = self.faults();
+        let skip = recent_faults.len().saturating_sub(failure::RECENT_FAULTS);
+        recent_faults.drain(..skip);
+        let mut recent_events = self.handle.events();
+        let skip = recent_events.len().saturating_sub(failure::RECENT_EVENTS);
+        recent_events.drain(..skip);
+        FailureContext {
+            seed: self.handle.seed,
+            rng: self.handle.rng_algorithm().to_string(),
+            elapsed: self.handle.time.elapsed(),
+            host,
+            task,
+            recent_faults,
+            recent_events,
+            pending_tasks: self.handle.tasks(),
+            buggify: self.handle.buggify_points(),
+        }
+    }
+
+    /// Writes `message` and the `FailureContext` of the runtime to the file set with
+    /// `Builder::failure_report`, if any.
+    fn write_failure_report(&self, message: &str) {
+        if let Some(path) = &self.failure_report {
+            let report = format!(
+                "simulation panicked: {}\n{}\n",
+                message,
+                self.failure_context()
+            );
+            if let Err(error) = std::fs::write(path, report) {
+                log::warn!("failed to write failure report to {:?}: {}", path, error);
+            }
+        }
     }
 
     fn enter<F, R>(&mut self, f: F) -> R
@@ -149,12 +727,16 @@ impl DeterministicRuntime {
             ref mut clock,
             ref mut executor,
             ref timer_handle,
+            ref coverage,
+            ref handle,
             ..
         } = *self;
 
-        let _reactor = tokio_net::driver::set_default(&reactor_handle);
+        let _ambient = crate::ambient::set_default(handle);
+        let _reactor = tokio_net::driver::set_default(reactor_handle);
+        let _coverage = assertions::set_default(coverage);
         let _guard = tokio_timer::timer::set_default(timer_handle);
-        tokio_timer::clock::with_default(&clock, || {
+        tokio_timer::clock::with_default(clock, || {
             let mut default_executor = tokio_executor::current_thread::TaskExecutor::current();
             tokio_executor::with_default(&mut default_executor, || f(executor))
         })
@@ -180,6 +762,66 @@ mod tests {
         });
     }
 
+    #[test]
+    /// Test that the simulated wall clock starts at 2020-01-01T00:00:00Z and advances with
+    /// simulated time.
+    fn unix_time() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            assert_eq!(handle.now_unix_millis(), 1_577_836_800_000);
+            handle.delay_from(Duration::from_millis(1500)).await;
+            assert_eq!(handle.now_unix_millis(), 1_577_836_801_500);
+            assert_eq!(handle.now_unix_nanos(), 1_577_836_801_500_000_000);
+        });
+    }
+
+    #[test]
+    /// Test that `run` waits for every spawned task, and reports tasks which can never be woken.
+    fn run_until_complete() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let (tx, mut rx) = futures::channel::oneshot::channel();
+        runtime.spawn(async move {
+            handle.delay_from(Duration::from_secs(10)).await;
+            tx.send(handle.now()).unwrap();
+        });
+        runtime.run().unwrap();
+        assert_eq!(runtime.handle().time.elapsed(), Duration::from_secs(10));
+        assert!(rx.try_recv().unwrap().is_some());
+
+        let (_tx, rx) = futures::channel::oneshot::channel::<()>();
+        runtime.spawn(async move {
+            let _ = rx.await;
+        });
+        match runtime.run() {
+            Err(Error::Deadlock { tasks }) => assert_eq!(tasks.len(), 1),
+            other => panic!("expected deadlock, got {:?}", other),
+        }
+    }
+
+    #[test]
+    /// Test that spawning onto a runtime which has been dropped returns an error.
+    fn try_spawn_after_shutdown() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        handle.try_spawn(async {}).unwrap();
+        runtime.run().unwrap();
+        drop(runtime);
+        match handle.try_spawn(async {}) {
+            Err(Error::Spawn { .. }) => {}
+            other => panic!("expected spawn error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlock")]
+    /// Test that `block_on` panics rather than hanging when the future can never be woken.
+    fn block_on_deadlock() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.block_on(futures::future::pending::<()>());
+    }
+
     #[test]
     /// Test that waiting on delays across spawned tasks results in the clock
     /// being advanced in accordance with the length of the delay.
@@ -223,4 +865,131 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    /// Test that clusters have separate address spaces which are only reachable over links.
+    fn clusters() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let servers = handle.new_cluster(FaultConfig::disabled());
+            let clients = handle.new_cluster(FaultConfig::disabled());
+            let _local = handle.bind(addr).await.unwrap();
+            let _remote = servers.bind(addr).await.unwrap();
+            assert_eq!(
+                clients.connect(addr).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+            clients.link(&servers, FaultConfig::disabled());
+            let _conn = clients.connect(addr).await.unwrap();
+            let remote = net::SocketAddr::new(servers.local_ip(), addr.port());
+            assert_eq!(servers.network_state().connections.get(&remote), Some(&1));
+            assert!(handle.network_state().connections.is_empty());
+            assert!(clients.network_state().listeners.is_empty());
+        });
+    }
+
+    #[test]
+    /// Test that an event stream yields the events recorded before it was created, followed by
+    /// those recorded later, and ends once the runtime is dropped.
+    fn event_stream() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let mut listener = runtime.block_on(handle.bind(addr)).unwrap();
+        let stream = handle.event_stream();
+        runtime.block_on(async {
+            let (client, server) = futures::join!(
+                handle.connect(addr),
+                crate::TcpListener::accept(&mut listener)
+            );
+            drop((client.unwrap(), server.unwrap()));
+        });
+        drop((runtime, handle, listener));
+        let events: Vec<_> = futures::executor::block_on_stream(stream)
+            .map(|logged| logged.event)
+            .collect();
+        let addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), addr.port());
+        assert_eq!(events[0], SimEvent::ListenerBound { addr });
+        let closed = events
+            .iter()
+            .filter(|event| matches!(event, SimEvent::ConnectionClosed { .. }))
+            .count();
+        assert_eq!(closed, 2);
+    }
+
+    #[test]
+    /// Test that a task dump names each live task and the resource it is waiting on.
+    fn task_dump() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let mut listener = runtime.block_on(handle.bind(addr)).unwrap();
+        let env = handle.clone();
+        handle.spawn_named("sleeper", async move {
+            env.delay_from(Duration::from_secs(60)).await;
+        });
+        let client = runtime.block_on(async {
+            let (client, server) = futures::join!(
+                handle.connect(addr),
+                crate::TcpListener::accept(&mut listener)
+            );
+            handle.spawn_named("server", async move {
+                let (mut server, _) = server.unwrap();
+                let _listener = listener;
+                let mut buf = [0; 1];
+                let _ = tokio::io::AsyncReadExt::read(&mut server, &mut buf).await;
+            });
+            handle.delay_from(Duration::from_secs(1)).await;
+            client.unwrap()
+        });
+        let dump = runtime.task_dump();
+        let blockers: Vec<_> = dump
+            .tasks
+            .iter()
+            .map(|task| (task.name.as_deref(), task.blocker.clone()))
+            .collect();
+        let client_addr = client.local_addr();
+        assert_eq!(
+            blockers,
+            vec![
+                (
+                    Some("sleeper"),
+                    Some(Blocker::Timer {
+                        deadline: Duration::from_secs(60)
+                    })
+                ),
+                (
+                    Some("server"),
+                    Some(Blocker::Read {
+                        local: client.peer_addr(),
+                        peer: client_addr
+                    })
+                ),
+            ]
+        );
+        assert!(dump
+            .to_string()
+            .contains("(server) spawned at 0ns, last polled at 0ns, waiting on read on"));
+        drop(client);
+    }
+
+    #[test]
+    /// Test that each cluster reports its own hostname and address.
+    fn identity() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let servers = handle.new_cluster(FaultConfig::disabled());
+        let clients = handle.new_cluster(FaultConfig::disabled());
+        assert_eq!(handle.hostname(), "localhost");
+        assert_eq!(handle.local_ip(), net::IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(servers.hostname(), "cluster-1");
+        assert_eq!(clients.local_ip(), net::IpAddr::from([10, 0, 0, 2]));
+        let node = clients.with_hostname("client-a");
+        assert_eq!(node.hostname(), "client-a");
+        assert_eq!(node.local_ip(), clients.local_ip());
+        let ambient = crate::dynamic::DynEnvironment::new(node);
+        assert_eq!(ambient.hostname(), "client-a");
+    }
 }
@@ -1,41 +1,340 @@
 //! This is just one of those things where I don't really know
 //! what the design should look like so I just slapped a bunch of
 //! stuff together. Sorry.
+use super::event::{EventLog, SimEvent};
+use crate::sync::wait::WaitQueue;
 use futures::channel::mpsc;
-use futures::{Poll, SinkExt, Stream, StreamExt};
-pub(crate) use pipe::Pipe;
+use futures::{Future, Poll, Stream, StreamExt};
+use rand::Rng;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    io, net, num,
+    any,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, io, net, num, ops,
     pin::Pin,
     sync,
     task::Context,
-    time::Duration,
+    time::{self, Duration},
 };
 use tokio_executor::park::Park;
 mod pipe;
 mod stream;
-use async_trait::async_trait;
-pub use stream::{ClientConnection, MemoryStream, ServerConnection};
+pub use stream::{ClientConnection, ConnectionId, Linger, MemoryStream, ServerConnection};
 
 #[derive(Debug)]
 struct Inner {
+    /// Address of the hosts of this cluster, see `cluster_ip`.
+    ip: net::IpAddr,
+
     /// Next port which will be allocated
     next_port: u16,
 
     /// Map of active listeners to channels which new connections can be sent on.
-    listeners: HashMap<num::NonZeroU16, mpsc::Sender<(stream::ServerConnection, net::SocketAddr)>>,
+    listeners: HashMap<num::NonZeroU16, Bound>,
+
+    /// Number of listeners bound, which the id of the next listener is assigned from.
+    bound: u64,
+
+    /// Ports of listeners of a restarted host which may not be bound again until the instant
+    /// given.
+    reserved: HashMap<num::NonZeroU16, time::Instant>,
+
+    /// Fault injectors of the open connections accepted by the listeners of this cluster.
+    fault_injectors: Connections,
+
+    /// Number of connections made to each port.
+    connections_made: HashMap<num::NonZeroU16, u64>,
+
+    /// Number of connections waiting to be accepted by the listener bound to each port.
+    backlogs: HashMap<num::NonZeroU16, usize>,
+
+    /// Fault injector for connections made within this cluster.
+    fault_injector: super::FaultInjectorHandle,
+
+    /// Clusters which connections made from this cluster can reach.
+    links: Vec<Link>,
+
+    /// Connection ends owned by this cluster which have not been dropped.
+    ends: OpenEnds,
+
+    /// Ports assigned to the client ends of connections made from this cluster.
+    ephemeral_ports: EphemeralPorts,
+
+    /// Mailboxes of the message channels bound in this cluster, by port. Message channels have
+    /// their own port space, as UDP sockets do.
+    mailboxes: HashMap<num::NonZeroU16, Mailbox>,
+
+    /// Wall clock of the hosts of this cluster.
+    clock: super::WallClock,
+
+    /// Memory held in the buffers and queues of this cluster.
+    memory: super::memory::HostMemory,
+
+    /// When the tasks of this cluster resume, if they were paused.
+    paused_until: Option<time::Instant>,
+}
+
+/// Mailbox of a message channel, which is downcast to the type of its messages by senders.
+pub(crate) type Mailbox = sync::Arc<dyn any::Any + Send + Sync>;
+
+/// Connection ends which are open, by local and peer address. Client addresses are reused once
+/// the ephemeral range wraps around, so each pair of addresses is counted.
+#[derive(Debug, Clone)]
+pub(crate) struct OpenEnds {
+    inner: sync::Arc<sync::Mutex<Ends>>,
+}
+
+impl Default for OpenEnds {
+    fn default() -> Self {
+        let ends = Ends {
+            open: BTreeMap::new(),
+            accepted: HashMap::new(),
+            waiters: WaitQueue::new(),
+            draining: HashMap::new(),
+        };
+        Self {
+            inner: sync::Arc::new(sync::Mutex::new(ends)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ends {
+    open: BTreeMap<(net::SocketAddr, net::SocketAddr), usize>,
+    /// Number of open server ends accepted by each listener, by the id of the listener.
+    accepted: HashMap<u64, usize>,
+    /// Tasks waiting for every end accepted by a listener to close.
+    waiters: WaitQueue,
+    /// Id of the listener each waiter is waiting on, by the id of the waiter.
+    draining: HashMap<u64, u64>,
+}
+
+impl OpenEnds {
+    /// Records an end opened at `local`, accepted by the listener with id `listener` if it is
+    /// a server end.
+    pub(crate) fn open(
+        &self,
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+        listener: Option<u64>,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        *lock.open.entry((local, peer)).or_insert(0) += 1;
+        if let Some(listener) = listener {
+            *lock.accepted.entry(listener).or_insert(0) += 1;
+        }
+    }
+
+    pub(crate) fn close(
+        &self,
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+        listener: Option<u64>,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(count) = lock.open.get_mut(&(local, peer)) {
+            *count -= 1;
+            if *count == 0 {
+                lock.open.remove(&(local, peer));
+            }
+        }
+        let listener = match listener {
+            Some(listener) => listener,
+            None => return,
+        };
+        if let Some(count) = lock.accepted.get_mut(&listener) {
+            *count -= 1;
+            if *count == 0 {
+                lock.accepted.remove(&listener);
+                let Ends {
+                    waiters, draining, ..
+                } = &mut *lock;
+                for waiter in
+                    waiters.wake_matching(|waiter| draining.get(&waiter) == Some(&listener))
+                {
+                    draining.remove(&waiter);
+                }
+            }
+        }
+    }
+
+    /// Polls until no end accepted by the listener with id `listener` is open, registering
+    /// the waker of `cx` on behalf of `waiter` to be woken once the last one closes.
+    fn poll_drained(
+        &self,
+        listener: u64,
+        waiter: &mut Option<u64>,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        let mut lock = self.inner.lock().unwrap();
+        if !lock.accepted.contains_key(&listener) {
+            if let Some(waiter) = waiter.take() {
+                lock.waiters.remove(waiter);
+                lock.draining.remove(&waiter);
+            }
+            return Poll::Ready(());
+        }
+        let id = match *waiter {
+            Some(id) => id,
+            None => {
+                let id = lock.waiters.next_id();
+                lock.draining.insert(id, listener);
+                *waiter = Some(id);
+                id
+            }
+        };
+        lock.waiters.register(id, cx.waker());
+        Poll::Pending
+    }
+
+    /// Stops `waiter` from waiting.
+    fn cancel(&self, waiter: u64) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.waiters.remove(waiter);
+        lock.draining.remove(&waiter);
+    }
+
+    fn list(&self) -> Vec<(net::SocketAddr, net::SocketAddr)> {
+        let lock = self.inner.lock().unwrap();
+        lock.open
+            .iter()
+            .flat_map(|(ends, count)| std::iter::repeat_n(*ends, *count))
+            .collect()
+    }
+}
+
+/// Fault injectors of the connections accepted into the backlogs of a cluster, by the port of
+/// the listener and the id of the connection. A connection is removed once both of its ends
+/// are dropped, or once it is disconnected.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Connections {
+    inner: sync::Arc<sync::Mutex<ConnectionsByPort>>,
+}
+
+type ConnectionsByPort = BTreeMap<
+    num::NonZeroU16,
+    BTreeMap<stream::ConnectionId, stream::MemoryConnectionFaultInjector>,
+>;
+
+impl Connections {
+    fn insert(&self, port: num::NonZeroU16, fault: stream::MemoryConnectionFaultInjector) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.entry(port).or_default().insert(fault.id(), fault);
+    }
+
+    pub(crate) fn remove(&self, port: num::NonZeroU16, id: stream::ConnectionId) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(connections) = lock.get_mut(&port) {
+            connections.remove(&id);
+            if connections.is_empty() {
+                lock.remove(&port);
+            }
+        }
+    }
+
+    /// Removes the connections made to `port`, returning their fault injectors.
+    fn take(&self, port: num::NonZeroU16) -> Vec<stream::MemoryConnectionFaultInjector> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.remove(&port)
+            .map(|connections| connections.into_values().collect())
+            .unwrap_or_default()
+    }
 
-    /// Fault injectors corresponding to a connection.
-    fault_injectors: HashMap<num::NonZeroU16, Vec<stream::MemoryConnectionFaultInjector>>,
+    /// Returns the number of open connections made to each port.
+    fn counts(&self) -> Vec<(num::NonZeroU16, usize)> {
+        let lock = self.inner.lock().unwrap();
+        lock.iter()
+            .map(|(port, connections)| (*port, connections.len()))
+            .collect()
+    }
+}
+
+/// Future returned by `Listener::drained`.
+#[derive(Debug)]
+struct Drained {
+    ends: OpenEnds,
+    /// Id of the listener whose accepted connections are waited on.
+    listener: u64,
+    /// Id of this future in the queue of waiters, once it has waited.
+    waiter: Option<u64>,
+}
+
+impl Future for Drained {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let Drained {
+            ends,
+            listener,
+            waiter,
+        } = &mut *self;
+        ends.poll_drained(*listener, waiter, cx)
+    }
+}
+
+impl Drop for Drained {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            self.ends.cancel(waiter);
+        }
+    }
+}
+
+/// A connection passed to a listener, along with the address of the client.
+type Incoming = (stream::ServerConnection, net::SocketAddr);
+
+/// A listener registered with its cluster.
+#[derive(Debug)]
+struct Bound {
+    /// Distinguishes the listener from others bound to the same port before or after it.
+    id: u64,
+    channel: mpsc::Sender<Incoming>,
+}
+
+/// A link allowing connections to be made to listeners of another cluster.
+#[derive(Debug)]
+struct Link {
+    target: sync::Weak<sync::Mutex<Inner>>,
+    /// Fault injector for connections made over this link.
+    fault_injector: super::FaultInjectorHandle,
 }
 
 impl Inner {
-    fn new() -> Self {
+    fn new(
+        fault_injector: super::FaultInjectorHandle,
+        ephemeral_ports: ops::RangeInclusive<u16>,
+    ) -> Self {
         Self {
+            ip: net::Ipv4Addr::LOCALHOST.into(),
             next_port: 1,
             listeners: HashMap::new(),
-            fault_injectors: HashMap::new(),
+            bound: 0,
+            reserved: HashMap::new(),
+            fault_injectors: Connections::default(),
+            connections_made: HashMap::new(),
+            backlogs: HashMap::new(),
+            fault_injector,
+            links: vec![],
+            ends: OpenEnds::default(),
+            ephemeral_ports: EphemeralPorts::new(ephemeral_ports),
+            mailboxes: HashMap::new(),
+            clock: super::WallClock::default(),
+            memory: Default::default(),
+            paused_until: None,
+        }
+    }
+
+    /// Returns the address of `port` on the hosts of this cluster.
+    fn addr(&self, port: num::NonZeroU16) -> net::SocketAddr {
+        net::SocketAddr::new(self.ip, port.get())
+    }
+
+    /// Returns the listeners and connection ends of this cluster which are still open.
+    fn open_resources(&self, host: String) -> OpenResources {
+        let mut listeners: Vec<_> = self.listeners.keys().map(|port| self.addr(*port)).collect();
+        listeners.sort();
+        OpenResources {
+            host,
+            listeners,
+            connections: self.ends.list(),
         }
     }
 }
@@ -44,8 +343,10 @@ impl Inner {
     /// Check if the provided port is in use or not. If `port` is 0, assign a new
     /// port.
     fn free_port(&mut self, port: u16) -> Result<num::NonZeroU16, io::Error> {
+        let now = tokio_timer::clock::now();
+        self.reserved.retain(|_, until| *until > now);
         if let Some(port) = num::NonZeroU16::new(port) {
-            if self.listeners.contains_key(&port) {
+            if self.listeners.contains_key(&port) || self.reserved.contains_key(&port) {
                 return Err(io::ErrorKind::AddrInUse.into());
             }
             Ok(port)
@@ -54,67 +355,160 @@ impl Inner {
             loop {
                 if let Some(port) = num::NonZeroU16::new(self.next_port) {
                     self.next_port += 1;
-                    if !self.listeners.contains_key(&port) {
+                    if !self.listeners.contains_key(&port) && !self.reserved.contains_key(&port) {
                         return Ok(port);
                     }
                 } else {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        String::from("could not find a port to bind to"),
-                    ));
+                    return Err(io::Error::other("could not find a port to bind to"));
                 }
             }
         }
     }
 
+    /// Returns true if the listener identified by `id` is still registered to `port`, rather
+    /// than having been closed by a restart of its host.
+    fn is_bound(&self, port: num::NonZeroU16, id: u64) -> bool {
+        self.listeners
+            .get(&port)
+            .is_some_and(|bound| bound.id == id)
+    }
+
     fn deregister_listener(&mut self, port: num::NonZeroU16) {
         self.listeners.remove(&port);
-        if let Some(faults) = self.fault_injectors.get(&port) {
-            for fault in faults {
-                fault.disconnect();
-            }
+        for fault in self.fault_injectors.take(port) {
+            fault.disconnect();
         }
-        self.fault_injectors.remove(&port);
+        self.backlogs.remove(&port);
     }
 
     fn register_new_listener(
         &mut self,
         port: u16,
-    ) -> Result<
-        (
-            num::NonZeroU16,
-            mpsc::Receiver<(stream::ServerConnection, net::SocketAddr)>,
-        ),
-        io::Error,
-    > {
+    ) -> Result<(num::NonZeroU16, u64, mpsc::Receiver<Incoming>), io::Error> {
         let port = self.free_port(port)?;
-        let (tx, rx) = mpsc::channel(1);
-        self.listeners.insert(port, tx);
-        Ok((port, rx))
+        let (channel, rx) = mpsc::channel(1);
+        self.bound += 1;
+        let id = self.bound;
+        self.listeners.insert(port, Bound { id, channel });
+        Ok((port, id, rx))
     }
 
-    fn listener_channel(
-        &self,
+    /// Returns the channel and id of the listener bound to `server_port`, and the number of
+    /// connections previously made to it, not counting the new connection.
+    fn reserve_connection(
+        &mut self,
         server_port: num::NonZeroU16,
-    ) -> Result<mpsc::Sender<(stream::ServerConnection, net::SocketAddr)>, io::Error> {
-        self.listeners
+    ) -> Result<(mpsc::Sender<Incoming>, u64, u64), io::Error> {
+        let (channel, listener) = self
+            .listeners
             .get(&server_port)
-            .cloned()
-            .ok_or_else(|| io::ErrorKind::ConnectionRefused.into())
+            .map(|bound| (bound.channel.clone(), bound.id))
+            .ok_or(io::ErrorKind::ConnectionRefused)?;
+        let made = self.connections_made.entry(server_port).or_insert(0);
+        *made += 1;
+        Ok((channel, listener, *made - 1))
     }
 }
 
+/// A listener bound to a port of the in-memory network. Connections can be accepted through
+/// `TcpListener::accept`, as a `Stream`, or by polling `poll_accept` from a manual `Future`.
+///
+/// Dropping a listener disconnects the connections it accepted. A graceful stop instead calls
+/// `close`, then waits for the accepted connections to finish with `drained`.
 pub struct Listener {
     ttl: u32,
     port: num::NonZeroU16,
-    stream: mpsc::Receiver<(stream::ServerConnection, net::SocketAddr)>,
+    /// Address the listener is bound to, on the hosts of its cluster.
+    addr: net::SocketAddr,
+    /// Id the listener is registered under.
+    id: u64,
+    /// Whether `close` was called, after which accepted connections outlive the listener.
+    closed: bool,
+    stream: mpsc::Receiver<Incoming>,
     inner: sync::Arc<sync::Mutex<Inner>>,
+    events: EventLog,
+}
+
+impl Listener {
+    /// Polls for a new connection, registering the waker of `cx` to be woken when a client
+    /// connects. Returns `NotConnected` once the listener is closed or the network has been
+    /// dropped.
+    pub fn poll_accept(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(stream::ServerConnection, net::SocketAddr), io::Error>> {
+        match futures::ready!(self.poll_connection(cx)) {
+            Some(sock) => Poll::Ready(Ok(sock)),
+            None => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    /// Polls for the next connection, recording the current task as waiting on this listener
+    /// if there is none. Returns `None` once the listener is closed.
+    fn poll_connection(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<(stream::ServerConnection, net::SocketAddr)>> {
+        // the receiver has been drained by `close`, and polling it again would panic.
+        if self.closed {
+            return Poll::Ready(None);
+        }
+        let poll = self.stream.poll_next_unpin(cx);
+        match poll {
+            Poll::Pending => super::task::set_blocker(super::Blocker::Accept { addr: self.addr }),
+            Poll::Ready(Some(_)) => {
+                if let Some(backlog) = self.inner.lock().unwrap().backlogs.get_mut(&self.port) {
+                    *backlog = backlog.saturating_sub(1);
+                }
+            }
+            Poll::Ready(None) => {}
+        }
+        poll
+    }
+
+    /// Stops accepting connections. Later connects to the address of the listener are refused,
+    /// and connections waiting to be accepted are closed, while connections already accepted
+    /// keep working until they are dropped, even once the listener is dropped. Pending and
+    /// later accepts fail with `NotConnected`.
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.stream.close();
+        while let Ok(Some(_)) = self.stream.try_next() {}
+        {
+            let mut lock = self.inner.lock().unwrap();
+            if !lock.is_bound(self.port, self.id) {
+                return;
+            }
+            lock.listeners.remove(&self.port);
+            lock.backlogs.remove(&self.port);
+            // the connections accepted so far are taken out of the registry of the port so
+            // that a listener bound to the port later does not disconnect them when dropped.
+            lock.fault_injectors.take(self.port);
+        }
+        let addr = self.addr;
+        self.events.record(SimEvent::ListenerClosed { addr });
+    }
+
+    /// Returns a future which completes once every connection accepted by this listener has
+    /// been dropped by the server. Connections may still be accepted until the listener is
+    /// closed, so the future may complete while more are on their way. Connections accepted by
+    /// other listeners bound to the same port are not waited on.
+    pub fn drained(&self) -> impl Future<Output = ()> + Send + 'static {
+        Drained {
+            ends: self.inner.lock().unwrap().ends.clone(),
+            listener: self.id,
+            waiter: None,
+        }
+    }
 }
 
 impl Stream for Listener {
     type Item = Result<stream::MemoryStream, io::Error>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let next = futures::ready!(self.stream.poll_next_unpin(cx));
+        let next = futures::ready!(self.poll_connection(cx));
         if let Some((sock, _)) = next {
             Poll::Ready(Some(Ok(sock)))
         } else {
@@ -123,21 +517,19 @@ impl Stream for Listener {
     }
 }
 
-#[async_trait]
 impl crate::TcpListener for Listener {
     type Stream = stream::MemoryStream;
-    async fn accept(&mut self) -> Result<(Self::Stream, net::SocketAddr), io::Error> {
-        if let Some(sock) = self.stream.next().await {
-            Ok(sock)
-        } else {
-            Err(io::ErrorKind::NotConnected.into())
-        }
+    type Incoming = Listener;
+    fn accept(
+        &mut self,
+    ) -> impl Future<Output = Result<(Self::Stream, net::SocketAddr), io::Error>> + Send {
+        futures::future::poll_fn(move |cx| self.poll_accept(cx))
+    }
+    fn incoming(self) -> Self::Incoming {
+        self
     }
     fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
-        Ok(net::SocketAddr::new(
-            net::Ipv4Addr::LOCALHOST.into(),
-            self.port.get(),
-        ))
+        Ok(self.addr)
     }
     fn ttl(&self) -> io::Result<u32> {
         Ok(self.ttl)
@@ -149,66 +541,808 @@ impl crate::TcpListener for Listener {
 
 impl Drop for Listener {
     fn drop(&mut self) {
-        self.inner.lock().unwrap().deregister_listener(self.port)
+        if self.closed {
+            return;
+        }
+        {
+            let mut lock = self.inner.lock().unwrap();
+            if !lock.is_bound(self.port, self.id) {
+                return;
+            }
+            lock.deregister_listener(self.port);
+        }
+        let addr = self.addr;
+        self.events.record(SimEvent::ListenerClosed { addr });
     }
 }
 
+/// Range client ends of connections are assigned ports from by default, the IANA dynamic
+/// port range.
+pub(crate) const EPHEMERAL_PORTS: ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug)]
+struct PortPool {
+    range: ops::RangeInclusive<u16>,
+    /// Port the search for a free port starts from.
+    next: u16,
+    in_use: BTreeSet<u16>,
+    /// Ports held in TIME_WAIT along with the server address they may not reconnect to, until
+    /// the instant given.
+    time_wait: BTreeMap<(u16, net::SocketAddr), time::Instant>,
+    /// Stream the port each search starts from is drawn from, if ports are randomized.
+    rng: Option<super::rng::SimRng>,
+}
+
+/// Ephemeral ports of a cluster, assigned to the client ends of connections made from it.
 #[derive(Debug, Clone)]
-pub struct NetworkHandle {
+pub(crate) struct EphemeralPorts {
+    pool: sync::Arc<sync::Mutex<PortPool>>,
+}
+
+impl Default for EphemeralPorts {
+    fn default() -> Self {
+        Self::new(EPHEMERAL_PORTS)
+    }
+}
+
+impl EphemeralPorts {
+    pub(crate) fn new(range: ops::RangeInclusive<u16>) -> Self {
+        let pool = PortPool {
+            next: *range.start(),
+            range,
+            in_use: BTreeSet::new(),
+            time_wait: BTreeMap::new(),
+            rng: None,
+        };
+        Self {
+            pool: sync::Arc::new(sync::Mutex::new(pool)),
+        }
+    }
+
+    /// Starts the search for each free port at a port drawn from `rng`.
+    fn seeded(self, rng: super::rng::SimRng) -> Self {
+        self.pool.lock().unwrap().rng = Some(rng);
+        self
+    }
+
+    /// Assigns the first free port following the last port assigned for a connection to
+    /// `server`, or following a port drawn from the seed if ports are randomized, wrapping
+    /// around the range. Fails with `AddrNotAvailable`, as `connect` does on Linux, once every
+    /// port of the range is held by an open connection or is in TIME_WAIT for `server`.
+    pub(crate) fn assign(&self, server: net::SocketAddr) -> Result<EphemeralPort, io::Error> {
+        let mut lock = self.pool.lock().unwrap();
+        let now = tokio_timer::clock::now();
+        lock.time_wait.retain(|_, until| *until > now);
+        let (start, end) = (*lock.range.start(), *lock.range.end());
+        if let Some(rng) = &mut lock.rng {
+            let offset = rng.gen_range(0, u32::from(end - start) + 1);
+            lock.next = start + offset as u16;
+        }
+        let port = (lock.next..=end)
+            .chain(start..lock.next)
+            .find(|port| {
+                !lock.in_use.contains(port) && !lock.time_wait.contains_key(&(*port, server))
+            })
+            .ok_or(io::ErrorKind::AddrNotAvailable)?;
+        lock.in_use.insert(port);
+        lock.next = if port == end { start } else { port + 1 };
+        Ok(EphemeralPort {
+            ports: self.clone(),
+            port,
+        })
+    }
+}
+
+/// An ephemeral port held by the client end of a connection, freed once dropped.
+#[derive(Debug)]
+pub(crate) struct EphemeralPort {
+    ports: EphemeralPorts,
+    port: u16,
+}
+
+impl EphemeralPort {
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Frees the port, keeping it unusable for connections to `server` until `until`.
+    pub(crate) fn time_wait(self, server: net::SocketAddr, until: time::Instant) {
+        let mut pool = self.ports.pool.lock().unwrap();
+        pool.time_wait.insert((self.port, server), until);
+    }
+}
+
+impl Drop for EphemeralPort {
+    fn drop(&mut self) {
+        self.ports.pool.lock().unwrap().in_use.remove(&self.port);
+    }
+}
+
+/// Snapshot of the state of the in-memory network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkState {
+    /// Addresses with a bound listener.
+    pub listeners: Vec<net::SocketAddr>,
+    /// Number of open connections to each server address.
+    pub connections: BTreeMap<net::SocketAddr, usize>,
+}
+
+/// Listeners and connections of a host which were still open when it was checked for leaks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenResources {
+    pub host: String,
+    /// Addresses of listeners which have not been dropped.
+    pub listeners: Vec<net::SocketAddr>,
+    /// Local and peer addresses of connection ends which have not been dropped.
+    pub connections: Vec<(net::SocketAddr, net::SocketAddr)>,
+}
+
+impl OpenResources {
+    /// Returns true if nothing was left open.
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty() && self.connections.is_empty()
+    }
+}
+
+impl fmt::Display for OpenResources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "host {} leaked {} listeners and {} connections",
+            self.host,
+            self.listeners.len(),
+            self.connections.len()
+        )?;
+        for addr in &self.listeners {
+            write!(f, "\n  listener {}", addr)?;
+        }
+        for (local, peer) in &self.connections {
+            write!(f, "\n  connection {} -> {}", local, peer)?;
+        }
+        Ok(())
+    }
+}
+
+/// A cluster of the network and the clusters it can reach, as rendered by
+/// `DeterministicRuntime::topology_dot`.
+#[derive(Debug, Clone)]
+pub(crate) struct ClusterTopology {
+    /// Position of the cluster in the order clusters were created.
+    pub(crate) index: usize,
+    /// Faults injected into connections made within the cluster.
+    pub(crate) config: super::FaultConfig,
+    pub(crate) listeners: Vec<net::SocketAddr>,
+    /// Number of connection ends owned by the cluster which are open.
+    pub(crate) connections: usize,
+    /// Index of each cluster this cluster is linked to, along with the faults of the link.
+    pub(crate) links: Vec<(usize, super::FaultConfig)>,
+}
+
+/// Every cluster of a network, each with its own address space.
+#[derive(Debug)]
+struct Clusters {
+    inners: Vec<sync::Arc<sync::Mutex<Inner>>>,
+    /// Next fault injector scope to assign to a cluster or link.
+    next_scope: u64,
+    /// Buffers and fault state reused by the connections of every cluster.
+    pools: stream::Pools,
+    /// Whether resources left open by a host fail the run, rather than being logged.
+    fail_on_leaks: bool,
+    /// Range of ephemeral ports of each cluster.
+    ephemeral_ports: ops::RangeInclusive<u16>,
+    /// Seed and algorithm the ephemeral ports of each cluster are drawn from, if they are
+    /// randomized.
+    port_seed: Option<(u64, super::rng::Algorithm)>,
+    /// Behavior of connection ends when dropped.
+    linger: stream::Linger,
+    /// Number of connections attempted between hosts of every cluster, which the id of the
+    /// next connection is assigned from.
+    connections: u64,
+    /// Budget the memory of every cluster is charged against.
+    memory: super::memory::MemoryBudget,
+}
+
+impl Clusters {
+    /// Returns the ephemeral ports of the `index`th cluster, drawn from a stream derived from
+    /// the seed and the index of the cluster if ports are randomized.
+    fn ephemeral_ports(&self, index: usize) -> EphemeralPorts {
+        let ports = EphemeralPorts::new(self.ephemeral_ports.clone());
+        match &self.port_seed {
+            Some((seed, algorithm)) => ports.seeded(super::rng::derive(
+                algorithm,
+                *seed,
+                &("ephemeral_ports", index),
+            )),
+            None => ports,
+        }
+    }
+
+    /// Replaces the ephemeral ports of every cluster, following a change to their range or seed.
+    fn reset_ephemeral_ports(&mut self) {
+        for (index, inner) in self.inners.iter().enumerate() {
+            inner.lock().unwrap().ephemeral_ports = self.ephemeral_ports(index);
+        }
+    }
+
+    /// Returns the index of the cluster `inner`.
+    fn index_of(&self, inner: &sync::Arc<sync::Mutex<Inner>>) -> Option<usize> {
+        self.inners
+            .iter()
+            .position(|cluster| sync::Arc::ptr_eq(cluster, inner))
+    }
+}
+
+/// Returns the address of the hosts of the `index`th cluster, `127.0.0.1` for the root cluster
+/// and `10.0.0.n` for the `n`th cluster created.
+pub(crate) fn cluster_ip(index: usize) -> net::IpAddr {
+    match index {
+        0 => net::Ipv4Addr::LOCALHOST.into(),
+        index => net::Ipv4Addr::from(0x0a00_0000 + index as u32).into(),
+    }
+}
+
+/// The cluster a connection is made to, along with the fault injector for the connection.
+struct Route {
+    target: sync::Arc<sync::Mutex<Inner>>,
+    /// Address of the listener, on the hosts of the target cluster.
+    server: net::SocketAddr,
+    channel: mpsc::Sender<Incoming>,
+    /// Id of the listener.
+    listener: u64,
     fault_injector: super::FaultInjectorHandle,
+    /// Number of connections previously made to the listener.
+    connection: u64,
+}
+
+/// A connection which has been routed to a listener, but not yet accepted into its backlog.
+#[derive(Debug)]
+struct PendingConnect {
+    target: sync::Arc<sync::Mutex<Inner>>,
+    channel: mpsc::Sender<Incoming>,
+    port: num::NonZeroU16,
+    fault_handle: stream::MemoryConnectionFaultInjector,
+    client: stream::ClientConnection,
+    server: Option<stream::ServerConnection>,
+}
+
+/// Future returned by `DeterministicRuntimeHandle::connect_addr`, resolving to a connection
+/// once the listener has room in its backlog.
+#[derive(Debug)]
+pub struct Connect {
+    /// Taken once the future has completed.
+    state: Option<Result<PendingConnect, io::Error>>,
+    events: EventLog,
+}
+
+impl Connect {
+    /// Polls for the connection to be accepted into the backlog of the listener, registering
+    /// the waker of `cx` to be woken when there is room.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after the connection has been returned.
+    pub fn poll_connect(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<stream::ClientConnection, io::Error>> {
+        let pending = match self.state.as_mut().expect("polled after completion") {
+            Ok(pending) => pending,
+            Err(_) => return Poll::Ready(Err(self.state.take().unwrap().unwrap_err())),
+        };
+        let sent = futures::ready!(pending.channel.poll_ready(cx)).and_then(|()| {
+            let server = pending.server.take().expect("sent connection twice");
+            pending
+                .channel
+                .start_send((server, pending.client.local_addr()))
+        });
+        if let Err(e) = sent {
+            self.state.take();
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionRefused, e)));
+        }
+        let PendingConnect {
+            target,
+            port,
+            fault_handle,
+            client,
+            ..
+        } = self.state.take().unwrap().unwrap();
+        let backlog = {
+            let mut lock = target.lock().unwrap();
+            lock.fault_injectors.insert(port, fault_handle);
+            let backlog = lock.backlogs.entry(port).or_insert(0);
+            *backlog += 1;
+            *backlog
+        };
+        self.events.record(SimEvent::ConnectionOpened {
+            client: client.local_addr(),
+            server: client.peer_addr(),
+            connection: client.connection_id(),
+        });
+        super::context::watermark(
+            super::QueueKind::AcceptBacklog,
+            &client.peer_addr(),
+            backlog - 1,
+            backlog,
+        );
+        Poll::Ready(Ok(client))
+    }
+}
+
+impl Future for Connect {
+    type Output = Result<stream::ClientConnection, io::Error>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().poll_connect(cx)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkHandle {
     inner: sync::Arc<sync::Mutex<Inner>>,
+    clusters: sync::Arc<sync::Mutex<Clusters>>,
+    pools: stream::Pools,
+    events: EventLog,
 }
 
 impl NetworkHandle {
-    fn new(
-        inner: sync::Arc<sync::Mutex<Inner>>,
-        fault_injector: super::FaultInjectorHandle,
-    ) -> Self {
+    fn new(clusters: sync::Arc<sync::Mutex<Clusters>>, events: EventLog) -> Self {
+        let (inner, pools) = {
+            let lock = clusters.lock().unwrap();
+            (sync::Arc::clone(&lock.inners[0]), lock.pools.clone())
+        };
         Self {
-            fault_injector,
             inner,
+            clusters,
+            pools,
+            events,
         }
     }
-    pub async fn connect(
+
+    fn next_scope(&self) -> u64 {
+        let mut lock = self.clusters.lock().unwrap();
+        lock.next_scope += 1;
+        lock.next_scope
+    }
+
+    /// Returns a handle to a new cluster with its own address space, sharing the fault
+    /// injector of this handle but injecting faults according to `config`.
+    pub(crate) fn new_cluster(&self, config: super::FaultConfig) -> NetworkHandle {
+        let scope = self.next_scope();
+        let fault_injector = {
+            let lock = self.inner.lock().unwrap();
+            lock.fault_injector.scoped(scope, config)
+        };
+        let mut clusters = self.clusters.lock().unwrap();
+        let mut inner = Inner::new(fault_injector, clusters.ephemeral_ports.clone());
+        inner.ip = cluster_ip(clusters.inners.len());
+        inner.ephemeral_ports = clusters.ephemeral_ports(clusters.inners.len());
+        inner.memory = clusters.memory.host(clusters.inners.len());
+        let inner = sync::Arc::new(sync::Mutex::new(inner));
+        clusters.inners.push(sync::Arc::clone(&inner));
+        drop(clusters);
+        NetworkHandle {
+            inner,
+            clusters: sync::Arc::clone(&self.clusters),
+            pools: self.pools.clone(),
+            events: self.events.clone(),
+        }
+    }
+
+    /// Returns the position of this cluster in the order clusters were created, the root
+    /// cluster being 0.
+    pub(crate) fn cluster_index(&self) -> usize {
+        let lock = self.clusters.lock().unwrap();
+        lock.index_of(&self.inner)
+            .expect("cluster is not registered")
+    }
+
+    /// Returns every cluster of the network along with the links between them.
+    pub(crate) fn topology(&self) -> Vec<ClusterTopology> {
+        let clusters = self.clusters.lock().unwrap();
+        let index_of = |target: &sync::Arc<sync::Mutex<Inner>>| {
+            clusters
+                .inners
+                .iter()
+                .position(|inner| sync::Arc::ptr_eq(inner, target))
+        };
+        clusters
+            .inners
+            .iter()
+            .enumerate()
+            .map(|(index, inner)| {
+                let lock = inner.lock().unwrap();
+                let mut listeners: Vec<_> =
+                    lock.listeners.keys().map(|port| lock.addr(*port)).collect();
+                listeners.sort();
+                let links = lock
+                    .links
+                    .iter()
+                    .filter_map(|link| {
+                        let target = index_of(&link.target.upgrade()?)?;
+                        Some((target, link.fault_injector.config().clone()))
+                    })
+                    .collect();
+                ClusterTopology {
+                    index,
+                    config: lock.fault_injector.config().clone(),
+                    listeners,
+                    connections: lock.ends.list().len(),
+                    links,
+                }
+            })
+            .collect()
+    }
+
+    /// Allows connections made from this cluster to reach listeners bound in the cluster of
+    /// `other`, injecting faults according to `config`. Connections to the address of a cluster
+    /// only reach that cluster, while for loopback addresses listeners bound in this cluster
+    /// take precedence over those of linked clusters, which are tried in the order they were
+    /// linked.
+    pub(crate) fn link(&self, other: &NetworkHandle, config: super::FaultConfig) {
+        let scope = self.next_scope();
+        let mut lock = self.inner.lock().unwrap();
+        let fault_injector = lock.fault_injector.scoped(scope, config);
+        lock.links.push(Link {
+            target: sync::Arc::downgrade(&other.inner),
+            fault_injector,
+        });
+    }
+
+    /// Returns the cluster whose hosts have the address `ip`. The root cluster is not returned,
+    /// as its loopback address is shared by the hosts of every cluster.
+    fn cluster_at(&self, ip: net::IpAddr) -> Option<sync::Arc<sync::Mutex<Inner>>> {
+        let clusters = self.clusters.lock().unwrap();
+        clusters
+            .inners
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(index, _)| cluster_ip(*index) == ip)
+            .map(|(_, inner)| sync::Arc::clone(inner))
+    }
+
+    /// Returns the clusters linked to this one which `ip` may reach, in the order they were
+    /// linked, along with the fault injector of each link. Only `target` is returned if `ip`
+    /// is the address of a cluster.
+    fn linked(
+        lock: &Inner,
+        target: Option<&sync::Arc<sync::Mutex<Inner>>>,
+    ) -> Vec<(sync::Arc<sync::Mutex<Inner>>, super::FaultInjectorHandle)> {
+        lock.links
+            .iter()
+            .filter_map(|link| Some((link.target.upgrade()?, link.fault_injector.clone())))
+            .filter(|(linked, _)| target.is_none_or(|target| sync::Arc::ptr_eq(target, linked)))
+            .collect()
+    }
+
+    /// Finds the cluster with a listener bound to `addr`, reserving a connection to it. The
+    /// address of a cluster only reaches that cluster, while loopback and other addresses reach
+    /// the listener bound to the port in this cluster, or else in the first linked cluster
+    /// with one. A connection within this cluster is routed with a single lock of the registry.
+    fn route(&self, addr: net::SocketAddr, port: num::NonZeroU16) -> Result<Route, io::Error> {
+        let target = self.cluster_at(addr.ip());
+        let links: Vec<_> = {
+            let mut lock = self.inner.lock().unwrap();
+            let local = target
+                .as_ref()
+                .is_none_or(|target| sync::Arc::ptr_eq(target, &self.inner));
+            if local {
+                if let Ok((channel, listener, connection)) = lock.reserve_connection(port) {
+                    return Ok(Route {
+                        target: sync::Arc::clone(&self.inner),
+                        server: lock.addr(port),
+                        channel,
+                        listener,
+                        fault_injector: lock.fault_injector.clone(),
+                        connection,
+                    });
+                }
+            }
+            Self::linked(&lock, target.as_ref())
+        };
+        for (target, fault_injector) in links {
+            let mut lock = target.lock().unwrap();
+            if let Ok((channel, listener, connection)) = lock.reserve_connection(port) {
+                let server = lock.addr(port);
+                drop(lock);
+                return Ok(Route {
+                    target,
+                    server,
+                    channel,
+                    listener,
+                    fault_injector,
+                    connection,
+                });
+            }
+        }
+        Err(io::ErrorKind::ConnectionRefused.into())
+    }
+
+    /// Registers the mailbox of a message channel bound to `port` of this cluster. Fails with
+    /// `AddrInUse` if another channel is bound to it.
+    pub(crate) fn bind_mailbox(
+        &self,
+        port: num::NonZeroU16,
+        mailbox: Mailbox,
+    ) -> Result<(), io::Error> {
+        let mut lock = self.inner.lock().unwrap();
+        if lock.mailboxes.contains_key(&port) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        lock.mailboxes.insert(port, mailbox);
+        Ok(())
+    }
+
+    pub(crate) fn unbind_mailbox(&self, port: num::NonZeroU16) {
+        self.inner.lock().unwrap().mailboxes.remove(&port);
+    }
+
+    /// Finds the mailbox bound to `addr`, in this cluster or the clusters linked to it as
+    /// `route` finds listeners, along with its address and the fault injector for messages
+    /// sent to it.
+    pub(crate) fn route_message(
         &self,
         addr: net::SocketAddr,
-    ) -> Result<stream::ClientConnection, io::Error> {
-        let port: num::NonZeroU16 = num::NonZeroU16::new(addr.port())
-            .ok_or_else(|| <io::ErrorKind as Into<io::Error>>::into(io::ErrorKind::InvalidInput))?;
-        let mut channel = { self.inner.lock().unwrap().listener_channel(port)? };
-        let (fault_handle, client, server) = stream::new_pair(self.fault_injector.clone(), port);
-        channel
-            .send((server, client.local_addr()))
-            .await
-            .map_err(|_| io::ErrorKind::ConnectionRefused)?;
-        {
+        port: num::NonZeroU16,
+    ) -> Option<(Mailbox, net::SocketAddr, super::FaultInjectorHandle)> {
+        let target = self.cluster_at(addr.ip());
+        let links: Vec<_> = {
+            let lock = self.inner.lock().unwrap();
+            let local = target
+                .as_ref()
+                .is_none_or(|target| sync::Arc::ptr_eq(target, &self.inner));
+            if let Some(mailbox) = lock.mailboxes.get(&port).filter(|_| local) {
+                let fault_injector = lock.fault_injector.clone();
+                return Some((sync::Arc::clone(mailbox), lock.addr(port), fault_injector));
+            }
+            Self::linked(&lock, target.as_ref())
+        };
+        links.into_iter().find_map(|(target, fault_injector)| {
+            let lock = target.lock().unwrap();
+            let mailbox = lock.mailboxes.get(&port).cloned()?;
+            Some((mailbox, lock.addr(port), fault_injector))
+        })
+    }
+
+    /// Returns the address of the hosts of this cluster.
+    pub(crate) fn ip(&self) -> net::IpAddr {
+        self.inner.lock().unwrap().ip
+    }
+
+    /// Reads the wall clock of this cluster at the simulated wall clock time `wall`, injecting
+    /// clock skew and anomalies.
+    pub(crate) fn system_time(&self, wall: time::SystemTime) -> time::SystemTime {
+        let mut lock = self.inner.lock().unwrap();
+        if !lock.clock.has_skew() {
+            let skew = lock.fault_injector.clock_skew();
+            lock.clock.set_skew(skew);
+        }
+        let anomaly = lock.fault_injector.clock_anomaly();
+        lock.clock.read(wall, anomaly)
+    }
+
+    /// Decides whether to skew a timer created by a host of this cluster.
+    pub(crate) fn timer_skew(&self) -> Option<super::fault::TimerSkew> {
+        let fault_injector = self.inner.lock().unwrap().fault_injector.clone();
+        fault_injector.timer_skew()
+    }
+
+    /// Returns the listeners and connection ends of this cluster which are still open, naming
+    /// the cluster `host`.
+    pub(crate) fn open_resources(&self, host: &str) -> OpenResources {
+        self.inner.lock().unwrap().open_resources(host.to_string())
+    }
+
+    /// Returns the resources still open in each cluster, by the index of the cluster.
+    pub(crate) fn open_resources_by_cluster(&self) -> Vec<(usize, OpenResources)> {
+        let lock = self.clusters.lock().unwrap();
+        lock.inners
+            .iter()
+            .enumerate()
+            .map(|(index, inner)| (index, inner.lock().unwrap().open_resources(String::new())))
+            .filter(|(_, resources)| !resources.is_empty())
+            .collect()
+    }
+
+    /// Sets the range of ephemeral ports of every cluster. Must be called before any
+    /// connection is made.
+    pub(crate) fn set_ephemeral_ports(&self, range: ops::RangeInclusive<u16>) {
+        let mut clusters = self.clusters.lock().unwrap();
+        clusters.ephemeral_ports = range;
+        clusters.reset_ephemeral_ports();
+    }
+
+    /// Draws the ephemeral ports of each cluster from a stream derived from `seed`, rather than
+    /// assigning them in order.
+    pub(crate) fn randomize_ephemeral_ports(&self, seed: u64, algorithm: super::rng::Algorithm) {
+        let mut clusters = self.clusters.lock().unwrap();
+        clusters.port_seed = Some((seed, algorithm));
+        clusters.reset_ephemeral_ports();
+    }
+
+    pub(crate) fn set_linger(&self, linger: Linger) {
+        self.clusters.lock().unwrap().linger = linger;
+    }
+
+    /// Sets the bytes each cluster may hold in buffers and queues before the run fails.
+    pub(crate) fn set_memory_budget(&self, limit: Option<u64>) {
+        self.clusters.lock().unwrap().memory.set_limit(limit);
+    }
+
+    /// Returns the memory held by each cluster, in the order clusters were created.
+    pub(crate) fn memory_stats(&self) -> Vec<super::MemoryStats> {
+        let clusters = self.clusters.lock().unwrap();
+        clusters
+            .inners
+            .iter()
+            .map(|inner| inner.lock().unwrap().memory.stats())
+            .collect()
+    }
+
+    /// Returns the memory of this cluster, which its message queues are charged to.
+    pub(crate) fn memory(&self) -> super::memory::HostMemory {
+        self.inner.lock().unwrap().memory.clone()
+    }
+
+    pub(crate) fn set_fail_on_leaks(&self, fail: bool) {
+        self.clusters.lock().unwrap().fail_on_leaks = fail;
+    }
+
+    pub(crate) fn fail_on_leaks(&self) -> bool {
+        self.clusters.lock().unwrap().fail_on_leaks
+    }
+
+    /// Returns a snapshot of the listeners and connections of this network.
+    pub fn state(&self) -> NetworkState {
+        let lock = self.inner.lock().unwrap();
+        let mut listeners: Vec<net::SocketAddr> =
+            lock.listeners.keys().map(|port| lock.addr(*port)).collect();
+        listeners.sort();
+        let connections = lock
+            .fault_injectors
+            .counts()
+            .into_iter()
+            .map(|(port, count)| (lock.addr(port), count))
+            .collect();
+        NetworkState {
+            listeners,
+            connections,
+        }
+    }
+    /// Closes every listener of this cluster as its host is killed, disconnecting the connections
+    /// they accepted. Each address may be reserved for a while by the fault injector, failing
+    /// binds until then with `AddrInUse`.
+    pub(crate) fn restart(&self) {
+        let addrs = {
             let mut lock = self.inner.lock().unwrap();
-            match lock.fault_injectors.entry(port) {
-                Entry::Occupied(mut o) => o.get_mut().push(fault_handle),
-                Entry::Vacant(v) => {
-                    v.insert(vec![fault_handle]);
+            let mut ports: Vec<_> = lock.listeners.keys().copied().collect();
+            ports.sort();
+            for port in &ports {
+                if let Some(mut bound) = lock.listeners.remove(port) {
+                    bound.channel.close_channel();
                 }
-            };
+                lock.deregister_listener(*port);
+                if let Some(until) = lock.fault_injector.rebind_delay(lock.addr(*port)) {
+                    lock.reserved.insert(*port, until);
+                }
+            }
+            let addrs: Vec<_> = ports.iter().map(|port| lock.addr(*port)).collect();
+            addrs
+        };
+        for addr in addrs {
+            self.events.record(SimEvent::ListenerClosed { addr });
         }
-        Ok(client)
     }
 
-    pub fn bind(&self, addr: net::SocketAddr) -> Result<Listener, io::Error> {
+    /// Pauses the tasks of this cluster for `duration`, or until the end of the current pause if
+    /// that is later.
+    pub(crate) fn pause(&self, duration: Duration) {
+        let until = tokio_timer::clock::now() + duration;
         let mut lock = self.inner.lock().unwrap();
-        let (port, listener_stream) = lock.register_new_listener(addr.port())?;
+        lock.paused_until = lock.paused_until.max(Some(until));
+    }
+
+    /// Returns when the tasks of this cluster resume, if they are paused.
+    pub(crate) fn paused_until(&self) -> Option<time::Instant> {
+        let now = tokio_timer::clock::now();
+        let lock = self.inner.lock().unwrap();
+        lock.paused_until.filter(|until| *until > now)
+    }
+
+    /// Returns a future connecting to the listener bound to `addr`, either in this cluster or
+    /// in a linked cluster, see `route`.
+    pub fn connect(&self, addr: net::SocketAddr) -> Connect {
+        let state = self.start_connect(addr);
+        Connect {
+            state: Some(state),
+            events: self.events.clone(),
+        }
+    }
+
+    fn start_connect(&self, addr: net::SocketAddr) -> Result<PendingConnect, io::Error> {
+        let port: num::NonZeroU16 = num::NonZeroU16::new(addr.port())
+            .ok_or_else(|| <io::ErrorKind as Into<io::Error>>::into(io::ErrorKind::InvalidInput))?;
+        let Route {
+            target,
+            server,
+            channel,
+            listener,
+            fault_injector,
+            connection,
+        } = self.route(addr, port)?;
+        let client_port = self.inner.lock().unwrap().ephemeral_ports.assign(server)?;
+        // the target may be this cluster, so its registry is locked only once this one is
+        // released.
+        let (client_ends, client_memory) = {
+            let lock = self.inner.lock().unwrap();
+            (lock.ends.clone(), lock.memory.clone())
+        };
+        let (server_ends, server_memory, connections) = {
+            let lock = target.lock().unwrap();
+            (
+                lock.ends.clone(),
+                lock.memory.clone(),
+                lock.fault_injectors.clone(),
+            )
+        };
+        let (linger, id, client_ip) = {
+            let mut clusters = self.clusters.lock().unwrap();
+            clusters.connections += 1;
+            let index = clusters.index_of(&self.inner).unwrap_or(0);
+            (
+                clusters.linger,
+                stream::ConnectionId(clusters.connections),
+                cluster_ip(index),
+            )
+        };
+        let endpoints = stream::Endpoints {
+            id,
+            listener,
+            client_ip,
+            client_port,
+            server,
+            client_ends,
+            server_ends,
+            client_memory,
+            server_memory,
+            linger,
+            connections,
+        };
+        let (fault_handle, client, server) =
+            stream::new_pair(&self.pools, fault_injector, port, connection, endpoints);
+        Ok(PendingConnect {
+            target,
+            channel,
+            port,
+            fault_handle,
+            client,
+            server: Some(server),
+        })
+    }
+
+    pub fn bind(&self, addr: net::SocketAddr) -> Result<Listener, io::Error> {
+        let (port, id, listener_stream, addr) = {
+            let mut lock = self.inner.lock().unwrap();
+            let (port, id, listener_stream) = lock.register_new_listener(addr.port())?;
+            (port, id, listener_stream, lock.addr(port))
+        };
+        self.events.record(SimEvent::ListenerBound { addr });
         Ok(Listener {
             ttl: 0,
             port,
+            addr,
+            id,
+            closed: false,
             stream: listener_stream,
             inner: sync::Arc::clone(&self.inner),
+            events: self.events.clone(),
         })
     }
 }
 
 pub(crate) struct Network<P> {
     park: P,
-    inner: sync::Arc<sync::Mutex<Inner>>,
-    fault_injector: super::FaultInjectorHandle,
+    clusters: sync::Arc<sync::Mutex<Clusters>>,
+    events: EventLog,
 }
 
 impl<P> Park for Network<P>
@@ -222,10 +1356,12 @@ where
     }
     fn park(&mut self) -> Result<(), Self::Error> {
         self.inject_faults();
+        self.check_memory();
         self.park.park()
     }
     fn park_timeout(&mut self, duration: Duration) -> Result<(), Self::Error> {
         self.inject_faults();
+        self.check_memory();
         self.park.park_timeout(duration)
     }
 }
@@ -234,37 +1370,74 @@ impl<P> Network<P>
 where
     P: Park,
 {
-    pub(crate) fn new_with_park(park: P, fault_injector: super::FaultInjectorHandle) -> Network<P> {
-        let inner = Inner {
-            next_port: 1,
-            listeners: HashMap::new(),
-            fault_injectors: HashMap::new(),
+    pub(crate) fn new_with_park(
+        park: P,
+        fault_injector: super::FaultInjectorHandle,
+        events: EventLog,
+    ) -> Network<P> {
+        let memory = super::memory::MemoryBudget::default();
+        let mut inner = Inner::new(fault_injector, EPHEMERAL_PORTS);
+        inner.memory = memory.host(0);
+        let clusters = Clusters {
+            inners: vec![sync::Arc::new(sync::Mutex::new(inner))],
+            next_scope: 0,
+            pools: stream::Pools::default(),
+            fail_on_leaks: false,
+            ephemeral_ports: EPHEMERAL_PORTS,
+            linger: stream::Linger::default(),
+            connections: 0,
+            port_seed: None,
+            memory,
         };
-        let inner = sync::Arc::new(sync::Mutex::new(inner));
         Network {
-            inner,
             park,
-            fault_injector,
+            clusters: sync::Arc::new(sync::Mutex::new(clusters)),
+            events,
         }
     }
 
     pub(crate) fn handle(&self) -> NetworkHandle {
-        NetworkHandle {
-            fault_injector: self.fault_injector.clone(),
-            inner: sync::Arc::clone(&self.inner),
-        }
+        NetworkHandle::new(sync::Arc::clone(&self.clusters), self.events.clone())
+    }
+
+    /// Fails the run if a cluster exceeded the memory budget.
+    fn check_memory(&self) {
+        let memory = self.clusters.lock().unwrap().memory.clone();
+        memory.check();
     }
 
     fn inject_faults(&self) {
-        let mut lock = self.inner.lock().unwrap();
-        for (_, v) in lock.fault_injectors.iter_mut() {
-            if let Some(idx) = self
-                .fault_injector
-                .pick_rand_connection_disconnect(0..v.len())
-            {
-                let fault_injector = v.remove(idx);
-                fault_injector.disconnect();
+        // the list of clusters is never locked while holding the registry of a cluster, so the
+        // clusters can be visited without first copying the list.
+        let clusters = self.clusters.lock().unwrap();
+        let now = tokio_timer::clock::now();
+        for inner in &clusters.inners {
+            let mut lock = inner.lock().unwrap();
+            let Inner {
+                fault_injectors,
+                fault_injector,
+                paused_until,
+                ..
+            } = &mut *lock;
+            if !paused_until.is_some_and(|until| until > now) {
+                if let Some(duration) = fault_injector.host_pause() {
+                    *paused_until = Some(now + duration);
+                }
             }
+            let mut connections = fault_injectors.inner.lock().unwrap();
+            for (port, v) in connections.iter_mut() {
+                let key = super::fault::StreamKey::Disconnect { port: port.get() };
+                let ids: Vec<_> = v.keys().copied().collect();
+                let picked =
+                    fault_injector.pick_rand_connection_disconnect(key, 0..ids.len(), |idx| {
+                        Some(v[&ids[idx]].addrs())
+                    });
+                if let Some(idx) = picked {
+                    let fault_injector = v.remove(&ids[idx]).unwrap();
+                    fault_injector.disconnect();
+                }
+            }
+            connections.retain(|_, v| !v.is_empty());
         }
     }
 }
@@ -274,7 +1447,7 @@ mod tests {
     use super::*;
     use crate::Environment;
     use crate::TcpListener;
-    use futures::StreamExt;
+    use futures::{SinkExt, StreamExt};
     use std::sync;
     use tokio::codec::{Framed, LinesCodec};
 
@@ -306,9 +1479,23 @@ mod tests {
         let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
         let handle = runtime.handle();
         let noop_fault_injector = crate::deterministic::FaultInjector::new_noop();
-        let network_inner = Inner::new();
-        let network_inner = sync::Arc::new(sync::Mutex::new(network_inner));
-        let network_handle = NetworkHandle::new(network_inner, noop_fault_injector.handle());
+        let network_inner = Inner::new(noop_fault_injector.handle(), EPHEMERAL_PORTS);
+        let clusters = Clusters {
+            inners: vec![sync::Arc::new(sync::Mutex::new(network_inner))],
+            next_scope: 0,
+            pools: stream::Pools::default(),
+            fail_on_leaks: false,
+            ephemeral_ports: EPHEMERAL_PORTS,
+            linger: stream::Linger::default(),
+            connections: 0,
+            port_seed: None,
+            memory: Default::default(),
+        };
+        let events = EventLog::new(
+            crate::deterministic::Time::new().clone_now(),
+            Default::default(),
+        );
+        let network_handle = NetworkHandle::new(sync::Arc::new(sync::Mutex::new(clusters)), events);
         runtime.block_on(async {
             // spawn server which binds to a port.
             let addr: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
@@ -334,4 +1521,514 @@ mod tests {
             }
         });
     }
+
+    /// Connects to and accepts from a listener within a single hand-written future.
+    struct Handshake<'a> {
+        listener: &'a mut Listener,
+        connect: Connect,
+        client: Option<ClientConnection>,
+        server: Option<ServerConnection>,
+    }
+
+    impl Future for Handshake<'_> {
+        type Output = (ClientConnection, ServerConnection);
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let this = self.get_mut();
+            if this.client.is_none() {
+                if let Poll::Ready(client) = this.connect.poll_connect(cx) {
+                    this.client = Some(client.unwrap());
+                }
+            }
+            if this.server.is_none() {
+                if let Poll::Ready(server) = this.listener.poll_accept(cx) {
+                    this.server = Some(server.unwrap().0);
+                }
+            }
+            if this.client.is_some() && this.server.is_some() {
+                Poll::Ready((this.client.take().unwrap(), this.server.take().unwrap()))
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    /// Test that connections can be made and accepted by polling from a manual `Future`.
+    fn poll_connect_accept() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime
+            .handle()
+            .new_cluster(crate::deterministic::FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let handshake = Handshake {
+                listener: &mut listener,
+                connect: handle.connect_addr(addr),
+                client: None,
+                server: None,
+            };
+            let (client, server) = handshake.await;
+            // the listener is reached at the address of its cluster.
+            let bound: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+            assert_eq!(client.peer_addr(), bound);
+            assert_eq!(server.peer_addr(), client.local_addr());
+            assert_eq!(handle.network_state().connections.get(&bound), Some(&1));
+            let mut refused = handle.connect_addr("127.0.0.1:9093".parse().unwrap());
+            let refused = futures::future::poll_fn(|cx| refused.poll_connect(cx)).await;
+            assert_eq!(
+                refused.unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+        });
+    }
+
+    #[test]
+    /// Test that the fault state of a connection is dropped once both of its ends are, and is
+    /// reused by the next connection.
+    fn closed_connections() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (client, (server, _)) = (client.unwrap(), server.unwrap());
+            drop(client);
+            assert_eq!(handle.network_state().connections.get(&addr), Some(&1));
+            drop(server);
+            assert!(handle.network_state().connections.is_empty());
+            for _ in 0..10 {
+                let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+                drop((client.unwrap(), server.unwrap()));
+            }
+            assert!(handle.network_state().connections.is_empty());
+        });
+        assert_eq!(runtime.handle().network.pools.faults.idle(), 1);
+    }
+
+    /// Accepts two connections through `TcpListener::incoming`, returning how many succeeded.
+    async fn accept_incoming<E: Environment>(env: E, addr: net::SocketAddr) -> usize {
+        let listener = env.bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clients = env.clone();
+        env.spawn(async move {
+            let first = clients.connect(addr).await.unwrap();
+            let second = clients.connect(addr).await.unwrap();
+            clients.delay_from(std::time::Duration::from_secs(1)).await;
+            drop((first, second));
+        });
+        let accepted: Vec<_> = listener.incoming().take(2).collect().await;
+        accepted.into_iter().filter(|conn| conn.is_ok()).count()
+    }
+
+    #[test]
+    /// Test that `incoming` yields accepted connections on both the simulated and real network.
+    fn incoming() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime
+            .handle()
+            .new_cluster(crate::deterministic::FaultConfig::disabled());
+        let addr = "127.0.0.1:9092".parse().unwrap();
+        assert_eq!(runtime.block_on(accept_incoming(handle, addr)), 2);
+
+        let mut runtime = crate::singlethread::SingleThreadedRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        assert_eq!(runtime.block_on(accept_incoming(handle, addr)), 2);
+    }
+
+    #[test]
+    /// Test that listeners and connections left open by a host are reported as leaks.
+    fn leaks() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .fail_on_leaks()
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let leaked = sync::Arc::new(sync::Mutex::new(vec![]));
+        let (env, kept) = (handle.clone(), sync::Arc::clone(&leaked));
+        runtime.spawn(async move {
+            let mut listener = env.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(env.connect(addr), listener.accept());
+            kept.lock()
+                .unwrap()
+                .push((client.unwrap(), server.unwrap().0));
+        });
+        match runtime.run() {
+            Err(crate::Error::ResourceLeak { resources }) => {
+                assert_eq!(resources.len(), 1);
+                assert_eq!(resources[0].host, "localhost");
+                assert!(resources[0].listeners.is_empty());
+                assert_eq!(resources[0].connections.len(), 2);
+            }
+            other => panic!("expected a leak, got {:?}", other),
+        }
+        leaked.lock().unwrap().clear();
+        assert!(runtime.open_resources().is_empty());
+
+        let bind = handle.run_host(async { handle.bind(addr).await.unwrap() });
+        match runtime.try_block_on(bind) {
+            Err(crate::Error::Panicked { message, .. }) => {
+                assert!(message.starts_with("host localhost leaked 1 listeners"))
+            }
+            other => panic!("expected a leak, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    /// Test that connections fail once every ephemeral port is held, and ports are reused once
+    /// freed.
+    fn ephemeral_port_exhaustion() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .ephemeral_ports(50000..=50002)
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let _listener = handle.bind(addr).await.unwrap();
+            let mut clients = vec![];
+            for _ in 0..3 {
+                clients.push(handle.connect(addr).await.unwrap());
+            }
+            let ports: Vec<_> = clients.iter().map(|c| c.local_addr().port()).collect();
+            assert_eq!(ports, vec![50000, 50001, 50002]);
+            let err = handle.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+            clients.remove(1);
+            let client = handle.connect(addr).await.unwrap();
+            assert_eq!(client.local_addr().port(), 50001);
+        });
+    }
+
+    #[test]
+    /// Test that ports of connections closed by their client are held in TIME_WAIT for
+    /// reconnects to the same server, until the wait elapses.
+    fn time_wait() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig, FaultKind};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig {
+                time_wait_prob: 1.0,
+                time_wait: Duration::from_secs(10)..Duration::from_secs(20),
+                ..FaultConfig::disabled()
+            })
+            .ephemeral_ports(50000..=50001)
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let other: net::SocketAddr = "127.0.0.1:9093".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let _other_listener = handle.bind(other).await.unwrap();
+            // closed by the server first, so the port is not held.
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            drop(server.unwrap());
+            drop(client.unwrap());
+            for _ in 0..2 {
+                drop(handle.connect(addr).await.unwrap());
+            }
+            let err = handle.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+            drop(handle.connect(other).await.unwrap());
+            handle.delay_from(Duration::from_secs(20)).await;
+            handle.connect(addr).await.unwrap();
+        });
+        let held = runtime
+            .faults()
+            .iter()
+            .filter(|fault| fault.kind == FaultKind::TimeWait)
+            .count();
+        assert_eq!(held, 4);
+    }
+
+    #[test]
+    /// Test that a closed listener refuses new connections while those it accepted keep
+    /// working, and that draining completes once they are dropped.
+    fn close_and_drain() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (mut client, mut server) = (client.unwrap(), server.unwrap().0);
+            listener.close();
+            let err = handle.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(listener.accept().await.is_err());
+            let drained = listener.drained();
+            drop(listener);
+
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            let waiter = crate::spawn_with_result(&handle, drained);
+            handle.delay_from(Duration::from_secs(1)).await;
+            drop(server);
+            waiter.await;
+        });
+    }
+
+    #[test]
+    /// Test that connections accepted by a closed listener are neither disconnected nor waited
+    /// on because of a listener bound to the same port later, and that waiting to drain
+    /// registers a single waker however often it is polled.
+    fn close_then_rebind() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut first = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), first.accept());
+            let (mut client, mut server) = (client.unwrap(), server.unwrap().0);
+            first.close();
+            let ends = handle.network.inner.lock().unwrap().ends.clone();
+            let mut drained = Box::pin(first.drained());
+            for _ in 0..3 {
+                assert!(futures::poll!(drained.as_mut()).is_pending());
+            }
+            assert_eq!(ends.inner.lock().unwrap().draining.len(), 1);
+
+            let mut second = handle.bind(addr).await.unwrap();
+            let (other, _accepted) = futures::join!(handle.connect(addr), second.accept());
+            let _other = other.unwrap();
+            drop(second);
+
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            drop(server);
+            drained.await;
+            assert!(ends.inner.lock().unwrap().draining.is_empty());
+        });
+    }
+
+    #[test]
+    /// Test that both ends of a connection share its id, which is recorded in its events.
+    fn connection_ids() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        use tokio::io::AsyncWriteExt;
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            for expected in 1..=2 {
+                let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+                let (mut client, server) = (client.unwrap(), server.unwrap().0);
+                assert_eq!(client.connection_id(), ConnectionId(expected));
+                assert_eq!(server.connection_id(), ConnectionId(expected));
+                client.write_all(b"ping").await.unwrap();
+            }
+        });
+        let ids: Vec<_> = runtime
+            .handle()
+            .events()
+            .into_iter()
+            .filter_map(|logged| match logged.event {
+                SimEvent::ConnectionOpened { connection, .. }
+                | SimEvent::BytesWritten { connection, .. } => Some(connection.0),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    /// Test that the listeners of a restarted host stop accepting, and their addresses stay in
+    /// use until the drawn rebind delay has elapsed.
+    fn restart_reserves_addresses() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig, FaultKind};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig {
+                rebind_delay_prob: 1.0,
+                rebind_delay: Duration::from_secs(5)..Duration::from_secs(10),
+                ..FaultConfig::disabled()
+            })
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut old = handle.bind(addr).await.unwrap();
+            handle.restart_host();
+            assert!(old.accept().await.is_err());
+            let err = handle.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let err = handle.bind(addr).await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+            handle.delay_from(Duration::from_secs(10)).await;
+            let mut new = handle.bind(addr).await.unwrap();
+            // the listener of the killed host no longer owns the address.
+            drop(old);
+            let (client, server) = futures::join!(handle.connect(addr), new.accept());
+            client.unwrap();
+            server.unwrap();
+        });
+        let delayed = runtime
+            .faults()
+            .iter()
+            .filter(|fault| fault.kind == FaultKind::RebindDelay)
+            .count();
+        assert_eq!(delayed, 1);
+    }
+
+    #[test]
+    /// Test that randomized source ports are drawn from the seed.
+    fn source_addresses() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        fn sources(seed: u64) -> Vec<net::SocketAddr> {
+            let mut runtime = DeterministicRuntime::builder()
+                .seed(seed)
+                .fault_config(FaultConfig::disabled())
+                .randomize_ephemeral_ports()
+                .build()
+                .unwrap();
+            let handle = runtime.handle();
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            runtime.block_on(async {
+                let mut listener = handle.bind(addr).await.unwrap();
+                let mut sources = vec![];
+                for _ in 0..3 {
+                    let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+                    let (client, (_, peer)) = (client.unwrap(), server.unwrap());
+                    assert_eq!(client.local_addr(), peer);
+                    sources.push(peer);
+                }
+                sources
+            })
+        }
+        let first = sources(1);
+        assert_eq!(first, sources(1));
+        assert_ne!(first, sources(2));
+        assert!(first
+            .iter()
+            .all(|addr| addr.ip() == net::Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    /// Test that connections made from a cluster carry the address of the cluster.
+    fn cluster_source_address() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .cluster("server", FaultConfig::disabled())
+            .cluster("client", FaultConfig::disabled())
+            .link("client", "server", FaultConfig::disabled())
+            .build()
+            .unwrap();
+        let server = runtime.cluster("server").unwrap();
+        let client = runtime.cluster("client").unwrap();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = server.bind(addr).await.unwrap();
+            let (conn, accepted) = futures::join!(client.connect(addr), listener.accept());
+            let (_, peer) = accepted.unwrap();
+            assert_eq!(peer.ip(), client.local_ip());
+            assert_eq!(conn.unwrap().local_addr(), peer);
+        });
+    }
+
+    #[test]
+    /// Test that the address of a cluster reaches only that cluster, while loopback reaches the
+    /// first linked cluster with a listener on the port.
+    fn cluster_addresses() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        use crate::TcpListener;
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .cluster("first", FaultConfig::disabled())
+            .cluster("second", FaultConfig::disabled())
+            .cluster("client", FaultConfig::disabled())
+            .link("client", "first", FaultConfig::disabled())
+            .link("client", "second", FaultConfig::disabled())
+            .build()
+            .unwrap();
+        let first = runtime.cluster("first").unwrap();
+        let second = runtime.cluster("second").unwrap();
+        let client = runtime.cluster("client").unwrap();
+        runtime.block_on(async {
+            let loopback: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let first_listener = first.bind(loopback).await.unwrap();
+            let second_listener = second.bind(loopback).await.unwrap();
+            let first_addr = first_listener.local_addr().unwrap();
+            let second_addr = second_listener.local_addr().unwrap();
+            assert_eq!(first_addr, net::SocketAddr::new(first.local_ip(), 9092));
+            assert_eq!(second_addr, net::SocketAddr::new(second.local_ip(), 9092));
+            let conn = client.connect(second_addr).await.unwrap();
+            assert_eq!(conn.peer_addr(), second_addr);
+            assert_eq!(
+                second.network_state().connections.get(&second_addr),
+                Some(&1)
+            );
+            assert!(first.network_state().connections.is_empty());
+            let conn = client.connect(loopback).await.unwrap();
+            assert_eq!(conn.peer_addr(), first_addr);
+            let own = net::SocketAddr::new(client.local_ip(), 9092);
+            assert!(client.connect(own).await.is_err());
+            // a cluster listening on the port is not reached at the address of another.
+            assert_eq!(
+                first.connect(second_addr).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+        });
+    }
+
+    #[test]
+    /// Test that accepting with a timeout fails with `TimedOut` once the timeout elapses in
+    /// simulated time, and returns connections made before then.
+    fn accept_timeout() {
+        use crate::deterministic::{DeterministicRuntime, FaultConfig};
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let start = handle.now();
+            let err = listener
+                .accept_timeout(Duration::from_secs(5))
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(handle.now() - start, Duration::from_secs(5));
+            let client = handle.clone();
+            handle.spawn(async move {
+                client.delay_from(Duration::from_secs(1)).await;
+                let _ = client.connect(addr).await;
+            });
+            let accepted = handle
+                .accept_timeout(&mut listener, Duration::from_secs(5))
+                .await;
+            assert!(accepted.is_ok());
+            assert_eq!(handle.now() - start, Duration::from_secs(6));
+        });
+    }
 }
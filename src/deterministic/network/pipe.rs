@@ -1,92 +1,309 @@
-use bytes::{Buf, BytesMut, IntoBuf};
+//! In-memory byte stream between the two halves of a connection.
+//!
+//! Bytes are copied into a ring buffer shared by a `PipeReader` and `PipeWriter`. The buffer
+//! grows on demand up to `CAPACITY` and is then reused, so steady streams of writes do not
+//! allocate. `Buf` and `BufMut` types such as `Bytes` are copied to and from the buffer
+//! directly through `poll_write_buf` and `poll_read_buf`, every chunk at once. A reader waiting on an empty buffer is woken by the next write, and
+//! a writer waiting on a full buffer is woken once `WRITE_WATERMARK` bytes are free, rather than
+//! after every read.
+//!
+//! Pipes are handed out by a `Pool`, which takes back the buffer of a pipe once both halves are
+//! dropped and hands it to the next pipe, so churning short connections reuses buffers rather
+//! than allocating new ones.
+//!
+//! Buffered bytes are charged to the memory of the host reading the pipe until they are read
+//! or discarded.
+use super::super::memory::HostMemory;
+use bytes::{Buf, BufMut};
 use futures::Poll;
-use std::{io, pin::Pin, task::Context};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync,
+    task::{Context, Waker},
+};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_sync::AtomicWaker;
 
-/// In-memory AsyncRead/AsyncWrite.
+/// Number of bytes which can be written to a pipe before the writer must wait for the reader.
+pub(crate) const CAPACITY: usize = 64 * 1024;
+
+/// Number of free bytes at which a writer waiting on a full buffer is woken.
+const WRITE_WATERMARK: usize = CAPACITY / 4;
+
 #[derive(Debug)]
-pub(crate) struct Pipe {
-    buf: Option<BytesMut>,
-    waker: AtomicWaker,
-    dropped: bool,
+struct Ring {
+    buf: VecDeque<u8>,
+    /// Set once the writer has shut down. The reader returns EOF once the buffer is drained.
+    shutdown: bool,
+    /// Set once either end of the connection was reset, failing reads and writes.
+    aborted: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    /// Memory of the host reading the pipe.
+    memory: HostMemory,
+}
+
+/// Number of idle buffers a `Pool` keeps for reuse, and of idle fault states a
+/// `stream::FaultPool` keeps.
+pub(crate) const POOL_IDLE: usize = 256;
+
+impl Ring {
+    fn new() -> Self {
+        Self {
+            buf: VecDeque::new(),
+            shutdown: false,
+            aborted: false,
+            reader: None,
+            writer: None,
+            memory: HostMemory::default(),
+        }
+    }
+
+    /// Discards the buffered bytes, releasing their memory.
+    fn clear(&mut self) {
+        self.memory.free(self.buf.len());
+        self.buf.clear();
+    }
+
+    /// Returns the ring to the state of a new pipe, keeping the capacity of the buffer.
+    fn reset(&mut self) {
+        self.clear();
+        self.shutdown = false;
+        self.aborted = false;
+        self.reader = None;
+        self.writer = None;
+    }
+
+    /// Discards the buffered bytes, failing every further read and write.
+    fn abort(&mut self) {
+        self.clear();
+        self.aborted = true;
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+        if let Some(writer) = self.writer.take() {
+            writer.wake();
+        }
+    }
+}
+
+/// Returns the reading and writing halves of a new pipe which is not pooled.
+#[cfg(test)]
+pub(crate) fn pipe() -> (PipeReader, PipeWriter) {
+    Pool::default().pipe(&HostMemory::default())
+}
+
+/// Buffers of closed pipes, waiting to be reused by new pipes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pool {
+    idle: sync::Arc<sync::Mutex<Vec<sync::Arc<sync::Mutex<Ring>>>>>,
 }
 
-impl Drop for Pipe {
+impl Pool {
+    /// Returns the reading and writing halves of a pipe, reusing an idle buffer if there is one.
+    /// Bytes buffered by the pipe are charged to `memory`.
+    pub(crate) fn pipe(&self, memory: &HostMemory) -> (PipeReader, PipeWriter) {
+        let ring = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| sync::Arc::new(sync::Mutex::new(Ring::new())));
+        ring.lock().unwrap().memory = memory.clone();
+        (
+            PipeReader {
+                ring: sync::Arc::clone(&ring),
+                pool: self.clone(),
+            },
+            PipeWriter {
+                ring,
+                pool: self.clone(),
+            },
+        )
+    }
+
+    /// Called as either half of a pipe is dropped, taking back `ring` if the other half is gone.
+    fn recycle(&self, ring: &sync::Arc<sync::Mutex<Ring>>) {
+        if sync::Arc::strong_count(ring) != 1 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < POOL_IDLE {
+            ring.lock().unwrap().reset();
+            idle.push(sync::Arc::clone(ring));
+        }
+    }
+}
+
+/// Reading half of a pipe.
+#[derive(Debug)]
+pub(crate) struct PipeReader {
+    ring: sync::Arc<sync::Mutex<Ring>>,
+    pool: Pool,
+}
+
+impl Drop for PipeReader {
     fn drop(&mut self) {
-        self.dropped = true;
-        self.waker.wake()
+        self.pool.recycle(&self.ring);
     }
 }
 
-impl Pipe {
-    pub(crate) fn new() -> Self {
-        Self {
-            buf: None,
-            waker: AtomicWaker::new(),
-            dropped: false,
+/// Writing half of a pipe.
+#[derive(Debug)]
+pub(crate) struct PipeWriter {
+    ring: sync::Arc<sync::Mutex<Ring>>,
+    pool: Pool,
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pool.recycle(&self.ring);
+    }
+}
+
+impl PipeReader {
+    /// Returns true once the writer has been shut down or dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        sync::Arc::strong_count(&self.ring) == 1 || self.ring.lock().unwrap().shutdown
+    }
+
+    pub(crate) fn abort(&self) {
+        self.ring.lock().unwrap().abort();
+    }
+
+    /// Reads buffered bytes directly into `dst`.
+    fn poll_read_into<B: BufMut>(
+        &self,
+        cx: &mut Context<'_>,
+        dst: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        let mut ring = self.ring.lock().unwrap();
+        if ring.aborted {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if ring.buf.is_empty() {
+            if ring.shutdown {
+                return Poll::Ready(Ok(0));
+            }
+            ring.reader = Some(cx.waker().clone());
+            return Poll::Pending;
         }
+        let amt = std::cmp::min(ring.buf.len(), dst.remaining_mut());
+        let (front, back) = ring.buf.as_slices();
+        let from_front = std::cmp::min(front.len(), amt);
+        dst.put_slice(&front[..from_front]);
+        dst.put_slice(&back[..amt - from_front]);
+        ring.buf.drain(..amt);
+        ring.memory.free(amt);
+        if CAPACITY - ring.buf.len() >= WRITE_WATERMARK {
+            if let Some(writer) = ring.writer.take() {
+                writer.wake();
+            }
+        }
+        Poll::Ready(Ok(amt))
     }
 }
 
-impl AsyncRead for Pipe {
+impl AsyncRead for PipeReader {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         dst: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        if self.dropped {
+        self.poll_read_into(cx, &mut io::Cursor::new(dst))
+    }
+    fn poll_read_buf<B: BufMut>(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        if !buf.has_remaining_mut() {
             return Poll::Ready(Ok(0));
         }
+        self.poll_read_into(cx, buf)
+    }
+}
 
-        if let Some(mut bytes) = self.buf.take() {
-            debug_assert!(!bytes.is_empty());
-            let amt = std::cmp::min(bytes.len(), dst.len());
-            let b = bytes.split_to(amt).freeze();
-            let mut b = b.into_buf();
-            b.copy_to_slice(&mut dst[..amt]);
-            if bytes.is_empty() {
-                self.buf.take();
-            } else {
-                self.buf.replace(bytes);
-            }
-            self.waker.wake();
-            Poll::Ready(Ok(amt))
-        } else {
-            self.waker.register_by_ref(cx.waker());
-            Poll::Pending
+impl PipeWriter {
+    /// Returns the number of bytes written which have not yet been read.
+    pub(crate) fn buffered(&self) -> usize {
+        self.ring.lock().unwrap().buf.len()
+    }
+
+    /// Shuts down the pipe, so the reader returns EOF once the buffer is drained.
+    pub(crate) fn close(&self) {
+        let mut ring = self.ring.lock().unwrap();
+        ring.shutdown = true;
+        if let Some(reader) = ring.reader.take() {
+            reader.wake();
+        }
+    }
+
+    pub(crate) fn abort(&self) {
+        self.ring.lock().unwrap().abort();
+    }
+
+    /// Copies as many bytes of `src` into the buffer as fit, across every chunk of `src`.
+    fn poll_write_from<B: Buf>(
+        &self,
+        cx: &mut Context<'_>,
+        src: &mut B,
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut ring = self.ring.lock().unwrap();
+        if ring.aborted {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if ring.shutdown {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if ring.buf.len() == CAPACITY {
+            ring.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let mut written = 0;
+        while src.has_remaining() && ring.buf.len() < CAPACITY {
+            let chunk = src.bytes();
+            let amt = std::cmp::min(CAPACITY - ring.buf.len(), chunk.len());
+            ring.buf.extend(&chunk[..amt]);
+            ring.memory.allocate(amt);
+            src.advance(amt);
+            written += amt;
         }
+        if let Some(reader) = ring.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(written))
     }
 }
 
-impl AsyncWrite for Pipe {
+impl AsyncWrite for PipeWriter {
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        if self.dropped {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
-        }
-        if self.buf.is_some() {
-            self.waker.register_by_ref(cx.waker());
-            Poll::Pending
-        } else {
-            self.buf = Some(buf.into());
-            self.waker.wake();
-            Poll::Ready(Ok(buf.len()))
+        self.poll_write_from(cx, &mut io::Cursor::new(buf))
+    }
+    fn poll_write_buf<B: Buf>(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<Result<usize, io::Error>> {
+        if !buf.has_remaining() {
+            return Poll::Ready(Ok(0));
         }
+        self.poll_write_from(cx, buf)
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        if self.dropped {
+        if self.ring.lock().unwrap().shutdown {
             return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
         }
         Poll::Ready(Ok(()))
     }
-    fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        self.dropped = true;
-        self.waker.wake();
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.close();
         Poll::Ready(Ok(()))
     }
 }
@@ -103,8 +320,7 @@ mod tests {
         let mut runtime = crate::deterministic::DeterministicRuntime::new_with_seed(3).unwrap();
         let handle = runtime.handle();
         runtime.block_on(async {
-            let rw = Pipe::new();
-            let (mut r, mut w) = tokio::io::split(rw);
+            let (mut r, mut w) = pipe();
             handle.spawn(async move {
                 w.write_all(b"foo").await.unwrap();
                 w.write_all(b"bar").await.unwrap();
@@ -116,22 +332,72 @@ mod tests {
         })
     }
 
+    #[test]
+    /// Tests that writes larger than the capacity of a pipe are transported across wrap-around.
+    fn ring_wraps() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let (mut r, mut w) = pipe();
+        runtime.block_on(async {
+            let sent: Vec<u8> = (0..CAPACITY * 3 + 7).map(|i| i as u8).collect();
+            let expected = sent.clone();
+            handle.spawn(async move {
+                w.write_all(&sent).await.unwrap();
+                w.shutdown().await.unwrap();
+            });
+            let mut received = vec![];
+            let mut chunk = vec![0; CAPACITY / 3];
+            loop {
+                match r.read(&mut chunk).await.unwrap() {
+                    0 => break,
+                    n => received.extend_from_slice(&chunk[..n]),
+                }
+            }
+            assert_eq!(received, expected);
+        })
+    }
+
+    #[test]
+    /// Tests that the buffer of a closed pipe is reused, emptied, by the next pipe of a pool.
+    fn pool_reuses_buffers() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let pool = Pool::default();
+        let (r, mut w) = pool.pipe(&HostMemory::default());
+        runtime.block_on(async {
+            w.write_all(b"foo").await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+        drop(w);
+        assert!(pool.idle.lock().unwrap().is_empty(), "reader is still open");
+        drop(r);
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        let (mut r, mut w) = pool.pipe(&HostMemory::default());
+        assert!(pool.idle.lock().unwrap().is_empty());
+        assert!(r.ring.lock().unwrap().buf.capacity() > 0);
+        runtime.block_on(async {
+            w.write_all(b"bar").await.unwrap();
+            let mut target = [0; 3];
+            r.read_exact(&mut target).await.unwrap();
+            assert_eq!(&target, b"bar");
+        });
+    }
+
     #[test]
     /// When a pipe is shutdown, the write side should return an error on subsequent writes, and the read
     /// side should always return Ok(0).
     /// TODO: Check if this logic matches TcpStream
     fn shutdown_pipe() {
         let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let rw = Pipe::new();
+        let (mut r, mut w) = pipe();
         runtime.block_on(async {
-            let (mut r, mut w) = tokio::io::split(rw);
-            w.write(b"foo").await.unwrap();
+            w.write_all(b"foo").await.unwrap();
             w.shutdown().await.unwrap();
             assert!(
                 w.write_all(b"foo").await.is_err(),
                 "expected write to fail after shutdown"
             );
-            let mut target = vec![0; 0];
+            let mut target = [0; 0];
             assert_eq!(
                 r.read(&mut target[..]).await.unwrap(),
                 0,
@@ -1,8 +1,16 @@
 //! InMemory TCPStream-like connection between a server and a client.
 //! Supports injecting delay or disconnect faults specific to the client or server
 //! side of a connection.
+use crate::deterministic::{
+    context,
+    fault::StreamKey,
+    task::{self, Blocker},
+    QueueKind, SimEvent,
+};
+use bytes::{Buf, BufMut};
 use futures::{FutureExt, Poll};
-use std::{io, net, pin::Pin, sync, task::Context};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, net, pin::Pin, sync, task::Context};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_sync::AtomicWaker;
 
@@ -12,24 +20,71 @@ use tokio_sync::AtomicWaker;
 #[derive(Debug)]
 pub struct MemoryStream {
     fault_injector: MemoryStreamFaultInjectorHandle,
-    reader: tokio_io::split::ReadHalf<super::Pipe>,
-    writer: tokio_io::split::WriteHalf<super::Pipe>,
+    reader: super::pipe::PipeReader,
+    writer: super::pipe::PipeWriter,
     local_addr: net::SocketAddr,
     peer_addr: net::SocketAddr,
+    /// Open ends of the cluster owning this end, which it is removed from once dropped.
+    ends: super::OpenEnds,
+    /// Ephemeral port held by the client end, freed once dropped.
+    port: Option<super::EphemeralPort>,
+    /// Whether data not yet read by the peer is delivered once dropped.
+    linger: Linger,
+    /// Id of the connection, shared by both ends.
+    id: ConnectionId,
+    /// Id of the listener which accepted the connection, if this is its server end.
+    listener: Option<u64>,
+    /// Registry of the cluster which accepted the connection, which it is removed from once
+    /// both ends are dropped.
+    connections: super::Connections,
+    /// Pool the fault state of the connection is returned to once both ends are dropped.
+    faults: FaultPool,
+}
+
+/// Identifies a connection between simulated hosts, shared by both of its ends and recorded in
+/// the events of the connection. Connections are numbered from 1 in the order they are made,
+/// so the same connection has the same id every time a seed is repeated, and the events logged
+/// by the hosts at either end of a connection can be matched up by it.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn-{}", self.0)
+    }
+}
+
+/// What happens to the bytes written to a connection but not yet read by the peer when an end
+/// is dropped, set with `Builder::linger` or `MemoryStream::set_linger`. Flushing can hide a
+/// missing shutdown handshake, while resetting can hide a peer which never reads a final
+/// response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Linger {
+    /// The peer reads the buffered bytes and then EOF, as when a socket is closed without
+    /// `SO_LINGER`.
+    #[default]
+    Flush,
+    /// The buffered bytes are discarded and reads and writes of the peer fail with
+    /// `ConnectionReset`, as when a socket with a zero `SO_LINGER` timeout is closed.
+    Reset,
 }
 
 /// Wraps a FaultInjector to provide connection specific fault injection.
 #[derive(Debug)]
 struct MemoryStreamFaultInjector {
-    /// Designates the types of fault errors which this fault injector will return.
-    mode: Mode,
+    /// Delays of reads of the MemoryStream.
+    read: Delays,
 
-    /// Active delay faults are stored here. If there is an active delay, all writes and reads
-    /// to the MemoryStream will be paused until this delay elapses.
-    delay: Option<tokio_timer::Delay>,
+    /// Delays of writes to the MemoryStream.
+    write: Delays,
 
-    /// Wrapped fault injector, used to query for delay faults.
-    fault_injector: crate::deterministic::FaultInjectorHandle,
+    /// Identifies the reads of this side of the connection to the fault injector.
+    key: StreamKey,
+
+    /// Identifies the writes of this side of the connection to the fault injector.
+    write_key: StreamKey,
 
     /// Disconnected fault injectors will return an appropriate disconnected error on calls to `poll_disconnected`,
     /// determined by the `Mode`.
@@ -38,9 +93,131 @@ struct MemoryStreamFaultInjector {
     /// Waker to awake yielded tasks when a disconnect is triggered.
     waker: AtomicWaker,
 }
+
+impl MemoryStreamFaultInjector {
+    fn new(key: StreamKey, write_key: StreamKey) -> Self {
+        Self {
+            read: Delays::default(),
+            write: Delays::default(),
+            key,
+            write_key,
+            disconnected: false,
+            waker: AtomicWaker::new(),
+        }
+    }
+}
+
+/// Fault state of both sides of a connection, shared by its ends and the registry of the
+/// cluster which accepted it.
+#[derive(Debug)]
+struct ConnectionFaults {
+    client: MemoryStreamFaultInjector,
+    server: MemoryStreamFaultInjector,
+
+    /// Wrapped fault injector, used to query for delay faults.
+    fault_injector: crate::deterministic::FaultInjectorHandle,
+
+    /// Client and server addresses of the connection, recorded with injected faults.
+    addrs: (net::SocketAddr, net::SocketAddr),
+
+    /// Port of the listener the connection was made to, and the id of the connection, which
+    /// it is registered under once accepted into the backlog.
+    key: (std::num::NonZeroU16, ConnectionId),
+
+    /// Number of ends of the connection which have not been dropped.
+    open: usize,
+}
+
+impl ConnectionFaults {
+    fn side(&mut self, mode: Mode) -> &mut MemoryStreamFaultInjector {
+        match mode {
+            Mode::Client => &mut self.client,
+            Mode::Server => &mut self.server,
+        }
+    }
+}
+
+/// Fault state of closed connections, waiting to be reused by new connections.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FaultPool {
+    idle: sync::Arc<sync::Mutex<Vec<sync::Arc<sync::Mutex<ConnectionFaults>>>>>,
+}
+
+impl FaultPool {
+    /// Returns `faults` behind a shared lock, reusing an idle allocation if there is one.
+    fn get(&self, faults: ConnectionFaults) -> sync::Arc<sync::Mutex<ConnectionFaults>> {
+        match self.idle.lock().unwrap().pop() {
+            Some(idle) => {
+                *idle.lock().unwrap() = faults;
+                idle
+            }
+            None => sync::Arc::new(sync::Mutex::new(faults)),
+        }
+    }
+
+    /// Called as either end of a connection is dropped, taking back `faults` if nothing else
+    /// refers to them.
+    fn recycle(&self, faults: &sync::Arc<sync::Mutex<ConnectionFaults>>) {
+        if sync::Arc::strong_count(faults) != 1 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < super::pipe::POOL_IDLE {
+            idle.push(sync::Arc::clone(faults));
+        }
+    }
+
+    /// Returns the number of idle allocations waiting to be reused.
+    #[cfg(test)]
+    pub(crate) fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/// Allocations reused by the connections of a network.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pools {
+    pub(crate) pipes: super::pipe::Pool,
+    pub(crate) faults: FaultPool,
+}
+
+/// Delay faults of one direction of a MemoryStream.
+#[derive(Debug, Default)]
+struct Delays {
+    /// The active delay fault, if any. Operations in this direction are paused until it
+    /// elapses.
+    delay: Option<tokio_timer::Delay>,
+
+    /// Number of operations which the fault injector has decided not to delay, allowing them
+    /// to proceed without consulting it.
+    quiet: u64,
+}
+
+impl Delays {
+    /// Polls the active delay if any. Otherwise returns `Poll::Ready(())`, consulting `next`
+    /// for the delay of the next operation once the quiet operations have been used up.
+    fn poll<F>(&mut self, cx: &mut Context<'_>, next: F) -> Poll<()>
+    where
+        F: FnOnce() -> (Option<tokio_timer::Delay>, u64),
+    {
+        if let Some(mut delay) = self.delay.take() {
+            if delay.poll_unpin(cx).is_pending() {
+                self.delay.replace(delay);
+                return Poll::Pending;
+            }
+        } else if self.quiet > 0 {
+            self.quiet -= 1;
+        } else {
+            let (delay, quiet) = next();
+            self.delay = delay;
+            self.quiet = quiet;
+        }
+        Poll::Ready(())
+    }
+}
 /// Mode determines which types of errors to return upon polling a memory stream
 /// with a fault injected. There are different types of errors for clients and servers.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum Mode {
     Client,
     Server,
@@ -52,45 +229,91 @@ enum Mode {
 /// This fault injector allows injecting faults specific to the client or server side of a connection.
 #[derive(Debug, Clone)]
 pub(crate) struct MemoryConnectionFaultInjector {
-    client: MemoryStreamFaultInjectorHandle,
-    server: MemoryStreamFaultInjectorHandle,
+    inner: sync::Arc<sync::Mutex<ConnectionFaults>>,
+    /// Client and server addresses of the connection.
+    addrs: (net::SocketAddr, net::SocketAddr),
+    /// Id of the connection.
+    id: ConnectionId,
 }
 
 impl MemoryConnectionFaultInjector {
-    /// Returns a new `MemoryConnectionFaultInjector` wrapping the provided `FaultInjectorHandle`.
+    /// Returns a new `MemoryConnectionFaultInjector` wrapping the provided `FaultInjectorHandle`,
+    /// reusing fault state from `pool`.
     ///
     /// [`FaultInjectorHandle`]:crate::next::FaultInjectorHandle
     /// [`MemoryConnectionFaultInjector`]:MemoryConnectionFaultInjector
-    fn new(fault_injector: super::super::FaultInjectorHandle) -> Self {
-        let client = MemoryStreamFaultInjectorHandle::new_with_fault_injector(
-            fault_injector.clone(),
-            Mode::Client,
-        );
-        let server =
-            MemoryStreamFaultInjectorHandle::new_with_fault_injector(fault_injector, Mode::Server);
-        Self { client, server }
+    fn new(
+        pool: &FaultPool,
+        fault_injector: super::super::FaultInjectorHandle,
+        (port, connection, id): (std::num::NonZeroU16, u64, ConnectionId),
+        addrs: (net::SocketAddr, net::SocketAddr),
+    ) -> Self {
+        let port_number = port.get();
+        let side = |server| {
+            MemoryStreamFaultInjector::new(
+                StreamKey::Socket {
+                    port: port_number,
+                    connection,
+                    server,
+                },
+                StreamKey::SocketWrite {
+                    port: port_number,
+                    connection,
+                    server,
+                },
+            )
+        };
+        let faults = ConnectionFaults {
+            client: side(false),
+            server: side(true),
+            fault_injector,
+            addrs,
+            key: (port, id),
+            open: 2,
+        };
+        Self {
+            inner: pool.get(faults),
+            addrs,
+            id,
+        }
+    }
+
+    /// Returns the client and server addresses of the connection.
+    pub(crate) fn addrs(&self) -> (net::SocketAddr, net::SocketAddr) {
+        self.addrs
+    }
+
+    /// Returns the id of the connection.
+    pub(crate) fn id(&self) -> ConnectionId {
+        self.id
     }
 
     /// Returns a handle to the fault injector corresponding to the client side of a MemoryConnection.
     /// This handle can be used to inject faults into client writes and server reads.
     fn client_handle(&self) -> MemoryStreamFaultInjectorHandle {
-        self.client.clone()
+        MemoryStreamFaultInjectorHandle {
+            inner: sync::Arc::clone(&self.inner),
+            mode: Mode::Client,
+        }
     }
 
     /// Returns a handle to the fault injector corresponding to the server side of a MemoryConnection.    
     /// This handle can be used to inject faults into server writes and client reads.
     fn server_handle(&self) -> MemoryStreamFaultInjectorHandle {
-        self.server.clone()
+        MemoryStreamFaultInjectorHandle {
+            inner: sync::Arc::clone(&self.inner),
+            mode: Mode::Server,
+        }
     }
 
     /// Disconnects the client, further client writes or server reads will return an error.
     pub(crate) fn disconnect_client(&self) {
-        self.client.set_disconnected();
+        self.client_handle().set_disconnected();
     }
 
     /// Disconnects the server, futher server writes or client reads will return an error.
     pub(crate) fn disconnect_server(&self) {
-        self.server.set_disconnected();
+        self.server_handle().set_disconnected();
     }
 
     /// Disconnects both the server and the client. Further reads and writes will return an error.
@@ -100,68 +323,87 @@ impl MemoryConnectionFaultInjector {
     }
 }
 
-/// Handle to a `MemoryStreamFaultInjector`.
+/// Handle to one side of the fault state of a connection.
 /// Supports both probability based delay faults as well as injecting disconnects.
 #[derive(Debug, Clone)]
 struct MemoryStreamFaultInjectorHandle {
-    inner: sync::Arc<sync::Mutex<MemoryStreamFaultInjector>>,
+    inner: sync::Arc<sync::Mutex<ConnectionFaults>>,
+
+    /// Designates the side of the connection, and the types of fault errors which this handle
+    /// will return.
+    mode: Mode,
 }
 
 impl MemoryStreamFaultInjectorHandle {
-    /// Returns a new `MemoryStreamFaultInjectorHandle` which can be used to track delay faults and inject
-    /// disconnects.
-    fn new_with_fault_injector(
-        fault_injector: super::super::FaultInjectorHandle,
-        mode: Mode,
-    ) -> Self {
-        let state = MemoryStreamFaultInjector {
-            mode,
-            delay: None,
-            fault_injector,
-            disconnected: false,
-            waker: AtomicWaker::new(),
-        };
-        let state = sync::Arc::new(sync::Mutex::new(state));
-        Self { inner: state }
+    /// Decides whether the port of the client end is held in TIME_WAIT as it is closed,
+    /// returning the instant until which it is held.
+    fn time_wait(&self) -> Option<std::time::Instant> {
+        let lock = self.inner.lock().unwrap();
+        lock.fault_injector.time_wait(lock.addrs)
     }
 
-    /// Poll any existing delay faults. If there is no existing delay faults, this method will return Poll::Ready(()) and attempt
-    /// to get one from the wrapped fault injector for the next call to `poll_delay`.
-    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+    /// Records that an end of the connection was dropped, returning the port and id the
+    /// connection is registered under once both ends are.
+    fn close(&self) -> Option<(std::num::NonZeroU16, ConnectionId)> {
         let mut lock = self.inner.lock().unwrap();
-        if let Some(mut delay) = lock.delay.take() {
-            if let Poll::Pending = delay.poll_unpin(cx) {
-                lock.delay.replace(delay);
-                Poll::Pending
-            } else {
-                Poll::Ready(())
-            }
+        lock.open -= 1;
+        if lock.open == 0 {
+            Some(lock.key)
         } else {
-            let new = lock.fault_injector.socket_read_delay();
-            std::mem::replace(&mut lock.delay, new);
-            Poll::Ready(())
+            None
         }
     }
 
+    /// Poll any existing read delay fault. If there is no existing delay fault, this method will
+    /// return Poll::Ready(()) and attempt to get one from the wrapped fault injector for the
+    /// next call to `poll_read_delay`.
+    fn poll_read_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let lock = &mut *self.inner.lock().unwrap();
+        let (fault_injector, addrs) = (&lock.fault_injector, lock.addrs);
+        let side = match self.mode {
+            Mode::Client => &mut lock.client,
+            Mode::Server => &mut lock.server,
+        };
+        let key = side.key;
+        side.read
+            .poll(cx, || fault_injector.socket_read_delay(key, addrs))
+    }
+
+    /// Poll any existing write delay fault, drawing the delay of the next write as
+    /// `poll_read_delay` does for reads.
+    fn poll_write_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let lock = &mut *self.inner.lock().unwrap();
+        let (fault_injector, addrs) = (&lock.fault_injector, lock.addrs);
+        let side = match self.mode {
+            Mode::Client => &mut lock.client,
+            Mode::Server => &mut lock.server,
+        };
+        let key = side.write_key;
+        side.write
+            .poll(cx, || fault_injector.socket_write_delay(key, addrs))
+    }
+
     /// Poll for an injected disconnect fault. Calls to `poll_disconnected` will register a waker
     /// in order to respond to externally injected disconnects.
     fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<io::Error> {
-        let lock = self.inner.lock().unwrap();
-        if lock.disconnected {
-            match lock.mode {
+        let mut lock = self.inner.lock().unwrap();
+        let side = lock.side(self.mode);
+        if side.disconnected {
+            match self.mode {
                 Mode::Client => return Poll::Ready(io::ErrorKind::ConnectionAborted.into()),
                 Mode::Server => return Poll::Ready(io::ErrorKind::ConnectionAborted.into()),
             }
         }
-        lock.waker.register_by_ref(cx.waker());
+        side.waker.register_by_ref(cx.waker());
         Poll::Pending
     }
 
     /// Sets this fault injector to signal on `poll_disconnected`, waking the registered task.
     fn set_disconnected(&self) {
         let mut lock = self.inner.lock().unwrap();
-        lock.disconnected = true;
-        lock.waker.wake();
+        let side = lock.side(self.mode);
+        side.disconnected = true;
+        side.waker.wake();
     }
 }
 
@@ -184,54 +426,117 @@ impl crate::TcpStream for MemoryStream {
     }
 }
 
-/// Returns a new in-memory connection between a server and a client.
+/// Clusters owning the two ends of a new connection.
+#[derive(Debug)]
+pub(crate) struct Endpoints {
+    /// Id of the new connection.
+    pub(crate) id: ConnectionId,
+    /// Id of the listener the connection is made to.
+    pub(crate) listener: u64,
+    /// Address of the hosts of the client cluster.
+    pub(crate) client_ip: net::IpAddr,
+    /// Ephemeral port of the client cluster, held by the client end.
+    pub(crate) client_port: super::EphemeralPort,
+    /// Address of the listener, on the hosts of the server cluster.
+    pub(crate) server: net::SocketAddr,
+    /// Open ends of the client cluster.
+    pub(crate) client_ends: super::OpenEnds,
+    /// Open ends of the server cluster.
+    pub(crate) server_ends: super::OpenEnds,
+    /// Memory of the client cluster, charged with the bytes waiting to be read by the client.
+    pub(crate) client_memory: super::super::memory::HostMemory,
+    /// Memory of the server cluster, charged with the bytes waiting to be read by the server.
+    pub(crate) server_memory: super::super::memory::HostMemory,
+    /// Behavior of both ends when dropped.
+    pub(crate) linger: Linger,
+    /// Registry of the server cluster, which the connection is added to once accepted into
+    /// the backlog of the listener.
+    pub(crate) connections: super::Connections,
+}
+
+/// Returns a new in-memory connection between a server and a client, with buffers and fault
+/// state from `pools`. `connection` counts the connections previously made to `port`,
+/// identifying the connection to the fault injector.
 pub(crate) fn new_pair(
+    pools: &Pools,
     fault_injector: super::super::FaultInjectorHandle,
     port: std::num::NonZeroU16,
+    connection: u64,
+    endpoints: Endpoints,
 ) -> (
     MemoryConnectionFaultInjector,
     ClientConnection,
     ServerConnection,
 ) {
-    let client_addr = net::SocketAddr::new(net::Ipv4Addr::LOCALHOST.into(), 0);
-    let server_addr = net::SocketAddr::new(net::Ipv4Addr::LOCALHOST.into(), port.get());
-
-    let client_pipe = super::Pipe::new();
-    let server_pipe = super::Pipe::new();
-    let (client_rx, client_tx) = tokio::io::split(client_pipe);
-    let (server_rx, server_tx) = tokio::io::split(server_pipe);
-    let fault_injector = MemoryConnectionFaultInjector::new(fault_injector);
+    let client_addr = net::SocketAddr::new(endpoints.client_ip, endpoints.client_port.port());
+    let server_addr = endpoints.server;
+
+    let (client_rx, client_tx) = pools.pipes.pipe(&endpoints.server_memory);
+    let (server_rx, server_tx) = pools.pipes.pipe(&endpoints.client_memory);
+    let fault_injector = MemoryConnectionFaultInjector::new(
+        &pools.faults,
+        fault_injector,
+        (port, connection, endpoints.id),
+        (client_addr, server_addr),
+    );
     let server_stream = MemoryStream::new(
-        fault_injector.server_handle(),
+        (
+            fault_injector.server_handle(),
+            endpoints.connections.clone(),
+            pools.faults.clone(),
+        ),
         client_rx,
         server_tx,
-        server_addr,
-        client_addr,
+        (server_addr, client_addr),
+        (endpoints.server_ends, Some(endpoints.listener)),
+        endpoints.linger,
+        endpoints.id,
     );
-    let client_stream = MemoryStream::new(
-        fault_injector.client_handle(),
+    let mut client_stream = MemoryStream::new(
+        (
+            fault_injector.client_handle(),
+            endpoints.connections,
+            pools.faults.clone(),
+        ),
         server_rx,
         client_tx,
-        client_addr,
-        server_addr,
+        (client_addr, server_addr),
+        (endpoints.client_ends, None),
+        endpoints.linger,
+        endpoints.id,
     );
+    client_stream.port = Some(endpoints.client_port);
     (fault_injector, client_stream, server_stream)
 }
 
 impl MemoryStream {
     fn new(
-        fault_injector: MemoryStreamFaultInjectorHandle,
-        reader: tokio_io::split::ReadHalf<super::Pipe>,
-        writer: tokio_io::split::WriteHalf<super::Pipe>,
-        local_addr: net::SocketAddr,
-        peer_addr: net::SocketAddr,
+        (fault_injector, connections, faults): (
+            MemoryStreamFaultInjectorHandle,
+            super::Connections,
+            FaultPool,
+        ),
+        reader: super::pipe::PipeReader,
+        writer: super::pipe::PipeWriter,
+        (local_addr, peer_addr): (net::SocketAddr, net::SocketAddr),
+        (ends, listener): (super::OpenEnds, Option<u64>),
+        linger: Linger,
+        id: ConnectionId,
     ) -> Self {
+        ends.open(local_addr, peer_addr, listener);
         MemoryStream {
             fault_injector,
             reader,
             writer,
             local_addr,
             peer_addr,
+            ends,
+            port: None,
+            linger,
+            id,
+            listener,
+            connections,
+            faults,
         }
     }
 
@@ -239,40 +544,188 @@ impl MemoryStream {
         self.local_addr
     }
     pub fn peer_addr(&self) -> net::SocketAddr {
-        self.local_addr
+        self.peer_addr
+    }
+
+    /// Returns the id of the connection, which is the same at both ends.
+    pub fn connection_id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Sets what happens to data the peer has not read once this end is dropped, overriding
+    /// the `Linger` the runtime was built with.
+    pub fn set_linger(&mut self, linger: Linger) {
+        self.linger = linger;
     }
 }
 
-impl AsyncRead for MemoryStream {
-    fn poll_read(
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.ends
+            .close(self.local_addr, self.peer_addr, self.listener);
+        context::record(SimEvent::ConnectionClosed {
+            from: self.local_addr,
+            to: self.peer_addr,
+            connection: self.id,
+        });
+        // the end closing first holds its port in TIME_WAIT, which only client ends have.
+        if let Some(port) = self.port.take() {
+            if !self.reader.is_closed() {
+                if let Some(until) = self.fault_injector.time_wait() {
+                    port.time_wait(self.peer_addr, until);
+                }
+            }
+        }
+        match self.linger {
+            Linger::Flush => self.writer.close(),
+            Linger::Reset => {
+                self.writer.abort();
+                self.reader.abort();
+            }
+        }
+        if let Some((port, id)) = self.fault_injector.close() {
+            self.connections.remove(port, id);
+            self.faults.recycle(&self.fault_injector.inner);
+        }
+    }
+}
+
+/// Direction of a connection, from the local to the peer address.
+struct Direction(net::SocketAddr, net::SocketAddr);
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.0, self.1)
+    }
+}
+
+impl MemoryStream {
+    /// Records that `written` bytes were written to the peer, checking the bytes buffered
+    /// against the socket buffer watermark.
+    fn wrote(&self, written: usize) {
+        context::record(SimEvent::BytesWritten {
+            from: self.local_addr,
+            to: self.peer_addr,
+            bytes: written,
+            connection: self.id,
+        });
+        let buffered = self.writer.buffered();
+        context::watermark(
+            QueueKind::SocketBuffer,
+            &Direction(self.local_addr, self.peer_addr),
+            buffered.saturating_sub(written),
+            buffered,
+        );
+    }
+
+    /// Records the current task as waiting on this connection if `poll` is pending.
+    fn blocked<T>(&self, poll: Poll<T>, write: bool) -> Poll<T> {
+        if poll.is_pending() {
+            let (local, peer) = (self.local_addr, self.peer_addr);
+            task::set_blocker(if write {
+                Blocker::Write { local, peer }
+            } else {
+                Blocker::Read { local, peer }
+            });
+        }
+        poll
+    }
+
+    fn poll_read_pipe(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        futures::ready!(self.as_mut().fault_injector.poll_delay(cx));
+        futures::ready!(self.as_mut().fault_injector.poll_read_delay(cx));
         if let Poll::Ready(e) = self.as_ref().fault_injector.poll_disconnected(cx) {
             return Poll::Ready(Err(e));
         }
         let reader = Pin::new(&mut self.reader);
         reader.poll_read(cx, buf)
     }
-}
+    fn poll_read_buf_pipe<B: BufMut>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.as_mut().fault_injector.poll_read_delay(cx));
+        if let Poll::Ready(e) = self.as_ref().fault_injector.poll_disconnected(cx) {
+            return Poll::Ready(Err(e));
+        }
+        let reader = Pin::new(&mut self.reader);
+        reader.poll_read_buf(cx, buf)
+    }
 
-impl AsyncWrite for MemoryStream {
-    fn poll_write(
+    fn poll_write_pipe(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        futures::ready!(self.as_mut().fault_injector.poll_delay(cx));
+        futures::ready!(self.as_mut().fault_injector.poll_write_delay(cx));
         if let Poll::Ready(e) = self.as_ref().fault_injector.poll_disconnected(cx) {
             return Poll::Ready(Err(e));
         }
         let writer = Pin::new(&mut self.writer);
-        writer.poll_write(cx, buf)
+        let written = futures::ready!(writer.poll_write(cx, buf))?;
+        self.wrote(written);
+        Poll::Ready(Ok(written))
+    }
+    fn poll_write_buf_pipe<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<Result<usize, io::Error>> {
+        futures::ready!(self.as_mut().fault_injector.poll_write_delay(cx));
+        if let Poll::Ready(e) = self.as_ref().fault_injector.poll_disconnected(cx) {
+            return Poll::Ready(Err(e));
+        }
+        let writer = Pin::new(&mut self.writer);
+        let written = futures::ready!(writer.poll_write_buf(cx, buf))?;
+        self.wrote(written);
+        Poll::Ready(Ok(written))
+    }
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.as_mut().poll_read_pipe(cx, buf);
+        self.blocked(poll, false)
+    }
+    fn poll_read_buf<B: BufMut>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.as_mut().poll_read_buf_pipe(cx, buf);
+        self.blocked(poll, false)
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let poll = self.as_mut().poll_write_pipe(cx, buf);
+        self.blocked(poll, true)
+    }
+    /// Writes every chunk of `buf` which fits, so `Bytes` and chained buffers produced by codecs
+    /// are copied once, directly into the connection.
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<Result<usize, io::Error>> {
+        let poll = self.as_mut().poll_write_buf_pipe(cx, buf);
+        self.blocked(poll, true)
     }
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        futures::ready!(self.as_mut().fault_injector.poll_delay(cx));
+        futures::ready!(self.as_mut().fault_injector.poll_write_delay(cx));
         if let Poll::Ready(e) = self.as_ref().fault_injector.poll_disconnected(cx) {
             return Poll::Ready(Err(e));
         }
@@ -283,7 +736,7 @@ impl AsyncWrite for MemoryStream {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        futures::ready!(self.as_mut().fault_injector.poll_delay(cx));
+        futures::ready!(self.as_mut().fault_injector.poll_write_delay(cx));
         let writer = Pin::new(&mut self.writer);
         writer.poll_shutdown(cx)
     }
@@ -296,6 +749,26 @@ mod tests {
     use futures::{SinkExt, StreamExt};
     use tokio::io::AsyncWriteExt;
 
+    impl Default for Endpoints {
+        fn default() -> Self {
+            Endpoints {
+                id: ConnectionId::default(),
+                listener: 0,
+                client_ip: net::Ipv4Addr::LOCALHOST.into(),
+                client_port: super::super::EphemeralPorts::default()
+                    .assign("127.0.0.1:9092".parse().unwrap())
+                    .unwrap(),
+                server: "127.0.0.1:9092".parse().unwrap(),
+                client_ends: Default::default(),
+                server_ends: Default::default(),
+                client_memory: Default::default(),
+                server_memory: Default::default(),
+                linger: Linger::default(),
+                connections: Default::default(),
+            }
+        }
+    }
+
     async fn pong_server(server: ServerConnection) -> Result<(), tokio::codec::LinesCodecError> {
         let mut transport = tokio::codec::Framed::new(server, tokio::codec::LinesCodec::new());
         while let Some(Ok(ping)) = transport.next().await {
@@ -307,6 +780,23 @@ mod tests {
         ))
     }
 
+    #[test]
+    /// Tests that each end of a connection reports the other end as its peer.
+    fn test_peer_addr() {
+        use crate::TcpListener;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (client, (server, _)) = (client.unwrap(), server.unwrap());
+            assert_eq!(client.peer_addr(), server.local_addr());
+            assert_eq!(server.peer_addr(), client.local_addr());
+            assert_ne!(client.peer_addr(), client.local_addr());
+        });
+    }
+
     #[test]
     /// Tests that messages can be sent and received using a pair of MemoryStreams.
     fn test_ping_pong() {
@@ -315,7 +805,13 @@ mod tests {
         runtime.block_on(async {
             let port = std::num::NonZeroU16::new(9092).unwrap();
             let noop_fault_injector = super::super::super::FaultInjector::new_noop();
-            let (_, server_conn, client_conn) = new_pair(noop_fault_injector.handle(), port);
+            let (_, server_conn, client_conn) = new_pair(
+                &Default::default(),
+                noop_fault_injector.handle(),
+                port,
+                0,
+                Default::default(),
+            );
             handle.spawn(pong_server(server_conn).map(|_| ()));
             let mut transport =
                 tokio::codec::Framed::new(client_conn, tokio::codec::LinesCodec::new());
@@ -327,6 +823,77 @@ mod tests {
         });
     }
 
+    #[test]
+    /// Tests that chained `Bytes` are written in a single call and read back into a `BytesMut`.
+    fn write_buf() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {
+            let port = std::num::NonZeroU16::new(9092).unwrap();
+            let noop_fault_injector = crate::deterministic::FaultInjector::new_noop();
+            let (_, mut server_conn, mut client_conn) = new_pair(
+                &Default::default(),
+                noop_fault_injector.handle(),
+                port,
+                0,
+                Default::default(),
+            );
+            let header = bytes::Bytes::from_static(b"len=5;");
+            let body = bytes::Bytes::from_static(b"hello");
+            let mut frame = bytes::IntoBuf::into_buf(header).chain(bytes::IntoBuf::into_buf(body));
+            let written = futures::future::poll_fn(|cx| {
+                Pin::new(&mut client_conn).poll_write_buf(cx, &mut frame)
+            })
+            .await
+            .unwrap();
+            assert_eq!(written, 11);
+            assert!(!frame.has_remaining());
+            let mut received = bytes::BytesMut::with_capacity(64);
+            futures::future::poll_fn(|cx| {
+                Pin::new(&mut server_conn).poll_read_buf(cx, &mut received)
+            })
+            .await
+            .unwrap();
+            assert_eq!(&received[..], b"len=5;hello");
+        });
+    }
+
+    #[test]
+    /// Tests that dropping an end with unread data flushes it followed by EOF, or resets the
+    /// connection, depending on its `Linger`.
+    fn linger() {
+        use tokio::io::AsyncReadExt;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {
+            let port = std::num::NonZeroU16::new(9092).unwrap();
+            let noop_fault_injector = crate::deterministic::FaultInjector::new_noop();
+            for &linger in &[Linger::Flush, Linger::Reset] {
+                let (_, mut server_conn, mut client_conn) = new_pair(
+                    &Default::default(),
+                    noop_fault_injector.handle(),
+                    port,
+                    0,
+                    Default::default(),
+                );
+                client_conn.set_linger(linger);
+                client_conn.write_all(b"bye").await.unwrap();
+                drop(client_conn);
+                let mut received = vec![];
+                let result = server_conn.read_to_end(&mut received).await;
+                match linger {
+                    Linger::Flush => {
+                        result.unwrap();
+                        assert_eq!(&received[..], b"bye");
+                    }
+                    Linger::Reset => {
+                        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+                        let err = server_conn.write_all(b"?").await.unwrap_err();
+                        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+                    }
+                }
+            }
+        });
+    }
+
     #[test]
     /// Tests that disconnecting the server and client will cause both the server and client to fail further
     /// reads/writes with an error.
@@ -336,8 +903,13 @@ mod tests {
         runtime.block_on(async {
             let port = std::num::NonZeroU16::new(9092).unwrap();
             let noop_fault_injector = super::super::super::FaultInjector::new_noop();
-            let (conn_handle, server_conn, client_conn) =
-                new_pair(noop_fault_injector.handle(), port);
+            let (conn_handle, server_conn, client_conn) = new_pair(
+                &Default::default(),
+                noop_fault_injector.handle(),
+                port,
+                0,
+                Default::default(),
+            );
             let server_status = crate::spawn_with_result(&handle, pong_server(server_conn));
             let mut transport =
                 tokio::codec::Framed::new(client_conn, tokio::codec::LinesCodec::new());
@@ -368,7 +940,7 @@ mod tests {
         runtime.block_on(async {
             let port = std::num::NonZeroU16::new(9092).unwrap();
             let noop_fault_injector = super::super::super::FaultInjector::new_noop();
-            let (conn_handle, server_conn, _) = new_pair(noop_fault_injector.handle(), port);
+            let (conn_handle, server_conn, _) = new_pair(&Default::default(), noop_fault_injector.handle(), port, 0, Endpoints::default());
             let server_status = crate::spawn_with_result(&handle, pong_server(server_conn));
             futures::pin_mut!(server_status);
             tokio_test::assert_pending!(futures::poll!(server_status.as_mut()), "expected the server status to be pending due to the MemoryConnection still being open");
@@ -386,7 +958,7 @@ mod tests {
         runtime.block_on(async {
             let port = std::num::NonZeroU16::new(9092).unwrap();
             let noop_fault_injector = crate::deterministic::FaultInjector::new_noop();
-            let (conn_handle, server_conn, mut client_conn) = new_pair(noop_fault_injector.handle(), port);
+            let (conn_handle, server_conn, mut client_conn) = new_pair(&Default::default(), noop_fault_injector.handle(), port, 0, Endpoints::default());
             let server_status = crate::spawn_with_result(&handle, pong_server(server_conn));
             futures::pin_mut!(server_status);
             tokio_test::assert_pending!(futures::poll!(server_status.as_mut()), "expected the server status to be pending due to the MemoryConnection still being open");
@@ -0,0 +1,128 @@
+//! Pausing every task of a host, as a long garbage collection or the migration of a virtual
+//! machine would.
+//!
+//! Tasks spawned through the handle of a cluster are wrapped in `Pausable`, which stops polling
+//! the task while the cluster is paused and wakes it once the pause ends. Other clusters keep
+//! running, and connections to the paused cluster keep buffering data, so its peers see it stop
+//! responding rather than disconnect. Timers of the paused host which come due during the pause
+//! fire once it ends, long after their deadlines.
+use super::DeterministicRuntimeHandle;
+use futures::{FutureExt, Poll};
+use pin_project::pin_project;
+use std::{future::Future, pin::Pin, task::Context};
+
+/// A task which is not polled while the cluster it was spawned in is paused.
+#[pin_project]
+#[derive(Debug)]
+pub(crate) struct Pausable<F> {
+    handle: DeterministicRuntimeHandle,
+    /// Timer for the end of the pause the task is waiting out, if any.
+    resume: Option<tokio_timer::Delay>,
+    #[pin]
+    future: F,
+}
+
+impl<F> Pausable<F> {
+    pub(crate) fn new(handle: DeterministicRuntimeHandle, future: F) -> Self {
+        Self {
+            handle,
+            resume: None,
+            future,
+        }
+    }
+}
+
+impl<F: Future> Future for Pausable<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        while let Some(until) = this.handle.network.paused_until() {
+            match this.resume {
+                Some(resume) if resume.deadline() == until => {}
+                _ => *this.resume = Some(this.handle.timer.delay(until)),
+            }
+            this.handle.wait_until(until);
+            futures::ready!(this.resume.as_mut().unwrap().poll_unpin(cx));
+        }
+        *this.resume = None;
+        this.future.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig, FaultKind},
+        Environment,
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    /// Spawns a task on `handle` recording the simulated time of each tick into `ticks`.
+    fn ticker(handle: &crate::deterministic::DeterministicRuntimeHandle) -> Arc<Mutex<Vec<u64>>> {
+        let ticks = Arc::new(Mutex::new(vec![]));
+        let (env, record) = (handle.clone(), ticks.clone());
+        let start = handle.now();
+        handle.spawn(async move {
+            for _ in 0..10 {
+                env.delay_from(Duration::from_secs(1)).await;
+                let elapsed = (env.now() - start).as_secs();
+                record.lock().unwrap().push(elapsed);
+            }
+        });
+        ticks
+    }
+
+    #[test]
+    /// Test that the tasks of a paused host stop while other hosts keep running.
+    fn pause_host() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let paused = handle.new_cluster(FaultConfig::disabled());
+        let running = handle.new_cluster(FaultConfig::disabled());
+        let (paused_ticks, running_ticks) = (ticker(&paused), ticker(&running));
+        runtime.block_on(async {
+            handle.delay_from(Duration::from_millis(2500)).await;
+            paused.pause_host(Duration::from_secs(5));
+            handle.delay_from(Duration::from_secs(20)).await;
+        });
+        assert_eq!(*running_ticks.lock().unwrap(), (1..=10).collect::<Vec<_>>());
+        assert_eq!(
+            *paused_ticks.lock().unwrap(),
+            vec![1, 2, 7, 8, 9, 10, 11, 12, 13, 14]
+        );
+    }
+
+    #[test]
+    /// Test that pauses are drawn from the seed and recorded as faults.
+    fn seeded_pauses() {
+        let run = |seed| {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            let handle = runtime.handle().new_cluster(FaultConfig {
+                host_pause_prob: 0.3,
+                host_pause: Duration::from_secs(1)..Duration::from_secs(5),
+                ..FaultConfig::disabled()
+            });
+            let ticks = ticker(&handle);
+            let main = runtime.handle();
+            runtime.block_on(async move {
+                main.delay_from(Duration::from_secs(120)).await;
+            });
+            let pauses = runtime
+                .faults()
+                .iter()
+                .filter(|fault| fault.kind == FaultKind::HostPause)
+                .count();
+            let ticks = ticks.lock().unwrap().clone();
+            (ticks, pauses)
+        };
+        let (ticks, pauses) = run(4);
+        assert!(pauses > 0);
+        assert_eq!((ticks.clone(), pauses), run(4));
+        assert_eq!(ticks.len(), 10);
+        assert!(ticks[9] > 10);
+    }
+}
@@ -0,0 +1,384 @@
+//! Versioned on-disk format for fault schedules, traces and failure reports.
+//!
+//! Artifacts are stored as JSON wrapped in an envelope recording the kind of artifact, the
+//! format version and the version of this crate which wrote it. Loading an artifact written
+//! with a newer format version fails with `Error::UnsupportedFormat` rather than silently
+//! misinterpreting it.
+use super::{
+    chrome_trace,
+    rng::{self, RngAlgorithm, SmallRngAlgorithm},
+    sequence, sweep, DeterministicRuntime, DiagramFormat, FaultRecord, LoggedEvent,
+};
+use crate::{buggify::BuggifyPoint, Error};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, panic, path::Path};
+
+/// Version of the artifact format written by this crate.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a, T> {
+    kind: &'a str,
+    version: u32,
+    crate_version: &'a str,
+    body: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    kind: String,
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct Envelope<T> {
+    body: T,
+}
+
+/// A value which can be saved and loaded in the versioned artifact format.
+pub trait Artifact: Serialize + DeserializeOwned {
+    /// Name identifying the kind of artifact in the envelope.
+    const KIND: &'static str;
+
+    fn to_json(&self) -> Result<String, Error> {
+        let envelope = EnvelopeRef {
+            kind: Self::KIND,
+            version: FORMAT_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION"),
+            body: self,
+        };
+        serde_json::to_string_pretty(&envelope).map_err(|source| Error::Serialization { source })
+    }
+
+    fn from_json(json: &str) -> Result<Self, Error> {
+        let header: Header =
+            serde_json::from_str(json).map_err(|source| Error::Serialization { source })?;
+        if header.kind != Self::KIND || header.version > FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat {
+                kind: header.kind,
+                version: header.version,
+            });
+        }
+        let envelope: Envelope<Self> =
+            serde_json::from_str(json).map_err(|source| Error::Serialization { source })?;
+        Ok(envelope.body)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        fs::write(path, self.to_json()?).map_err(|source| Error::Io { source })
+    }
+
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let json = fs::read_to_string(path).map_err(|source| Error::Io { source })?;
+        Self::from_json(&json)
+    }
+}
+
+/// The faults injected during a run of a seed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultSchedule {
+    pub seed: u64,
+    /// Name of the `RngAlgorithm` the faults were drawn from. Schedules written before the
+    /// algorithm was recorded used `SmallRngAlgorithm`.
+    #[serde(default = "default_rng")]
+    pub rng: String,
+    pub faults: Vec<FaultRecord>,
+}
+
+fn default_rng() -> String {
+    SmallRngAlgorithm.name().to_string()
+}
+
+impl Artifact for FaultSchedule {
+    const KIND: &'static str = "fault_schedule";
+}
+
+impl FaultSchedule {
+    /// Returns a runtime for the seed which only injects the faults in this schedule. Fails with
+    /// `Error::UnknownRngAlgorithm` if the schedule was drawn from an algorithm which is not
+    /// built in to this crate.
+    pub fn runtime(&self) -> Result<DeterministicRuntime, Error> {
+        let algorithm =
+            rng::algorithm_by_name(&self.rng).ok_or_else(|| Error::UnknownRngAlgorithm {
+                name: self.rng.clone(),
+            })?;
+        let mut runtime = DeterministicRuntime::builder()
+            .seed(self.seed)
+            .algorithm(algorithm)
+            .build()?;
+        runtime.allow_only_faults(self.faults.iter().map(|fault| fault.id));
+        Ok(runtime)
+    }
+}
+
+/// The events recorded during a run of a seed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trace {
+    pub seed: u64,
+    pub events: Vec<LoggedEvent>,
+}
+
+impl Artifact for Trace {
+    const KIND: &'static str = "trace";
+}
+
+impl Trace {
+    /// Returns the index of the first event at which this trace and `other` differ, or `None`
+    /// if they are identical.
+    pub fn diverges_from(&self, other: &Trace) -> Option<u64> {
+        let common = self
+            .events
+            .iter()
+            .zip(other.events.iter())
+            .position(|(a, b)| a != b);
+        match common {
+            Some(index) => Some(index as u64),
+            None if self.events.len() != other.events.len() => {
+                Some(self.events.len().min(other.events.len()) as u64)
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the records captured by `logger::SimLogger` during the run.
+    pub fn logs(&self) -> Vec<String> {
+        self.events
+            .iter()
+            .filter_map(crate::logger::format_line)
+            .collect()
+    }
+
+    /// Returns the records captured by `logger::SimLogger` during the run, split into the log
+    /// of each host.
+    pub fn logs_by_host(&self) -> BTreeMap<String, Vec<String>> {
+        crate::logger::lines_by_host(&self.events)
+    }
+
+    /// Writes the log of each host to `<host>.log` in the directory `dir`, which is created
+    /// if missing. Bytes of the host name other than ASCII letters, digits, `.`, `_` and `-`
+    /// are percent-encoded, so every file is written within `dir` and no two hosts share one.
+    pub fn save_logs<P: AsRef<Path>>(&self, dir: P) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(|source| Error::Io { source })?;
+        for (host, lines) in self.logs_by_host() {
+            let mut contents = lines.join("\n");
+            contents.push('\n');
+            fs::write(dir.join(format!("{}.log", file_name(&host))), contents)
+                .map_err(|source| Error::Io { source })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the events in the Chrome trace event format, which can be opened with
+    /// `chrome://tracing` or the Perfetto UI.
+    pub fn to_chrome_trace(&self) -> Result<String, Error> {
+        serde_json::to_string(&chrome_trace::trace_events(&self.events))
+            .map_err(|source| Error::Serialization { source })
+    }
+
+    /// Returns a sequence diagram of the messages exchanged during the run, in `format`. At most
+    /// `max_messages` messages are included.
+    pub fn to_sequence_diagram(&self, format: DiagramFormat, max_messages: usize) -> String {
+        sequence::render(&self.events, format, max_messages)
+    }
+
+    /// Returns the timeline of the connection between `a` and `b`, which may be given in either
+    /// order.
+    pub fn connection_timeline(
+        &self,
+        a: std::net::SocketAddr,
+        b: std::net::SocketAddr,
+    ) -> super::ConnectionTimeline {
+        super::ConnectionTimeline::from_events(&self.events, a, b)
+    }
+
+    /// Writes the events to `path` in the Chrome trace event format.
+    pub fn save_chrome_trace<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        fs::write(path, self.to_chrome_trace()?).map_err(|source| Error::Io { source })
+    }
+}
+
+/// Everything needed to reproduce a failing seed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailureReport {
+    /// The panic message of the failure.
+    pub message: String,
+    pub schedule: FaultSchedule,
+    pub trace: Trace,
+    /// The `buggify!` points evaluated during the run, empty in reports written before they
+    /// were recorded.
+    #[serde(default)]
+    pub buggify: BTreeMap<String, BuggifyPoint>,
+}
+
+impl Artifact for FailureReport {
+    const KIND: &'static str = "failure_report";
+}
+
+impl FailureReport {
+    /// Runs `test` with `seed`, returning a report if it fails.
+    pub fn capture<F>(seed: u64, test: F) -> Option<FailureReport>
+    where
+        F: Fn(&mut DeterministicRuntime),
+    {
+        let runtime = DeterministicRuntime::new_with_seed(seed).expect("failed to build runtime");
+        Self::run(seed, runtime, &test)
+    }
+
+    /// Replays the failure, running `test` with the seed and fault schedule of this report.
+    /// Returns the report of the replayed run if it fails, which can be compared against this
+    /// report to find where the runs diverged.
+    pub fn replay<F>(&self, test: F) -> Option<FailureReport>
+    where
+        F: Fn(&mut DeterministicRuntime),
+    {
+        let runtime = self.schedule.runtime().expect("failed to build runtime");
+        Self::run(self.schedule.seed, runtime, &test)
+    }
+
+    fn run<F>(seed: u64, mut runtime: DeterministicRuntime, test: &F) -> Option<FailureReport>
+    where
+        F: Fn(&mut DeterministicRuntime),
+    {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| test(&mut runtime)));
+        let payload = result.err()?;
+        Some(FailureReport {
+            message: sweep::panic_message(&*payload),
+            schedule: FaultSchedule {
+                seed,
+                rng: runtime.handle().rng_algorithm().to_string(),
+                faults: runtime.faults(),
+            },
+            trace: Trace {
+                seed,
+                events: runtime.handle().events(),
+            },
+            buggify: runtime
+                .handle()
+                .buggify_points()
+                .into_iter()
+                .map(|(label, point)| (label.to_string(), point))
+                .collect(),
+        })
+    }
+}
+
+/// Escapes `host` for use as a file name, percent-encoding every byte which is not an ASCII
+/// letter, digit, `.`, `_` or `-`. Names made only of dots are encoded too, as they name
+/// directories.
+fn file_name(host: &str) -> String {
+    let dots = host.bytes().all(|b| b == b'.');
+    let mut name = String::with_capacity(host.len());
+    for b in host.bytes() {
+        match b {
+            b'.' if dots => name.push_str("%2E"),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => name.push(b as char),
+            _ => name.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::SimEvent, Environment, TcpListener};
+    use std::net;
+
+    fn accept_then_fail(runtime: &mut DeterministicRuntime) {
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let client = handle.clone();
+            handle.spawn(async move {
+                let _ = client.connect(addr).await;
+            });
+            let _ = listener.accept().await.unwrap();
+            crate::buggify!("after accept");
+            panic!("failed after accepting");
+        })
+    }
+
+    #[test]
+    /// Test that a failure report survives a round trip and replays to the same trace.
+    fn round_trip_and_replay() {
+        let report = FailureReport::capture(4, accept_then_fail).unwrap();
+        assert_eq!(report.message, "failed after accepting");
+        assert_eq!(report.buggify["after accept"].evaluated, 1);
+        let loaded = FailureReport::from_json(&report.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, report);
+        let replayed = loaded.replay(accept_then_fail).unwrap();
+        assert_eq!(replayed.trace.diverges_from(&report.trace), None);
+        assert_eq!(replayed.buggify, report.buggify);
+    }
+
+    #[test]
+    /// Test that artifacts from newer format versions, or of another kind, are rejected.
+    fn rejects_unknown_formats() {
+        let json = format!(
+            r#"{{"kind": "trace", "version": {}, "body": {{"seed": 0, "events": []}}}}"#,
+            FORMAT_VERSION + 1
+        );
+        match Trace::from_json(&json) {
+            Err(Error::UnsupportedFormat { version, .. }) => {
+                assert_eq!(version, FORMAT_VERSION + 1)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let trace = Trace {
+            seed: 0,
+            events: vec![],
+        };
+        assert!(FaultSchedule::from_json(&trace.to_json().unwrap()).is_err());
+    }
+
+    #[test]
+    /// Test that the log of each host is written to a file of its own within the directory,
+    /// whatever the name of the host.
+    fn save_logs() {
+        let log = |index, host: &str, message: &str| LoggedEvent {
+            index,
+            elapsed: std::time::Duration::from_millis(index),
+            event: SimEvent::Log {
+                host: Some(host.to_string()),
+                task: None,
+                level: "INFO".to_string(),
+                target: "kv".to_string(),
+                message: message.to_string(),
+            },
+        };
+        let trace = Trace {
+            seed: 0,
+            events: vec![
+                log(0, "node-1", "started"),
+                log(1, "../x", "escaped"),
+                log(2, "[::1]:80", "ipv6"),
+                log(3, "node-1", "stopped"),
+            ],
+        };
+        let dir = std::env::temp_dir().join(format!("simulation-save-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        trace.save_logs(dir.join("logs")).unwrap();
+        let mut files: Vec<_> = fs::read_dir(dir.join("logs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            ["%5B%3A%3A1%5D%3A80.log", "..%2Fx.log", "node-1.log"]
+        );
+        let hosts = trace.logs_by_host();
+        for (host, file) in &[
+            ("node-1", "node-1.log"),
+            ("../x", "..%2Fx.log"),
+            ("[::1]:80", "%5B%3A%3A1%5D%3A80.log"),
+        ] {
+            let contents = fs::read_to_string(dir.join("logs").join(file)).unwrap();
+            assert_eq!(contents, format!("{}\n", hosts[*host].join("\n")));
+        }
+        assert_eq!(hosts["node-1"].len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+//! Derivation of independent random number streams from a seed.
+//!
+//! Rather than drawing every random decision from a single RNG, where the outcome of a draw
+//! depends on how many draws came before it, each source of randomness is identified by a
+//! stable key and draws from its own RNG derived from the seed and that key. Adding a task or
+//! a connection elsewhere in a test then leaves the decisions of unrelated streams unchanged.
+//!
+//! The generator used for each stream is chosen by an `RngAlgorithm`. Keys are always hashed
+//! with FNV-1a, so whether a seed reproduces the same run across versions of this crate,
+//! versions of `rand` and platforms depends only on the algorithm:
+//!
+//! * `SmallRngAlgorithm`, the default, uses `rand::rngs::SmallRng`. It is fast, but `rand` does
+//!   not guarantee its output is the same between releases or between 32 and 64-bit
+//!   platforms, so seeds are only stable for a given `rand` version and pointer width.
+//! * `ChaChaAlgorithm` uses ChaCha with 20 rounds, whose output is fixed by its specification,
+//!   so seeds are stable across platforms and releases.
+use super::{audit::RngAudit, RngDraw};
+use crate::RngHandle;
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    panic::Location,
+    sync,
+};
+
+/// A random number generator algorithm used for the streams of a `DeterministicRuntime`.
+pub trait RngAlgorithm: fmt::Debug + Send + Sync + 'static {
+    /// Name identifying the algorithm in saved artifacts.
+    fn name(&self) -> &'static str;
+    /// Returns a generator seeded with `seed`.
+    fn seed_from_u64(&self, seed: u64) -> Box<dyn RngCore + Send>;
+}
+
+/// `rand::rngs::SmallRng`, whose output depends on the version of `rand` and the platform.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmallRngAlgorithm;
+
+impl RngAlgorithm for SmallRngAlgorithm {
+    fn name(&self) -> &'static str {
+        "small_rng"
+    }
+
+    fn seed_from_u64(&self, seed: u64) -> Box<dyn RngCore + Send> {
+        Box::new(SmallRng::seed_from_u64(seed))
+    }
+}
+
+/// ChaCha with 20 rounds, whose output is the same on every platform and release.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChaChaAlgorithm;
+
+impl RngAlgorithm for ChaChaAlgorithm {
+    fn name(&self) -> &'static str {
+        "chacha20"
+    }
+
+    fn seed_from_u64(&self, seed: u64) -> Box<dyn RngCore + Send> {
+        Box::new(ChaCha20Rng::seed_from_u64(seed))
+    }
+}
+
+/// Returns the built-in algorithm identified by `name`.
+pub(crate) fn algorithm_by_name(name: &str) -> Option<Algorithm> {
+    match name {
+        "small_rng" => Some(Algorithm::new(SmallRngAlgorithm)),
+        "chacha20" => Some(Algorithm::new(ChaChaAlgorithm)),
+        _ => None,
+    }
+}
+
+/// Shared handle to the algorithm of a runtime, along with the audit its draws are recorded
+/// to, if enabled.
+#[derive(Debug, Clone)]
+pub(crate) struct Algorithm {
+    rng: sync::Arc<dyn RngAlgorithm>,
+    audit: Option<RngAudit>,
+}
+
+impl Algorithm {
+    pub(crate) fn new<A: RngAlgorithm>(algorithm: A) -> Self {
+        Self {
+            rng: sync::Arc::new(algorithm),
+            audit: None,
+        }
+    }
+
+    /// Records every draw made from streams of this algorithm.
+    pub(crate) fn audited(self) -> Self {
+        Self {
+            audit: Some(RngAudit::default()),
+            ..self
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        self.rng.name()
+    }
+
+    /// Returns the draws recorded so far, or nothing if the algorithm is not audited.
+    pub(crate) fn draws(&self) -> Vec<RngDraw> {
+        self.audit
+            .as_ref()
+            .map(|audit| audit.draws())
+            .unwrap_or_default()
+    }
+
+    /// Returns a generator for the stream seeded with `seed`, described by `stream` in audits.
+    pub(crate) fn stream(&self, seed: u64, stream: &dyn fmt::Debug) -> SimRng {
+        let rng = self.rng.seed_from_u64(seed);
+        match &self.audit {
+            Some(audit) => SimRng(audit.record(rng, stream)),
+            None => SimRng(rng),
+        }
+    }
+}
+
+/// A generator for a single stream.
+pub(crate) struct SimRng(Box<dyn RngCore + Send>);
+
+impl fmt::Debug for SimRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimRng").finish()
+    }
+}
+
+impl SimRng {
+    pub(crate) fn from_entropy() -> Self {
+        SimRng(Box::new(SmallRng::from_entropy()))
+    }
+}
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+/// 64-bit FNV-1a hasher. Unlike `DefaultHasher`, its output is specified and will not
+/// change between Rust releases or platforms.
+#[derive(Debug, Clone)]
+pub(crate) struct StableHasher(u64);
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    pub(crate) fn new() -> Self {
+        StableHasher(Self::OFFSET_BASIS)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64)
+    }
+}
+
+/// Returns a hash of `value` which is stable across runs, platforms and compiler versions.
+pub(crate) fn stable_hash<H: Hash + ?Sized>(value: &H) -> u64 {
+    let mut hasher = StableHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns an RNG for the stream identified by `key`, derived from `seed`.
+pub(crate) fn derive<K>(algorithm: &Algorithm, seed: u64, key: &K) -> SimRng
+where
+    K: Hash + fmt::Debug + ?Sized,
+{
+    algorithm.stream(stable_hash(&(seed, key)), &key)
+}
+
+/// File, line and column of a callsite.
+type Callsite = (&'static str, u32, u32);
+
+/// Source of RNG streams for a runtime, for primitives identified by their callsite.
+#[derive(Debug, Clone)]
+pub(crate) struct Entropy {
+    seed: u64,
+    algorithm: Algorithm,
+    /// Number of streams handed out for each callsite.
+    callsites: sync::Arc<sync::Mutex<HashMap<Callsite, u64>>>,
+    /// Streams handed out to tasks through `Environment::rng`, by the key of the task's stream.
+    tasks: sync::Arc<sync::Mutex<HashMap<Option<u64>, RngHandle>>>,
+    /// Streams handed out to components through `Environment::rng_for`.
+    components: sync::Arc<sync::Mutex<HashMap<String, RngHandle>>>,
+}
+
+impl Entropy {
+    pub(crate) fn new(seed: u64, algorithm: Algorithm) -> Self {
+        Self {
+            seed,
+            algorithm,
+            callsites: sync::Arc::new(sync::Mutex::new(HashMap::new())),
+            tasks: sync::Arc::new(sync::Mutex::new(HashMap::new())),
+            components: sync::Arc::new(sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns an RNG for the next primitive created at `location`. Streams are keyed by the
+    /// callsite and the number of primitives previously created there, so primitives created
+    /// elsewhere do not affect them.
+    pub(crate) fn callsite(&self, location: &'static Location<'static>) -> SimRng {
+        derive(&self.algorithm, self.seed, &self.next(location))
+    }
+
+    /// Returns a stable id for the next primitive created at `location`, keyed in the same way
+    /// as `callsite`.
+    pub(crate) fn callsite_id(&self, location: &'static Location<'static>) -> u64 {
+        stable_hash(&self.next(location))
+    }
+
+    pub(crate) fn algorithm(&self) -> &Algorithm {
+        &self.algorithm
+    }
+
+    /// Returns the stream of the task whose stream is keyed by `task`, see `Tasks::stream`, or
+    /// of code running outside of any task.
+    pub(crate) fn task(&self, task: Option<u64>) -> RngHandle {
+        let (seed, algorithm) = (self.seed, &self.algorithm);
+        self.tasks
+            .lock()
+            .unwrap()
+            .entry(task)
+            .or_insert_with(|| {
+                let stream = ("task", task);
+                RngHandle::derived(algorithm.clone(), stable_hash(&(seed, stream)), &stream)
+            })
+            .clone()
+    }
+
+    /// Returns the stream of the component identified by `name`.
+    pub(crate) fn component(&self, name: &str) -> RngHandle {
+        let (seed, algorithm) = (self.seed, &self.algorithm);
+        self.components
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let stream = ("component", name);
+                RngHandle::derived(algorithm.clone(), stable_hash(&(seed, stream)), &stream)
+            })
+            .clone()
+    }
+
+    fn next(&self, location: &'static Location<'static>) -> (&'static str, Callsite, u64) {
+        let callsite = (location.file(), location.line(), location.column());
+        let n = {
+            let mut lock = self.callsites.lock().unwrap();
+            let count = lock.entry(callsite).or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        ("callsite", callsite, n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    /// Test that hashes match the published FNV-1a test vectors.
+    fn fnv_vectors() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = StableHasher::new();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    /// Test that each algorithm produces the values it has always produced for a seed. A
+    /// failure here means seeds recorded with earlier versions no longer reproduce their runs.
+    fn seed_stability() {
+        let draws = |algorithm: Algorithm| {
+            let mut rng = derive(&algorithm, 1, "stability");
+            (0..3).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            draws(Algorithm::new(ChaChaAlgorithm)),
+            vec![
+                0xf8c9_a227_9600_6c82,
+                0x2645_2e51_fb0d_a034,
+                0x597f_0434_0b22_e74d
+            ]
+        );
+        // `SmallRng` is only stable for a given version of `rand` and pointer width.
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(
+            draws(Algorithm::new(SmallRngAlgorithm)),
+            vec![
+                0x0812_d096_f643_a489,
+                0x9ebf_230d_0568_9b40,
+                0x3c7e_ebff_40f8_c144
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that streams are determined by the seed and key alone.
+    fn independent_streams() {
+        let draws = |rng: &mut SimRng| (0..8).map(|_| rng.gen::<u64>()).collect::<Vec<_>>();
+        let derive = |seed, key| derive(&Algorithm::new(SmallRngAlgorithm), seed, key);
+        let mut a = derive(1, "a");
+        let _ = draws(&mut derive(1, "b"));
+        assert_eq!(draws(&mut a), draws(&mut derive(1, "a")));
+        assert_ne!(draws(&mut derive(1, "a")), draws(&mut derive(2, "a")));
+    }
+}
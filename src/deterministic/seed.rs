@@ -0,0 +1,161 @@
+//! Seeds written as decimal, hexadecimal or words.
+//!
+//! A seed copied from a CI log into a chat message and back into a terminal is easily mangled,
+//! and a twenty digit number is hard to read out. `Seed` parses a seed written in decimal, in
+//! hexadecimal prefixed with `0x`, or as a list of words separated by dashes, each word
+//! standing for a byte. All three forms of a seed reproduce the same run, and the runtime
+//! echoes them when it starts running.
+//!
+//! ```
+//! # use simulation::deterministic::Seed;
+//! let seed: Seed = "0x2a".parse().unwrap();
+//! assert_eq!(seed, Seed(42));
+//! assert_eq!(seed.to_words().parse::<Seed>().unwrap(), Seed(42));
+//! assert_eq!("  42 ".parse::<Seed>().unwrap(), seed);
+//! ```
+use crate::Error;
+use std::{fmt, str};
+
+/// Words standing for each value of a byte.
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "agent", "alarm", "album", "alpha", "amber", "angle", "ankle",
+    "apple", "april", "arena", "armor", "arrow", "atlas", "badge", "baker", "bamboo", "banjo",
+    "barn", "basin", "beach", "beard", "bench", "berry", "bison", "blade", "blank", "blaze",
+    "bloom", "board", "bonus", "boost", "brave", "bread", "brick", "brook", "brush", "bugle",
+    "cabin", "cable", "camel", "canal", "candy", "canoe", "cargo", "cedar", "chalk", "charm",
+    "chess", "chief", "cider", "clerk", "cliff", "clock", "cloud", "coach", "comet", "coral",
+    "cotton", "couch", "crane", "crown", "cube", "daisy", "dance", "delta", "denim", "depot",
+    "diary", "dolphin", "dome", "donut", "dragon", "drum", "dune", "eagle", "earth", "easel",
+    "echo", "elbow", "elder", "ember", "emerald", "engine", "epoch", "fable", "falcon", "feast",
+    "fern", "ferry", "fiber", "field", "flame", "flint", "flute", "focus", "forest", "fossil",
+    "frost", "fudge", "galaxy", "garden", "garlic", "gecko", "ghost", "giant", "ginger", "glacier",
+    "globe", "grape", "gravel", "guitar", "habit", "harbor", "hazel", "heron", "hinge", "hippo",
+    "honey", "hotel", "icicle", "igloo", "index", "indigo", "iris", "island", "ivory", "jacket",
+    "jaguar", "jelly", "jersey", "jewel", "judge", "juice", "jungle", "kayak", "kernel", "kettle",
+    "kitten", "koala", "label", "ladder", "lagoon", "lantern", "laser", "lemon", "lilac", "linen",
+    "lizard", "llama", "locket", "lotus", "lunar", "magnet", "mango", "maple", "marble", "meadow",
+    "melon", "metro", "mint", "mirror", "monkey", "mosaic", "motor", "muffin", "napkin", "nectar",
+    "needle", "nickel", "noble", "noodle", "north", "nutmeg", "oasis", "ocean", "olive", "omega",
+    "onion", "opal", "orbit", "orchid", "otter", "oyster", "paddle", "panda", "parrot", "pebble",
+    "pepper", "piano", "pilot", "pixel", "planet", "plaza", "pocket", "polar", "poppy", "prism",
+    "pulse", "puzzle", "quail", "quartz", "quest", "quiver", "rabbit", "radar", "raven", "razor",
+    "relic", "ribbon", "rider", "robin", "rocket", "ruby", "saddle", "salmon", "satin", "scarf",
+    "shadow", "silver", "sketch", "socket", "spider", "spruce", "squid", "stone", "sugar",
+    "summit", "sunset", "swan", "teapot", "temple", "thunder", "tiger", "toast", "topaz", "torch",
+    "tulip", "tundra", "turtle", "umbrella", "unicorn", "valley", "velvet", "violet", "vortex",
+    "walnut", "walrus", "willow", "window", "yacht", "zebra", "zenith", "zipper",
+];
+
+/// A seed of a `DeterministicRuntime`. Displays in decimal, its canonical form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Seed(pub u64);
+
+impl Seed {
+    pub fn to_hex(self) -> String {
+        format!("{:#x}", self.0)
+    }
+
+    /// Returns the seed as words separated by dashes, one for each byte without leading zero
+    /// bytes.
+    pub fn to_words(self) -> String {
+        let bytes = self.0.to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count().min(7);
+        bytes[skip..]
+            .iter()
+            .map(|byte| WORDS[*byte as usize])
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    fn from_words(value: &str) -> Option<Seed> {
+        let words: Vec<_> = value.split('-').collect();
+        if words.len() > 8 {
+            return None;
+        }
+        words.iter().try_fold(Seed(0), |seed, word| {
+            let word = word.to_ascii_lowercase();
+            let byte = WORDS.iter().position(|candidate| *candidate == word)?;
+            Some(Seed(seed.0 << 8 | byte as u64))
+        })
+    }
+}
+
+impl fmt::Display for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl str::FromStr for Seed {
+    type Err = Error;
+
+    /// Parses a seed written in decimal, in hexadecimal prefixed with `0x`, or in words.
+    /// Surrounding whitespace and quotes, and underscores between digits, are ignored.
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let trimmed = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        let digits = trimmed.replace('_', "");
+        let seed = if let Some(hex) = digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            u64::from_str_radix(hex, 16).ok().map(Seed)
+        } else if digits.chars().all(|c| c.is_ascii_digit()) {
+            digits.parse().ok().map(Seed)
+        } else {
+            Seed::from_words(trimmed)
+        };
+        seed.ok_or_else(|| Error::InvalidSeed {
+            value: value.to_string(),
+        })
+    }
+}
+
+impl From<u64> for Seed {
+    fn from(seed: u64) -> Self {
+        Seed(seed)
+    }
+}
+
+impl From<Seed> for u64 {
+    fn from(seed: Seed) -> Self {
+        seed.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that every form of a seed parses back to it, and that malformed seeds are rejected.
+    fn round_trip() {
+        for seed in &[0, 1, 42, 0xdead_beef, u64::MAX] {
+            let seed = Seed(*seed);
+            for form in &[seed.to_string(), seed.to_hex(), seed.to_words()] {
+                assert_eq!(form.parse::<Seed>().unwrap(), seed, "{}", form);
+            }
+        }
+        assert_eq!(Seed(0).to_words(), "acid");
+        assert_eq!(Seed(0x0100).to_words(), "acorn-acid");
+        assert_eq!(Seed(u64::MAX).to_words().split('-').count(), 8);
+        assert_eq!(" \"1_000\"\n".parse::<Seed>().unwrap(), Seed(1000));
+        assert_eq!("Acorn-ACID".parse::<Seed>().unwrap(), Seed(0x0100));
+        for value in &["", "abc", "0x", "0xfg", "18446744073709551616", "acid-nope"] {
+            assert!(value.parse::<Seed>().is_err(), "{}", value);
+        }
+        let nine = ["acid"; 9].join("-");
+        assert!(nine.parse::<Seed>().is_err());
+    }
+
+    #[test]
+    /// Test that each word stands for a single byte.
+    fn words() {
+        let mut words = WORDS.to_vec();
+        words.sort();
+        words.dedup();
+        assert_eq!(words.len(), 256);
+        assert!(words
+            .iter()
+            .all(|word| word.chars().all(|c| c.is_ascii_lowercase())));
+    }
+}
@@ -0,0 +1,193 @@
+//! Sequence diagrams of the messages exchanged during a run.
+//!
+//! Each address which opened a connection or wrote to one becomes a participant, connections
+//! and writes become messages labelled with the simulated time at which they occurred, and
+//! injected faults become notes. Diagrams are rendered as Mermaid or PlantUML.
+use super::{LoggedEvent, SimEvent};
+use std::{fmt::Write, net, time};
+
+/// The syntax a sequence diagram is rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagramFormat {
+    Mermaid,
+    PlantUml,
+}
+
+enum Line {
+    Message {
+        from: usize,
+        to: usize,
+        label: String,
+        dashed: bool,
+    },
+    Note(String),
+}
+
+fn millis(elapsed: time::Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+fn participant(participants: &mut Vec<net::SocketAddr>, addr: net::SocketAddr) -> usize {
+    match participants.iter().position(|p| *p == addr) {
+        Some(idx) => idx,
+        None => {
+            participants.push(addr);
+            participants.len() - 1
+        }
+    }
+}
+
+/// Renders the messages in `events` as a sequence diagram, including at most `max_messages`
+/// messages.
+pub(crate) fn render(events: &[LoggedEvent], format: DiagramFormat, max_messages: usize) -> String {
+    let mut participants = vec![];
+    let mut lines = vec![];
+    let mut messages = 0;
+    let mut omitted = 0;
+    for logged in events {
+        let at = millis(logged.elapsed);
+        let (from, to, label, dashed) = match &logged.event {
+            SimEvent::ConnectionOpened { client, server, .. } => {
+                (*client, *server, format!("[{:.3}ms] connect", at), true)
+            }
+            SimEvent::BytesWritten {
+                from, to, bytes, ..
+            } => (*from, *to, format!("[{:.3}ms] {} bytes", at, bytes), false),
+            SimEvent::FaultInjected(fault) => {
+                if messages < max_messages {
+                    lines.push(Line::Note(format!("[{:.3}ms] fault {:?}", at, fault.kind)));
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if messages == max_messages {
+            omitted += 1;
+            continue;
+        }
+        messages += 1;
+        let from = participant(&mut participants, from);
+        let to = participant(&mut participants, to);
+        lines.push(Line::Message {
+            from,
+            to,
+            label,
+            dashed,
+        });
+    }
+    if omitted > 0 {
+        lines.push(Line::Note(format!("{} more messages omitted", omitted)));
+    }
+
+    let mut out = String::new();
+    match format {
+        DiagramFormat::Mermaid => out.push_str("sequenceDiagram\n"),
+        DiagramFormat::PlantUml => out.push_str("@startuml\n"),
+    }
+    for (idx, addr) in participants.iter().enumerate() {
+        match format {
+            DiagramFormat::Mermaid => writeln!(out, "    participant P{} as {}", idx, addr),
+            DiagramFormat::PlantUml => writeln!(out, "participant \"{}\" as P{}", addr, idx),
+        }
+        .unwrap();
+    }
+    let last = participants.len().saturating_sub(1);
+    for line in lines {
+        match (format, line) {
+            (
+                DiagramFormat::Mermaid,
+                Line::Message {
+                    from,
+                    to,
+                    label,
+                    dashed,
+                },
+            ) => {
+                let arrow = if dashed { "-->>" } else { "->>" };
+                writeln!(out, "    P{}{}P{}: {}", from, arrow, to, label)
+            }
+            (
+                DiagramFormat::PlantUml,
+                Line::Message {
+                    from,
+                    to,
+                    label,
+                    dashed,
+                },
+            ) => {
+                let arrow = if dashed { "-->" } else { "->" };
+                writeln!(out, "P{} {} P{} : {}", from, arrow, to, label)
+            }
+            (_, Line::Note(_)) if participants.is_empty() => Ok(()),
+            (DiagramFormat::Mermaid, Line::Note(note)) => {
+                writeln!(out, "    Note over P0,P{}: {}", last, note)
+            }
+            (DiagramFormat::PlantUml, Line::Note(note)) => {
+                writeln!(out, "note over P0, P{} : {}", last, note)
+            }
+        }
+        .unwrap();
+    }
+    if format == DiagramFormat::PlantUml {
+        out.push_str("@enduml\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        Environment, TcpListener,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn ping_pong_events() -> Vec<LoggedEvent> {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4];
+                for _ in 0..3 {
+                    socket.read_exact(&mut buf).await.unwrap();
+                    socket.write_all(b"pong").await.unwrap();
+                }
+                // keep the connection open until the client has read the last response.
+                let _ = socket.read(&mut buf).await;
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            let mut buf = [0; 4];
+            for _ in 0..3 {
+                socket.write_all(b"ping").await.unwrap();
+                socket.read_exact(&mut buf).await.unwrap();
+            }
+        });
+        runtime.handle().events()
+    }
+
+    #[test]
+    /// Test that connections and writes are rendered as messages between participants.
+    fn mermaid() {
+        let diagram = render(&ping_pong_events(), DiagramFormat::Mermaid, 100);
+        assert!(diagram.starts_with("sequenceDiagram\n"));
+        // the client connects from the address of its cluster, to the listener at that address.
+        assert!(diagram.contains("participant P0 as 10.0.0.1:49152"));
+        assert!(diagram.contains("participant P1 as 10.0.0.1:9092"));
+        assert!(diagram.contains("P0-->>P1: [0.000ms] connect"));
+        assert_eq!(diagram.matches("P0->>P1").count(), 3);
+        assert_eq!(diagram.matches("P1->>P0").count(), 3);
+    }
+
+    #[test]
+    /// Test that messages beyond the cap are omitted.
+    fn capped() {
+        let diagram = render(&ping_pong_events(), DiagramFormat::PlantUml, 2);
+        assert!(diagram.starts_with("@startuml\n") && diagram.ends_with("@enduml\n"));
+        assert_eq!(diagram.matches(" : [").count(), 2);
+        assert!(diagram.contains("5 more messages omitted"));
+    }
+}
@@ -0,0 +1,282 @@
+//! Aggregate statistics of a run, counted from its events as they are recorded.
+use super::{FaultKind, MemoryStats, SimEvent};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, time};
+
+/// Totals describing the activity of a run so far, returned by
+/// `DeterministicRuntimeHandle::stats`. Comparing these across the seeds of a sweep exposes
+/// anomalous runs, such as seeds where no fault was injected or little data was exchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunStats {
+    /// Simulated time since the start of the run.
+    pub simulated: time::Duration,
+    /// Wall time since the runtime was created.
+    pub wall: time::Duration,
+    pub tasks_spawned: u64,
+    pub tasks_completed: u64,
+    /// Number of times any task was polled.
+    pub polls: u64,
+    /// Number of faults injected, by kind.
+    pub faults: BTreeMap<FaultKind, u64>,
+    pub connections_opened: u64,
+    /// Bytes written to in-memory connections.
+    pub bytes_written: u64,
+    /// Number of times simulated time was advanced towards the deadline of a pending timer.
+    pub timer_advances: u64,
+    /// Time spent in each phase marked with `DeterministicRuntimeHandle::phase`.
+    #[serde(default)]
+    pub phases: Vec<PhaseStats>,
+    /// Memory held by the simulated buffers and queues of each host. Aggregated stats hold the
+    /// most used and peak memory of each host across runs.
+    #[serde(default)]
+    pub memory: Vec<MemoryStats>,
+}
+
+/// Simulated and wall time spent in a phase of a run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PhaseStats {
+    pub name: String,
+    pub simulated: time::Duration,
+    pub wall: time::Duration,
+}
+
+impl PhaseStats {
+    /// Returns how many times faster than real time the phase was simulated.
+    pub fn compression(&self) -> f64 {
+        compression(self.simulated, self.wall)
+    }
+
+    /// Returns true if simulating the phase took longer than the simulated time it covers,
+    /// which usually points to a busy loop or tasks which never wait on a timer.
+    pub fn is_slower_than_real_time(&self) -> bool {
+        self.wall > self.simulated
+    }
+}
+
+fn compression(simulated: time::Duration, wall: time::Duration) -> f64 {
+    if wall == time::Duration::from_secs(0) {
+        f64::INFINITY
+    } else {
+        simulated.as_secs_f64() / wall.as_secs_f64()
+    }
+}
+
+impl RunStats {
+    /// Counts `event` in these totals.
+    pub(crate) fn count(&mut self, event: &SimEvent) {
+        match event {
+            SimEvent::TaskSpawned { .. } => self.tasks_spawned += 1,
+            SimEvent::TaskCompleted { .. } => self.tasks_completed += 1,
+            SimEvent::TaskPolled { .. } => self.polls += 1,
+            SimEvent::FaultInjected(fault) => *self.faults.entry(fault.kind).or_insert(0) += 1,
+            SimEvent::ConnectionOpened { .. } => self.connections_opened += 1,
+            SimEvent::BytesWritten { bytes, .. } => self.bytes_written += *bytes as u64,
+            SimEvent::TimeAdvanced { .. } => self.timer_advances += 1,
+            _ => {}
+        }
+    }
+
+    /// Adds the totals of `other` to these, for aggregating the stats of many runs.
+    pub(crate) fn merge(&mut self, other: &RunStats) {
+        self.simulated += other.simulated;
+        self.wall += other.wall;
+        self.tasks_spawned += other.tasks_spawned;
+        self.tasks_completed += other.tasks_completed;
+        self.polls += other.polls;
+        for (kind, count) in &other.faults {
+            *self.faults.entry(*kind).or_insert(0) += count;
+        }
+        self.connections_opened += other.connections_opened;
+        self.bytes_written += other.bytes_written;
+        self.timer_advances += other.timer_advances;
+        for phase in &other.phases {
+            match self
+                .phases
+                .iter_mut()
+                .find(|total| total.name == phase.name)
+            {
+                Some(total) => {
+                    total.simulated += phase.simulated;
+                    total.wall += phase.wall;
+                }
+                None => self.phases.push(phase.clone()),
+            }
+        }
+        for host in &other.memory {
+            match self.memory.iter_mut().find(|total| total.host == host.host) {
+                Some(total) => {
+                    total.used = total.used.max(host.used);
+                    total.peak = total.peak.max(host.peak);
+                }
+                None => self.memory.push(host.clone()),
+            }
+        }
+    }
+
+    /// Returns how many times faster than real time the run was simulated.
+    pub fn compression(&self) -> f64 {
+        compression(self.simulated, self.wall)
+    }
+
+    /// Returns the phases which took longer to simulate than the simulated time they cover.
+    pub fn slow_phases(&self) -> impl Iterator<Item = &PhaseStats> {
+        self.phases
+            .iter()
+            .filter(|phase| phase.is_slower_than_real_time())
+    }
+
+    /// Returns the total number of faults injected.
+    pub fn total_faults(&self) -> u64 {
+        self.faults.values().sum()
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "simulated {:?} in {:?} ({:.1}x), {} tasks spawned, {} completed, {} polls, {} timer advances, \
+             {} connections, {} bytes written, {} faults",
+            self.simulated,
+            self.wall,
+            self.compression(),
+            self.tasks_spawned,
+            self.tasks_completed,
+            self.polls,
+            self.timer_advances,
+            self.connections_opened,
+            self.bytes_written,
+            self.total_faults()
+        )?;
+        for (kind, count) in &self.faults {
+            write!(f, "\n  {:?}: {}", kind, count)?;
+        }
+        for phase in &self.phases {
+            write!(
+                f,
+                "\n  phase {:?}: simulated {:?} in {:?} ({:.1}x)",
+                phase.name,
+                phase.simulated,
+                phase.wall,
+                phase.compression()
+            )?;
+            if phase.is_slower_than_real_time() {
+                write!(f, ", slower than real time")?;
+            }
+        }
+        for host in &self.memory {
+            write!(
+                f,
+                "\n  memory of {}: {} bytes held, {} bytes at peak",
+                host.host, host.used, host.peak
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunStats;
+    use crate::{
+        deterministic::{DeterministicRuntime, EventRetention, FaultConfig},
+        metrics::Metrics,
+        Environment, TcpListener,
+    };
+    use std::{net, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that the stats of a run count its tasks, timers and network activity.
+    fn counts() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime
+            .handle()
+            .new_cluster(crate::deterministic::FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            handle.delay_from(Duration::from_secs(1)).await;
+        });
+        let stats = runtime.handle().stats();
+        assert_eq!(stats.tasks_spawned, 1);
+        assert_eq!(stats.tasks_completed, 1);
+        assert!(stats.polls >= 2);
+        assert_eq!(stats.connections_opened, 1);
+        assert_eq!(stats.bytes_written, 5);
+        assert!(stats.timer_advances >= 1);
+        assert_eq!(stats.simulated, Duration::from_secs(1));
+        assert_eq!(stats.total_faults(), 0);
+    }
+
+    /// Runs a connection exchanging data and recording a metric under `retention`, returning
+    /// the stats and metrics of the run.
+    fn counted(retention: EventRetention) -> (RunStats, Metrics) {
+        let mut runtime = DeterministicRuntime::builder()
+            .seed(7)
+            .event_retention(retention)
+            .build()
+            .unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+            let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+            for _ in 0..10 {
+                client.write_all(b"hello").await.unwrap();
+                let mut buf = [0; 5];
+                server.read_exact(&mut buf).await.unwrap();
+                crate::metrics::increment_counter("echoes", 1);
+                handle.delay_from(Duration::from_millis(100)).await;
+            }
+        });
+        let stats = runtime.handle().stats();
+        (stats, runtime.handle().metrics())
+    }
+
+    #[test]
+    /// Test that stats and metrics count every event, whichever events are kept.
+    fn retention() {
+        let (all, all_metrics) = counted(EventRetention::All);
+        assert_eq!(all.bytes_written, 50);
+        assert_eq!(all_metrics.counter("echoes"), 10);
+        for &retention in &[EventRetention::Last(3), EventRetention::Disabled] {
+            let (stats, metrics) = counted(retention);
+            assert_eq!(stats.polls, all.polls);
+            assert_eq!(stats.timer_advances, all.timer_advances);
+            assert_eq!(stats.bytes_written, all.bytes_written);
+            assert_eq!(stats.connections_opened, 1);
+            assert_eq!(stats.tasks_completed, all.tasks_completed);
+            assert_eq!(metrics, all_metrics);
+        }
+    }
+
+    #[test]
+    /// Test that phases are timed separately and busy phases are flagged as slow.
+    fn phases() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            handle.phase("sleep");
+            handle.delay_from(Duration::from_secs(60)).await;
+            handle.phase("busy");
+            std::thread::sleep(Duration::from_millis(5));
+        });
+        let stats = runtime.handle().stats();
+        let names: Vec<_> = stats.phases.iter().map(|phase| &phase.name[..]).collect();
+        assert_eq!(names, vec!["sleep", "busy"]);
+        assert_eq!(stats.phases[0].simulated, Duration::from_secs(60));
+        assert!(!stats.phases[0].is_slower_than_real_time());
+        assert_eq!(stats.phases[1].simulated, Duration::from_secs(0));
+        let slow: Vec<_> = stats.slow_phases().map(|phase| &phase.name[..]).collect();
+        assert_eq!(slow, vec!["busy"]);
+        assert!(stats.compression() > 1.0);
+        assert!(stats.to_string().contains("phase \"busy\""));
+    }
+}
@@ -0,0 +1,732 @@
+//! Run a simulation test across many seeds, collecting failures and assertion coverage.
+use super::{report::Artifact, rng, Builder, DeterministicRuntime, RunStats};
+use crate::{assertions::Observations, buggify::BuggifyPoint};
+use serde::{Deserialize, Serialize};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt, ops, panic, path,
+    sync::{self, atomic, mpsc},
+    thread,
+};
+
+/// A test failure observed while running a particular seed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedFailure {
+    /// Seed which caused the failure.
+    pub seed: u64,
+    /// Panic message of the failed run.
+    pub message: String,
+}
+
+impl SeedFailure {
+    /// Returns an identifier for the kind of failure, for grouping seeds which failed the same
+    /// way. Messages which differ only in their digits, such as addresses, counts or durations,
+    /// share a fingerprint.
+    pub fn fingerprint(&self) -> String {
+        let masked: String = self
+            .message
+            .chars()
+            .map(|c| if c.is_ascii_digit() { '#' } else { c })
+            .collect();
+        format!("{:016x}", rng::stable_hash(&masked))
+    }
+}
+
+/// Coverage of a `sometimes!` label aggregated over every seed of a sweep.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LabelCoverage {
+    /// Number of seeds in which the condition held at least once.
+    pub seeds_satisfied: u64,
+    /// Observations summed over all seeds.
+    pub observations: Observations,
+}
+
+/// Activity of a `buggify!` point aggregated over every seed of a sweep.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuggifyCoverage {
+    /// Number of seeds in which the point was enabled.
+    pub seeds_enabled: u64,
+    /// Number of seeds in which the point fired at least once.
+    pub seeds_fired: u64,
+    /// Number of times the point was evaluated, over all seeds.
+    pub evaluated: u64,
+    /// Number of times the point fired, over all seeds.
+    pub fired: u64,
+}
+
+/// Assertions and fault points evaluated during a single seed.
+struct SeedCoverage {
+    assertions: BTreeMap<&'static str, Observations>,
+    buggify: BTreeMap<&'static str, BuggifyPoint>,
+}
+
+/// Results of running a [`Sweep`].
+///
+/// [`Sweep`]: Sweep
+#[derive(Debug, Clone, Default)]
+pub struct SweepReport {
+    /// Number of seeds which were run.
+    pub seeds_run: u64,
+    /// Seeds which caused the test to fail.
+    pub failures: Vec<SeedFailure>,
+    /// Coverage for each `sometimes!` label evaluated during the sweep.
+    pub coverage: BTreeMap<&'static str, LabelCoverage>,
+    /// Activity of each `buggify!` point evaluated during the sweep.
+    pub buggify: BTreeMap<&'static str, BuggifyCoverage>,
+    /// Activity summed over every seed run.
+    pub stats: RunStats,
+}
+
+impl SweepReport {
+    /// Returns true if no seed failed.
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Returns the `sometimes!` labels which were evaluated but never satisfied by any seed.
+    pub fn unreached(&self) -> Vec<&'static str> {
+        self.coverage
+            .iter()
+            .filter(|(_, coverage)| coverage.seeds_satisfied == 0)
+            .map(|(label, _)| *label)
+            .collect()
+    }
+
+    /// Returns the `buggify!` points which were evaluated but never fired in any seed, so the
+    /// paths they guard were never exercised.
+    pub fn unfired(&self) -> Vec<&'static str> {
+        self.buggify
+            .iter()
+            .filter(|(_, coverage)| coverage.seeds_fired == 0)
+            .map(|(label, _)| *label)
+            .collect()
+    }
+
+    /// Returns the report in a form which can be written as JSON for CI pipelines.
+    pub fn summary(&self) -> SweepSummary {
+        SweepSummary {
+            seeds_run: self.seeds_run,
+            failures: self
+                .failures
+                .iter()
+                .map(|failure| FailureSummary {
+                    seed: failure.seed,
+                    message: failure.message.clone(),
+                    fingerprint: failure.fingerprint(),
+                })
+                .collect(),
+            coverage: self
+                .coverage
+                .iter()
+                .map(|(label, coverage)| {
+                    let summary = CoverageSummary {
+                        seeds_satisfied: coverage.seeds_satisfied,
+                        evaluated: coverage.observations.evaluated,
+                        satisfied: coverage.observations.satisfied,
+                    };
+                    (label.to_string(), summary)
+                })
+                .collect(),
+            unreached: self
+                .unreached()
+                .iter()
+                .map(|label| label.to_string())
+                .collect(),
+            buggify: self
+                .buggify
+                .iter()
+                .map(|(label, coverage)| (label.to_string(), *coverage))
+                .collect(),
+            unfired: self
+                .unfired()
+                .iter()
+                .map(|label| label.to_string())
+                .collect(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Folds the outcome of a seed into the report.
+    fn record(&mut self, result: &SeedResult, coverage: SeedCoverage) {
+        self.seeds_run += 1;
+        self.stats.merge(&result.stats);
+        self.record_coverage(coverage.assertions);
+        self.record_buggify(coverage.buggify);
+        if let Some(message) = &result.failure {
+            self.failures.push(SeedFailure {
+                seed: result.seed,
+                message: message.clone(),
+            });
+        }
+    }
+
+    fn record_coverage(&mut self, observed: BTreeMap<&'static str, Observations>) {
+        for (label, observations) in observed {
+            let coverage = self.coverage.entry(label).or_default();
+            coverage.observations.evaluated += observations.evaluated;
+            coverage.observations.satisfied += observations.satisfied;
+            if observations.satisfied > 0 {
+                coverage.seeds_satisfied += 1;
+            }
+        }
+    }
+
+    fn record_buggify(&mut self, points: BTreeMap<&'static str, BuggifyPoint>) {
+        for (label, point) in points {
+            let coverage = self.buggify.entry(label).or_default();
+            coverage.evaluated += point.evaluated;
+            coverage.fired += point.fired;
+            if point.enabled {
+                coverage.seeds_enabled += 1;
+            }
+            if point.fired > 0 {
+                coverage.seeds_fired += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Display for SweepReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} seeds run, {} failed",
+            self.seeds_run,
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            writeln!(f, "  seed {} failed: {}", failure.seed, failure.message)?;
+        }
+        for label in self.unreached() {
+            writeln!(f, "  sometimes! label never satisfied: {:?}", label)?;
+        }
+        for label in self.unfired() {
+            writeln!(f, "  buggify! point never fired: {:?}", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// A failing seed in a `SweepSummary`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureSummary {
+    pub seed: u64,
+    pub message: String,
+    /// See `SeedFailure::fingerprint`.
+    pub fingerprint: String,
+}
+
+/// Coverage of a `sometimes!` label in a `SweepSummary`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageSummary {
+    /// Number of seeds in which the condition held at least once.
+    pub seeds_satisfied: u64,
+    /// Number of times the assertion was evaluated, over all seeds.
+    pub evaluated: u64,
+    /// Number of times the condition held, over all seeds.
+    pub satisfied: u64,
+}
+
+/// Machine-readable form of a `SweepReport`, for CI pipelines to file issues for new failure
+/// fingerprints and track flakiness and coverage over time. Written by a sweep configured with
+/// `Sweep::json_report`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SweepSummary {
+    pub seeds_run: u64,
+    pub failures: Vec<FailureSummary>,
+    pub coverage: BTreeMap<String, CoverageSummary>,
+    /// Labels which were evaluated but never satisfied by any seed.
+    pub unreached: Vec<String>,
+    /// Activity of each `buggify!` point, empty in summaries written before it was recorded.
+    #[serde(default)]
+    pub buggify: BTreeMap<String, BuggifyCoverage>,
+    /// `buggify!` points which were evaluated but never fired in any seed.
+    #[serde(default)]
+    pub unfired: Vec<String>,
+    /// Activity summed over every seed run.
+    pub stats: RunStats,
+}
+
+impl Artifact for SweepSummary {
+    const KIND: &'static str = "sweep_summary";
+}
+
+/// Runs a test once for every seed in a range.
+///
+/// ```rust
+/// use simulation::deterministic::Sweep;
+///
+/// let report = Sweep::new(0..10).run(|runtime| {
+///     let handle = runtime.handle();
+///     runtime.block_on(async move {
+///         simulation::sometimes!(handle.seed() % 2 == 0, "even seed");
+///     });
+/// });
+/// assert!(report.is_success());
+/// assert!(report.unreached().is_empty());
+/// ```
+#[derive(Clone)]
+pub struct Sweep {
+    seeds: ops::Range<u64>,
+    /// Number of threads used by `run_parallel`, or `None` for one per core.
+    workers: Option<usize>,
+    /// Where to write the `SweepSummary` once the sweep completes.
+    json_report: Option<path::PathBuf>,
+    /// Returns the builder each worker builds the runtime of every seed it runs from.
+    builder: sync::Arc<dyn Fn() -> Builder + Send + Sync>,
+}
+
+impl fmt::Debug for Sweep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sweep")
+            .field("seeds", &self.seeds)
+            .field("workers", &self.workers)
+            .field("json_report", &self.json_report)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Outcome of a single seed of a sweep, passed to the observer of `run_with` and
+/// `run_parallel_with` as seeds complete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedResult {
+    pub seed: u64,
+    /// Panic message if the seed failed.
+    pub failure: Option<String>,
+    /// Activity of the run, for spotting seeds which behaved unlike the others.
+    pub stats: super::RunStats,
+}
+
+/// Progress of a sweep, passed to the observer of `run_with` and `run_parallel_with` each time
+/// a seed completes.
+#[derive(Debug, Clone, Copy)]
+pub struct SweepProgress<'a> {
+    /// Number of seeds in the sweep.
+    pub total: u64,
+    /// Outcome of the seed which just completed.
+    pub result: &'a SeedResult,
+    /// Partial report covering every seed completed so far, including `result`.
+    pub report: &'a SweepReport,
+}
+
+impl SweepProgress<'_> {
+    /// Returns the number of seeds completed so far.
+    pub fn completed(&self) -> u64 {
+        self.report.seeds_run
+    }
+
+    /// Returns the number of completed seeds which failed.
+    pub fn failed(&self) -> usize {
+        self.report.failures.len()
+    }
+
+    /// Returns the fraction of the sweep completed so far, between 0 and 1.
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.completed() as f64 / self.total as f64
+        }
+    }
+}
+
+impl fmt::Display for SweepProgress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}/{}] seed {} {}, {} failed so far",
+            self.completed(),
+            self.total,
+            self.result.seed,
+            if self.result.failure.is_some() {
+                "failed"
+            } else {
+                "passed"
+            },
+            self.failed()
+        )?;
+        let unreached = self.report.unreached().len();
+        if unreached > 0 {
+            write!(f, ", {} sometimes! labels unreached", unreached)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sweep {
+    pub fn new(seeds: ops::Range<u64>) -> Self {
+        Self {
+            seeds,
+            workers: None,
+            json_report: None,
+            builder: sync::Arc::new(Builder::new),
+        }
+    }
+
+    /// Sets the function returning the `Builder` the runtime of each seed is built from, such
+    /// as to set its `FaultConfig` or `EventRetention`. The function is called once by each
+    /// worker, which then builds a runtime for every seed it runs from the same builder, with
+    /// the seed replaced. Seeds are not announced, as failing seeds are listed in the report.
+    pub fn builder<B>(mut self, builder: B) -> Self
+    where
+        B: Fn() -> Builder + Send + Sync + 'static,
+    {
+        self.builder = sync::Arc::new(builder);
+        self
+    }
+
+    /// Sets the number of threads `run_parallel` runs seeds on. Defaults to the number of
+    /// cores.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers.max(1));
+        self
+    }
+
+    /// Writes the `SweepSummary` of the sweep to `path` as JSON once every seed has run. The run
+    /// panics if the report cannot be written.
+    pub fn json_report<P: Into<path::PathBuf>>(mut self, path: P) -> Self {
+        self.json_report = Some(path.into());
+        self
+    }
+
+    /// Runs `test` with a fresh `DeterministicRuntime` for each seed, built from the `builder`
+    /// of the sweep. Panics are caught and reported as failures of the corresponding seed.
+    pub fn run<F>(&self, test: F) -> SweepReport
+    where
+        F: Fn(&mut DeterministicRuntime),
+    {
+        self.run_with(test, |_| {})
+    }
+
+    /// Runs seeds like `run`, calling `on_seed` with the progress of the sweep as each seed
+    /// completes, so long sweeps can report partial results while they run.
+    pub fn run_with<F, O>(&self, test: F, mut on_seed: O) -> SweepReport
+    where
+        F: Fn(&mut DeterministicRuntime),
+        O: FnMut(&SweepProgress<'_>),
+    {
+        let mut report = SweepReport::default();
+        let builder = self.worker_builder();
+        for seed in self.seeds.clone() {
+            let (result, coverage) = run_seed(&builder, seed, &test);
+            report.record(&result, coverage);
+            on_seed(&self.progress(&result, &report));
+        }
+        self.finish(report)
+    }
+
+    /// Writes the JSON report of the sweep, panicking if it cannot be written so that CI
+    /// relying on it fails rather than finding it missing.
+    fn finish(&self, report: SweepReport) -> SweepReport {
+        if let Some(path) = &self.json_report {
+            if let Err(error) = report.summary().save(path) {
+                panic!("failed to write sweep report to {:?}: {}", path, error);
+            }
+        }
+        report
+    }
+
+    /// Returns the builder a worker builds the runtime of each seed from.
+    fn worker_builder(&self) -> Builder {
+        (self.builder)().announce_seed(false)
+    }
+
+    fn progress<'a>(&self, result: &'a SeedResult, report: &'a SweepReport) -> SweepProgress<'a> {
+        SweepProgress {
+            total: self.seeds.end.saturating_sub(self.seeds.start),
+            result,
+            report,
+        }
+    }
+}
+
+impl Sweep {
+    /// Runs `test` for each seed like `run`, spreading seeds over worker threads. Each worker
+    /// pulls the next unclaimed seed and runs it on a runtime built from its own `Builder`, so a sweep
+    /// keeps every core busy even when some seeds take far longer than others. A seed produces
+    /// the same run regardless of the worker it lands on.
+    pub fn run_parallel<F>(&self, test: F) -> SweepReport
+    where
+        F: Fn(&mut DeterministicRuntime) + Sync,
+    {
+        self.run_parallel_with(test, |_| {})
+    }
+
+    /// Runs seeds like `run_parallel`, calling `on_seed` on the calling thread with the
+    /// progress of the sweep as each seed completes. Seeds complete in no particular order, so
+    /// the partial report seen by `on_seed` lists failures in completion order, but failures in
+    /// the returned report are ordered by seed.
+    pub fn run_parallel_with<F, O>(&self, test: F, mut on_seed: O) -> SweepReport
+    where
+        F: Fn(&mut DeterministicRuntime) + Sync,
+        O: FnMut(&SweepProgress<'_>),
+    {
+        let workers = self
+            .workers
+            .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+        let next = atomic::AtomicU64::new(self.seeds.start);
+        let end = self.seeds.end;
+        let (tx, rx) = mpsc::channel();
+        let mut report = SweepReport::default();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+                let (next, test) = (&next, &test);
+                scope.spawn(move || {
+                    let builder = self.worker_builder();
+                    loop {
+                        let seed = next.fetch_add(1, atomic::Ordering::Relaxed);
+                        if seed >= end {
+                            return;
+                        }
+                        if tx.send(run_seed(&builder, seed, test)).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+            for (result, coverage) in rx {
+                report.record(&result, coverage);
+                on_seed(&self.progress(&result, &report));
+            }
+        });
+        report.failures.sort_by_key(|failure| failure.seed);
+        self.finish(report)
+    }
+}
+
+/// Runs `test` for `seed` on a fresh runtime built from `builder`, returning its outcome along
+/// with the `sometimes!` assertions and `buggify!` points it evaluated.
+fn run_seed<F>(builder: &Builder, seed: u64, test: &F) -> (SeedResult, SeedCoverage)
+where
+    F: Fn(&mut DeterministicRuntime),
+{
+    let mut runtime = builder
+        .build_seed(seed)
+        .unwrap_or_else(|error| panic!("failed to build runtime for seed {}: {}", seed, error));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| test(&mut runtime)));
+    let result = SeedResult {
+        seed,
+        failure: result.err().map(|payload| panic_message(&*payload)),
+        stats: runtime.handle().stats(),
+    };
+    let coverage = SeedCoverage {
+        assertions: runtime.coverage().snapshot(),
+        buggify: runtime.handle().buggify_points(),
+    };
+    (result, coverage)
+}
+
+/// Extracts the message from a panic payload.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("<non-string panic payload>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that failing seeds are reported and the sweep continues past them.
+    fn failures() {
+        let report = Sweep::new(0..4).run(|runtime| {
+            let seed = runtime.handle().seed();
+            runtime.block_on(async move {
+                crate::always!(seed != 2, "seed is not two");
+            })
+        });
+        assert_eq!(report.seeds_run, 4);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].seed, 2);
+        assert!(report.failures[0].message.contains("seed is not two"));
+    }
+
+    #[test]
+    /// Test that a parallel sweep reports the same failures and coverage as a sequential one.
+    fn parallel() {
+        let test = |runtime: &mut DeterministicRuntime| {
+            let handle = runtime.handle();
+            runtime.block_on(async move {
+                let draw = crate::Environment::rng(&handle).gen_range(0..8u64);
+                crate::sometimes!(draw == 0, "drew zero");
+                crate::always!(draw != 7, "drew seven");
+            })
+        };
+        let sequential = Sweep::new(0..64).run(test);
+        let mut streamed = vec![];
+        let parallel = Sweep::new(0..64)
+            .workers(4)
+            .run_parallel_with(test, |progress| streamed.push(progress.result.seed));
+        streamed.sort_unstable();
+        assert_eq!(streamed, (0..64).collect::<Vec<_>>());
+        assert_eq!(parallel.seeds_run, 64);
+        assert_eq!(parallel.failures, sequential.failures);
+        assert_eq!(parallel.coverage, sequential.coverage);
+        assert!(!parallel.is_success());
+    }
+
+    #[test]
+    /// Test that each worker builds the runtimes of its seeds from one builder returned by the
+    /// factory of the sweep.
+    fn builder() {
+        let built = sync::Arc::new(atomic::AtomicUsize::new(0));
+        let calls = sync::Arc::clone(&built);
+        let report = Sweep::new(0..16)
+            .workers(2)
+            .builder(move || {
+                calls.fetch_add(1, atomic::Ordering::Relaxed);
+                Builder::new().event_retention(crate::deterministic::EventRetention::Disabled)
+            })
+            .run_parallel(|runtime| {
+                let handle = runtime.handle();
+                runtime.block_on(async {});
+                assert!(handle.events().is_empty());
+                assert!(handle.stats().tasks_spawned > 0);
+            });
+        assert!(report.is_success());
+        assert_eq!(report.seeds_run, 16);
+        assert_eq!(built.load(atomic::Ordering::Relaxed), 2);
+        let indices = sync::Arc::new(sync::Mutex::new(vec![]));
+        let observed = sync::Arc::clone(&indices);
+        Sweep::new(3..6)
+            .builder(move || {
+                let observed = sync::Arc::clone(&observed);
+                Builder::new().seed(100).observer(move |logged| {
+                    observed.lock().unwrap().push(logged.index);
+                })
+            })
+            .run(|runtime| {
+                let seed = runtime.handle().seed();
+                assert!((3..6).contains(&seed));
+                runtime.block_on(async {});
+            });
+        // the observer sees every run, each numbering its events from 0.
+        let runs = indices
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|index| **index == 0)
+            .count();
+        assert_eq!(runs, 3);
+    }
+
+    #[test]
+    /// Test that the observer sees the running failure count and coverage after every seed.
+    fn progress() {
+        let mut seen = vec![];
+        let report = Sweep::new(0..4).run_with(
+            |runtime| {
+                let seed = runtime.handle().seed();
+                runtime.block_on(async move {
+                    crate::sometimes!(seed == 3, "last seed");
+                    crate::always!(seed != 1, "seed is not one");
+                })
+            },
+            |progress| {
+                seen.push((
+                    progress.completed(),
+                    progress.failed(),
+                    progress.report.unreached().len(),
+                ));
+                assert_eq!(progress.total, 4);
+            },
+        );
+        assert_eq!(seen, vec![(1, 0, 1), (2, 1, 1), (3, 1, 1), (4, 1, 0)]);
+        assert_eq!(report.failures.len(), 1);
+    }
+
+    #[test]
+    /// Test that the JSON report of a sweep round trips and fingerprints failures by kind.
+    fn json_report() {
+        let path = std::env::temp_dir().join("simulation-sweep-json-report.json");
+        let report = Sweep::new(0..6).json_report(&path).run(|runtime| {
+            let seed = runtime.handle().seed();
+            runtime.block_on(async move {
+                crate::sometimes!(seed > 10, "large seed");
+                assert!(seed % 3 != 0, "seed {} is a multiple of three", seed);
+            })
+        });
+        let summary = SweepSummary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary, report.summary());
+        assert_eq!(summary.seeds_run, 6);
+        assert_eq!(summary.unreached, vec!["large seed"]);
+        assert_eq!(summary.coverage["large seed"].evaluated, 6);
+        assert_eq!(summary.stats.tasks_spawned, 6);
+        let seeds: Vec<_> = summary.failures.iter().map(|f| f.seed).collect();
+        assert_eq!(seeds, vec![0, 3]);
+        assert_eq!(
+            summary.failures[0].fingerprint,
+            summary.failures[1].fingerprint
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to write sweep report to")]
+    /// Test that a sweep whose JSON report cannot be written fails.
+    fn json_report_unwritable() {
+        let path = std::env::temp_dir()
+            .join("simulation-sweep-missing-dir")
+            .join("report.json");
+        Sweep::new(0..1).json_report(path).run(|_| {});
+    }
+
+    #[test]
+    /// Test that `sometimes!` labels which are never satisfied are reported as unreached.
+    fn coverage() {
+        let report = Sweep::new(0..4).run(|runtime| {
+            let seed = runtime.handle().seed();
+            runtime.block_on(async move {
+                crate::sometimes!(seed == 3, "last seed");
+                crate::sometimes!(seed > 10, "large seed");
+            })
+        });
+        assert!(report.is_success());
+        assert_eq!(report.unreached(), vec!["large seed"]);
+        let last_seed = report.coverage["last seed"];
+        assert_eq!(last_seed.seeds_satisfied, 1);
+        assert_eq!(last_seed.observations.evaluated, 4);
+    }
+
+    #[test]
+    /// Test that the seeds in which each `buggify!` point was enabled and fired are counted,
+    /// and points which never fired are reported.
+    fn buggify() {
+        let report = Sweep::new(0..16).run(|runtime| {
+            runtime.block_on(async {
+                for _ in 0..100 {
+                    crate::buggify!("often");
+                }
+            })
+        });
+        let often = report.buggify["often"];
+        assert_eq!(often.evaluated, 1600);
+        assert!(often.seeds_enabled > 0 && often.seeds_enabled < 16);
+        assert_eq!(often.seeds_fired, often.seeds_enabled);
+        assert!(often.fired > often.seeds_fired);
+        assert!(report.unfired().is_empty());
+        let summary = report.summary();
+        assert_eq!(summary.buggify["often"], often);
+        assert_eq!(report.to_string(), "16 seeds run, 0 failed\n");
+        let report = Sweep::new(0..4).run(|runtime| {
+            runtime.block_on(async {
+                crate::buggify!("once");
+            })
+        });
+        assert_eq!(report.buggify["once"].evaluated, 4);
+        assert_eq!(report.unfired(), vec!["once"]);
+        assert_eq!(report.summary().unfired, vec!["once"]);
+        assert!(report
+            .to_string()
+            .contains("buggify! point never fired: \"once\""));
+    }
+}
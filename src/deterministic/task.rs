@@ -0,0 +1,505 @@
+//! Tracking of tasks spawned onto the deterministic runtime.
+use super::event::{EventLog, SimEvent};
+use futures::Poll;
+use pin_project::{pin_project, pinned_drop};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    fmt,
+    future::Future,
+    net,
+    pin::Pin,
+    sync::{self, atomic},
+    task::Context,
+    time,
+};
+
+/// Identifies a task spawned onto a `DeterministicRuntime`. Tasks are numbered in the order
+/// they are spawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TaskId(pub u64);
+
+/// A simulated resource which a task was waiting on when it last returned `Pending`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blocker {
+    /// Accepting a connection on the listener bound to `addr`.
+    Accept { addr: net::SocketAddr },
+    /// Reading from the end of a connection at `local`, connected to `peer`.
+    Read {
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+    },
+    /// Writing to the end of a connection at `local`, connected to `peer`.
+    Write {
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+    },
+    /// A timer firing at `deadline`, in simulated time since the start of the run.
+    Timer { deadline: time::Duration },
+}
+
+impl fmt::Display for Blocker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Blocker::Accept { addr } => write!(f, "accept on {}", addr),
+            Blocker::Read { local, peer } => write!(f, "read on {} from {}", local, peer),
+            Blocker::Write { local, peer } => write!(f, "write on {} to {}", local, peer),
+            Blocker::Timer { deadline } => write!(f, "timer at {:?}", deadline),
+        }
+    }
+}
+
+/// Information about a task which has not yet completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    /// Name given to the task with `DeterministicRuntimeHandle::spawn_named`.
+    pub name: Option<String>,
+    /// Simulated time since the start of the run at which the task was spawned.
+    pub spawned_at: time::Duration,
+    /// Simulated time since the start of the run at which the task was last polled, or `None`
+    /// if it has never been polled.
+    pub polled_at: Option<time::Duration>,
+    /// Number of times the task has been polled.
+    pub polls: u64,
+    /// The resource the task was waiting on when it was last polled, if known.
+    pub blocker: Option<Blocker>,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task {}", self.id.0)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, " spawned at {:?}", self.spawned_at)?;
+        match self.polled_at {
+            Some(polled_at) => write!(f, ", last polled at {:?}", polled_at)?,
+            None => write!(f, ", never polled")?,
+        }
+        if let Some(blocker) = &self.blocker {
+            write!(f, ", waiting on {}", blocker)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every live task of a runtime, returned by `DeterministicRuntime::task_dump`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskDump {
+    /// Simulated time since the start of the run at which the dump was taken.
+    pub elapsed: time::Duration,
+    pub tasks: Vec<TaskInfo>,
+}
+
+impl fmt::Display for TaskDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} live tasks at {:?}", self.tasks.len(), self.elapsed)?;
+        for task in &self.tasks {
+            write!(f, "\n  {}", task)?;
+            if let Some(polled_at) = task.polled_at {
+                write!(f, ", pending for {:?}", self.elapsed - polled_at)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bookkeeping for a live task, shared with the task so that polls are counted without
+/// looking the task up.
+#[derive(Debug)]
+struct Live {
+    name: Option<String>,
+    /// Key of the task's random stream, see `Tasks::stream`.
+    stream: u64,
+    spawned_at: time::Duration,
+    polls: atomic::AtomicU64,
+    /// When the task was last polled, and what it was waiting on once the poll returned.
+    waiting: sync::Mutex<(Option<time::Duration>, Option<Blocker>)>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    next_id: u64,
+    live: BTreeMap<TaskId, sync::Arc<Live>>,
+    /// Number of tasks spawned with each name, or without one, by the stream of their parent.
+    spawned: HashMap<(u64, Option<String>), u64>,
+    /// The first task which panicked while being polled, and the host it was running on.
+    panicked: Option<(TaskId, Option<String>)>,
+    /// Real time a poll may take before the task is reported as blocking the executor.
+    blocking_threshold: Option<time::Duration>,
+    /// Whether a blocking poll fails the run, rather than being logged.
+    fail_on_blocking: bool,
+    /// Number of events which may be recorded before the run fails.
+    max_events: Option<u64>,
+}
+
+/// Registry of live tasks.
+#[derive(Debug, Clone)]
+pub(crate) struct Tasks {
+    events: EventLog,
+    now: super::time::Now,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<TaskId>> = const { Cell::new(None) };
+    /// Resource the current poll is waiting on, set by the resource as it returns `Pending`.
+    static BLOCKER: RefCell<Option<Blocker>> = const { RefCell::new(None) };
+}
+
+/// Records that the task currently being polled is waiting on `blocker`.
+pub(crate) fn set_blocker(blocker: Blocker) {
+    if current().is_some() {
+        BLOCKER.with(|current| *current.borrow_mut() = Some(blocker));
+    }
+}
+
+/// Returns the id of the task currently being polled on this thread.
+pub(crate) fn current() -> Option<TaskId> {
+    CURRENT.with(|current| current.get())
+}
+
+impl Tasks {
+    pub(crate) fn new(events: EventLog, now: super::time::Now) -> Self {
+        let inner = Inner {
+            next_id: 0,
+            live: BTreeMap::new(),
+            spawned: HashMap::new(),
+            panicked: None,
+            blocking_threshold: None,
+            fail_on_blocking: false,
+            max_events: None,
+        };
+        Self {
+            events,
+            now,
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Registers a new task, wrapping `future` so that its polls are tracked.
+    pub(crate) fn track<F>(&self, future: F) -> Task<F> {
+        self.track_named(None, future)
+    }
+
+    /// Registers a new task named `name`, wrapping `future` so that its polls are tracked.
+    pub(crate) fn track_named<F>(&self, name: Option<String>, future: F) -> Task<F> {
+        let (id, live) = {
+            let mut lock = self.inner.lock().unwrap();
+            let parent = current()
+                .and_then(|parent| lock.live.get(&parent))
+                .map_or(0, |parent| parent.stream);
+            let spawned = lock.spawned.entry((parent, name.clone())).or_insert(0);
+            let stream = super::rng::stable_hash(&(parent, &name, *spawned));
+            *spawned += 1;
+            let live = sync::Arc::new(Live {
+                name,
+                stream,
+                spawned_at: self.now.elapsed(),
+                polls: atomic::AtomicU64::new(0),
+                waiting: sync::Mutex::new((None, None)),
+            });
+            let id = TaskId(lock.next_id);
+            lock.next_id += 1;
+            lock.live.insert(id, sync::Arc::clone(&live));
+            (id, live)
+        };
+        self.events.record(SimEvent::TaskSpawned { task: id });
+        Task {
+            id,
+            live,
+            tasks: self.clone(),
+            future,
+        }
+    }
+
+    /// Returns the key of the random stream of the live task `id`. Keys are derived from the
+    /// key of the task which spawned it, its name, and the number of tasks spawned before it
+    /// by the same parent with the same name. Reordering the spawns of differently named tasks
+    /// therefore leaves their streams unchanged, as does spawning tasks anywhere else.
+    pub(crate) fn stream(&self, id: TaskId) -> Option<u64> {
+        let lock = self.inner.lock().unwrap();
+        lock.live.get(&id).map(|live| live.stream)
+    }
+
+    /// Returns every task which has not yet completed.
+    pub(crate) fn live(&self) -> Vec<TaskInfo> {
+        let lock = self.inner.lock().unwrap();
+        lock.live
+            .iter()
+            .map(|(id, live)| {
+                let (polled_at, blocker) = live.waiting.lock().unwrap().clone();
+                TaskInfo {
+                    id: *id,
+                    name: live.name.clone(),
+                    spawned_at: live.spawned_at,
+                    polled_at,
+                    polls: live.polls.load(atomic::Ordering::Relaxed),
+                    blocker,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the first task which panicked while being polled, and the host it was running
+    /// on.
+    pub(crate) fn panicked(&self) -> Option<(TaskId, Option<String>)> {
+        self.inner.lock().unwrap().panicked.clone()
+    }
+
+    /// Sets the real time a poll may take before the task is reported as blocking the
+    /// executor, and whether doing so fails the run.
+    pub(crate) fn set_blocking_threshold(&self, threshold: Option<time::Duration>, fail: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.blocking_threshold = threshold;
+        lock.fail_on_blocking = fail;
+    }
+
+    /// Sets the number of events which may be recorded before the run fails.
+    pub(crate) fn set_max_events(&self, max_events: Option<u64>) {
+        self.inner.lock().unwrap().max_events = max_events;
+    }
+
+    /// Fails the run if more events were recorded than its budget allows, describing where the
+    /// events went. Checked before each poll, so no lock of the runtime is held.
+    fn check_budget(&self) {
+        let budget = match self.inner.lock().unwrap().max_events {
+            Some(budget) if self.events.len() > budget => budget,
+            _ => return,
+        };
+        let categories = self.events.categories();
+        // completed tasks are not counted, but a run exceeding its budget is usually spinning in
+        // a task which is still live.
+        let mut busiest = self.live();
+        busiest.sort_by_key(|info| (std::cmp::Reverse(info.polls), info.id));
+        let mut message = format!(
+            "event budget of {} exceeded after {:?} of simulated time; events by category: {:?}; \
+             most polled tasks:",
+            budget,
+            self.now.elapsed(),
+            categories
+        );
+        for info in busiest.iter().take(3) {
+            message.push_str(&format!(" task {}", info.id.0));
+            if let Some(name) = &info.name {
+                message.push_str(&format!(" ({})", name));
+            }
+            message.push_str(&format!(" {} polls,", info.polls));
+        }
+        message.pop();
+        panic!("{}", message);
+    }
+
+    /// Reports the task `id` if its poll held the executor thread for longer than the blocking
+    /// threshold. Such polls make no progress in simulated time, and usually come from blocking
+    /// calls such as `std::thread::sleep` or synchronous IO.
+    fn check_blocking(&self, id: TaskId, live: &Live, wall: time::Duration) {
+        let fail = match &*self.inner.lock().unwrap() {
+            Inner {
+                blocking_threshold: Some(threshold),
+                fail_on_blocking,
+                ..
+            } if wall > *threshold => *fail_on_blocking,
+            _ => return,
+        };
+        self.events.record(SimEvent::TaskBlocked { task: id, wall });
+        let name = match &live.name {
+            Some(name) => format!("task {} ({})", id.0, name),
+            None => format!("task {}", id.0),
+        };
+        let message = format!(
+            "{} blocked the executor for {:?} of real time without yielding",
+            name, wall
+        );
+        if fail {
+            panic!("{}", message);
+        }
+        log::warn!("{}", message);
+    }
+
+    fn remove(&self, id: TaskId) -> bool {
+        self.inner.lock().unwrap().live.remove(&id).is_some()
+    }
+}
+
+/// A future tracked by `Tasks`.
+#[pin_project(PinnedDrop)]
+pub(crate) struct Task<F> {
+    id: TaskId,
+    live: sync::Arc<Live>,
+    tasks: Tasks,
+    #[pin]
+    future: F,
+}
+
+impl<F: Future> Future for Task<F> {
+    type Output = F::Output;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        this.tasks.check_budget();
+        this.live.polls.fetch_add(1, atomic::Ordering::Relaxed);
+        // polls are the most frequent event, so they are only recorded if something sees them.
+        if !this.tasks.events.skip_poll() {
+            this.tasks
+                .events
+                .record(SimEvent::TaskPolled { task: *this.id });
+        }
+        let _current = CurrentGuard::enter(*this.id, this.tasks);
+        let started = time::Instant::now();
+        let result = this.future.poll(cx);
+        this.tasks
+            .check_blocking(*this.id, this.live, started.elapsed());
+        if result.is_ready() {
+            if this.tasks.remove(*this.id) {
+                this.tasks
+                    .events
+                    .record(SimEvent::TaskCompleted { task: *this.id });
+            }
+            return result;
+        }
+        let now = this.tasks.now.elapsed();
+        let blocker = BLOCKER.with(|current| current.borrow_mut().take());
+        let mut waiting = this.live.waiting.lock().unwrap();
+        // timers are only seen as they are created, so a task still waiting on a timer which has
+        // not fired keeps it as its blocker when no other resource was polled.
+        let blocker = blocker.or_else(|| match waiting.1.take() {
+            Some(Blocker::Timer { deadline }) if deadline > now => {
+                Some(Blocker::Timer { deadline })
+            }
+            _ => None,
+        });
+        *waiting = (Some(now), blocker);
+        result
+    }
+}
+
+/// Sets the current task for the duration of a poll, recording the task and its host if the
+/// poll panics.
+struct CurrentGuard<'a> {
+    id: TaskId,
+    tasks: &'a Tasks,
+    prev: Option<TaskId>,
+    prev_host: Option<String>,
+}
+
+impl<'a> CurrentGuard<'a> {
+    fn enter(id: TaskId, tasks: &'a Tasks) -> Self {
+        let prev = CURRENT.with(|current| current.replace(Some(id)));
+        BLOCKER.with(|current| current.borrow_mut().take());
+        let prev_host = crate::logger::current_host();
+        Self {
+            id,
+            tasks,
+            prev,
+            prev_host,
+        }
+    }
+}
+
+impl Drop for CurrentGuard<'_> {
+    fn drop(&mut self) {
+        // a panicking poll leaves the host of the task installed.
+        let host = crate::logger::replace_host(self.prev_host.take());
+        if std::thread::panicking() {
+            if let Ok(mut lock) = self.tasks.inner.lock() {
+                lock.panicked.get_or_insert((self.id, host));
+            }
+        }
+        CURRENT.with(|current| current.set(self.prev));
+    }
+}
+
+#[pinned_drop]
+impl<F> PinnedDrop for Task<F> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        this.tasks.remove(*this.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment, Error};
+
+    /// Returns a runtime reporting polls which take longer than 20ms, spawning a task named
+    /// "sleeper" which blocks the executor thread and a task which does not.
+    fn runtime(fail: bool) -> DeterministicRuntime {
+        let mut builder =
+            DeterministicRuntime::builder().blocking_threshold(time::Duration::from_millis(20));
+        if fail {
+            builder = builder.fail_on_blocking();
+        }
+        let runtime = builder.build().unwrap();
+        let handle = runtime.handle();
+        handle.spawn_named("sleeper", async {
+            std::thread::sleep(time::Duration::from_millis(50));
+        });
+        handle.spawn_named("quick", async {});
+        runtime
+    }
+
+    #[test]
+    /// Test that only a poll holding the executor past the threshold is reported.
+    fn blocking() {
+        let mut runtime = runtime(false);
+        runtime.run().unwrap();
+        let blocked: Vec<_> = runtime
+            .handle()
+            .events()
+            .into_iter()
+            .filter_map(|logged| match logged.event {
+                SimEvent::TaskBlocked { task, wall } => Some((task, wall)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].0, TaskId(0));
+        assert!(blocked[0].1 >= time::Duration::from_millis(50));
+    }
+
+    #[test]
+    /// Test that a blocking poll fails the run if configured to, naming the task.
+    fn fail_on_blocking() {
+        let mut runtime = runtime(true);
+        let handle = runtime.handle();
+        let result = runtime.try_block_on(async {
+            handle.delay_from(time::Duration::from_secs(1)).await;
+        });
+        match result {
+            Err(Error::Panicked { message, .. }) => {
+                assert!(message.contains("task 0 (sleeper) blocked the executor"))
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    /// Test that exceeding the event budget fails the run, naming the task which spun.
+    fn event_budget() {
+        let mut runtime = DeterministicRuntime::builder()
+            .max_events(1000)
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let result = runtime.try_block_on(async {
+            handle.spawn_named(
+                "spinner",
+                futures::future::poll_fn(|cx| {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }),
+            );
+            handle.delay_from(time::Duration::from_secs(1)).await;
+        });
+        match result {
+            Err(Error::Panicked { message, .. }) => {
+                assert!(message.starts_with("event budget of 1000 exceeded after 0ns"));
+                assert!(message.ends_with("task 1 (spinner) 499 polls"));
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+}
@@ -1,13 +1,38 @@
 //! A mock source of time, allowing for determinstic control of the progress
 //! of time.
-use std::{sync, time};
+use super::{
+    context,
+    event::{EventLog, SimEvent},
+    fault::{ClockAnomaly, ClockSkew},
+    PhaseStats,
+};
+use futures::Poll;
+use pin_project::pin_project;
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{self, atomic},
+    task::Context,
+    time,
+};
 
 #[derive(Debug)]
 struct State {
     /// Time basis for which mock time is derived.
     base: time::Instant,
+    /// Wall clock time at the start of the run.
+    epoch: time::SystemTime,
     /// The amount of mock time which has elapsed.
     advance: time::Duration,
+    /// The amount of mock time which may elapse before the runtime panics.
+    limit: Option<time::Duration>,
+    /// Set when the executor parked with no task woken and no timer pending.
+    deadlocked: bool,
+    /// Number of `FreezeTime` futures running, while which time does not advance.
+    frozen: usize,
+    /// Name, mock time and wall time at the start of each phase marked so far.
+    phases: Vec<(String, time::Duration, time::Duration)>,
 }
 
 impl State {
@@ -20,6 +45,10 @@ impl State {
     }
 }
 
+/// Seconds since the unix epoch of the wall clock at the start of a run unless configured
+/// otherwise, 2020-01-01T00:00:00Z.
+const DEFAULT_EPOCH_SECS: u64 = 1_577_836_800;
+
 /// A mock source of time, providing deterministic control of time.
 #[derive(Debug, Clone)]
 pub(crate) struct Time {
@@ -30,7 +59,12 @@ impl Default for Time {
     fn default() -> Self {
         let state = State {
             base: time::Instant::now(),
+            epoch: time::UNIX_EPOCH + time::Duration::from_secs(DEFAULT_EPOCH_SECS),
             advance: time::Duration::from_millis(0),
+            limit: None,
+            deadlocked: false,
+            frozen: 0,
+            phases: vec![],
         };
         Self {
             inner: sync::Arc::new(sync::Mutex::new(state)),
@@ -50,6 +84,64 @@ impl Time {
     pub(crate) fn now(&self) -> time::Instant {
         self.inner.lock().unwrap().now()
     }
+    /// Return the amount of mock time which has elapsed.
+    pub(crate) fn elapsed(&self) -> time::Duration {
+        self.inner.lock().unwrap().advance
+    }
+    /// Returns the simulated wall clock time, which advances along with mock time.
+    pub(crate) fn system_time(&self) -> time::SystemTime {
+        let lock = self.inner.lock().unwrap();
+        lock.epoch + lock.advance
+    }
+    /// Sets the simulated wall clock time at the start of the run.
+    pub(crate) fn set_epoch(&self, epoch: time::SystemTime) {
+        self.inner.lock().unwrap().epoch = epoch;
+    }
+    /// Return the wall time which has passed since this time source was created.
+    pub(crate) fn wall_elapsed(&self) -> time::Duration {
+        self.inner.lock().unwrap().base.elapsed()
+    }
+    /// Ends the current phase, if any, and starts a phase named `name`.
+    pub(crate) fn start_phase(&self, name: &str) {
+        let mut lock = self.inner.lock().unwrap();
+        let (advance, wall) = (lock.advance, lock.base.elapsed());
+        lock.phases.push((name.to_string(), advance, wall));
+    }
+    /// Returns the mock and wall time spent in each phase, the last phase lasting until now.
+    pub(crate) fn phases(&self) -> Vec<PhaseStats> {
+        let lock = self.inner.lock().unwrap();
+        let now = (lock.advance, lock.base.elapsed());
+        lock.phases
+            .iter()
+            .enumerate()
+            .map(|(i, (name, simulated, wall))| {
+                let end = lock.phases.get(i + 1).map_or(now, |next| (next.1, next.2));
+                PhaseStats {
+                    name: name.clone(),
+                    simulated: end.0 - *simulated,
+                    wall: end.1 - *wall,
+                }
+            })
+            .collect()
+    }
+    /// Sets the amount of mock time which may elapse before the runtime panics.
+    pub(crate) fn set_limit(&self, limit: Option<time::Duration>) {
+        self.inner.lock().unwrap().limit = limit;
+    }
+
+    /// Stops time from advancing until the returned guard is dropped.
+    fn freeze(&self) -> Frozen {
+        self.inner.lock().unwrap().frozen += 1;
+        Frozen {
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+
+    /// Returns whether the executor has parked with no task woken and no timer pending since
+    /// the last call, in which case no task can make progress.
+    pub(crate) fn take_deadlocked(&self) -> bool {
+        std::mem::replace(&mut self.inner.lock().unwrap().deadlocked, false)
+    }
 
     /// Creates an instance of `Now` from this deterministic time source.
     ///
@@ -68,11 +160,11 @@ impl Time {
     /// advances the determinstic time source on `Park::park_with_timeout`.
     ///
     /// [`Park`]:[tokio_executor::park::Park]
-    pub(crate) fn wrap_park<P>(&self, park: P) -> Park<P>
+    pub(crate) fn wrap_park<P>(&self, park: P, events: EventLog) -> Park<P>
     where
         P: tokio_executor::park::Park,
     {
-        Park::wrap(sync::Arc::clone(&self.inner), park)
+        Park::wrap(sync::Arc::clone(&self.inner), park, events)
     }
 }
 
@@ -89,6 +181,11 @@ impl Now {
     fn new(state: sync::Arc<sync::Mutex<State>>) -> Self {
         Self { inner: state }
     }
+
+    /// Return the amount of mock time which has elapsed.
+    pub(crate) fn elapsed(&self) -> time::Duration {
+        self.inner.lock().unwrap().advance
+    }
 }
 
 impl tokio_timer::clock::Now for Now {
@@ -109,32 +206,297 @@ impl tokio_timer::timer::Now for Now {
 pub(crate) struct Park<P> {
     inner: sync::Arc<sync::Mutex<State>>,
     inner_park: P,
+    events: EventLog,
+    /// Set when a task is woken, time is not advanced until the woken task has been polled.
+    unparked: sync::Arc<atomic::AtomicBool>,
 }
 
 impl<P> Park<P> {
-    fn wrap(state: sync::Arc<sync::Mutex<State>>, park: P) -> Self {
+    fn wrap(state: sync::Arc<sync::Mutex<State>>, park: P, events: EventLog) -> Self {
         Self {
             inner: state,
             inner_park: park,
+            events,
+            unparked: sync::Arc::new(atomic::AtomicBool::new(false)),
         }
     }
 }
 
+/// `Unpark` handle recording that the executor has work to do.
+#[derive(Debug)]
+pub(crate) struct Unpark<U> {
+    inner: U,
+    unparked: sync::Arc<atomic::AtomicBool>,
+}
+
+impl<U> tokio_executor::park::Unpark for Unpark<U>
+where
+    U: tokio_executor::park::Unpark,
+{
+    fn unpark(&self) {
+        self.unparked.store(true, atomic::Ordering::SeqCst);
+        self.inner.unpark()
+    }
+}
+
 impl<P> tokio_executor::park::Park for Park<P>
 where
     P: tokio_executor::park::Park,
 {
-    type Unpark = P::Unpark;
+    type Unpark = Unpark<P::Unpark>;
     type Error = P::Error;
     fn unpark(&self) -> Self::Unpark {
-        self.inner_park.unpark()
+        Unpark {
+            inner: self.inner_park.unpark(),
+            unparked: sync::Arc::clone(&self.unparked),
+        }
     }
     fn park(&mut self) -> Result<(), Self::Error> {
-        self.inner_park.park()
+        // the timer only parks without a timeout when no timer is pending, so unless a task was
+        // woken nothing can ever wake the executor. Rather than blocking forever, the deadlock
+        // is flagged for the runtime to report.
+        if !self.unparked.swap(false, atomic::Ordering::SeqCst) {
+            self.inner.lock().unwrap().deadlocked = true;
+        }
+        self.inner_park.park_timeout(time::Duration::from_millis(0))
     }
     fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
-        let mut lock = self.inner.lock().unwrap();
-        lock.advance(duration);
+        // a task was woken since the last park, so the executor is not yet idle.
+        let duration = if self.unparked.swap(false, atomic::Ordering::SeqCst) {
+            time::Duration::from_millis(0)
+        } else {
+            duration
+        };
+        {
+            let mut lock = self.inner.lock().unwrap();
+            if lock.frozen > 0 && duration > time::Duration::from_millis(0) {
+                drop(lock);
+                panic!("simulated time is frozen, but no task can make progress until it advances");
+            }
+            lock.advance(duration);
+            if let Some(limit) = lock.limit.filter(|limit| lock.advance > *limit) {
+                drop(lock);
+                panic!("simulated time limit of {:?} exceeded", limit);
+            }
+        }
+        if duration > time::Duration::from_millis(0) {
+            self.events.record(SimEvent::TimeAdvanced { by: duration });
+        }
         self.inner_park.park_timeout(time::Duration::from_millis(0))
     }
 }
+
+/// The wall clock of a host, which may be skewed from simulated time and drift from it by
+/// whole seconds as clock anomalies are injected.
+#[derive(Debug, Default)]
+pub(crate) struct WallClock {
+    /// The skew of the clock, or `None` if it has not been drawn yet.
+    skew: Option<Option<ClockSkew>>,
+    /// Seconds the clock is ahead of simulated time, or behind if negative.
+    offset: i64,
+    /// The latest time read from the clock.
+    last: Option<time::SystemTime>,
+}
+
+impl WallClock {
+    /// Returns whether the skew of the clock has been drawn.
+    pub(crate) fn has_skew(&self) -> bool {
+        self.skew.is_some()
+    }
+
+    /// Sets the skew of the clock, which is applied to every later read.
+    pub(crate) fn set_skew(&mut self, skew: Option<ClockSkew>) {
+        self.skew = Some(skew);
+    }
+
+    /// Reads the clock at the simulated wall clock time `wall`, first applying `anomaly`.
+    pub(crate) fn read(
+        &mut self,
+        wall: time::SystemTime,
+        anomaly: Option<ClockAnomaly>,
+    ) -> time::SystemTime {
+        let wall = match self.skew {
+            Some(Some(skew)) => skew.apply(wall),
+            _ => wall,
+        };
+        let backwards = match anomaly {
+            Some(ClockAnomaly::Skip) => {
+                self.offset += 1;
+                false
+            }
+            Some(ClockAnomaly::Repeat { backwards }) => {
+                self.offset -= 1;
+                backwards
+            }
+            None => false,
+        };
+        let offset = time::Duration::from_secs(self.offset.unsigned_abs());
+        let mut now = if self.offset >= 0 {
+            wall + offset
+        } else {
+            wall - offset
+        };
+        // unless the clock was just moved backwards, reads never decrease.
+        if let Some(last) = self.last.filter(|last| *last > now && !backwards) {
+            now = last;
+        }
+        self.last = Some(now);
+        now
+    }
+}
+
+/// Guard holding time frozen, returned by `Time::freeze`.
+#[derive(Debug)]
+struct Frozen {
+    inner: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().frozen -= 1;
+    }
+}
+
+/// Future returned by `Environment::freeze_time`. Under the `DeterministicRuntime`, simulated
+/// time does not advance from when it is first polled until it completes or is dropped, so
+/// every read of the clock in between returns the same instant. If every task ends up waiting
+/// for time to advance while it is frozen, the runtime panics. Outside of the deterministic
+/// runtime the future is polled unchanged.
+#[pin_project]
+pub struct FreezeTime<F> {
+    #[pin]
+    future: F,
+    frozen: Option<Frozen>,
+}
+
+impl<F> fmt::Debug for FreezeTime<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FreezeTime")
+            .field("frozen", &self.frozen.is_some())
+            .finish()
+    }
+}
+
+impl<F> FreezeTime<F> {
+    pub(crate) fn new(future: F) -> Self {
+        Self {
+            future,
+            frozen: None,
+        }
+    }
+}
+
+impl<F: Future> Future for FreezeTime<F> {
+    type Output = F::Output;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if this.frozen.is_none() {
+            *this.frozen = context::current().map(|handle| handle.time.freeze());
+        }
+        let output = futures::ready!(this.future.poll(cx));
+        *this.frozen = None;
+        Poll::Ready(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DEFAULT_EPOCH_SECS;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig, FaultKind},
+        Environment,
+    };
+    use futures::channel::oneshot;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    /// Test that time does not advance while a frozen future runs, even once a timer of another
+    /// task is due, and advances again once it completes.
+    fn freeze() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let start = handle.now();
+            let env = handle.clone();
+            handle.spawn(async move {
+                env.delay_from(Duration::from_millis(1)).await;
+            });
+            let frozen = handle.freeze_time(async {
+                for i in 0..10 {
+                    let (tx, rx) = oneshot::channel();
+                    handle.spawn(async move {
+                        let _ = tx.send(i);
+                    });
+                    assert_eq!(rx.await.unwrap(), i);
+                    assert_eq!(handle.now(), start);
+                }
+            });
+            frozen.await;
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(handle.now() - start, Duration::from_secs(1));
+        });
+    }
+
+    #[test]
+    /// Test that waiting for a timer while time is frozen fails the run.
+    fn frozen_timer() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let result = runtime.try_block_on(async {
+            let delay = handle.delay_from(Duration::from_secs(1));
+            handle.freeze_time(delay).await;
+        });
+        match result {
+            Err(crate::Error::Panicked { message, .. }) => {
+                assert!(message.contains("simulated time is frozen"))
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+
+    /// Reads the wall clock every 100ms for 100s, returning the times read along with the
+    /// number of skipped and repeated seconds.
+    fn read_clock(backwards: bool) -> (Vec<SystemTime>, usize, usize) {
+        let mut runtime = DeterministicRuntime::builder()
+            .seed(7)
+            .fault_config(FaultConfig {
+                clock_anomaly_prob: 0.01,
+                clock_backwards: backwards,
+                ..FaultConfig::disabled()
+            })
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let reads = runtime.block_on(async {
+            let mut reads = vec![];
+            for _ in 0..1000 {
+                reads.push(handle.system_time());
+                handle.delay_from(Duration::from_millis(100)).await;
+            }
+            reads
+        });
+        let faults = runtime.faults();
+        let count = |kind| faults.iter().filter(|f| f.kind == kind).count();
+        (
+            reads,
+            count(FaultKind::ClockSkip),
+            count(FaultKind::ClockRepeat),
+        )
+    }
+
+    #[test]
+    /// Test that injected clock anomalies shift the wall clock by a second, which only moves
+    /// backwards if configured to.
+    fn clock_anomalies() {
+        let (reads, skips, repeats) = read_clock(false);
+        assert!(skips > 0 && repeats > 0);
+        assert!(reads.windows(2).all(|pair| pair[0] <= pair[1]));
+        let start = UNIX_EPOCH + Duration::from_secs(DEFAULT_EPOCH_SECS);
+        let expected = start + Duration::from_millis(999 * 100) + Duration::from_secs(skips as u64);
+        assert_eq!(reads[999], expected - Duration::from_secs(repeats as u64));
+
+        let (reads, skips, repeats) = read_clock(true);
+        assert!(skips > 0 && repeats > 0);
+        assert!(reads.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+}
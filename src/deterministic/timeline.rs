@@ -0,0 +1,207 @@
+//! Timeline of a single connection, gathered from the event log of a run.
+//!
+//! A connection is identified by the addresses of its two ends. Client ends hold an ephemeral
+//! port of their cluster until they are dropped, so the addresses are unique among open
+//! connections, but may be reused by a later connection.
+use super::{report::Artifact, FaultKind, FaultRecord, LoggedEvent, SimEvent};
+use serde::{Deserialize, Serialize};
+use std::{fmt, net, time};
+
+/// Something which happened to a connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ConnectionEvent {
+    Opened,
+    /// `bytes` were written by the client, to be read by the server.
+    ClientWrote {
+        bytes: usize,
+    },
+    /// `bytes` were written by the server, to be read by the client.
+    ServerWrote {
+        bytes: usize,
+    },
+    /// A delay or disconnect was injected into the connection.
+    Fault(FaultRecord),
+    ClientClosed,
+    ServerClosed,
+}
+
+/// A `ConnectionEvent` along with when it occurred.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    /// Position of the event in the event log of the run.
+    pub index: u64,
+    /// Simulated time since the start of the run at which the event occurred.
+    pub elapsed: time::Duration,
+    pub event: ConnectionEvent,
+}
+
+/// Every event of a connection, in the order they occurred. If the addresses of the connection
+/// were reused by a later connection, the events of both are included, each starting with
+/// `ConnectionEvent::Opened`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionTimeline {
+    pub client: net::SocketAddr,
+    pub server: net::SocketAddr,
+    pub entries: Vec<TimelineEntry>,
+}
+
+impl Artifact for ConnectionTimeline {
+    const KIND: &'static str = "connection_timeline";
+}
+
+impl ConnectionTimeline {
+    /// Gathers the timeline of the connection between `a` and `b` from `events`. The addresses
+    /// may be given in either order, such as the local and peer address of either end.
+    pub(crate) fn from_events(
+        events: &[LoggedEvent],
+        a: net::SocketAddr,
+        b: net::SocketAddr,
+    ) -> Self {
+        let (client, server) = events
+            .iter()
+            .find_map(|logged| match logged.event {
+                SimEvent::ConnectionOpened { client, server, .. }
+                    if (client, server) == (a, b) || (client, server) == (b, a) =>
+                {
+                    Some((client, server))
+                }
+                _ => None,
+            })
+            .unwrap_or((a, b));
+        let entries = events
+            .iter()
+            .filter_map(|logged| {
+                let event = match &logged.event {
+                    SimEvent::ConnectionOpened {
+                        client: c,
+                        server: s,
+                        ..
+                    } if (*c, *s) == (client, server) => ConnectionEvent::Opened,
+                    SimEvent::BytesWritten {
+                        from, to, bytes, ..
+                    } if (*from, *to) == (client, server) => {
+                        ConnectionEvent::ClientWrote { bytes: *bytes }
+                    }
+                    SimEvent::BytesWritten {
+                        from, to, bytes, ..
+                    } if (*from, *to) == (server, client) => {
+                        ConnectionEvent::ServerWrote { bytes: *bytes }
+                    }
+                    SimEvent::FaultInjected(fault)
+                        if fault.connection == Some((client, server)) =>
+                    {
+                        ConnectionEvent::Fault(fault.clone())
+                    }
+                    SimEvent::ConnectionClosed { from, to, .. }
+                        if (*from, *to) == (client, server) =>
+                    {
+                        ConnectionEvent::ClientClosed
+                    }
+                    SimEvent::ConnectionClosed { from, to, .. }
+                        if (*from, *to) == (server, client) =>
+                    {
+                        ConnectionEvent::ServerClosed
+                    }
+                    _ => return None,
+                };
+                Some(TimelineEntry {
+                    index: logged.index,
+                    elapsed: logged.elapsed,
+                    event,
+                })
+            })
+            .collect();
+        ConnectionTimeline {
+            client,
+            server,
+            entries,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionTimeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection {} -> {}", self.client, self.server)?;
+        for entry in &self.entries {
+            write!(f, "\n  [{:?}] #{} ", entry.elapsed, entry.index)?;
+            match &entry.event {
+                ConnectionEvent::Opened => write!(f, "opened")?,
+                ConnectionEvent::ClientWrote { bytes } => {
+                    write!(f, "client wrote {} bytes", bytes)?
+                }
+                ConnectionEvent::ServerWrote { bytes } => {
+                    write!(f, "server wrote {} bytes", bytes)?
+                }
+                ConnectionEvent::Fault(fault) => match fault.kind {
+                    FaultKind::Disconnect => write!(f, "disconnected (fault {})", fault.id.0)?,
+                    kind => write!(f, "{:?} (fault {})", kind, fault.id.0)?,
+                },
+                ConnectionEvent::ClientClosed => write!(f, "client closed")?,
+                ConnectionEvent::ServerClosed => write!(f, "server closed")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        Environment, TcpListener,
+    };
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that the timeline of a connection includes only its own traffic and injected faults.
+    fn timeline() {
+        let mut runtime = DeterministicRuntime::new_with_seed(5).unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig {
+            socket_read_delay_prob: 1.0,
+            socket_read_delay: Duration::from_millis(10)..Duration::from_millis(20),
+            ..FaultConfig::disabled()
+        });
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let (client, other) = runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let mut ends = vec![];
+            for _ in 0..2 {
+                let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+                let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+                client.write_all(b"ping").await.unwrap();
+                let mut buf = [0; 4];
+                server.read_exact(&mut buf).await.unwrap();
+                server.write_all(b"pong!").await.unwrap();
+                ends.push((client.local_addr(), server.local_addr()));
+            }
+            (ends[0], ends[1])
+        });
+        assert_ne!(client, other);
+        let timeline = runtime.handle().connection_timeline(client.1, client.0);
+        assert_eq!((timeline.client, timeline.server), client);
+        let events: Vec<_> = timeline
+            .entries
+            .iter()
+            .map(|entry| &entry.event)
+            .filter(|event| !matches!(event, ConnectionEvent::Fault(_)))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                &ConnectionEvent::Opened,
+                &ConnectionEvent::ClientWrote { bytes: 4 },
+                &ConnectionEvent::ServerWrote { bytes: 5 },
+                &ConnectionEvent::ServerClosed,
+                &ConnectionEvent::ClientClosed,
+            ]
+        );
+        assert!(timeline
+            .entries
+            .iter()
+            .any(|entry| matches!(entry.event, ConnectionEvent::Fault(_))));
+        let json = timeline.to_json().unwrap();
+        assert_eq!(ConnectionTimeline::from_json(&json).unwrap(), timeline);
+    }
+}
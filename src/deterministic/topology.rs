@@ -0,0 +1,127 @@
+//! Graphviz rendering of the simulated network.
+//!
+//! Each cluster becomes a node listing its listeners and open connections, and each link an
+//! edge labelled with the delays and disconnects injected into connections made over it.
+//! Clusters which can not reach each other in either direction are joined by a dashed
+//! "partitioned" edge, which makes a missing link stand out.
+use super::{network::ClusterTopology, FaultConfig};
+use std::{collections::HashMap, fmt::Write, ops, time};
+
+fn delay(name: &str, range: &ops::Range<time::Duration>, prob: f64) -> String {
+    if prob > 0.0 {
+        format!("{} {:?}..{:?} p={}", name, range.start, range.end, prob)
+    } else {
+        format!("{} none", name)
+    }
+}
+
+/// Describes the delays and disconnects injected according to `config`, one per line.
+fn faults(config: &FaultConfig) -> Vec<String> {
+    vec![
+        delay(
+            "connect",
+            &config.listener_connection_delay,
+            config.listener_connection_delay_prob,
+        ),
+        delay(
+            "read",
+            &config.socket_read_delay,
+            config.socket_read_delay_prob,
+        ),
+        delay(
+            "write",
+            &config.socket_write_delay,
+            config.socket_write_delay_prob,
+        ),
+        format!("disconnect p={}", config.disconnect_prob),
+    ]
+}
+
+/// Escapes `label` for a quoted DOT string, joining lines with DOT line breaks.
+fn label(lines: &[String]) -> String {
+    lines
+        .iter()
+        .map(|line| line.replace('\\', "\\\\").replace('"', "\\\""))
+        .collect::<Vec<_>>()
+        .join("\\n")
+}
+
+/// Renders `clusters` as a DOT digraph, naming each cluster by `names` and titling the graph
+/// with the simulated time `elapsed`.
+pub(crate) fn render(
+    clusters: &[ClusterTopology],
+    names: &HashMap<usize, String>,
+    elapsed: time::Duration,
+) -> String {
+    let name = |index: usize| {
+        names
+            .get(&index)
+            .cloned()
+            .unwrap_or_else(|| format!("cluster-{}", index))
+    };
+    let mut out = String::new();
+    writeln!(out, "digraph topology {{").unwrap();
+    writeln!(out, "  label=\"simulated time {:?}\";", elapsed).unwrap();
+    writeln!(out, "  node [shape=box];").unwrap();
+    for cluster in clusters {
+        let mut lines = vec![name(cluster.index)];
+        for addr in &cluster.listeners {
+            lines.push(format!("listener {}", addr));
+        }
+        lines.push(format!("{} open connections", cluster.connections));
+        lines.extend(faults(&cluster.config));
+        writeln!(out, "  c{} [label=\"{}\"];", cluster.index, label(&lines)).unwrap();
+    }
+    for cluster in clusters {
+        for (target, config) in &cluster.links {
+            writeln!(
+                out,
+                "  c{} -> c{} [label=\"{}\"];",
+                cluster.index,
+                target,
+                label(&faults(config))
+            )
+            .unwrap();
+        }
+    }
+    let linked = |from: &ClusterTopology, to: usize| from.links.iter().any(|(t, _)| *t == to);
+    for (i, a) in clusters.iter().enumerate() {
+        for b in &clusters[i + 1..] {
+            if !linked(a, b.index) && !linked(b, a.index) {
+                writeln!(
+                    out,
+                    "  c{} -> c{} [style=dashed, color=red, dir=none, label=\"partitioned\"];",
+                    a.index, b.index
+                )
+                .unwrap();
+            }
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{DeterministicRuntime, FaultConfig};
+
+    #[test]
+    /// Test that clusters, links and missing links are rendered.
+    fn clusters_and_links() {
+        let runtime = DeterministicRuntime::builder()
+            .cluster("east", FaultConfig::disabled())
+            .cluster("west", FaultConfig::disabled())
+            .cluster("north", FaultConfig::disabled())
+            .link("east", "west", FaultConfig::default())
+            .build()
+            .unwrap();
+        let dot = runtime.topology_dot();
+        assert!(dot.starts_with("digraph topology {\n"));
+        assert!(dot.contains("c0 [label=\"localhost\\n0 open connections"));
+        let east = dot.find("[label=\"east").unwrap();
+        assert!(dot[..east].ends_with("c1 "));
+        assert!(dot.contains("c1 -> c2 [label=\"connect 0ns..10s p=0.1"));
+        assert!(dot.contains("c1 -> c3 [style=dashed"));
+        assert!(!dot.contains("c1 -> c2 [style=dashed"));
+    }
+}
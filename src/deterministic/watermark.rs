@@ -0,0 +1,169 @@
+//! Alerts raised when a queue grows past a configured depth.
+//!
+//! Queues which are never drained fast enough, such as the backlog of a listener nobody
+//! accepts from or a channel without backpressure, grow without bound in production. Under
+//! simulation they usually stay small enough to go unnoticed, so a watermark turns the first
+//! time a queue crosses a depth into a `SimEvent::WatermarkExceeded`, optionally failing the
+//! run at that point.
+use super::{context, SimEvent};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A kind of queue monitored by `Watermarks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum QueueKind {
+    /// Connections made to a listener which have not been accepted.
+    AcceptBacklog,
+    /// Bytes written to a connection which have not been read by the peer.
+    SocketBuffer,
+    /// Values sent on a `sync::mpsc` channel which have not been received.
+    Channel,
+}
+
+/// Depths at which each kind of queue raises an alert, set with `Builder::watermarks`.
+/// Queues without a watermark are not monitored.
+///
+/// ```
+/// # use simulation::deterministic::{DeterministicRuntime, Watermarks};
+/// let runtime = DeterministicRuntime::builder()
+///     .watermarks(Watermarks {
+///         accept_backlog: Some(16),
+///         channel: Some(1024),
+///         fail: true,
+///         ..Watermarks::default()
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Number of connections waiting to be accepted by a listener.
+    pub accept_backlog: Option<usize>,
+    /// Number of bytes buffered in one direction of a connection.
+    pub socket_buffer: Option<usize>,
+    /// Number of values buffered in a channel.
+    pub channel: Option<usize>,
+    /// Whether exceeding a watermark panics, failing the run, rather than only being recorded.
+    pub fail: bool,
+}
+
+impl Watermarks {
+    fn get(&self, queue: QueueKind) -> Option<usize> {
+        match queue {
+            QueueKind::AcceptBacklog => self.accept_backlog,
+            QueueKind::SocketBuffer => self.socket_buffer,
+            QueueKind::Channel => self.channel,
+        }
+    }
+
+    /// Raises an alert if the depth of `queue`, identified by `name`, grew from `before` to
+    /// past its watermark.
+    pub(crate) fn check(
+        &self,
+        queue: QueueKind,
+        name: &dyn fmt::Display,
+        before: usize,
+        after: usize,
+    ) {
+        let watermark = match self.get(queue) {
+            Some(watermark) if before <= watermark && after > watermark => watermark,
+            _ => return,
+        };
+        let event = SimEvent::WatermarkExceeded {
+            queue,
+            name: name.to_string(),
+            depth: after,
+            watermark,
+        };
+        let message = format!(
+            "{:?} {} grew to {}, past its watermark of {}",
+            queue, name, after, watermark
+        );
+        context::record(event);
+        if self.fail {
+            panic!("{}", message);
+        }
+        log::warn!("{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        Environment,
+    };
+    use std::net;
+
+    #[test]
+    /// Test that a growing channel and accept backlog each raise a single alert.
+    fn alerts() {
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .watermarks(Watermarks {
+                accept_backlog: Some(2),
+                channel: Some(3),
+                ..Watermarks::default()
+            })
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let (mut tx, _rx) = crate::sync::mpsc::unbounded_channel();
+            for i in 0..10 {
+                tx.send(i).await.unwrap();
+            }
+            let _listener = handle.bind(addr).await.unwrap();
+            let mut clients = vec![];
+            for _ in 0..4 {
+                clients.push(handle.connect(addr).await.unwrap());
+            }
+        });
+        let alerts: Vec<_> = runtime
+            .handle()
+            .events()
+            .into_iter()
+            .filter_map(|logged| match logged.event {
+                SimEvent::WatermarkExceeded {
+                    queue, name, depth, ..
+                } => Some((queue, name, depth)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].0, QueueKind::Channel);
+        assert!(alerts[0].1.contains("watermark.rs"));
+        assert_eq!(alerts[0].2, 4);
+        assert_eq!(
+            (alerts[1].0, &alerts[1].1[..], alerts[1].2),
+            (QueueKind::AcceptBacklog, "127.0.0.1:9092", 3)
+        );
+    }
+
+    #[test]
+    /// Test that exceeding a watermark fails the run if configured to.
+    fn fail() {
+        let mut runtime = DeterministicRuntime::builder()
+            .watermarks(Watermarks {
+                channel: Some(1),
+                fail: true,
+                ..Watermarks::default()
+            })
+            .build()
+            .unwrap();
+        let result = runtime.try_block_on(async {
+            let (mut tx, _rx) = crate::sync::mpsc::channel(8);
+            for i in 0..2 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        match result {
+            Err(crate::Error::Panicked { message, .. }) => {
+                assert!(message.contains("past its watermark of 1"))
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Differential execution of a scenario on the deterministic and real runtimes.
+//!
+//! A scenario generic over `Environment` is run once per seed on the `DeterministicRuntime`
+//! and on the `SingleThreadedRuntime` using real time and networking. Observable results which
+//! only occur on one backend indicate that the simulation has diverged from reality, or that
+//! the real run did not exercise a behavior found by the simulation.
+use crate::{
+    deterministic::DeterministicRuntime, singlethread::SingleThreadedRuntime, Environment,
+};
+use async_trait::async_trait;
+use std::{fmt, ops};
+
+/// A test body which can be run against any `Environment`.
+#[async_trait]
+pub trait Scenario: Sync {
+    /// The observable result of running the scenario.
+    type Output: fmt::Debug + Clone + PartialEq + Send;
+    async fn run<E: Environment>(&self, env: E) -> Self::Output;
+}
+
+/// Outputs observed while running a scenario on both backends.
+#[derive(Debug, Clone)]
+pub struct DifferentialReport<T> {
+    /// Distinct outputs of the simulated runs, paired with the seeds which produced them.
+    pub simulated: Vec<(T, Vec<u64>)>,
+    /// Distinct outputs of the real runs.
+    pub real: Vec<T>,
+}
+
+impl<T> DifferentialReport<T>
+where
+    T: PartialEq,
+{
+    /// Outputs produced by the real runtime which no seed reproduced in simulation.
+    pub fn real_only(&self) -> Vec<&T> {
+        self.real
+            .iter()
+            .filter(|output| !self.simulated.iter().any(|(sim, _)| sim == *output))
+            .collect()
+    }
+
+    /// Outputs produced in simulation which were never observed on the real runtime, along
+    /// with the seeds which produced them.
+    pub fn simulated_only(&self) -> Vec<&(T, Vec<u64>)> {
+        self.simulated
+            .iter()
+            .filter(|(output, _)| !self.real.contains(output))
+            .collect()
+    }
+
+    /// Returns true if both backends produced the same set of outputs.
+    pub fn is_consistent(&self) -> bool {
+        self.real_only().is_empty() && self.simulated_only().is_empty()
+    }
+}
+
+/// Runs a `Scenario` on both runtimes.
+#[derive(Debug, Clone)]
+pub struct Differential {
+    seeds: ops::Range<u64>,
+    real_runs: usize,
+}
+
+impl Differential {
+    /// Returns a new `Differential` running the deterministic runtime with each of `seeds`
+    /// and the real runtime once.
+    pub fn new(seeds: ops::Range<u64>) -> Self {
+        Self {
+            seeds,
+            real_runs: 1,
+        }
+    }
+
+    /// Sets the number of times the scenario is run on the real runtime.
+    pub fn real_runs(mut self, runs: usize) -> Self {
+        self.real_runs = runs;
+        self
+    }
+
+    pub fn run<S: Scenario>(&self, scenario: &S) -> DifferentialReport<S::Output> {
+        let mut report = DifferentialReport {
+            simulated: vec![],
+            real: vec![],
+        };
+        for seed in self.seeds.clone() {
+            let mut runtime =
+                DeterministicRuntime::new_with_seed(seed).expect("failed to build runtime");
+            let handle = runtime.handle();
+            let output = runtime.block_on(scenario.run(handle));
+            match report.simulated.iter_mut().find(|(o, _)| *o == output) {
+                Some((_, seeds)) => seeds.push(seed),
+                None => report.simulated.push((output, vec![seed])),
+            }
+        }
+        for _ in 0..self.real_runs {
+            let mut runtime = SingleThreadedRuntime::new().expect("failed to build runtime");
+            let handle = runtime.handle();
+            let output = runtime.block_on(scenario.run(handle));
+            if !report.real.contains(&output) {
+                report.real.push(output);
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TcpListener;
+    use std::{net, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    struct Echo;
+
+    #[async_trait]
+    impl Scenario for Echo {
+        type Output = String;
+        async fn run<E: Environment>(&self, env: E) -> String {
+            let addr: net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let mut listener = env.bind(addr).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            env.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    socket.write_all(b"hello").await.unwrap();
+                }
+            });
+            let mut socket = env.connect(addr).await.unwrap();
+            let mut buf = [0; 5];
+            socket.read_exact(&mut buf).await.unwrap();
+            String::from_utf8(buf.to_vec()).unwrap()
+        }
+    }
+
+    struct ExactDelay;
+
+    #[async_trait]
+    impl Scenario for ExactDelay {
+        type Output = bool;
+        async fn run<E: Environment>(&self, env: E) -> bool {
+            let start = env.now();
+            env.delay_from(Duration::from_millis(10)).await;
+            env.now() - start == Duration::from_millis(10)
+        }
+    }
+
+    #[test]
+    /// Test that a scenario with the same behavior on both backends is consistent.
+    fn consistent() {
+        let report = Differential::new(0..5).run(&Echo);
+        assert!(report.is_consistent(), "{:?}", report);
+        assert_eq!(report.simulated[0].1, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    /// Test that behaviors which only occur in simulation are flagged.
+    fn divergent() {
+        let report = Differential::new(0..2).run(&ExactDelay);
+        assert!(!report.is_consistent());
+        assert_eq!(report.simulated_only(), vec![&(true, vec![0, 1])]);
+        assert_eq!(report.real_only(), vec![&false]);
+    }
+}
@@ -0,0 +1,323 @@
+//! Object-safe wrappers over `Environment`.
+//!
+//! `Environment` has associated types and generic methods, so it cannot be used as a trait
+//! object and code holding one must be generic over it. `DynEnvironment` erases the type of an
+//! environment behind a box, with streams and listeners boxed as `Box<dyn TcpStreamObj>` and
+//! `Box<dyn TcpListenerObj>`, so plugins and other trait objects can hold an environment
+//! without a type parameter. `DynEnvironment` itself implements `Environment`, at the cost of
+//! an allocation per spawn, connection and accept. Performance sensitive code should remain
+//! generic over `Environment`.
+use crate::{Environment, Error, RngHandle, TcpListener, TcpStream, ToSocketAddrs};
+use futures::{
+    future::BoxFuture,
+    stream::{BoxStream, StreamExt},
+    Future, FutureExt,
+};
+use std::{any::Any, fmt, io, net, time};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// An object-safe `TcpStream`.
+pub trait TcpStreamObj: AsyncRead + AsyncWrite + Unpin + Send {
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+    fn peer_addr(&self) -> io::Result<net::SocketAddr>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl<T: TcpStream + Send> TcpStreamObj for T {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self)
+    }
+}
+
+impl TcpStream for Box<dyn TcpStreamObj> {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        (**self).local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        (**self).peer_addr()
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+}
+
+/// An object-safe `TcpListener`, accepting boxed streams.
+pub trait TcpListenerObj: Send {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Box<dyn TcpStreamObj>, net::SocketAddr)>>;
+    fn incoming(self: Box<Self>) -> BoxStream<'static, io::Result<Box<dyn TcpStreamObj>>>;
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+    fn ttl(&self) -> io::Result<u32>;
+    fn set_ttl(&self, ttl: u32) -> io::Result<()>;
+}
+
+impl<L> TcpListenerObj for L
+where
+    L: TcpListener + Send,
+    L::Stream: 'static,
+    L::Incoming: 'static,
+{
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Box<dyn TcpStreamObj>, net::SocketAddr)>> {
+        TcpListener::accept(self)
+            .map(|result| {
+                result.map(|(stream, addr)| (Box::new(stream) as Box<dyn TcpStreamObj>, addr))
+            })
+            .boxed()
+    }
+    fn incoming(self: Box<Self>) -> BoxStream<'static, io::Result<Box<dyn TcpStreamObj>>> {
+        TcpListener::incoming(*self)
+            .map(|result| result.map(|stream| Box::new(stream) as Box<dyn TcpStreamObj>))
+            .boxed()
+    }
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        TcpListener::ttl(self)
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        TcpListener::set_ttl(self, ttl)
+    }
+}
+
+impl TcpListener for Box<dyn TcpListenerObj> {
+    type Stream = Box<dyn TcpStreamObj>;
+    type Incoming = BoxStream<'static, io::Result<Self::Stream>>;
+    async fn accept(&mut self) -> io::Result<(Self::Stream, net::SocketAddr)> {
+        (**self).accept().await
+    }
+    fn incoming(self) -> Self::Incoming {
+        TcpListenerObj::incoming(self)
+    }
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        (**self).local_addr()
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        (**self).ttl()
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        (**self).set_ttl(ttl)
+    }
+}
+
+/// The object-safe subset of `Environment` which `DynEnvironment` delegates to.
+trait EnvironmentObj: Send + Sync {
+    fn clone_box(&self) -> Box<dyn EnvironmentObj>;
+    fn as_any(&self) -> &dyn Any;
+    fn try_spawn(&self, future: BoxFuture<'static, ()>) -> Result<(), Error>;
+    fn now(&self) -> time::Instant;
+    fn system_time(&self) -> time::SystemTime;
+    fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay;
+    fn rng(&self) -> RngHandle;
+    fn rng_for(&self, name: &str) -> RngHandle;
+    fn hostname(&self) -> String;
+    fn local_ip(&self) -> net::IpAddr;
+    fn bind(
+        &self,
+        addr: net::SocketAddr,
+    ) -> BoxFuture<'static, io::Result<Box<dyn TcpListenerObj>>>;
+    fn connect(
+        &self,
+        addrs: Vec<net::SocketAddr>,
+    ) -> BoxFuture<'static, io::Result<Box<dyn TcpStreamObj>>>;
+}
+
+impl<E: Environment + Sync> EnvironmentObj for E {
+    fn clone_box(&self) -> Box<dyn EnvironmentObj> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn try_spawn(&self, future: BoxFuture<'static, ()>) -> Result<(), Error> {
+        Environment::try_spawn(self, future)
+    }
+    fn now(&self) -> time::Instant {
+        Environment::now(self)
+    }
+    fn system_time(&self) -> time::SystemTime {
+        Environment::system_time(self)
+    }
+    fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay {
+        Environment::delay(self, deadline)
+    }
+    fn rng(&self) -> RngHandle {
+        Environment::rng(self)
+    }
+    fn rng_for(&self, name: &str) -> RngHandle {
+        Environment::rng_for(self, name)
+    }
+    fn hostname(&self) -> String {
+        Environment::hostname(self)
+    }
+    fn local_ip(&self) -> net::IpAddr {
+        Environment::local_ip(self)
+    }
+    fn bind(
+        &self,
+        addr: net::SocketAddr,
+    ) -> BoxFuture<'static, io::Result<Box<dyn TcpListenerObj>>> {
+        let env = self.clone();
+        async move {
+            let listener = Environment::bind(&env, addr).await?;
+            Ok(Box::new(listener) as Box<dyn TcpListenerObj>)
+        }
+        .boxed()
+    }
+    fn connect(
+        &self,
+        addrs: Vec<net::SocketAddr>,
+    ) -> BoxFuture<'static, io::Result<Box<dyn TcpStreamObj>>> {
+        let env = self.clone();
+        async move {
+            let stream = Environment::connect(&env, addrs).await?;
+            Ok(Box::new(stream) as Box<dyn TcpStreamObj>)
+        }
+        .boxed()
+    }
+}
+
+/// An `Environment` whose type has been erased.
+pub struct DynEnvironment {
+    inner: Box<dyn EnvironmentObj>,
+}
+
+impl DynEnvironment {
+    /// Erases the type of `env`.
+    pub fn new<E: Environment + Sync>(env: E) -> Self {
+        Self {
+            inner: Box::new(env),
+        }
+    }
+
+    /// Returns the erased environment if it is an `E`, looking through nested
+    /// `DynEnvironment`s.
+    pub(crate) fn downcast_ref<E: Environment>(&self) -> Option<&E> {
+        let env = self.inner.as_any();
+        match env.downcast_ref::<E>() {
+            Some(env) => Some(env),
+            None => env.downcast_ref::<DynEnvironment>()?.downcast_ref(),
+        }
+    }
+}
+
+impl Clone for DynEnvironment {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone_box(),
+        }
+    }
+}
+
+impl fmt::Debug for DynEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynEnvironment").finish()
+    }
+}
+
+impl Environment for DynEnvironment {
+    type TcpStream = Box<dyn TcpStreamObj>;
+    type TcpListener = Box<dyn TcpListenerObj>;
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.try_spawn(future.boxed())
+    }
+    fn now(&self) -> time::Instant {
+        self.inner.now()
+    }
+    fn system_time(&self) -> time::SystemTime {
+        self.inner.system_time()
+    }
+    fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay {
+        self.inner.delay(deadline)
+    }
+    /// Returns a timeout which is registered with the timer of the runtime it is first polled
+    /// on, which must be the runtime of the wrapped environment.
+    fn timeout<T>(&self, value: T, timeout: time::Duration) -> crate::Timeout<T> {
+        crate::Timeout::new(tokio_timer::Timeout::new(value, timeout))
+    }
+    fn rng(&self) -> RngHandle {
+        self.inner.rng()
+    }
+    fn rng_for(&self, name: &str) -> RngHandle {
+        self.inner.rng_for(name)
+    }
+    fn hostname(&self) -> String {
+        self.inner.hostname()
+    }
+    fn local_ip(&self) -> net::IpAddr {
+        self.inner.local_ip()
+    }
+    async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
+    where
+        A: Into<net::SocketAddr> + Send + Sync,
+    {
+        self.inner.bind(addr.into()).await
+    }
+    async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        let addrs = addr.to_socket_addrs()?;
+        self.inner.connect(addrs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DynEnvironment;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        Environment, TcpStream,
+    };
+    use std::{net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A plugin holding an environment without a type parameter.
+    struct Plugin {
+        env: DynEnvironment,
+    }
+
+    fn assert_erasable<E: Environment + Sync>() {}
+
+    #[test]
+    /// Test that a type-erased environment uses the simulated network and clock.
+    fn simulated() {
+        assert_erasable::<crate::singlethread::SingleThreadedRuntimeHandle>();
+        assert_erasable::<crate::threadpool::ThreadPoolRuntimeHandle>();
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let plugin = Plugin {
+            env: DynEnvironment::new(runtime.handle().new_cluster(FaultConfig::disabled())),
+        };
+        runtime.block_on(async move {
+            let env = plugin.env;
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = env.bind(addr).await.unwrap();
+            let bound = listener.local_addr().unwrap();
+            let server = env.clone();
+            env.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                server.delay_from(time::Duration::from_secs(30)).await;
+                socket.write_all(b"pong").await.unwrap();
+                let _ = socket.read(&mut [0; 1]).await;
+            });
+            let start = env.now();
+            let mut socket = env.connect(addr).await.unwrap();
+            assert_eq!(socket.peer_addr().unwrap(), bound);
+            let mut buf = [0; 4];
+            env.timeout(socket.read_exact(&mut buf), time::Duration::from_secs(60))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf, b"pong");
+            assert!(env.now() - start >= time::Duration::from_secs(30));
+        });
+    }
+}
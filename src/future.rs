@@ -0,0 +1,253 @@
+//! Combinators which poll their futures in an order drawn from the runtime seed.
+//!
+//! `futures::select!` and `FuturesUnordered` poll their branches in a fixed or wake-driven
+//! order, so code which accidentally depends on one branch being preferred when several are
+//! ready passes every run. Under the `DeterministicRuntime`, these combinators poll branches in
+//! an order drawn from an RNG derived from the seed and the location they were created at,
+//! exposing that dependency on some seeds. Outside of the deterministic runtime branches are
+//! polled in order.
+use crate::deterministic::context;
+use crate::deterministic::rng::SimRng;
+use futures::{future::Either, Future, FutureExt, Poll, Stream};
+use rand::seq::SliceRandom;
+use std::{fmt, iter::FromIterator, panic::Location, pin::Pin, task::Context};
+
+/// Returns the order in which to poll `n` branches.
+fn poll_order(rng: &mut Option<SimRng>, n: usize) -> Vec<usize> {
+    let mut order = (0..n).collect::<Vec<_>>();
+    if let Some(rng) = rng {
+        order.shuffle(rng);
+    }
+    order
+}
+
+/// Future returned by `select_seeded`.
+pub struct SelectSeeded<A, B> {
+    inner: Option<(A, B)>,
+    rng: Option<SimRng>,
+}
+
+impl<A, B> fmt::Debug for SelectSeeded<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SelectSeeded").finish()
+    }
+}
+
+/// Waits for either of two futures to complete, like `futures::future::select`, polling them
+/// in an order drawn from the runtime seed.
+#[track_caller]
+pub fn select_seeded<A, B>(a: A, b: B) -> SelectSeeded<A, B>
+where
+    A: Future + Unpin,
+    B: Future + Unpin,
+{
+    SelectSeeded {
+        inner: Some((a, b)),
+        rng: context::callsite_rng(Location::caller()),
+    }
+}
+
+impl<A, B> Future for SelectSeeded<A, B>
+where
+    A: Future + Unpin,
+    B: Future + Unpin,
+{
+    type Output = Either<(A::Output, B), (B::Output, A)>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let (a, b) = this.inner.as_mut().expect("cannot poll SelectSeeded twice");
+        for branch in poll_order(&mut this.rng, 2) {
+            if branch == 0 {
+                if let Poll::Ready(value) = a.poll_unpin(cx) {
+                    let (_, b) = this.inner.take().unwrap();
+                    return Poll::Ready(Either::Left((value, b)));
+                }
+            } else if let Poll::Ready(value) = b.poll_unpin(cx) {
+                let (a, _) = this.inner.take().unwrap();
+                return Poll::Ready(Either::Right((value, a)));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Future returned by `select_all_seeded`.
+pub struct SelectAllSeeded<F> {
+    inner: Vec<F>,
+    rng: Option<SimRng>,
+}
+
+impl<F> fmt::Debug for SelectAllSeeded<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SelectAllSeeded")
+            .field("len", &self.inner.len())
+            .finish()
+    }
+}
+
+/// Waits for any of `futures` to complete, like `futures::future::select_all`, polling them in
+/// an order drawn from the runtime seed. Resolves to the output of the completed future, its
+/// index and the remaining futures.
+///
+/// # Panics
+///
+/// Panics if `futures` is empty.
+#[track_caller]
+pub fn select_all_seeded<I>(futures: I) -> SelectAllSeeded<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future + Unpin,
+{
+    let inner = futures.into_iter().collect::<Vec<_>>();
+    assert!(
+        !inner.is_empty(),
+        "select_all_seeded requires at least one future"
+    );
+    SelectAllSeeded {
+        inner,
+        rng: context::callsite_rng(Location::caller()),
+    }
+}
+
+impl<F> Future for SelectAllSeeded<F>
+where
+    F: Future + Unpin,
+{
+    type Output = (F::Output, usize, Vec<F>);
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        for idx in poll_order(&mut this.rng, this.inner.len()) {
+            if let Poll::Ready(value) = this.inner[idx].poll_unpin(cx) {
+                this.inner.swap_remove(idx);
+                let rest = std::mem::take(&mut this.inner);
+                return Poll::Ready((value, idx, rest));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// A set of futures which yields their outputs as they complete, like `FuturesUnordered`,
+/// polling the futures in an order drawn from the runtime seed.
+///
+/// Every future in the set is polled whenever the set is polled, which is simpler than tracking
+/// which futures have been woken and is cheap enough for the sizes of sets used in tests.
+pub struct SeededFuturesUnordered<F> {
+    futures: Vec<Pin<Box<F>>>,
+    rng: Option<SimRng>,
+}
+
+impl<F> fmt::Debug for SeededFuturesUnordered<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeededFuturesUnordered")
+            .field("len", &self.futures.len())
+            .finish()
+    }
+}
+
+impl<F: Future> SeededFuturesUnordered<F> {
+    #[track_caller]
+    pub fn new() -> Self {
+        Self {
+            futures: vec![],
+            rng: context::callsite_rng(Location::caller()),
+        }
+    }
+
+    /// Adds `future` to the set.
+    pub fn push(&mut self, future: F) {
+        self.futures.push(Box::pin(future));
+    }
+
+    /// Returns the number of futures which have not yet completed.
+    pub fn len(&self) -> usize {
+        self.futures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.futures.is_empty()
+    }
+}
+
+impl<F: Future> Default for SeededFuturesUnordered<F> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Future> FromIterator<F> for SeededFuturesUnordered<F> {
+    #[track_caller]
+    fn from_iter<I: IntoIterator<Item = F>>(iter: I) -> Self {
+        let mut set = Self::new();
+        for future in iter {
+            set.push(future);
+        }
+        set
+    }
+}
+
+impl<F> Unpin for SeededFuturesUnordered<F> {}
+
+impl<F: Future> Stream for SeededFuturesUnordered<F> {
+    type Item = F::Output;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.futures.is_empty() {
+            return Poll::Ready(None);
+        }
+        for idx in poll_order(&mut this.rng, this.futures.len()) {
+            if let Poll::Ready(value) = this.futures[idx].as_mut().poll(cx) {
+                this.futures.swap_remove(idx);
+                return Poll::Ready(Some(value));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use futures::{future, StreamExt};
+
+    fn run<F: Future>(seed: u64, future: F) -> F::Output {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        runtime.block_on(future)
+    }
+
+    #[test]
+    /// Test that the branch chosen when both are ready depends on the seed.
+    fn select_bias() {
+        let left = |seed| {
+            run(seed, async {
+                let selected = select_seeded(future::ready(0), future::ready(1)).await;
+                matches!(selected, Either::Left(_))
+            })
+        };
+        assert_eq!(left(1), left(1));
+        assert!((0..10).any(|seed| left(seed) != left(seed + 1)));
+    }
+
+    #[test]
+    /// Test that ready futures are yielded in an order which depends on the seed.
+    fn unordered_order() {
+        let order = |seed| {
+            run(seed, async {
+                let set = (0..8)
+                    .map(future::ready)
+                    .collect::<SeededFuturesUnordered<_>>();
+                set.collect::<Vec<_>>().await
+            })
+        };
+        assert_eq!(order(1), order(1));
+        assert!((0..10).any(|seed| order(seed) != order(seed + 1)));
+        let mut sorted = order(1);
+        sorted.sort();
+        assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+        let (_, idx, rest) = run(1, select_all_seeded((0..4).map(future::ready)));
+        assert!(idx < 4);
+        assert_eq!(rest.len(), 3);
+    }
+}
@@ -0,0 +1,220 @@
+//! A channel for running `tonic` gRPC clients through an `Environment`.
+//!
+//! `tonic` clients are generic over any `Service<http::Request<_>>` returning an
+//! `http::Response<_>`, so `GrpcChannel` can be passed to a generated client in place of
+//! `tonic::transport::Channel`. Requests are sent over HTTP/2 connections made with
+//! `HyperConnector`, and connections which are disconnected by the deterministic runtime are
+//! reestablished on the next request.
+//!
+//! Deadlines set with the `grpc-timeout` header are enforced with `Environment::timeout`, so
+//! they expire in simulated time rather than wall clock time.
+use crate::{hyper_compat::HyperConnector, Environment};
+use futures::Poll;
+use hyper::body::Payload;
+use std::{error, fmt, future::Future, pin::Pin, task::Context, time};
+
+/// The error type of `GrpcChannel`.
+pub type GrpcError = Box<dyn error::Error + Send + Sync>;
+
+/// Error returned when a request exceeds the deadline set by its `grpc-timeout` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded(());
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadline exceeded")
+    }
+}
+
+impl error::Error for DeadlineExceeded {}
+
+/// A `Service` which sends gRPC requests to a server in the simulation.
+pub struct GrpcChannel<E, B> {
+    env: E,
+    origin: http::Uri,
+    client: hyper::Client<HyperConnector<E>, B>,
+}
+
+impl<E: Clone, B> Clone for GrpcChannel<E, B> {
+    fn clone(&self) -> Self {
+        Self {
+            env: self.env.clone(),
+            origin: self.origin.clone(),
+            client: self.client.clone(),
+        }
+    }
+}
+
+impl<E, B> fmt::Debug for GrpcChannel<E, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcChannel")
+            .field("origin", &self.origin)
+            .finish()
+    }
+}
+
+impl<E, B> GrpcChannel<E, B>
+where
+    E: Environment + Sync,
+    B: Payload + Send + Unpin,
+    B::Data: Send + Unpin,
+{
+    /// Returns a channel sending requests to `origin`, which must have an IP address as its
+    /// host, for example `http://10.0.0.1:50051`.
+    pub fn new(env: E, origin: http::Uri) -> Self {
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build(HyperConnector::new(env.clone()));
+        Self {
+            env,
+            origin,
+            client,
+        }
+    }
+
+    /// Replaces the scheme and authority of `uri` with those of the origin.
+    fn with_origin(&self, uri: &http::Uri) -> Result<http::Uri, GrpcError> {
+        let mut parts = uri.clone().into_parts();
+        parts.scheme = self.origin.scheme_part().cloned();
+        parts.authority = self.origin.authority_part().cloned();
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some(http::uri::PathAndQuery::from_static("/"));
+        }
+        Ok(http::Uri::from_parts(parts)?)
+    }
+}
+
+/// Parses a `grpc-timeout` header value, which is an integer of at most 8 digits followed by
+/// a unit.
+fn parse_grpc_timeout(value: &http::HeaderValue) -> Option<time::Duration> {
+    let value = value.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    let timeout = match unit {
+        "H" => time::Duration::from_secs(amount * 60 * 60),
+        "M" => time::Duration::from_secs(amount * 60),
+        "S" => time::Duration::from_secs(amount),
+        "m" => time::Duration::from_millis(amount),
+        "u" => time::Duration::from_micros(amount),
+        "n" => time::Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+impl<E, B> tower_service::Service<http::Request<B>> for GrpcChannel<E, B>
+where
+    E: Environment + Sync,
+    B: Payload + Send + Unpin,
+    B::Data: Send + Unpin,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = GrpcError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        let uri = match self.with_origin(request.uri()) {
+            Ok(uri) => uri,
+            Err(err) => return Box::pin(futures::future::err(err)),
+        };
+        *request.uri_mut() = uri;
+        *request.version_mut() = http::Version::HTTP_2;
+        let deadline = request
+            .headers()
+            .get("grpc-timeout")
+            .and_then(parse_grpc_timeout);
+        let response = self.client.request(request);
+        match deadline {
+            Some(deadline) => {
+                let response = self.env.timeout(response, deadline);
+                Box::pin(async move {
+                    match response.await {
+                        Ok(response) => Ok(response?),
+                        Err(_) => Err(DeadlineExceeded(()).into()),
+                    }
+                })
+            }
+            None => Box::pin(async move { Ok(response.await?) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        hyper_compat::HyperAcceptor,
+    };
+    use std::{net, time::Duration};
+    use tower_service::Service;
+
+    #[test]
+    /// Test that requests reach an HTTP/2 server, and that `grpc-timeout` deadlines expire in
+    /// simulated time.
+    fn deadline() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:50051".parse().unwrap();
+            let listener = handle.bind(addr).await.unwrap();
+            let env = handle.clone();
+            let make_service = hyper::service::make_service_fn(move |_| {
+                let env = env.clone();
+                async move {
+                    Ok::<_, hyper::Error>(hyper::service::service_fn(move |request| {
+                        let env = env.clone();
+                        async move {
+                            if request.uri().path() == "/slow" {
+                                env.delay_from(Duration::from_secs(60)).await;
+                            }
+                            Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::empty()))
+                        }
+                    }))
+                }
+            });
+            let server = hyper::Server::builder(HyperAcceptor::new(listener))
+                .http2_only(true)
+                .serve(make_service);
+            handle.spawn(async move {
+                server.await.unwrap();
+            });
+
+            let mut channel =
+                GrpcChannel::new(handle.clone(), "http://127.0.0.1:50051".parse().unwrap());
+            let request = |path: &str| {
+                http::Request::post(path)
+                    .header("grpc-timeout", "5S")
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            };
+            let response = channel.call(request("/fast")).await.unwrap();
+            assert_eq!(response.status(), 200);
+
+            let start = handle.now();
+            let err = channel.call(request("/slow")).await.unwrap_err();
+            assert!(err.is::<DeadlineExceeded>());
+            let elapsed = handle.now() - start;
+            assert!(elapsed >= Duration::from_secs(5) && elapsed < Duration::from_secs(60));
+        });
+    }
+
+    #[test]
+    /// Test parsing of `grpc-timeout` header values.
+    fn grpc_timeout() {
+        let parse = |value| parse_grpc_timeout(&http::HeaderValue::from_static(value));
+        assert_eq!(parse("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(parse("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse("123456789S"), None);
+        assert_eq!(parse("5x"), None);
+        assert_eq!(parse("S"), None);
+    }
+}
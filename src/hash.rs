@@ -0,0 +1,71 @@
+//! Hash maps whose iteration order is determined by the seed.
+//!
+//! `std::collections::HashMap` seeds its hasher with random keys, so iterating over a map
+//! visits entries in a different order on every run. `DeterministicHasherBuilder` uses SipHash
+//! keys derived from the seed of the `DeterministicRuntime` instead, making iteration order
+//! repeatable for a seed while still varying between seeds.
+use crate::deterministic::{context, rng::stable_hash};
+use std::hash::BuildHasher;
+
+/// A `HashMap` using `DeterministicHasherBuilder`.
+pub type HashMap<K, V> = std::collections::HashMap<K, V, DeterministicHasherBuilder>;
+
+/// A `HashSet` using `DeterministicHasherBuilder`.
+pub type HashSet<T> = std::collections::HashSet<T, DeterministicHasherBuilder>;
+
+/// Builds SipHash hashers with fixed keys derived from a seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeterministicHasherBuilder {
+    k0: u64,
+    k1: u64,
+}
+
+impl DeterministicHasherBuilder {
+    /// Returns a builder with keys derived from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            k0: stable_hash(&(seed, "hasher", 0)),
+            k1: stable_hash(&(seed, "hasher", 1)),
+        }
+    }
+}
+
+impl Default for DeterministicHasherBuilder {
+    /// Returns a builder with keys derived from the seed of the runtime executing on this
+    /// thread, or from a seed of 0 outside of a `DeterministicRuntime`.
+    fn default() -> Self {
+        let seed = context::current().map_or(0, |handle| handle.seed());
+        Self::new(seed)
+    }
+}
+
+impl BuildHasher for DeterministicHasherBuilder {
+    #[allow(deprecated)]
+    type Hasher = std::hash::SipHasher;
+
+    #[allow(deprecated)]
+    fn build_hasher(&self) -> Self::Hasher {
+        std::hash::SipHasher::new_with_keys(self.k0, self.k1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    fn iteration_order(seed: u64) -> Vec<u32> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        runtime.block_on(async {
+            let set: HashSet<u32> = (0..32).collect();
+            set.into_iter().collect()
+        })
+    }
+
+    #[test]
+    /// Test that iteration order is repeatable for a seed and varies between seeds.
+    fn seeded_iteration_order() {
+        assert_eq!(iteration_order(1), iteration_order(1));
+        assert_ne!(iteration_order(1), iteration_order(2));
+    }
+}
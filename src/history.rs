@@ -0,0 +1,347 @@
+//! Operation histories for checking the correctness of a simulated system.
+//!
+//! Workloads record when each operation is invoked and when it completes, using the
+//! environment's notion of time. The resulting history can be exported in the
+//! [Jepsen](https://github.com/jepsen-io/jepsen) EDN format for use with external
+//! linearizability checkers such as Knossos, or checked directly for simple registers.
+use crate::Environment;
+use std::{collections::HashSet, fmt, hash::Hash, sync, time};
+
+/// Identifies an operation recorded in a `History`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OpId(usize);
+
+/// The way in which an operation completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<Ret> {
+    /// The operation took effect and returned a value.
+    Ok(Ret),
+    /// The operation definitely did not take effect.
+    Fail,
+    /// It is unknown whether the operation took effect, for example due to a timeout.
+    Info,
+}
+
+/// A single operation invoked by a process.
+#[derive(Debug, Clone)]
+pub struct Operation<Op, Ret> {
+    pub process: u64,
+    pub op: Op,
+    pub invoked_at: time::Instant,
+    /// Time and outcome of the operation, `None` if it never completed.
+    pub completion: Option<(time::Instant, Outcome<Ret>)>,
+}
+
+/// A record of operations invoked against a system under test.
+///
+/// Cloned histories share the same underlying record, so a history can be handed out to
+/// every simulated client.
+#[derive(Debug)]
+pub struct History<Op, Ret> {
+    inner: sync::Arc<sync::Mutex<Vec<Operation<Op, Ret>>>>,
+}
+
+impl<Op, Ret> Clone for History<Op, Ret> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<Op, Ret> Default for History<Op, Ret> {
+    fn default() -> Self {
+        Self {
+            inner: sync::Arc::new(sync::Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<Op, Ret> History<Op, Ret>
+where
+    Op: Clone,
+    Ret: Clone,
+{
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records that `process` invoked `op`.
+    pub fn invoke<E: Environment>(&self, env: &E, process: u64, op: Op) -> OpId {
+        let mut lock = self.inner.lock().unwrap();
+        lock.push(Operation {
+            process,
+            op,
+            invoked_at: env.now(),
+            completion: None,
+        });
+        OpId(lock.len() - 1)
+    }
+
+    /// Records that the operation `id` took effect, returning `ret`.
+    pub fn ok<E: Environment>(&self, env: &E, id: OpId, ret: Ret) {
+        self.complete(env, id, Outcome::Ok(ret))
+    }
+
+    /// Records that the operation `id` did not take effect.
+    pub fn fail<E: Environment>(&self, env: &E, id: OpId) {
+        self.complete(env, id, Outcome::Fail)
+    }
+
+    /// Records that the outcome of operation `id` is unknown.
+    pub fn info<E: Environment>(&self, env: &E, id: OpId) {
+        self.complete(env, id, Outcome::Info)
+    }
+
+    fn complete<E: Environment>(&self, env: &E, id: OpId, outcome: Outcome<Ret>) {
+        let mut lock = self.inner.lock().unwrap();
+        let operation = &mut lock[id.0];
+        assert!(
+            operation.completion.is_none(),
+            "operation {:?} completed twice",
+            id
+        );
+        operation.completion = Some((env.now(), outcome));
+    }
+
+    /// Returns every operation recorded so far, in invocation order.
+    pub fn operations(&self) -> Vec<Operation<Op, Ret>> {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+/// An operation which can be written as an entry of a Jepsen history.
+pub trait JepsenOp<Ret> {
+    /// The `:f` of the operation, for example `"read"`.
+    fn function(&self) -> &'static str;
+    /// The EDN encoded `:value` of the operation. `ret` is `None` for invocations.
+    fn value(&self, ret: Option<&Ret>) -> String;
+}
+
+impl<Op, Ret> History<Op, Ret>
+where
+    Op: Clone + JepsenOp<Ret>,
+    Ret: Clone,
+{
+    /// Exports this history in the Jepsen EDN format, one entry per line. Times are
+    /// nanoseconds since the first invocation.
+    pub fn to_edn(&self) -> String {
+        let operations = self.operations();
+        let start = match operations.iter().map(|o| o.invoked_at).min() {
+            Some(start) => start,
+            None => return String::new(),
+        };
+        let mut entries = vec![];
+        for operation in &operations {
+            entries.push((operation.invoked_at, "invoke", operation, None));
+            if let Some((at, outcome)) = &operation.completion {
+                let (kind, ret) = match outcome {
+                    Outcome::Ok(ret) => ("ok", Some(ret)),
+                    Outcome::Fail => ("fail", None),
+                    Outcome::Info => ("info", None),
+                };
+                entries.push((*at, kind, operation, ret));
+            }
+        }
+        // stable sort, so invocations precede completions recorded at the same instant.
+        entries.sort_by_key(|(at, ..)| *at);
+        let mut edn = String::new();
+        for (at, kind, operation, ret) in entries {
+            edn.push_str(&format!(
+                "{{:process {}, :type :{}, :f :{}, :value {}, :time {}}}\n",
+                operation.process,
+                kind,
+                operation.op.function(),
+                operation.op.value(ret),
+                (at - start).as_nanos()
+            ));
+        }
+        edn
+    }
+}
+
+/// Operations against a single read/write register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterOp<V> {
+    Read,
+    Write(V),
+}
+
+impl<V: fmt::Display> JepsenOp<Option<V>> for RegisterOp<V> {
+    fn function(&self) -> &'static str {
+        match self {
+            RegisterOp::Read => "read",
+            RegisterOp::Write(_) => "write",
+        }
+    }
+
+    fn value(&self, ret: Option<&Option<V>>) -> String {
+        match (self, ret) {
+            (RegisterOp::Write(v), _) => v.to_string(),
+            (RegisterOp::Read, Some(Some(v))) => v.to_string(),
+            (RegisterOp::Read, _) => String::from("nil"),
+        }
+    }
+}
+
+/// A register operation reduced to the information required to linearize it.
+struct Interval<V> {
+    start: time::Instant,
+    /// `None` if the operation may take effect at any point after `start`.
+    end: Option<time::Instant>,
+    op: RegisterOp<V>,
+    /// The value observed by a completed read.
+    read: Option<V>,
+}
+
+impl<V> History<RegisterOp<V>, Option<V>>
+where
+    V: Clone + Eq + Hash,
+{
+    /// Checks that this history of register operations is linearizable, starting from an
+    /// unset register. Reads of an unset register return `None`.
+    ///
+    /// Failed operations are ignored, while writes with an unknown outcome may or may not
+    /// have taken effect.
+    pub fn is_linearizable(&self) -> bool {
+        let mut intervals = vec![];
+        for operation in self.operations() {
+            match (operation.completion, &operation.op) {
+                (Some((_, Outcome::Fail)), _) => {}
+                (Some((end, Outcome::Ok(read))), op) => intervals.push(Interval {
+                    start: operation.invoked_at,
+                    end: Some(end),
+                    op: op.clone(),
+                    read,
+                }),
+                // reads with an unknown outcome have no effect on the register.
+                (_, RegisterOp::Read) => {}
+                (_, op) => intervals.push(Interval {
+                    start: operation.invoked_at,
+                    end: None,
+                    op: op.clone(),
+                    read: None,
+                }),
+            }
+        }
+        let mut linearized = vec![false; intervals.len()];
+        let mut visited = HashSet::new();
+        linearize(&intervals, &mut linearized, None, &mut visited)
+    }
+}
+
+/// Wing & Gong style search for a valid linearization of `intervals`.
+fn linearize<V>(
+    intervals: &[Interval<V>],
+    linearized: &mut Vec<bool>,
+    value: Option<V>,
+    visited: &mut HashSet<(Vec<bool>, Option<V>)>,
+) -> bool
+where
+    V: Clone + Eq + Hash,
+{
+    let pending = || (0..intervals.len()).filter(|idx| !linearized[*idx]);
+    if pending().all(|idx| intervals[idx].end.is_none()) {
+        return true;
+    }
+    if !visited.insert((linearized.clone(), value.clone())) {
+        return false;
+    }
+    // an operation may be linearized next if it was invoked before every pending operation
+    // returned.
+    let deadline = pending().filter_map(|idx| intervals[idx].end).min();
+    let candidates: Vec<usize> = pending()
+        .filter(|idx| deadline.is_none_or(|deadline| intervals[*idx].start < deadline))
+        .collect();
+    for idx in candidates {
+        let interval = &intervals[idx];
+        let next = match &interval.op {
+            RegisterOp::Write(v) => Some(v.clone()),
+            RegisterOp::Read if interval.read == value => value.clone(),
+            RegisterOp::Read => continue,
+        };
+        linearized[idx] = true;
+        if linearize(intervals, linearized, next, visited) {
+            return true;
+        }
+        linearized[idx] = false;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::time::Duration;
+
+    #[test]
+    /// Test that a sequential history is linearizable, while a stale read is not.
+    fn register() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let history = History::new();
+            let write = history.invoke(&handle, 0, RegisterOp::Write(1));
+            handle.delay_from(Duration::from_millis(10)).await;
+            history.ok(&handle, write, None);
+            let read = history.invoke(&handle, 1, RegisterOp::Read);
+            handle.delay_from(Duration::from_millis(10)).await;
+            history.ok(&handle, read, Some(1));
+            assert!(history.is_linearizable());
+
+            let stale = history.invoke(&handle, 1, RegisterOp::Read);
+            handle.delay_from(Duration::from_millis(10)).await;
+            history.ok(&handle, stale, None);
+            assert!(!history.is_linearizable());
+        });
+    }
+
+    #[test]
+    /// Test that reads concurrent with a write, or following a write with an unknown outcome,
+    /// may observe either value.
+    fn concurrent() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let history = History::new();
+            let write = history.invoke(&handle, 0, RegisterOp::Write(1));
+            let old = history.invoke(&handle, 1, RegisterOp::Read);
+            let new = history.invoke(&handle, 2, RegisterOp::Read);
+            handle.delay_from(Duration::from_millis(10)).await;
+            history.ok(&handle, old, None);
+            history.ok(&handle, new, Some(1));
+            history.ok(&handle, write, None);
+            assert!(history.is_linearizable());
+
+            let unknown = history.invoke(&handle, 0, RegisterOp::Write(2));
+            history.info(&handle, unknown);
+            let read = history.invoke(&handle, 1, RegisterOp::Read);
+            handle.delay_from(Duration::from_millis(10)).await;
+            history.ok(&handle, read, Some(2));
+            assert!(history.is_linearizable());
+        });
+    }
+
+    #[test]
+    /// Test that histories are exported as Jepsen EDN with simulated timestamps.
+    fn edn() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let history = History::new();
+            let write = history.invoke(&handle, 0, RegisterOp::Write(3));
+            handle.delay_from(Duration::from_millis(1)).await;
+            history.ok(&handle, write, None);
+            let read = history.invoke(&handle, 1, RegisterOp::Read);
+            history.fail(&handle, read);
+            assert_eq!(
+                history.to_edn(),
+                "{:process 0, :type :invoke, :f :write, :value 3, :time 0}\n\
+                 {:process 0, :type :ok, :f :write, :value 3, :time 1000000}\n\
+                 {:process 1, :type :invoke, :f :read, :value nil, :time 1000000}\n\
+                 {:process 1, :type :fail, :f :read, :value nil, :time 1000000}\n"
+            );
+        });
+    }
+}
@@ -0,0 +1,423 @@
+//! A scripted HTTP/1.1 server for testing HTTP clients under simulation.
+//!
+//! `HttpStub` answers requests with responses scripted per method and path, without a server
+//! framework. Faults are drawn from `Environment::rng_for`, so under the `DeterministicRuntime`
+//! the same seed delays, fails and truncates the same responses: a response may be delayed,
+//! replaced with a `503 Service Unavailable`, or cut off partway through its body by closing
+//! the connection.
+//!
+//! ```
+//! # use simulation::{deterministic::DeterministicRuntime, Environment};
+//! use simulation::http_stub::{HttpStub, StubResponse};
+//! # use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//! let mut runtime = DeterministicRuntime::new().unwrap();
+//! let handle = runtime.handle();
+//! let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+//! runtime.block_on(async {
+//!     let stub = HttpStub::new().route("GET", "/health", StubResponse::new(200, "ok"));
+//!     let listener = handle.bind(addr).await.unwrap();
+//!     let server = stub.clone().serve(handle.clone(), listener);
+//!     handle.spawn(async move {
+//!         let _ = server.await;
+//!     });
+//!     let mut socket = handle.connect(addr).await.unwrap();
+//!     socket
+//!         .write_all(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n")
+//!         .await
+//!         .unwrap();
+//!     let mut response = String::new();
+//!     socket.read_to_string(&mut response).await.unwrap();
+//!     assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+//!     assert_eq!(stub.requests()[0].path, "/health");
+//! });
+//! ```
+use crate::{Environment, RngHandle, TcpListener};
+use rand::Rng;
+use std::{collections::HashMap, io, ops, sync, time};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// Largest request head accepted, after which the connection is closed.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// A response served by an `HttpStub`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StubResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl StubResponse {
+    pub fn new<B: Into<Vec<u8>>>(status: u16, body: B) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    /// Adds a header to the response. `Content-Length` is always set from the body.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// A request received by an `HttpStub`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StubRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl StubRequest {
+    /// Returns the value of the first header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &value[..])
+    }
+}
+
+/// Faults injected into the responses of an `HttpStub`. Every fault is disabled by default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StubFaults {
+    /// The range of duration a response can be delayed by.
+    pub delay: ops::Range<time::Duration>,
+    /// The probability of a response being delayed, 0..1.
+    pub delay_prob: f64,
+    /// The probability of a response being replaced with a `503 Service Unavailable`, 0..1.
+    pub server_error_prob: f64,
+    /// The probability of the connection being closed partway through a response body, 0..1.
+    pub drop_mid_body_prob: f64,
+}
+
+impl Default for StubFaults {
+    fn default() -> Self {
+        Self {
+            delay: time::Duration::from_millis(0)..time::Duration::from_millis(1000),
+            delay_prob: 0.0,
+            server_error_prob: 0.0,
+            drop_mid_body_prob: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    /// Responses of each route, by method and path. The last response of a route is repeated
+    /// once the others have been served.
+    routes: HashMap<(String, String), Vec<StubResponse>>,
+    requests: Vec<StubRequest>,
+}
+
+/// An HTTP/1.1 server answering requests with scripted responses. Clones share their script
+/// and the requests received, so a clone can be kept to inspect the requests once the stub is
+/// serving. Requests without a route are answered with `404 Not Found`.
+#[derive(Debug, Clone, Default)]
+pub struct HttpStub {
+    script: sync::Arc<sync::Mutex<Script>>,
+    faults: StubFaults,
+}
+
+impl HttpStub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `response` to the responses of `method` requests for `path`, which are served
+    /// in the order they were added.
+    pub fn route(self, method: &str, path: &str, response: StubResponse) -> Self {
+        self.script
+            .lock()
+            .unwrap()
+            .routes
+            .entry((method.to_string(), path.to_string()))
+            .or_default()
+            .push(response);
+        self
+    }
+
+    /// Sets the faults injected into responses.
+    pub fn faults(mut self, faults: StubFaults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Returns the requests received so far, in the order they were received.
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.script.lock().unwrap().requests.clone()
+    }
+
+    /// Accepts connections from `listener`, serving requests until accepting fails. Faults are
+    /// drawn from the stream of `Environment::rng_for` named after the listener address.
+    pub async fn serve<E, L>(self, env: E, mut listener: L) -> io::Result<()>
+    where
+        E: Environment,
+        L: TcpListener,
+        L::Stream: Unpin + 'static,
+    {
+        let name = format!("http_stub:{}", listener.local_addr()?);
+        let mut rng = env.rng_for(&name);
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let connection = self
+                .clone()
+                .serve_connection(env.clone(), rng.clone(), socket);
+            // keep the faults of each connection independent of how many requests others make.
+            rng = rng.fork("next");
+            env.spawn(async move {
+                if let Err(e) = connection.await {
+                    log::debug!("http stub connection closed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn serve_connection<E, S>(
+        self,
+        env: E,
+        mut rng: RngHandle,
+        mut socket: S,
+    ) -> io::Result<()>
+    where
+        E: Environment,
+        S: crate::TcpStream,
+    {
+        let mut buf = vec![];
+        while let Some(request) = read_request(&mut socket, &mut buf).await? {
+            let close = request
+                .header("connection")
+                .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+            let response = self.respond(request);
+            if rng.gen_bool(self.faults.delay_prob) {
+                let delay = rng.gen_range(self.faults.delay.clone());
+                env.delay_from(delay).await;
+            }
+            let response = if rng.gen_bool(self.faults.server_error_prob) {
+                StubResponse::new(503, "injected fault")
+            } else {
+                response
+            };
+            let mut head = format!(
+                "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+                response.status,
+                reason(response.status),
+                response.body.len()
+            );
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str("\r\n");
+            socket.write_all(head.as_bytes()).await?;
+            if rng.gen_bool(self.faults.drop_mid_body_prob) {
+                let written = rng.gen_range(0..response.body.len().max(1));
+                socket.write_all(&response.body[..written]).await?;
+                return Ok(());
+            }
+            socket.write_all(&response.body).await?;
+            if close {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Records `request`, returning the next response scripted for it.
+    fn respond(&self, request: StubRequest) -> StubResponse {
+        let mut script = self.script.lock().unwrap();
+        let key = (request.method.clone(), request.path.clone());
+        script.requests.push(request);
+        match script.routes.get_mut(&key) {
+            Some(responses) if responses.len() > 1 => responses.remove(0),
+            Some(responses) => responses[0].clone(),
+            None => StubResponse::new(404, "not found"),
+        }
+    }
+}
+
+/// Reads the next request from `socket`, returning `None` if the connection was closed between
+/// requests. `buf` holds bytes read past the end of the previous request.
+async fn read_request<S>(socket: &mut S, buf: &mut Vec<u8>) -> io::Result<Option<StubRequest>>
+where
+    S: AsyncRead + Unpin,
+{
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(invalid("request head too large"));
+        }
+        let mut chunk = [0; 4096];
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 {
+            return if buf.is_empty() {
+                Ok(None)
+            } else {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            };
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    };
+    let head = String::from_utf8(buf[..end].to_vec()).map_err(|_| invalid("invalid head"))?;
+    buf.drain(..end + 4);
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(invalid("invalid request line")),
+    };
+    let headers: Vec<_> = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_at(line.find(':')?);
+            Some((name.trim().to_string(), value[1..].trim().to_string()))
+        })
+        .collect();
+    let mut request = StubRequest {
+        method,
+        path,
+        headers,
+        body: vec![],
+    };
+    let length = match request.header("content-length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| invalid("invalid content length"))?,
+        None => 0,
+    };
+    while buf.len() < length {
+        let mut chunk = [0; 4096];
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+    request.body = buf.drain(..length).collect();
+    Ok(Some(request))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, FaultConfig};
+    use std::net;
+
+    /// Sends a request on a new connection, returning the response read until the server
+    /// closes the connection.
+    async fn get<E: Environment>(env: &E, addr: net::SocketAddr, path: &str) -> String {
+        let mut socket = env.connect(addr).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+            path
+        );
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        socket.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    /// Test that scripted responses are served in order, repeating the last one.
+    fn scripted() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let stub = HttpStub::new()
+            .route("GET", "/", StubResponse::new(500, "down"))
+            .route(
+                "GET",
+                "/",
+                StubResponse::new(200, "up").header("X-Try", "2"),
+            );
+        runtime.block_on(async {
+            let listener = handle.bind(addr).await.unwrap();
+            let server = stub.clone().serve(handle.clone(), listener);
+            handle.spawn(async move {
+                let _ = server.await;
+            });
+            let first = get(&handle, addr, "/").await;
+            assert!(first.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+            assert!(first.ends_with("\r\n\r\ndown"));
+            for _ in 0..2 {
+                let response = get(&handle, addr, "/").await;
+                assert!(response.contains("X-Try: 2\r\n"));
+                assert!(response.ends_with("\r\n\r\nup"));
+            }
+            assert!(get(&handle, addr, "/missing")
+                .await
+                .starts_with("HTTP/1.1 404"));
+        });
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[3].path, "/missing");
+        assert_eq!(requests[0].body, b"hi");
+    }
+
+    #[test]
+    /// Test that injected server errors and truncated bodies are the same for a seed.
+    fn faults() {
+        let run = |seed| {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+            let addr: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+            let stub = HttpStub::new()
+                .route("GET", "/", StubResponse::new(200, "0123456789"))
+                .faults(StubFaults {
+                    delay_prob: 0.5,
+                    server_error_prob: 0.2,
+                    drop_mid_body_prob: 0.2,
+                    ..StubFaults::default()
+                });
+            runtime.block_on(async {
+                let listener = handle.bind(addr).await.unwrap();
+                let server = stub.serve(handle.clone(), listener);
+                handle.spawn(async move {
+                    let _ = server.await;
+                });
+                let mut responses = vec![];
+                for _ in 0..20 {
+                    responses.push(get(&handle, addr, "/").await);
+                }
+                responses
+            })
+        };
+        let responses = run(1);
+        assert_eq!(responses, run(1));
+        assert!(responses.iter().any(|r| r.starts_with("HTTP/1.1 503")));
+        assert!(responses
+            .iter()
+            .any(|r| r.starts_with("HTTP/1.1 200") && !r.ends_with("0123456789")));
+        assert!(responses.iter().any(|r| r.ends_with("\r\n\r\n0123456789")));
+    }
+}
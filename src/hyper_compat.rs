@@ -0,0 +1,193 @@
+//! Adapters for running `hyper` clients and servers through an `Environment`.
+//!
+//! `HyperConnector` implements hyper's `Connect` trait with `Environment::connect`, so an HTTP
+//! client can send requests to servers in the simulation, with latency and disconnects injected
+//! by the deterministic runtime. There is no DNS in the simulation, so request URIs must use IP
+//! addresses.
+//!
+//! `HyperAcceptor` implements hyper's `Accept` trait for a listener returned by
+//! `Environment::bind`, so a hyper server can be hosted inside the simulation.
+use crate::{Environment, TcpListener};
+use futures::{FutureExt, Poll};
+use hyper::{
+    client::connect::{Connect, Connected, Destination},
+    server::accept::Accept,
+};
+use std::{fmt, future::Future, io, net, pin::Pin, task::Context};
+
+/// A hyper `Connect` implementation which connects through an `Environment`.
+#[derive(Clone)]
+pub struct HyperConnector<E> {
+    env: E,
+}
+
+impl<E> fmt::Debug for HyperConnector<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HyperConnector").finish()
+    }
+}
+
+impl<E: Environment + Sync> HyperConnector<E> {
+    pub fn new(env: E) -> Self {
+        Self { env }
+    }
+}
+
+/// Returns the address of `dst`, using the default port of its scheme if it has none.
+fn destination_addr(dst: &Destination) -> io::Result<net::SocketAddr> {
+    let ip: net::IpAddr = dst.host().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "cannot resolve {:?}, hosts must be IP addresses",
+                dst.host()
+            ),
+        )
+    })?;
+    let port = match (dst.port(), dst.scheme()) {
+        (Some(port), _) => port,
+        (None, "https") => 443,
+        (None, _) => 80,
+    };
+    Ok(net::SocketAddr::new(ip, port))
+}
+
+impl<E: Environment + Sync> Connect for HyperConnector<E> {
+    type Transport = E::TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(E::TcpStream, Connected)>> + Send>>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let env = self.env.clone();
+        Box::pin(async move {
+            let addr = destination_addr(&dst)?;
+            let stream = env.connect(addr).await?;
+            Ok((stream, Connected::new()))
+        })
+    }
+}
+
+type AcceptFuture<L> =
+    Pin<Box<dyn Future<Output = (L, io::Result<<L as TcpListener>::Stream>)> + Send>>;
+
+/// A hyper `Accept` implementation which accepts connections from a listener.
+pub struct HyperAcceptor<L: TcpListener> {
+    /// The listener, while no accept is in progress.
+    listener: Option<L>,
+    /// An accept in progress, which returns the listener once it completes.
+    accept: Option<AcceptFuture<L>>,
+}
+
+impl<L: TcpListener> fmt::Debug for HyperAcceptor<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HyperAcceptor").finish()
+    }
+}
+
+impl<L> HyperAcceptor<L>
+where
+    L: TcpListener + Send + 'static,
+{
+    pub fn new(listener: L) -> Self {
+        Self {
+            listener: Some(listener),
+            accept: None,
+        }
+    }
+}
+
+impl<L> Accept for HyperAcceptor<L>
+where
+    L: TcpListener + Send + Unpin + 'static,
+{
+    type Conn = L::Stream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = &mut *self;
+        if let Some(mut listener) = this.listener.take() {
+            this.accept = Some(Box::pin(async move {
+                let result = listener.accept().await.map(|(stream, _)| stream);
+                (listener, result)
+            }));
+        }
+        let accept = this.accept.as_mut().expect("accept missing");
+        let (listener, result) = futures::ready!(accept.poll_unpin(cx));
+        this.accept = None;
+        this.listener = Some(listener);
+        Poll::Ready(Some(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        TcpListener,
+    };
+    use futures::TryStreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that a hyper client can make requests to a server in the simulation.
+    fn client_request() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = socket.read(&mut buf).await.unwrap();
+                let response = b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello";
+                socket.write_all(response).await.unwrap();
+                // keep the connection open until the client has read the response.
+                let _ = socket.read(&mut buf).await;
+            });
+            let client = hyper::Client::builder()
+                .build::<_, hyper::Body>(HyperConnector::new(handle.clone()));
+            let response = client
+                .get("http://127.0.0.1:8080/".parse().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+            let body = response.into_body().try_concat().await.unwrap();
+            assert_eq!(&body[..], b"hello");
+        });
+    }
+
+    #[test]
+    /// Test that a hyper server hosted in the simulation serves requests.
+    fn server() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+            let listener = handle.bind(addr).await.unwrap();
+            let make_service = hyper::service::make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(|_| async {
+                    Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from("hi")))
+                }))
+            });
+            let server = hyper::Server::builder(HyperAcceptor::new(listener)).serve(make_service);
+            handle.spawn(async move {
+                server.await.unwrap();
+            });
+            let client = hyper::Client::builder()
+                .build::<_, hyper::Body>(HyperConnector::new(handle.clone()));
+            for _ in 0..2 {
+                let response = client
+                    .get("http://127.0.0.1:8080/".parse().unwrap())
+                    .await
+                    .unwrap();
+                let body = response.into_body().try_concat().await.unwrap();
+                assert_eq!(&body[..], b"hi");
+            }
+        });
+    }
+}
@@ -0,0 +1,28 @@
+//! Identity of the host the process is running on.
+use std::{env, fs, net};
+
+/// Returns the hostname of this machine, or `localhost` if it cannot be determined.
+pub(crate) fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Returns the address of the interface used to reach other hosts, or the loopback address if
+/// there is none. No packets are sent, connecting a UDP socket only selects a route.
+pub(crate) fn local_ip() -> net::IpAddr {
+    net::UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("192.0.2.1:9")?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .ok()
+        .filter(|ip| !ip.is_unspecified())
+        .unwrap_or_else(|| net::Ipv4Addr::LOCALHOST.into())
+}
@@ -94,8 +94,7 @@
 //!            }
 //!        }
 //!    }
-//!    #[test]
-//!    fn test() {
+//!    fn main() {
 //!        // This particular example will fail with a seed value of 22 due to not handling disconnects.
 //!        let mut runtime = simulation::deterministic::DeterministicRuntime::new_with_seed(1).unwrap();
 //!        let handle = runtime.handle();
@@ -113,15 +112,55 @@
 //! [Tokio]: https://github.com/tokio-rs
 //! [CurrentThread]:[tokio_executor::current_thread::CurrentThread]
 //! [Delay]:[tokio_timer::Delay]
-//! [Timeout]:[tokio_timer::Timeout]
+//! [Timeout]:[Timeout]
 
-use async_trait::async_trait;
-use futures::{Future, FutureExt};
-use std::{io, net, time};
+use futures::{Future, FutureExt, Stream};
+use std::{fmt, io, net, time};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+pub mod ambient;
+pub mod assertions;
+#[cfg(feature = "async-std")]
+pub mod asyncstd;
+pub mod backoff;
+pub mod buggify;
+#[cfg(feature = "compat")]
+pub mod compat;
+pub mod connect;
 pub mod deterministic;
+pub mod differential;
+pub mod dynamic;
+pub mod future;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hash;
+pub mod history;
+pub mod http_stub;
+#[cfg(feature = "hyper")]
+pub mod hyper_compat;
+mod identity;
+pub mod logger;
+#[cfg(feature = "loom")]
+pub mod loom;
+pub mod metrics;
+pub mod otel;
+mod rng;
+pub mod rpc;
+pub mod schedule;
+#[cfg(feature = "tower")]
+pub mod service;
 pub mod singlethread;
+pub mod sync;
+pub mod threadpool;
+mod timeout;
+pub mod tls;
+mod uuid;
+
+pub use ambient::{bind, connect, delay, delay_for, now, spawn, timeout, try_spawn};
+pub use connect::ToSocketAddrs;
+pub use rng::RngHandle;
+pub use timeout::{Elapsed, Timeout};
+pub use uuid::Uuid;
 
 mod example {
     use crate::{Environment, TcpListener};
@@ -190,21 +229,153 @@ pub enum Error {
     RuntimeBuild {
         source: io::Error,
     },
-    CurrentThreadRun {
-        source: tokio_executor::current_thread::RunError,
+    CurrentThreadTurn {
+        source: tokio_executor::current_thread::TurnError,
+    },
+    /// Reading or writing a saved artifact failed.
+    Io {
+        source: io::Error,
+    },
+    /// A saved artifact could not be encoded or decoded.
+    Serialization {
+        source: serde_json::Error,
+    },
+    /// A saved artifact uses a format version this crate does not understand.
+    UnsupportedFormat {
+        kind: String,
+        version: u32,
+    },
+    /// A saved artifact was produced with an RNG algorithm this crate does not provide.
+    UnknownRngAlgorithm {
+        name: String,
+    },
+    /// An environment variable configuring the runtime has a value which could not be parsed.
+    InvalidEnvVar {
+        name: String,
+        value: String,
     },
+    /// A seed is not written in decimal, hexadecimal prefixed with `0x`, or seed words.
+    InvalidSeed {
+        value: String,
+    },
+    /// A link was configured to or from a cluster which was not configured.
+    UnknownCluster {
+        name: String,
+    },
+    /// Every remaining task of a simulation is waiting, and no timer is pending to wake them.
+    Deadlock {
+        tasks: Vec<deterministic::TaskInfo>,
+    },
+    /// A simulation panicked with `message`.
+    Panicked {
+        message: String,
+        context: Box<deterministic::FailureContext>,
+    },
+    /// Hosts left listeners or connections open once every task of a simulation completed.
+    ResourceLeak {
+        resources: Vec<deterministic::OpenResources>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Spawn { source } => write!(f, "failed to spawn task: {}", source),
+            Error::RuntimeBuild { source } => write!(f, "failed to build runtime: {}", source),
+            Error::CurrentThreadTurn { source } => write!(f, "failed to run executor: {}", source),
+            Error::Io { source } => write!(f, "failed to access artifact: {}", source),
+            Error::Serialization { source } => {
+                write!(f, "failed to encode or decode artifact: {}", source)
+            }
+            Error::UnsupportedFormat { kind, version } => {
+                write!(f, "unsupported {} format version {}", kind, version)
+            }
+            Error::UnknownRngAlgorithm { name } => write!(f, "unknown RNG algorithm `{}`", name),
+            Error::InvalidEnvVar { name, value } => {
+                write!(
+                    f,
+                    "invalid value `{}` for environment variable {}",
+                    value, name
+                )
+            }
+            Error::InvalidSeed { value } => write!(f, "invalid seed `{}`", value),
+            Error::UnknownCluster { name } => write!(f, "unknown cluster `{}`", name),
+            Error::Deadlock { tasks } => {
+                let ids: Vec<_> = tasks.iter().map(|task| task.id.0.to_string()).collect();
+                write!(f, "deadlock, tasks {} can never be woken", ids.join(", "))?;
+                for task in tasks {
+                    write!(f, "\n  {}", task)?;
+                }
+                Ok(())
+            }
+            Error::Panicked { message, context } => {
+                write!(f, "simulation panicked: {}\n{}", message, context)
+            }
+            Error::ResourceLeak { resources } => {
+                write!(f, "resources left open")?;
+                for leaked in resources {
+                    write!(f, "\n{}", leaked)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Spawn { source } => Some(source),
+            Error::RuntimeBuild { source } | Error::Io { source } => Some(source),
+            Error::CurrentThreadTurn { source } => Some(source),
+            Error::Serialization { source } => Some(source),
+            _ => None,
+        }
+    }
 }
 
-#[async_trait]
 pub trait Environment: Unpin + Sized + Clone + Send + 'static {
     type TcpStream: TcpStream + Send + 'static + Unpin;
     type TcpListener: TcpListener + Send + 'static + Unpin;
 
+    /// Spawns `future` onto the executor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the executor has shut down. Use `try_spawn` where that may race with spawning.
     fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.try_spawn(future).expect("failed to spawn task")
+    }
+    /// Spawns `future` onto the executor, returning `Error::Spawn` if the executor has shut
+    /// down.
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
     where
         F: Future<Output = ()> + Send + 'static;
     /// Return the time now according to the executor.
     fn now(&self) -> time::Instant;
+    /// Returns the wall clock time. In deterministic mode, this is a simulated time which
+    /// starts at the time configured with `Builder::start_time` and advances with `now`.
+    fn system_time(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
+    /// Returns the milliseconds since the unix epoch according to `system_time`, for timestamps
+    /// written to wire formats or logs. Times before the epoch return 0.
+    fn now_unix_millis(&self) -> u64 {
+        unix_time(self.system_time()).as_millis() as u64
+    }
+    /// Returns the nanoseconds since the unix epoch according to `system_time`. Times before
+    /// the epoch return 0.
+    fn now_unix_nanos(&self) -> u64 {
+        unix_time(self.system_time()).as_nanos() as u64
+    }
+    /// Runs `future` without simulated time advancing, so every read of the clock while it
+    /// runs returns the same instant. Time is not frozen outside of deterministic mode.
+    fn freeze_time<F: Future>(&self, future: F) -> deterministic::FreezeTime<F> {
+        deterministic::FreezeTime::new(future)
+    }
     /// Returns a delay future which completes after the provided instant.
     fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay;
     /// Returns a delay future which completes at some time from now.
@@ -212,15 +383,89 @@ pub trait Environment: Unpin + Sized + Clone + Send + 'static {
         let now = self.now();
         self.delay(now + from_now)
     }
-    /// Creates a timeout future which will execute blah blah
-    fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio_timer::Timeout<T>;
+    /// Requires `value` to complete before `timeout` has elapsed, resolving to its output or to
+    /// `Elapsed` if it did not complete in time.
+    fn timeout<T>(&self, value: T, timeout: time::Duration) -> Timeout<T>;
+    /// Returns a source of randomness. In deterministic mode, values are drawn from a stream
+    /// derived from the seed and the calling task.
+    fn rng(&self) -> RngHandle;
+    /// Returns a source of randomness for the component identified by `name`. In deterministic
+    /// mode, the stream is derived from the seed and `name` alone, so it is unaffected by
+    /// randomness drawn by other components.
+    fn rng_for(&self, name: &str) -> RngHandle;
+    /// Shuffles `values` in place, drawing from `rng`.
+    fn shuffle<T>(&self, values: &mut [T]) {
+        rand::seq::SliceRandom::shuffle(values, &mut self.rng())
+    }
+    /// Returns `amount` elements of `values` chosen at random and in random order, or all of
+    /// them if there are fewer than `amount`, drawing from `rng`.
+    fn sample<I: IntoIterator>(&self, values: I, amount: usize) -> Vec<I::Item> {
+        let mut rng = self.rng();
+        let mut sample =
+            rand::seq::IteratorRandom::choose_multiple(values.into_iter(), &mut rng, amount);
+        // only which elements are chosen is random, not their order.
+        rand::seq::SliceRandom::shuffle(&mut sample[..], &mut rng);
+        sample
+    }
+    /// Returns a random identifier. In deterministic mode, ids are drawn from a stream derived
+    /// from the seed, so a run generates the same ids every time it is repeated.
+    fn next_id(&self) -> u64 {
+        rand::RngCore::next_u64(&mut self.rng_for("simulation::ids"))
+    }
+    /// Returns a backoff for retrying an operation according to `policy`, with jitter drawn
+    /// from `rng` and delays awaited with `delay`.
+    fn backoff(&self, policy: backoff::BackoffPolicy) -> backoff::Backoff<Self> {
+        backoff::Backoff::new(self.clone(), policy)
+    }
+    /// Spawns a task running `job` at each time of `schedule`, according to `system_time`.
+    /// Runs are never concurrent: a run which comes due while the job is running is skipped.
+    fn schedule<F, Fut>(&self, schedule: schedule::Schedule, job: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(schedule::run(self.clone(), schedule, job))
+    }
+    /// Returns a random version 4 UUID, drawn from the same stream as `next_id`.
+    fn new_uuid(&self) -> Uuid {
+        let mut bytes = [0; 16];
+        rand::RngCore::fill_bytes(&mut self.rng_for("simulation::ids"), &mut bytes);
+        Uuid::from_random_bytes(bytes)
+    }
+
+    /// Returns the name of the host this environment runs on. In deterministic mode, this is
+    /// the name of the simulated host.
+    fn hostname(&self) -> String {
+        identity::hostname()
+    }
+    /// Returns the address other hosts reach this host on. In deterministic mode, this is the
+    /// address of the simulated host.
+    fn local_ip(&self) -> net::IpAddr {
+        identity::local_ip()
+    }
 
-    async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
+    /// Accepts a connection on `listener`, failing with `TimedOut` if none arrives within
+    /// `timeout`.
+    fn accept_timeout<'a, L>(
+        &self,
+        listener: &'a mut L,
+        timeout: time::Duration,
+    ) -> impl Future<Output = io::Result<(L::Stream, net::SocketAddr)>> + Send + 'a
     where
-        A: Into<net::SocketAddr> + Send + Sync;
-    async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
+        L: TcpListener + Send,
+    {
+        let accept = self.timeout(listener.accept(), timeout);
+        async move { timed_out(accept.await) }
+    }
+
+    fn bind<A>(&self, addr: A) -> impl Future<Output = io::Result<Self::TcpListener>> + Send
     where
         A: Into<net::SocketAddr> + Send + Sync;
+    /// Opens a connection to one of the candidate addresses `addr`, as described in the
+    /// `connect` module.
+    fn connect<A>(&self, addr: A) -> impl Future<Output = io::Result<Self::TcpStream>> + Send
+    where
+        A: ToSocketAddrs + Send + Sync;
 }
 
 pub trait TcpStream: AsyncRead + AsyncWrite + Unpin {
@@ -229,15 +474,48 @@ pub trait TcpStream: AsyncRead + AsyncWrite + Unpin {
     fn shutdown(&self) -> io::Result<()>;
 }
 
-#[async_trait]
 pub trait TcpListener {
     type Stream: TcpStream + Send;
-    async fn accept(&mut self) -> Result<(Self::Stream, net::SocketAddr), io::Error>;
+    /// Stream of accepted connections returned by `incoming`.
+    type Incoming: Stream<Item = io::Result<Self::Stream>> + Send + Unpin;
+    fn accept(
+        &mut self,
+    ) -> impl Future<Output = Result<(Self::Stream, net::SocketAddr), io::Error>> + Send;
+    /// Accepts a connection, failing with `TimedOut` if none arrives within `timeout`
+    /// according to the time of the ambient environment.
+    ///
+    /// # Panics
+    ///
+    /// Panics if polled outside of a runtime.
+    fn accept_timeout(
+        &mut self,
+        timeout: time::Duration,
+    ) -> impl Future<Output = Result<(Self::Stream, net::SocketAddr), io::Error>> + Send
+    where
+        Self: Send,
+    {
+        async move { timed_out(ambient::timeout(timeout, self.accept()).await) }
+    }
+    /// Returns a stream of the connections accepted by this listener.
+    fn incoming(self) -> Self::Incoming
+    where
+        Self: Sized;
     fn local_addr(&self) -> Result<net::SocketAddr, io::Error>;
     fn ttl(&self) -> io::Result<u32>;
     fn set_ttl(&self, ttl: u32) -> io::Result<()>;
 }
 
+/// Flattens the result of a timed out IO operation, failing with `TimedOut` if it elapsed.
+fn timed_out<T>(result: Result<io::Result<T>, Elapsed>) -> io::Result<T> {
+    result?
+}
+
+/// Returns the time elapsed between the unix epoch and `time`, or zero if `time` is before it.
+fn unix_time(time: time::SystemTime) -> time::Duration {
+    time.duration_since(time::UNIX_EPOCH)
+        .unwrap_or_else(|_| time::Duration::from_secs(0))
+}
+
 pub fn spawn_with_result<F, E, U>(env: &E, future: F) -> impl Future<Output = U>
 where
     F: Future<Output = U> + Send + 'static,
@@ -0,0 +1,256 @@
+//! A `log` implementation which captures records into the log of the deterministic runtime.
+//!
+//! Once `SimLogger` is installed, records logged while a `DeterministicRuntime` is executing
+//! on the thread are stored as `SimEvent::Log` events of that runtime, along with the task
+//! which logged them and the host set with `with_host`, or else the hostname of the cluster
+//! the task runs in. Logs are then captured per-seed, and included in the `Trace` of a
+//! `FailureReport`, where `Trace::logs_by_host` splits them into the log of each host as if
+//! they were collected from separate machines. Records logged outside of a runtime are written
+//! to stderr. Events of code instrumented with `tracing` are captured the same way by
+//! `otel::SpanLayer`.
+use crate::{
+    deterministic::{context, LoggedEvent, SimEvent},
+    Environment,
+};
+use futures::Poll;
+use pin_project::pin_project;
+use std::{cell::RefCell, collections::BTreeMap, future::Future, pin::Pin, task::Context};
+
+thread_local! {
+    static HOST: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A `log::Log` implementation recording into the event log of the current runtime.
+#[derive(Debug)]
+pub struct SimLogger {
+    level: log::LevelFilter,
+    echo: bool,
+}
+
+impl Default for SimLogger {
+    fn default() -> Self {
+        Self {
+            level: log::LevelFilter::Info,
+            echo: false,
+        }
+    }
+}
+
+impl SimLogger {
+    /// Returns a logger for records of `Info` and above, which does not echo captured records.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the most verbose level which is recorded.
+    pub fn with_level(mut self, level: log::LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets whether records captured by a runtime are also written to stderr.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Installs this logger as the global logger.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl log::Log for SimLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = record.level().to_string();
+        let logged = match capture(&level, record.target(), record.args().to_string()) {
+            Some(logged) => logged,
+            None => {
+                eprintln!("{} {}: {}", level, record.target(), record.args());
+                return;
+            }
+        };
+        if self.echo {
+            if let Some(line) = format_line(&logged) {
+                eprintln!("{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Records `message` as a `SimEvent::Log` event of the runtime executing on this thread,
+/// attributed to the current host and task. Returns `None` outside of a runtime.
+pub(crate) fn capture(level: &str, target: &str, message: String) -> Option<LoggedEvent> {
+    context::current()?;
+    context::record(SimEvent::Log {
+        host: host(),
+        task: context::current_task(),
+        level: level.to_string(),
+        target: target.to_string(),
+        message,
+    })
+}
+
+/// Returns the host records logged on this thread are attributed to, the host set with
+/// `with_host` or else the hostname of the ambient environment.
+pub(crate) fn host() -> Option<String> {
+    current_host().or_else(|| crate::ambient::current().map(|env| env.hostname()))
+}
+
+/// Formats a `SimEvent::Log` event as a line prefixed with the simulated time, host and task
+/// it was logged by. Returns `None` for other events.
+pub(crate) fn format_line(logged: &LoggedEvent) -> Option<String> {
+    match &logged.event {
+        SimEvent::Log {
+            host,
+            task,
+            level,
+            target,
+            message,
+        } => {
+            let task = task.map_or_else(|| "-".to_string(), |task| task.0.to_string());
+            Some(format!(
+                "[{:.6}s {} task={}] {} {}: {}",
+                logged.elapsed.as_secs_f64(),
+                host.as_deref().unwrap_or("-"),
+                task,
+                level,
+                target,
+                message
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Formats the records among `events` as by `format_line`, grouped by the host which logged
+/// them. Records logged without a host are grouped under `-`.
+pub(crate) fn lines_by_host(events: &[LoggedEvent]) -> BTreeMap<String, Vec<String>> {
+    let mut hosts = BTreeMap::<_, Vec<_>>::new();
+    for logged in events {
+        if let (SimEvent::Log { host, .. }, Some(line)) = (&logged.event, format_line(logged)) {
+            let host = host.clone().unwrap_or_else(|| "-".to_string());
+            hosts.entry(host).or_default().push(line);
+        }
+    }
+    hosts
+}
+
+/// Returns the host set with `with_host` for the future being polled on this thread.
+pub(crate) fn current_host() -> Option<String> {
+    HOST.with(|host| host.borrow().clone())
+}
+
+/// Installs `host` for the future being polled on this thread, returning the previous host.
+pub(crate) fn replace_host(host: Option<String>) -> Option<String> {
+    HOST.with(|current| current.replace(host))
+}
+
+/// Runs `future` with records it logs attributed to `host`.
+pub fn with_host<F: Future>(host: &str, future: F) -> WithHost<F> {
+    WithHost {
+        host: host.to_string(),
+        future,
+    }
+}
+
+/// Future returned by `with_host`.
+#[pin_project]
+#[derive(Debug)]
+pub struct WithHost<F> {
+    host: String,
+    #[pin]
+    future: F,
+}
+
+impl<F: Future> Future for WithHost<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let prev = replace_host(Some(this.host.clone()));
+        let result = this.future.poll(cx);
+        replace_host(prev);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultConfig},
+        Environment,
+    };
+    use std::time::Duration;
+
+    #[test]
+    /// Test that records are captured by the runtime, prefixed with time, host and task.
+    fn captured_records() {
+        let _ = SimLogger::new().init();
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let env = handle.clone();
+            crate::spawn_with_result(
+                &handle,
+                with_host("node-1", async move {
+                    env.delay_from(Duration::from_millis(1500)).await;
+                    log::info!("elected leader");
+                }),
+            )
+            .await;
+            log::debug!("not recorded");
+        });
+        let logs = runtime.handle().logs();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(
+            logs[0],
+            "[1.500000s node-1 task=1] INFO simulation::logger::tests: elected leader"
+        );
+    }
+
+    #[test]
+    /// Test that records are separated by the cluster of the task which logged them.
+    fn records_by_host() {
+        let _ = SimLogger::new().init();
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let server = handle.new_cluster(FaultConfig::disabled());
+            let client = handle
+                .new_cluster(FaultConfig::disabled())
+                .with_hostname("client");
+            let (a, b) = (server.clone(), client.clone());
+            futures::join!(
+                crate::spawn_with_result(&server, async move {
+                    a.delay_from(Duration::from_secs(1)).await;
+                    log::info!("serving");
+                }),
+                crate::spawn_with_result(&client, async move {
+                    b.delay_from(Duration::from_secs(2)).await;
+                    log::warn!("request failed");
+                })
+            );
+            log::info!("done");
+        });
+        let logs = runtime.handle().logs_by_host();
+        assert_eq!(
+            logs.keys().collect::<Vec<_>>(),
+            vec!["client", "cluster-1", "localhost"]
+        );
+        assert!(logs["cluster-1"][0].ends_with("serving"));
+        assert!(logs["client"][0].ends_with("request failed"));
+        assert!(logs["localhost"][0].ends_with("done"));
+    }
+}
@@ -0,0 +1,88 @@
+//! Running simulations under [loom], which checks the memory orderings of atomics and locks.
+//!
+//! `model` runs a simulation once for each interleaving loom explores, building a fresh
+//! `DeterministicRuntime` with the same seed every time. The simulation supplies deterministic
+//! time, scheduling and network, so a failure is caused by either a memory ordering or a fault
+//! drawn from the seed, and both can be reproduced from the same test.
+//!
+//! Application code shares state through the types re-exported from `sync` and `thread`
+//! instead of `std`, for instance behind a `cfg` of its own:
+//!
+//! ```ignore
+//! #[cfg(feature = "loom")]
+//! use simulation::loom::sync::atomic::AtomicUsize;
+//! #[cfg(not(feature = "loom"))]
+//! use std::sync::atomic::AtomicUsize;
+//! ```
+//!
+//! [loom]: https://github.com/tokio-rs/loom
+use crate::deterministic::{Builder, DeterministicRuntime, DeterministicRuntimeHandle};
+use futures::Future;
+
+/// Atomics and locks whose interleavings are explored by loom.
+pub use ::loom::sync;
+/// Threads whose interleavings are explored by loom.
+pub use ::loom::thread;
+
+/// Runs the simulation returned by `test` under loom, with a `DeterministicRuntime` seeded
+/// with `seed`.
+///
+/// # Panics
+///
+/// Panics if the simulation panics for any interleaving explored by loom.
+pub fn model<F, U>(seed: u64, test: F)
+where
+    F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+    U: Future<Output = ()>,
+{
+    model_with(move || DeterministicRuntime::builder().seed(seed), test)
+}
+
+/// Runs the simulation returned by `test` under loom, with a `DeterministicRuntime` built
+/// by `builder`. `builder` is called once for every interleaving explored, and should return
+/// the same configuration each time. The seed is not announced by each interleaving.
+///
+/// # Panics
+///
+/// Panics if the runtime cannot be built, or if the simulation panics for any interleaving
+/// explored by loom.
+pub fn model_with<B, F, U>(builder: B, test: F)
+where
+    B: Fn() -> Builder + Send + Sync + 'static,
+    F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+    U: Future<Output = ()>,
+{
+    ::loom::model(move || {
+        let mut runtime = builder()
+            .announce_seed(false)
+            .build()
+            .expect("failed to build runtime");
+        let handle = runtime.handle();
+        runtime.block_on(test(handle));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use std::time::Duration;
+    use sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn delays_resolve_within_each_interleaving() {
+        model(3, |handle| async move {
+            let count = Arc::new(AtomicUsize::new(0));
+            let writer = count.clone();
+            let thread = thread::spawn(move || {
+                writer.fetch_add(1, Ordering::Release);
+            });
+            handle.delay_from(Duration::from_secs(1)).await;
+            thread.join().unwrap();
+            assert_eq!(count.load(Ordering::Acquire), 1);
+        });
+    }
+}
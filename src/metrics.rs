@@ -0,0 +1,290 @@
+//! Metrics captured per-run with simulated timestamps.
+//!
+//! Counters, gauges and histograms recorded while a `DeterministicRuntime` is executing on the
+//! thread are stored as `SimEvent::Metric` events of that runtime, timestamped in simulated
+//! time. `DeterministicRuntimeHandle::metrics` returns a snapshot which can be queried in
+//! assertions, such as checking that no more than 3 leader elections occurred. Outside of a
+//! runtime, recording a metric does nothing.
+//!
+//! With the `metrics` feature, `SimRecorder` forwards the counters, gauges and histograms of
+//! the `metrics` facade crate to this module, so code instrumented with `metrics::counter!`
+//! and friends is captured without changes.
+use crate::deterministic::{context, LoggedEvent, SimEvent};
+use serde::{Deserialize, Serialize};
+use std::time;
+
+/// The kind of a metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetricKind {
+    /// A count which is incremented.
+    Counter,
+    /// A value which is set.
+    Gauge,
+    /// A distribution of recorded values.
+    Histogram,
+}
+
+fn record(name: &str, kind: MetricKind, value: f64) {
+    context::record(SimEvent::Metric {
+        name: name.to_string(),
+        kind,
+        value,
+    });
+}
+
+/// Increments the counter `name` by `value`.
+pub fn increment_counter(name: &str, value: u64) {
+    record(name, MetricKind::Counter, value as f64)
+}
+
+/// Sets the gauge `name` to `value`.
+pub fn set_gauge(name: &str, value: f64) {
+    record(name, MetricKind::Gauge, value)
+}
+
+/// Records `value` in the histogram `name`.
+pub fn record_histogram(name: &str, value: f64) {
+    record(name, MetricKind::Histogram, value)
+}
+
+/// A `metrics::Recorder` forwarding to the runtime executing on the thread, installed with
+/// `metrics::set_global_recorder` or `metrics::with_local_recorder`.
+///
+/// Each metric is named after its key, followed by its labels as `name{label=value,...}` if
+/// it has any. Setting a counter to an absolute value increments it up to that value, and
+/// incrementing or decrementing a gauge sets it relative to its last value in the run.
+/// Descriptions and units are ignored.
+///
+/// Requires the `metrics` feature.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimRecorder;
+
+/// A metric registered with `SimRecorder`.
+#[cfg(feature = "metrics")]
+struct Handle {
+    name: String,
+}
+
+#[cfg(feature = "metrics")]
+impl Handle {
+    fn new(key: &::metrics::Key) -> std::sync::Arc<Self> {
+        let mut name = key.name().to_string();
+        let labels: Vec<_> = key
+            .labels()
+            .map(|label| format!("{}={}", label.key(), label.value()))
+            .collect();
+        if !labels.is_empty() {
+            name.push_str(&format!("{{{}}}", labels.join(",")));
+        }
+        std::sync::Arc::new(Self { name })
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl ::metrics::CounterFn for Handle {
+    fn increment(&self, value: u64) {
+        increment_counter(&self.name, value)
+    }
+
+    fn absolute(&self, value: u64) {
+        if let Some(total) = context::with_metrics(|metrics| metrics.counter(&self.name)) {
+            if value > total {
+                increment_counter(&self.name, value - total)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl ::metrics::GaugeFn for Handle {
+    fn increment(&self, value: f64) {
+        if let Some(last) = context::with_metrics(|metrics| metrics.gauge(&self.name)) {
+            set_gauge(&self.name, last.unwrap_or(0.0) + value)
+        }
+    }
+
+    fn decrement(&self, value: f64) {
+        ::metrics::GaugeFn::increment(self, -value)
+    }
+
+    fn set(&self, value: f64) {
+        set_gauge(&self.name, value)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl ::metrics::HistogramFn for Handle {
+    fn record(&self, value: f64) {
+        record_histogram(&self.name, value)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl ::metrics::Recorder for SimRecorder {
+    fn describe_counter(
+        &self,
+        _: ::metrics::KeyName,
+        _: Option<::metrics::Unit>,
+        _: ::metrics::SharedString,
+    ) {
+    }
+
+    fn describe_gauge(
+        &self,
+        _: ::metrics::KeyName,
+        _: Option<::metrics::Unit>,
+        _: ::metrics::SharedString,
+    ) {
+    }
+
+    fn describe_histogram(
+        &self,
+        _: ::metrics::KeyName,
+        _: Option<::metrics::Unit>,
+        _: ::metrics::SharedString,
+    ) {
+    }
+
+    fn register_counter(
+        &self,
+        key: &::metrics::Key,
+        _: &::metrics::Metadata<'_>,
+    ) -> ::metrics::Counter {
+        ::metrics::Counter::from_arc(Handle::new(key))
+    }
+
+    fn register_gauge(
+        &self,
+        key: &::metrics::Key,
+        _: &::metrics::Metadata<'_>,
+    ) -> ::metrics::Gauge {
+        ::metrics::Gauge::from_arc(Handle::new(key))
+    }
+
+    fn register_histogram(
+        &self,
+        key: &::metrics::Key,
+        _: &::metrics::Metadata<'_>,
+    ) -> ::metrics::Histogram {
+        ::metrics::Histogram::from_arc(Handle::new(key))
+    }
+}
+
+/// A value recorded for a metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub kind: MetricKind,
+    pub value: f64,
+    /// Simulated time since the start of the run at which the value was recorded.
+    pub elapsed: time::Duration,
+}
+
+/// The metrics recorded during a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    samples: Vec<Sample>,
+}
+
+impl Metrics {
+    /// Adds the value of `logged` if it is a metric.
+    pub(crate) fn count(&mut self, logged: &LoggedEvent) {
+        if let SimEvent::Metric { name, kind, value } = &logged.event {
+            self.samples.push(Sample {
+                name: name.clone(),
+                kind: *kind,
+                value: *value,
+                elapsed: logged.elapsed,
+            });
+        }
+    }
+
+    /// Returns every value recorded for `name`, in the order they were recorded.
+    pub fn samples<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Sample> + 'a {
+        self.samples
+            .iter()
+            .filter(move |sample| sample.name == name)
+    }
+
+    /// Returns the total of the counter `name`, or 0 if it was never incremented.
+    pub fn counter(&self, name: &str) -> u64 {
+        self.samples(name)
+            .filter(|sample| sample.kind == MetricKind::Counter)
+            .map(|sample| sample.value as u64)
+            .sum()
+    }
+
+    /// Returns the last value the gauge `name` was set to.
+    pub fn gauge(&self, name: &str) -> Option<f64> {
+        self.samples(name)
+            .filter(|sample| sample.kind == MetricKind::Gauge)
+            .last()
+            .map(|sample| sample.value)
+    }
+
+    /// Returns the values recorded in the histogram `name`.
+    pub fn histogram(&self, name: &str) -> Vec<f64> {
+        self.samples(name)
+            .filter(|sample| sample.kind == MetricKind::Histogram)
+            .map(|sample| sample.value)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that metrics are captured by the runtime with simulated timestamps.
+    fn captured_metrics() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            for term in 0..3 {
+                handle.delay_from(Duration::from_secs(1)).await;
+                increment_counter("elections", 1);
+                set_gauge("term", f64::from(term));
+                record_histogram("election_ms", 150.0 + f64::from(term));
+            }
+        });
+        let metrics = runtime.handle().metrics();
+        assert_eq!(metrics.counter("elections"), 3);
+        assert_eq!(metrics.counter("missing"), 0);
+        assert_eq!(metrics.gauge("term"), Some(2.0));
+        assert_eq!(metrics.histogram("election_ms"), vec![150.0, 151.0, 152.0]);
+        let last = metrics.samples("elections").last().unwrap();
+        assert!(last.elapsed >= Duration::from_secs(3));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    /// Test that metrics recorded through the `metrics` facade are captured by the runtime.
+    fn facade() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            for _ in 0..3 {
+                handle.delay_from(Duration::from_secs(1)).await;
+                ::metrics::with_local_recorder(&SimRecorder, || {
+                    ::metrics::counter!("elections").increment(1);
+                    ::metrics::counter!("requests", "node" => "a").absolute(10);
+                    ::metrics::gauge!("leaders").increment(1.0);
+                    ::metrics::histogram!("election_ms").record(150.0);
+                });
+            }
+            ::metrics::with_local_recorder(&SimRecorder, || {
+                ::metrics::gauge!("leaders").decrement(2.0);
+            });
+        });
+        let metrics = runtime.handle().metrics();
+        assert_eq!(metrics.counter("elections"), 3);
+        assert_eq!(metrics.counter("requests{node=a}"), 10);
+        assert_eq!(metrics.gauge("leaders"), Some(1.0));
+        assert_eq!(metrics.histogram("election_ms"), vec![150.0; 3]);
+        assert!(metrics.samples("elections").last().unwrap().elapsed >= Duration::from_secs(3));
+    }
+}
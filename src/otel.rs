@@ -0,0 +1,265 @@
+//! Spans timestamped by the environment's clock, exported in the OTLP JSON format.
+//!
+//! `SpanRecorder` buffers finished spans in memory, taking their start and end times from
+//! `Environment::now` and their trace and span ids from `Environment::rng_for`. Under the
+//! `DeterministicRuntime` a run therefore produces the same spans, with the same simulated
+//! durations, every time its seed is repeated. `SpanRecorder::to_otlp_json` renders the spans
+//! as an OTLP `ExportTraceServiceRequest`, which can be posted to the `/v1/traces` endpoint of
+//! an OpenTelemetry collector to view the run in an existing trace UI.
+//!
+//! Code already instrumented with `tracing`, directly or through `tracing-opentelemetry`,
+//! records into a `SpanRecorder` by installing a `SpanLayer`, available with the `tracing`
+//! feature.
+use crate::{Environment, RngHandle};
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::{fmt, sync, time};
+
+#[cfg(feature = "tracing")]
+mod layer;
+#[cfg(feature = "tracing")]
+pub use layer::SpanLayer;
+
+/// Unix time in nanoseconds at which a recorder's clock starts, 2020-01-01T00:00:00Z.
+pub const DEFAULT_EPOCH_NANOS: u64 = 1_577_836_800_000_000_000;
+
+#[derive(Debug, Clone)]
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: String,
+    start: u64,
+    end: u64,
+    attributes: Vec<(String, String)>,
+}
+
+struct Inner {
+    rng: RngHandle,
+    spans: Vec<SpanData>,
+}
+
+/// Records spans in memory, timestamped by an `Environment`.
+#[derive(Clone)]
+pub struct SpanRecorder<E> {
+    env: E,
+    service_name: String,
+    start: time::Instant,
+    epoch_nanos: u64,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl<E> fmt::Debug for SpanRecorder<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        f.debug_struct("SpanRecorder")
+            .field("service_name", &self.service_name)
+            .field("spans", &lock.spans.len())
+            .finish()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl<E: Environment> SpanRecorder<E> {
+    /// Returns a recorder for spans of `service_name`. The time at which the recorder is
+    /// created is exported as `DEFAULT_EPOCH_NANOS`.
+    pub fn new(env: E, service_name: &str) -> Self {
+        let inner = Inner {
+            rng: env.rng_for("simulation::otel"),
+            spans: vec![],
+        };
+        Self {
+            start: env.now(),
+            env,
+            service_name: service_name.to_string(),
+            epoch_nanos: DEFAULT_EPOCH_NANOS,
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Sets the Unix time in nanoseconds at which the recorder's clock starts.
+    pub fn with_epoch(mut self, epoch_nanos: u64) -> Self {
+        self.epoch_nanos = epoch_nanos;
+        self
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.epoch_nanos + (self.env.now() - self.start).as_nanos() as u64
+    }
+
+    /// Starts a span named `name` in a new trace.
+    pub fn span(&self, name: &str) -> Span<E> {
+        let mut trace_id = [0; 16];
+        self.inner.lock().unwrap().rng.fill_bytes(&mut trace_id);
+        self.start_span(name, trace_id, None)
+    }
+
+    fn start_span(&self, name: &str, trace_id: [u8; 16], parent_id: Option<[u8; 8]>) -> Span<E> {
+        let mut span_id = [0; 8];
+        self.inner.lock().unwrap().rng.fill_bytes(&mut span_id);
+        Span {
+            recorder: self.clone(),
+            data: Some(SpanData {
+                trace_id,
+                span_id,
+                parent_id,
+                name: name.to_string(),
+                start: self.timestamp(),
+                end: 0,
+                attributes: vec![],
+            }),
+        }
+    }
+
+    /// Returns the number of spans which have ended.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().spans.len()
+    }
+
+    /// Returns `true` if no span has ended.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the spans which have ended as an OTLP JSON `ExportTraceServiceRequest`.
+    pub fn to_otlp_json(&self) -> String {
+        let lock = self.inner.lock().unwrap();
+        let spans: Vec<Value> = lock
+            .spans
+            .iter()
+            .map(|span| {
+                let attributes: Vec<Value> = span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+                    .collect();
+                let mut value = json!({
+                    "traceId": hex(&span.trace_id),
+                    "spanId": hex(&span.span_id),
+                    "name": span.name,
+                    "kind": 1,
+                    "startTimeUnixNano": span.start.to_string(),
+                    "endTimeUnixNano": span.end.to_string(),
+                    "attributes": attributes,
+                });
+                if let Some(parent_id) = span.parent_id {
+                    value["parentSpanId"] = json!(hex(&parent_id));
+                }
+                value
+            })
+            .collect();
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": {"stringValue": self.service_name},
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": {"name": "simulation", "version": env!("CARGO_PKG_VERSION")},
+                    "spans": spans,
+                }],
+            }],
+        });
+        request.to_string()
+    }
+}
+
+/// A span which is recorded when it is ended or dropped.
+pub struct Span<E: Environment> {
+    recorder: SpanRecorder<E>,
+    data: Option<SpanData>,
+}
+
+impl<E: Environment> fmt::Debug for Span<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.data.as_ref().map(|data| data.name.as_str());
+        f.debug_struct("Span").field("name", &name).finish()
+    }
+}
+
+impl<E: Environment> Span<E> {
+    /// Starts a span named `name` as a child of this span.
+    pub fn child(&self, name: &str) -> Span<E> {
+        let data = self.data.as_ref().expect("span ended");
+        self.recorder
+            .start_span(name, data.trace_id, Some(data.span_id))
+    }
+
+    /// Sets an attribute on the span.
+    pub fn set_attribute(&mut self, key: &str, value: impl Into<String>) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key.to_string(), value.into()));
+        }
+    }
+
+    /// Sets the `sim.connection_id` attribute to `id`, so the spans of the hosts at either end
+    /// of a simulated connection can be matched up.
+    pub fn set_connection(&mut self, id: crate::deterministic::ConnectionId) {
+        self.set_attribute("sim.connection_id", id.to_string());
+    }
+
+    /// Ends the span at the current time.
+    pub fn end(mut self) {
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end = self.recorder.timestamp();
+            self.recorder.inner.lock().unwrap().spans.push(data);
+        }
+    }
+}
+
+impl<E: Environment> Drop for Span<E> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::time::Duration;
+
+    fn export(seed: u64) -> Value {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let recorder = SpanRecorder::new(handle.clone(), "kv");
+            let mut request = recorder.span("request");
+            request.set_attribute("key", "a");
+            let write = request.child("write");
+            handle.delay_from(Duration::from_millis(10)).await;
+            write.end();
+            request.end();
+            serde_json::from_str(&recorder.to_otlp_json()).unwrap()
+        })
+    }
+
+    #[test]
+    /// Test that spans are timestamped in simulated time, with ids drawn from the seed.
+    fn simulated_spans() {
+        assert_eq!(export(1), export(1));
+        assert_ne!(export(1), export(2));
+        let export = export(1);
+        let spans = export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 2);
+        let (write, request) = (&spans[0], &spans[1]);
+        assert_eq!(write["parentSpanId"], request["spanId"]);
+        assert_eq!(write["traceId"], request["traceId"]);
+        let nanos =
+            |span: &Value, field: &str| span[field].as_str().unwrap().parse::<u64>().unwrap();
+        assert_eq!(nanos(request, "startTimeUnixNano"), DEFAULT_EPOCH_NANOS);
+        let duration = nanos(write, "endTimeUnixNano") - nanos(write, "startTimeUnixNano");
+        assert!((10_000_000..20_000_000).contains(&duration));
+    }
+}
@@ -0,0 +1,201 @@
+//! A `tracing` layer recording spans into a `SpanRecorder`, and events into the log of the
+//! runtime.
+use super::{Span, SpanRecorder};
+use crate::Environment;
+use std::fmt;
+use tracing_core::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// A `tracing_subscriber::Layer` recording the spans of code instrumented with `tracing` into
+/// a `SpanRecorder`, so they are timestamped by the environment's clock and exported with
+/// `SpanRecorder::to_otlp_json`. The fields of each span are recorded as its attributes, and a
+/// span entered within another span becomes its child in the same trace, and spans started by a
+/// host carry its name as the `host.name` attribute.
+///
+/// Events are captured as `SimEvent::Log` events of the runtime executing on the thread, as
+/// `logger::SimLogger` captures records, so they appear in `logs_by_host` and the logs saved
+/// with a failure report under the host which emitted them.
+///
+/// Requires the `tracing` feature.
+#[derive(Debug, Clone)]
+pub struct SpanLayer<E> {
+    recorder: SpanRecorder<E>,
+}
+
+impl<E> SpanLayer<E> {
+    /// Returns a layer recording spans into `recorder`.
+    pub fn new(recorder: SpanRecorder<E>) -> Self {
+        Self { recorder }
+    }
+}
+
+/// Records the fields of a `tracing` span as attributes of a recorded span.
+struct Attributes<'a, E: Environment>(&'a mut Span<E>);
+
+impl<E: Environment> Visit for Attributes<'_, E> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.set_attribute(field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.set_attribute(field.name(), format!("{:?}", value));
+    }
+}
+
+/// Formats the fields of a `tracing` event as a log message, the `message` field followed by
+/// the others as `name=value`.
+#[derive(Default)]
+struct Message {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Message {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.fields
+                .push_str(&format!(" {}={}", field.name(), value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message.push_str(&format!("{:?}", value));
+        } else {
+            self.fields
+                .push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+}
+
+impl<E, S> Layer<S> for SpanLayer<E>
+where
+    E: Environment + Send + Sync + 'static,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("new span missing from the registry");
+        let name = attrs.metadata().name();
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<Span<E>>().map(|p| p.child(name)));
+        let mut recorded = parent.unwrap_or_else(|| self.recorder.span(name));
+        attrs.record(&mut Attributes(&mut recorded));
+        if let Some(host) = crate::logger::host() {
+            recorded.set_attribute("host.name", host);
+        }
+        span.extensions_mut().insert(recorded);
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        let metadata = event.metadata();
+        let message = message.message + &message.fields;
+        crate::logger::capture(&metadata.level().to_string(), metadata.target(), message);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(recorded) = span.extensions_mut().get_mut::<Span<E>>() {
+                values.record(&mut Attributes(recorded));
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(recorded) = span.extensions_mut().remove::<Span<E>>() {
+                recorded.end();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use serde_json::Value;
+    use std::time::Duration;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    /// Test that `tracing` spans are recorded in simulated time, nested as they were entered.
+    fn records_tracing_spans() {
+        let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+        let handle = runtime.handle();
+        let export: Value = runtime.block_on(async {
+            let recorder = SpanRecorder::new(handle.clone(), "kv");
+            let layer = SpanLayer::new(recorder.clone());
+            let _guard =
+                tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+            let request = tracing::info_span!("request", key = "a", attempt = 1);
+            let write = request.in_scope(|| tracing::info_span!("write"));
+            handle.delay_from(Duration::from_millis(10)).await;
+            drop(write);
+            drop(request);
+            serde_json::from_str(&recorder.to_otlp_json()).unwrap()
+        });
+        let spans = export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        // the executor instruments its polls, so spans are found by name.
+        let span = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap();
+        let (write, request) = (span("write"), span("request"));
+        assert!(request.get("parentSpanId").is_none());
+        assert_eq!(write["parentSpanId"], request["spanId"]);
+        assert_eq!(write["traceId"], request["traceId"]);
+        assert_eq!(request["attributes"][0]["key"], "key");
+        assert_eq!(request["attributes"][0]["value"]["stringValue"], "a");
+        assert_eq!(request["attributes"][1]["value"]["stringValue"], "1");
+        let nanos =
+            |span: &Value, field: &str| span[field].as_str().unwrap().parse::<u64>().unwrap();
+        let duration = nanos(write, "endTimeUnixNano") - nanos(write, "startTimeUnixNano");
+        assert!((10_000_000..20_000_000).contains(&duration));
+    }
+
+    #[test]
+    /// Test that `tracing` events are captured in the log of the host which emitted them, and
+    /// that spans carry the name of the host which started them.
+    fn records_tracing_events() {
+        let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+        let handle = runtime.handle();
+        let export: Value = runtime.block_on(async {
+            let recorder = SpanRecorder::new(handle.clone(), "kv");
+            let layer = SpanLayer::new(recorder.clone());
+            let _guard =
+                tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+            let env = handle.clone();
+            crate::logger::with_host("node-1", async move {
+                let _span = tracing::info_span!("election").entered();
+                env.delay_from(Duration::from_millis(1500)).await;
+                tracing::warn!(term = 2, "elected leader");
+            })
+            .await;
+            serde_json::from_str(&recorder.to_otlp_json()).unwrap()
+        });
+        let logs = runtime.handle().logs_by_host();
+        assert_eq!(
+            logs["node-1"],
+            vec![
+                "[1.500000s node-1 task=0] WARN simulation::otel::layer::tests: elected leader \
+                 term=2"
+            ]
+        );
+        let spans = export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let election = spans
+            .iter()
+            .find(|span| span["name"] == "election")
+            .unwrap();
+        assert_eq!(election["attributes"][0]["key"], "host.name");
+        assert_eq!(election["attributes"][0]["value"]["stringValue"], "node-1");
+    }
+}
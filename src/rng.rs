@@ -0,0 +1,204 @@
+//! Randomness provided by an `Environment`.
+//!
+//! Applications which call `rand::thread_rng` for backoff jitter or sampling behave differently
+//! on every run, even under the deterministic runtime. `Environment::rng` instead returns an
+//! `RngHandle`, which under the `DeterministicRuntime` draws from a stream derived from the
+//! seed and the task calling it. Each task has its own stream, so draws made by one task do not
+//! change the values seen by another. A task's stream is derived from the stream of the task
+//! which spawned it and the task's name, so tasks named with
+//! `DeterministicRuntimeHandle::spawn_named` keep their streams when spawns are reordered.
+//!
+//! Components can be given their own streams with `Environment::rng_for`, and split further
+//! with `RngHandle::fork`. A child stream is derived from the identity of its parent rather
+//! than its state, so a component consuming more randomness does not perturb the streams of
+//! other components.
+use crate::deterministic::rng::{stable_hash, Algorithm, SimRng};
+use rand::{distributions::uniform::SampleUniform, seq::SliceRandom, Rng, RngCore};
+use std::{fmt, ops, sync};
+
+/// A shared handle to a random number generator.
+///
+/// Clones of a handle draw from the same stream.
+#[derive(Clone)]
+pub struct RngHandle {
+    inner: sync::Arc<sync::Mutex<SimRng>>,
+    /// Seed identifying the stream and the algorithm generating it, if it was derived from the
+    /// runtime seed.
+    seed: Option<(u64, Algorithm)>,
+}
+
+impl fmt::Debug for RngHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RngHandle").finish()
+    }
+}
+
+impl RngHandle {
+    /// Returns a handle for the stream identified by `seed`, described by `stream` in audits.
+    pub(crate) fn derived(algorithm: Algorithm, seed: u64, stream: &dyn fmt::Debug) -> Self {
+        Self {
+            inner: sync::Arc::new(sync::Mutex::new(algorithm.stream(seed, stream))),
+            seed: Some((seed, algorithm)),
+        }
+    }
+
+    /// Returns a handle seeded from the operating system.
+    pub(crate) fn from_entropy() -> Self {
+        Self {
+            inner: sync::Arc::new(sync::Mutex::new(SimRng::from_entropy())),
+            seed: None,
+        }
+    }
+
+    /// Returns a child stream identified by `name`, which is independent of this stream and
+    /// of how much has been drawn from it. Forking the same name twice returns streams which
+    /// produce the same values.
+    pub fn fork(&self, name: &str) -> RngHandle {
+        match &self.seed {
+            Some((seed, algorithm)) => Self::derived(
+                algorithm.clone(),
+                stable_hash(&(seed, "fork", name)),
+                &("fork", seed, name),
+            ),
+            None => Self::from_entropy(),
+        }
+    }
+
+    /// Returns a value sampled uniformly from `range`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is empty.
+    pub fn gen_range<T: SampleUniform>(&mut self, range: ops::Range<T>) -> T {
+        self.inner.lock().unwrap().gen_range(range.start, range.end)
+    }
+
+    /// Returns a reference to a random element of `values`, or `None` if it is empty.
+    pub fn choose<'a, T>(&mut self, values: &'a [T]) -> Option<&'a T> {
+        values.choose(&mut *self.inner.lock().unwrap())
+    }
+}
+
+impl RngCore for RngHandle {
+    fn next_u32(&mut self) -> u32 {
+        self.inner.lock().unwrap().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.inner.lock().unwrap().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.lock().unwrap().fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.inner.lock().unwrap().try_fill_bytes(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    /// Returns values drawn by a spawned task, after the main task has made `draws` draws.
+    fn spawned_draws(seed: u64, draws: usize) -> Vec<u64> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let mut rng = handle.rng();
+            for _ in 0..draws {
+                rng.gen::<u64>();
+            }
+            let env = handle.clone();
+            crate::spawn_with_result(&handle, async move {
+                let mut rng = env.rng();
+                (0..4).map(|_| rng.gen_range(0..1000)).collect()
+            })
+            .await
+        })
+    }
+
+    #[test]
+    /// Test that forked streams do not depend on how much has been drawn from their parent.
+    fn forks() {
+        let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let draw = |mut rng: crate::RngHandle| (0..4).map(|_| rng.gen()).collect::<Vec<u64>>();
+            let raft = handle.rng_for("raft");
+            let compaction = draw(raft.fork("compaction"));
+            draw(raft.clone());
+            assert_eq!(draw(raft.fork("compaction")), compaction);
+            assert_ne!(draw(raft.fork("election")), compaction);
+            assert_ne!(
+                draw(handle.rng_for("storage")),
+                draw(handle.rng_for("raft"))
+            );
+        });
+    }
+
+    #[test]
+    /// Test that each task draws from its own stream derived from the seed.
+    fn per_task_streams() {
+        assert_eq!(spawned_draws(1, 0), spawned_draws(1, 0));
+        assert_eq!(spawned_draws(1, 0), spawned_draws(1, 10));
+        assert_ne!(spawned_draws(1, 0), spawned_draws(2, 0));
+    }
+
+    /// Returns values drawn by tasks named `names`, spawned in that order, by name.
+    fn named_draws(names: &[&'static str]) -> BTreeMap<&'static str, u64> {
+        let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let draws = BTreeMap::<&'static str, u64>::new();
+            let draws = std::sync::Arc::new(std::sync::Mutex::new(draws));
+            for &name in names {
+                let (env, draws) = (handle.clone(), draws.clone());
+                handle.spawn_named(name, async move {
+                    let value = env.rng().gen();
+                    draws.lock().unwrap().insert(name, value);
+                });
+            }
+            handle.delay_from(std::time::Duration::from_secs(1)).await;
+            let draws = draws.lock().unwrap().clone();
+            draws
+        })
+    }
+
+    #[test]
+    /// Test that the streams of named tasks do not depend on the order they are spawned in.
+    fn named_task_streams() {
+        let draws = named_draws(&["raft", "storage"]);
+        assert_eq!(draws, named_draws(&["storage", "raft"]));
+        assert_eq!(draws["raft"], named_draws(&["gossip", "raft"])["raft"]);
+        assert_ne!(draws["raft"], draws["storage"]);
+    }
+
+    #[test]
+    /// Test that shuffles and samples are drawn from the seed.
+    fn shuffle_and_sample() {
+        let run = |seed| {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            let handle = runtime.handle();
+            runtime.block_on(async {
+                let mut peers: Vec<u32> = (0..20).collect();
+                handle.shuffle(&mut peers);
+                let sample = handle.sample(0..20u32, 5);
+                let all = handle.sample(0..3u32, 5);
+                (peers, sample, all)
+            })
+        };
+        let (peers, sample, all) = run(1);
+        assert_eq!((peers.clone(), sample.clone(), all.clone()), run(1));
+        assert_ne!(peers, run(2).0);
+        let mut sorted = peers.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+        assert_eq!(sample.len(), 5);
+        assert!(sample.iter().all(|peer| *peer < 20));
+        assert_eq!(all.len(), 3);
+    }
+}
@@ -0,0 +1,296 @@
+//! A minimal request/response RPC layer over the connections of an `Environment`.
+//!
+//! Requests and responses are encoded as JSON and framed with a length prefix, so any types
+//! implementing `Serialize` and `Deserialize` can be exchanged without generated code or an RPC
+//! framework. A call may be given a deadline, which is enforced with `Environment::timeout` by
+//! the client and sent along with the request, so the server abandons requests whose caller has
+//! given up. Under the `DeterministicRuntime` deadlines expire in simulated time.
+//!
+//! ```
+//! # use simulation::{deterministic::DeterministicRuntime, rpc, Environment};
+//! # use std::time::Duration;
+//! let mut runtime = DeterministicRuntime::new().unwrap();
+//! let handle = runtime.handle();
+//! let addr = "127.0.0.1:9092".parse().unwrap();
+//! runtime.block_on(async {
+//!     let listener = handle.bind(addr).await.unwrap();
+//!     let server = rpc::serve(handle.clone(), listener, |x: u64| async move { x * 2 });
+//!     handle.spawn(async move {
+//!         let _ = server.await;
+//!     });
+//!     let mut client = rpc::RpcClient::<_, u64, u64>::new(handle.clone(), addr);
+//!     assert_eq!(client.call(21).await.unwrap(), 42);
+//!     let answer = client.call_with_deadline(21, Duration::from_secs(60)).await;
+//!     assert_eq!(answer.unwrap(), 42);
+//! });
+//! ```
+use crate::{Environment, TcpListener};
+use futures::{SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{error, fmt, future::Future, io, marker::PhantomData, net, time};
+use tokio::codec::{Framed, LengthDelimitedCodec};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request<T> {
+    id: u64,
+    /// Time remaining before the caller gives up on the request.
+    timeout: Option<time::Duration>,
+    body: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Reply<T> {
+    Ok(T),
+    DeadlineExceeded,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response<T> {
+    id: u64,
+    reply: Reply<T>,
+}
+
+/// Error returned by `RpcClient` calls.
+#[derive(Debug)]
+pub enum RpcError {
+    /// Connecting to the server failed, or the connection failed or was closed mid-call.
+    Io { source: io::Error },
+    /// A request or response could not be encoded or decoded.
+    Serialization { source: serde_json::Error },
+    /// The call did not complete before its deadline.
+    DeadlineExceeded,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Io { source } => write!(f, "rpc connection failed: {}", source),
+            RpcError::Serialization { source } => write!(f, "invalid rpc message: {}", source),
+            RpcError::DeadlineExceeded => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
+impl error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RpcError::Io { source } => Some(source),
+            RpcError::Serialization { source } => Some(source),
+            RpcError::DeadlineExceeded => None,
+        }
+    }
+}
+
+impl From<io::Error> for RpcError {
+    fn from(source: io::Error) -> Self {
+        RpcError::Io { source }
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(source: serde_json::Error) -> Self {
+        RpcError::Serialization { source }
+    }
+}
+
+/// Accepts connections from `listener`, answering each request with the response returned by
+/// `handler`. Requests of a connection are handled one at a time, in the order they were sent.
+/// Returns once accepting a connection fails.
+pub async fn serve<E, L, H, F, Req, Resp>(env: E, mut listener: L, handler: H) -> io::Result<()>
+where
+    E: Environment,
+    L: TcpListener,
+    L::Stream: Unpin + 'static,
+    H: Fn(Req) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Resp> + Send + 'static,
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + Send + 'static,
+{
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let connection = serve_connection(env.clone(), socket, handler.clone());
+        env.spawn(async move {
+            if let Err(e) = connection.await {
+                log::debug!("rpc connection closed: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_connection<E, S, H, F, Req, Resp>(
+    env: E,
+    socket: S,
+    handler: H,
+) -> Result<(), RpcError>
+where
+    E: Environment,
+    S: crate::TcpStream,
+    H: Fn(Req) -> F,
+    F: Future<Output = Resp>,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
+    while let Some(frame) = transport.next().await {
+        let request: Request<Req> = serde_json::from_slice(&frame?)?;
+        let response = handler(request.body);
+        let reply = match request.timeout {
+            Some(timeout) => match env.timeout(response, timeout).await {
+                Ok(response) => Reply::Ok(response),
+                Err(_) => Reply::DeadlineExceeded,
+            },
+            None => Reply::Ok(response.await),
+        };
+        let response = Response {
+            id: request.id,
+            reply,
+        };
+        transport
+            .send(serde_json::to_vec(&response)?.into())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Client calling a server started with `serve`, sending requests of type `Req` and receiving
+/// responses of type `Resp`. The connection is made on the first call, and made again on the
+/// next call after a call fails.
+pub struct RpcClient<E: Environment, Req, Resp> {
+    env: E,
+    addr: net::SocketAddr,
+    transport: Option<Framed<E::TcpStream, LengthDelimitedCodec>>,
+    next_id: u64,
+    _types: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<E: Environment, Req, Resp> fmt::Debug for RpcClient<E, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcClient")
+            .field("addr", &self.addr)
+            .field("connected", &self.transport.is_some())
+            .finish()
+    }
+}
+
+impl<E, Req, Resp> RpcClient<E, Req, Resp>
+where
+    E: Environment,
+    Req: Serialize,
+    Resp: DeserializeOwned,
+{
+    pub fn new(env: E, addr: net::SocketAddr) -> Self {
+        Self {
+            env,
+            addr,
+            transport: None,
+            next_id: 0,
+            _types: PhantomData,
+        }
+    }
+
+    /// Sends `request`, waiting for its response for as long as it takes.
+    pub async fn call(&mut self, request: Req) -> Result<Resp, RpcError> {
+        self.call_inner(request, None).await
+    }
+
+    /// Sends `request`, failing with `RpcError::DeadlineExceeded` if no response is received
+    /// within `timeout`.
+    pub async fn call_with_deadline(
+        &mut self,
+        request: Req,
+        timeout: time::Duration,
+    ) -> Result<Resp, RpcError> {
+        let env = self.env.clone();
+        match env
+            .timeout(self.call_inner(request, Some(timeout)), timeout)
+            .await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                // a late response would be read by the next call, so the connection is dropped.
+                self.transport = None;
+                Err(RpcError::DeadlineExceeded)
+            }
+        }
+    }
+
+    async fn call_inner(
+        &mut self,
+        body: Req,
+        timeout: Option<time::Duration>,
+    ) -> Result<Resp, RpcError> {
+        let result = self.exchange(body, timeout).await;
+        if let Err(RpcError::Io { .. }) | Err(RpcError::Serialization { .. }) = result {
+            self.transport = None;
+        }
+        result
+    }
+
+    async fn exchange(
+        &mut self,
+        body: Req,
+        timeout: Option<time::Duration>,
+    ) -> Result<Resp, RpcError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = serde_json::to_vec(&Request { id, timeout, body })?;
+        if self.transport.is_none() {
+            let socket = self.env.connect(self.addr).await?;
+            self.transport = Some(Framed::new(socket, LengthDelimitedCodec::new()));
+        }
+        let transport = self.transport.as_mut().unwrap();
+        transport.send(request.into()).await?;
+        let frame = transport
+            .next()
+            .await
+            .unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))?;
+        let response: Response<Resp> = serde_json::from_slice(&frame)?;
+        if response.id != id {
+            let message = format!("response {} to request {}", response.id, id);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+        }
+        match response.reply {
+            Reply::Ok(response) => Ok(response),
+            Reply::DeadlineExceeded => Err(RpcError::DeadlineExceeded),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, FaultConfig};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that a call exceeding its deadline fails in simulated time, and that the client
+    /// reconnects for the following calls.
+    fn deadlines() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        runtime.block_on(async {
+            let listener = handle.bind(addr).await.unwrap();
+            let env = handle.clone();
+            let sleeper = handle.clone();
+            let sleep = move |secs: u64| {
+                let env = sleeper.clone();
+                async move {
+                    env.delay_from(Duration::from_secs(secs)).await;
+                    secs
+                }
+            };
+            handle.spawn(async move {
+                let _ = serve(env, listener, sleep).await;
+            });
+            let mut client = RpcClient::<_, u64, u64>::new(handle.clone(), addr);
+            let start = handle.now();
+            let result = client.call_with_deadline(10, Duration::from_secs(5)).await;
+            assert!(matches!(result, Err(RpcError::DeadlineExceeded)));
+            assert_eq!(handle.now() - start, Duration::from_secs(5));
+            assert_eq!(client.call(1).await.unwrap(), 1);
+            let result = client.call_with_deadline(2, Duration::from_secs(5)).await;
+            assert_eq!(result.unwrap(), 2);
+        });
+    }
+}
@@ -0,0 +1,347 @@
+//! Periodic jobs driven by the wall clock of an `Environment`.
+//!
+//! Jobs such as compaction or snapshotting usually run on a cron schedule, and their
+//! interactions with the rest of a system only show up hours into a run. `Environment::schedule`
+//! runs a job at the times of a `Schedule`, read from `Environment::system_time`, so under the
+//! `DeterministicRuntime` a day of jobs runs in moments of simulated time, with the faults of the
+//! run injected around them.
+//!
+//! Schedules are written as cron expressions of five fields: minute, hour, day of month, month
+//! and day of week, in UTC. Each field is `*`, a value, a range `a-b` or a list of those
+//! separated by commas, each optionally followed by a step `/n`. Days of the week are numbered
+//! from Sunday, which is either 0 or 7. As with cron, if both the day of the month and the day
+//! of the week are restricted, a day matching either runs the job.
+//!
+//! ```
+//! # use simulation::{deterministic::DeterministicRuntime, Environment};
+//! # use std::{sync::{Arc, Mutex}, time::Duration};
+//! let mut runtime = DeterministicRuntime::new().unwrap();
+//! let handle = runtime.handle();
+//! let runs = Arc::new(Mutex::new(vec![]));
+//! runtime.block_on(async {
+//!     let (env, ran) = (handle.clone(), Arc::clone(&runs));
+//!     handle.schedule("*/15 * * * *".parse().unwrap(), move || {
+//!         ran.lock().unwrap().push(env.system_time());
+//!         async {}
+//!     });
+//!     handle.delay_from(Duration::from_secs(90 * 60)).await;
+//! });
+//! assert_eq!(runs.lock().unwrap().len(), 5);
+//! ```
+use crate::Environment;
+use std::{error, fmt, future::Future, str, time};
+
+const MINUTE: u64 = 60;
+const DAY: u64 = 24 * 60 * MINUTE;
+/// Number of days searched for the next run of a cron schedule. Every valid day of the month
+/// and day of the week combination occurs within 8 years, such as the 29th of February.
+const MAX_DAYS: u64 = 8 * 366;
+
+/// Times at which a scheduled job runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Cron(Cron),
+    Every(time::Duration),
+}
+
+/// The values matched by each field of a cron expression, as bitsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month or the day of the week was `*`.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Error returned when parsing a cron expression fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// The expression does not have five fields.
+    FieldCount { expression: String },
+    /// A field is malformed or has a value out of range.
+    InvalidField { field: &'static str, value: String },
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::FieldCount { expression } => {
+                write!(f, "expected 5 fields in cron expression {:?}", expression)
+            }
+            ScheduleError::InvalidField { field, value } => {
+                write!(f, "invalid {} field {:?}", field, value)
+            }
+        }
+    }
+}
+
+impl error::Error for ScheduleError {}
+
+impl Schedule {
+    /// Returns a schedule running every `interval` of wall clock time, at multiples of
+    /// `interval` since the unix epoch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn every(interval: time::Duration) -> Self {
+        assert!(interval > time::Duration::from_secs(0), "interval is zero");
+        Self {
+            kind: Kind::Every(interval),
+        }
+    }
+
+    /// Returns the first time strictly after `after` at which the schedule runs, or `None` if
+    /// it never runs again.
+    pub fn next_after(&self, after: time::SystemTime) -> Option<time::SystemTime> {
+        let since_epoch = after.duration_since(time::UNIX_EPOCH).ok()?;
+        match &self.kind {
+            Kind::Every(interval) => {
+                let periods = since_epoch.as_nanos() / interval.as_nanos() + 1;
+                let nanos = periods.checked_mul(interval.as_nanos())?;
+                let secs = (nanos / 1_000_000_000) as u64;
+                let next = time::Duration::new(secs, (nanos % 1_000_000_000) as u32);
+                Some(time::UNIX_EPOCH + next)
+            }
+            Kind::Cron(cron) => {
+                let minute = since_epoch.as_secs() / MINUTE + 1;
+                let next = cron.next_from(minute)?;
+                Some(time::UNIX_EPOCH + time::Duration::from_secs(next * MINUTE))
+            }
+        }
+    }
+}
+
+impl Cron {
+    /// Returns the first minute since the unix epoch from `minute` onwards which matches.
+    fn next_from(&self, minute: u64) -> Option<u64> {
+        let first_day = minute * MINUTE / DAY;
+        for day in first_day..first_day + MAX_DAYS {
+            if !self.matches_day(day) {
+                continue;
+            }
+            let start = if day == first_day {
+                minute - day * DAY / MINUTE
+            } else {
+                0
+            };
+            let found = (start..DAY / MINUTE).find(|of_day| {
+                self.hours & 1 << (of_day / 60) != 0 && self.minutes & 1 << (of_day % 60) != 0
+            });
+            if let Some(of_day) = found {
+                return Some(day * DAY / MINUTE + of_day);
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, of_month) = civil_from_days(day);
+        // 1970-01-01 was a Thursday.
+        let weekday = (day + 4) % 7;
+        let by_month = self.days & 1 << of_month != 0;
+        let by_week = self.weekdays & 1 << weekday != 0;
+        let by_day = match (self.any_day, self.any_weekday) {
+            (false, false) => by_month || by_week,
+            _ => by_month && by_week,
+        };
+        by_day && self.months & 1 << month != 0
+    }
+}
+
+/// Converts days since the unix epoch to a year, month and day of the month.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Parses a field of a cron expression into the bitset of values it matches.
+fn parse_field(field: &'static str, value: &str, min: u64, max: u64) -> Result<u64, ScheduleError> {
+    let invalid = || ScheduleError::InvalidField {
+        field,
+        value: value.to_string(),
+    };
+    let number = |s: &str| match s.parse::<u64>() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        _ => Err(invalid()),
+    };
+    let mut bits = 0;
+    for item in value.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(i) => match item[i + 1..].parse::<u64>() {
+                Ok(step) if step > 0 => (&item[..i], step),
+                _ => return Err(invalid()),
+            },
+            None => (item, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (number(&range[..i])?, number(&range[i + 1..])?)
+        } else if step > 1 {
+            (number(range)?, max)
+        } else {
+            let n = number(range)?;
+            (n, n)
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl str::FromStr for Schedule {
+    type Err = ScheduleError;
+
+    fn from_str(expression: &str) -> Result<Self, ScheduleError> {
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ScheduleError::FieldCount {
+                expression: expression.to_string(),
+            });
+        }
+        let mut weekdays = parse_field("day of week", fields[4], 0, 7)?;
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        let cron = Cron {
+            minutes: parse_field("minute", fields[0], 0, 59)?,
+            hours: parse_field("hour", fields[1], 0, 23)?,
+            days: parse_field("day of month", fields[2], 1, 31)?,
+            months: parse_field("month", fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        };
+        Ok(Schedule {
+            kind: Kind::Cron(cron),
+        })
+    }
+}
+
+/// Runs `job` at each time of `schedule`, returned by `Environment::schedule`. Runs which come
+/// due while the job is running are skipped.
+pub(crate) async fn run<E, F, Fut>(env: E, schedule: Schedule, mut job: F)
+where
+    E: Environment,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    while let Some(next) = schedule.next_after(env.system_time()) {
+        let wait = next
+            .duration_since(env.system_time())
+            .unwrap_or_else(|_| time::Duration::from_secs(0));
+        env.delay_from(wait).await;
+        job().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, FaultConfig};
+    use std::{sync, time::Duration};
+
+    /// Returns the time `days`, `hours` and `minutes` after 2020-01-01T00:00:00Z, a Wednesday.
+    fn at(days: u64, hours: u64, minutes: u64) -> time::SystemTime {
+        let secs = 1_577_836_800 + days * DAY + hours * 3600 + minutes * MINUTE;
+        time::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    /// Test that the next run of cron expressions follows cron's rules for days.
+    fn next_after() {
+        let schedule = |expression: &str| expression.parse::<Schedule>().unwrap();
+        let weekdays = schedule("0 9 * * 1-5");
+        assert_eq!(weekdays.next_after(at(0, 9, 0)), Some(at(1, 9, 0)));
+        // Friday, then the following Monday.
+        assert_eq!(weekdays.next_after(at(2, 8, 59)), Some(at(2, 9, 0)));
+        assert_eq!(weekdays.next_after(at(2, 9, 0)), Some(at(5, 9, 0)));
+        let steps = schedule("5,*/20 */6 * * *");
+        assert_eq!(steps.next_after(at(0, 0, 5)), Some(at(0, 0, 20)));
+        assert_eq!(steps.next_after(at(0, 0, 40)), Some(at(0, 6, 0)));
+        // the 1st of a month or any Sunday, the first of which is the 5th.
+        let either = schedule("30 0 1 * 0");
+        assert_eq!(either.next_after(at(0, 1, 0)), Some(at(4, 0, 30)));
+        assert_eq!(either.next_after(at(30, 1, 0)), Some(at(31, 0, 30)));
+        assert_eq!(
+            schedule("0 0 29 2 *").next_after(at(0, 0, 0)),
+            Some(at(59, 0, 0))
+        );
+        assert_eq!(schedule("0 0 30 2 *").next_after(at(0, 0, 0)), None);
+        let hourly = Schedule::every(Duration::from_secs(3600));
+        assert_eq!(hourly.next_after(at(0, 0, 5)), Some(at(0, 1, 0)));
+        assert!(matches!(
+            "* * *".parse::<Schedule>(),
+            Err(ScheduleError::FieldCount { .. })
+        ));
+        assert_eq!(
+            "60 * * * *".parse::<Schedule>(),
+            Err(ScheduleError::InvalidField {
+                field: "minute",
+                value: "60".to_string()
+            })
+        );
+    }
+
+    #[test]
+    /// Test that a scheduled job runs at the times of its schedule in simulated time, skipping
+    /// runs which come due while it is running.
+    fn schedule() {
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .start_time(at(0, 0, 5))
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let runs = sync::Arc::new(sync::Mutex::new(vec![]));
+        runtime.block_on(async {
+            let (env, ran) = (handle.clone(), sync::Arc::clone(&runs));
+            handle.schedule("*/15 * * * *".parse().unwrap(), move || {
+                let (env, ran) = (env.clone(), sync::Arc::clone(&ran));
+                async move {
+                    let start = env.system_time();
+                    ran.lock().unwrap().push(start);
+                    if start == at(0, 0, 30) {
+                        env.delay_from(Duration::from_secs(20 * 60)).await;
+                    }
+                }
+            });
+            handle.delay_from(Duration::from_secs(2 * 3600)).await;
+        });
+        assert_eq!(
+            *runs.lock().unwrap(),
+            vec![
+                at(0, 0, 15),
+                at(0, 0, 30),
+                at(0, 1, 0),
+                at(0, 1, 15),
+                at(0, 1, 30),
+                at(0, 1, 45),
+                at(0, 2, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Adapters for connecting `tower` based clients through an `Environment`.
+//!
+//! `Connector` implements `Service<SocketAddr>` by calling `Environment::connect`, and so also
+//! implements `tower_make::MakeConnection`. Clients built from tower layers such as buffer,
+//! retry and timeout can then be run against the in-memory network of the deterministic
+//! runtime without custom glue.
+use crate::Environment;
+use futures::Poll;
+use std::{fmt, future::Future, io, net, pin::Pin, task::Context};
+
+/// A `Service` which connects to the address it is called with.
+#[derive(Clone)]
+pub struct Connector<E> {
+    env: E,
+}
+
+impl<E> fmt::Debug for Connector<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connector").finish()
+    }
+}
+
+impl<E: Environment> Connector<E> {
+    pub fn new(env: E) -> Self {
+        Self { env }
+    }
+}
+
+impl<E: Environment> tower_service::Service<net::SocketAddr> for Connector<E> {
+    type Response = E::TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<E::TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, addr: net::SocketAddr) -> Self::Future {
+        let env = self.env.clone();
+        Box::pin(async move { env.connect(addr).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TcpListener, TcpStream};
+    use tower_make::MakeConnection;
+
+    #[test]
+    /// Test that connections made through the connector reach listeners in the simulation.
+    fn make_connection() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let mut connector = Connector::new(handle.clone());
+            futures::future::poll_fn(|cx| MakeConnection::poll_ready(&mut connector, cx))
+                .await
+                .unwrap();
+            let conn = connector.make_connection(addr).await.unwrap();
+            let (_, client) = listener.accept().await.unwrap();
+            assert_eq!(TcpStream::local_addr(&conn).unwrap(), client);
+        });
+    }
+}
@@ -0,0 +1,152 @@
+//! Recording of the latencies of real connections, saved as a `LatencyProfile`.
+use crate::deterministic::LatencyProfile;
+use futures::Poll;
+use std::{
+    io,
+    pin::Pin,
+    sync,
+    task::Context,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_timer::clock::Clock;
+
+/// Records the latencies of connects made through a `SingleThreadedRuntimeHandle`, and of
+/// reads and writes of the streams passed to `LatencyRecorder::stream`.
+///
+/// Clones of a recorder add to the same profile.
+#[derive(Debug, Clone)]
+pub struct LatencyRecorder {
+    clock: Clock,
+    profile: sync::Arc<sync::Mutex<LatencyProfile>>,
+}
+
+impl LatencyRecorder {
+    pub(crate) fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            profile: sync::Arc::new(sync::Mutex::new(LatencyProfile::default())),
+        }
+    }
+
+    /// Returns the latencies recorded so far.
+    pub fn profile(&self) -> LatencyProfile {
+        self.profile.lock().unwrap().clone()
+    }
+
+    /// Returns `stream`, recording how long each of its reads and writes waits.
+    pub fn stream<S>(&self, stream: S) -> RecordedStream<S> {
+        RecordedStream {
+            inner: stream,
+            recorder: self.clone(),
+            read_started: None,
+            write_started: None,
+        }
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub(crate) fn record_connect(&self, latency: Duration) {
+        self.profile.lock().unwrap().connect.push(latency);
+    }
+
+    /// Records the wait of an operation which completed, which started at `started` if it did
+    /// not complete when first polled.
+    fn record<F>(&self, started: Option<Instant>, samples: F)
+    where
+        F: FnOnce(&mut LatencyProfile) -> &mut Vec<Duration>,
+    {
+        let latency = started.map_or(Duration::from_secs(0), |started| {
+            self.now().saturating_duration_since(started)
+        });
+        samples(&mut self.profile.lock().unwrap()).push(latency);
+    }
+}
+
+/// A stream whose reads and writes are recorded by a `LatencyRecorder`.
+#[derive(Debug)]
+pub struct RecordedStream<S> {
+    inner: S,
+    recorder: LatencyRecorder,
+    /// When the pending read, if any, first returned `Poll::Pending`.
+    read_started: Option<Instant>,
+    /// When the pending write, if any, first returned `Poll::Pending`.
+    write_started: Option<Instant>,
+}
+
+impl<S> RecordedStream<S> {
+    /// Returns the wrapped stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: crate::TcpStream> crate::TcpStream for RecordedStream<S> {
+    fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Pending => {
+                if this.read_started.is_none() {
+                    this.read_started = Some(this.recorder.now());
+                }
+                Poll::Pending
+            }
+            Poll::Ready(result) => {
+                let started = this.read_started.take();
+                if result.is_ok() {
+                    this.recorder.record(started, |profile| &mut profile.read);
+                }
+                Poll::Ready(result)
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Pending => {
+                if this.write_started.is_none() {
+                    this.write_started = Some(this.recorder.now());
+                }
+                Poll::Pending
+            }
+            Poll::Ready(result) => {
+                let started = this.write_started.take();
+                if result.is_ok() {
+                    this.recorder.record(started, |profile| &mut profile.write);
+                }
+                Poll::Ready(result)
+            }
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,29 +1,40 @@
 use crate::Error;
-use async_trait::async_trait;
 use futures::Future;
 use std::{io, net::SocketAddr, time};
 use tokio_executor::current_thread;
 use tokio_net::driver::Reactor;
 use tokio_timer::{clock::Clock, timer};
+mod latency;
 mod net;
+mod proxy;
+pub use latency::{LatencyRecorder, RecordedStream};
+pub use proxy::{ChaosProxy, ProxyConfig};
 #[derive(Debug, Clone)]
 pub struct SingleThreadedRuntimeHandle {
     executor_handle: current_thread::Handle,
     clock_handle: Clock,
     timer_handle: timer::Handle,
+    latencies: Option<LatencyRecorder>,
+}
+
+impl SingleThreadedRuntimeHandle {
+    /// Returns the recorder of latencies observed by this runtime, if latencies are recorded
+    /// with `SingleThreadedRuntime::record_latencies`.
+    pub fn latencies(&self) -> Option<LatencyRecorder> {
+        self.latencies.clone()
+    }
 }
 
-#[async_trait]
 impl crate::Environment for SingleThreadedRuntimeHandle {
     type TcpStream = tokio::net::TcpStream;
     type TcpListener = tokio::net::TcpListener;
-    fn spawn<F>(&self, future: F)
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
     where
         F: Future<Output = ()> + Send + 'static,
     {
         self.executor_handle
-            .spawn(future)
-            .expect("failed to spawn task")
+            .spawn(crate::ambient::scope(self.clone(), future))
+            .map_err(|source| Error::Spawn { source })
     }
     fn now(&self) -> time::Instant {
         self.clock_handle.now()
@@ -31,8 +42,14 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
     fn delay(&self, deadline: time::Instant) -> tokio::timer::Delay {
         self.timer_handle.delay(deadline)
     }
-    fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio::timer::Timeout<T> {
-        self.timer_handle.timeout(value, timeout)
+    fn timeout<T>(&self, value: T, timeout: time::Duration) -> crate::Timeout<T> {
+        crate::Timeout::new(self.timer_handle.timeout(value, timeout))
+    }
+    fn rng(&self) -> crate::RngHandle {
+        crate::RngHandle::from_entropy()
+    }
+    fn rng_for(&self, _name: &str) -> crate::RngHandle {
+        crate::RngHandle::from_entropy()
     }
     async fn bind<A>(&self, addr: A) -> Result<Self::TcpListener, io::Error>
     where
@@ -42,9 +59,22 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
     }
     async fn connect<A>(&self, addr: A) -> Result<Self::TcpStream, io::Error>
     where
-        A: Into<SocketAddr> + Send + Sync,
+        A: crate::ToSocketAddrs + Send + Sync,
     {
-        tokio::net::TcpStream::connect(addr.into()).await
+        let addrs = addr.to_socket_addrs()?;
+        let latencies = self.latencies.clone();
+        crate::connect::connect_any(self, addrs, move |addr| {
+            let latencies = latencies.clone();
+            async move {
+                let started = latencies.as_ref().map(LatencyRecorder::now);
+                let stream = tokio::net::TcpStream::connect(addr).await?;
+                if let (Some(latencies), Some(started)) = (latencies, started) {
+                    latencies.record_connect(latencies.now().saturating_duration_since(started));
+                }
+                Ok(stream)
+            }
+        })
+        .await
     }
 }
 
@@ -53,6 +83,7 @@ pub struct SingleThreadedRuntime {
     timer_handle: tokio_timer::timer::Handle,
     clock: Clock,
     executor: current_thread::CurrentThread<timer::Timer<Reactor>>,
+    latencies: Option<LatencyRecorder>,
 }
 
 impl SingleThreadedRuntime {
@@ -68,6 +99,7 @@ impl SingleThreadedRuntime {
             timer_handle,
             clock,
             executor,
+            latencies: None,
         };
         Ok(runtime)
     }
@@ -80,8 +112,20 @@ impl SingleThreadedRuntime {
             executor_handle,
             clock_handle,
             timer_handle,
+            latencies: self.latencies.clone(),
         }
     }
+
+    /// Records the latency of every connect made through handles returned after this call,
+    /// returning the recorder. Reads and writes are recorded for streams wrapped with
+    /// `LatencyRecorder::stream`. The `LatencyProfile` returned by `LatencyRecorder::profile`
+    /// can be saved, and applied to the `FaultConfig` of a `DeterministicRuntime`.
+    pub fn record_latencies(&mut self) -> LatencyRecorder {
+        let clock = self.clock.clone();
+        self.latencies
+            .get_or_insert_with(|| LatencyRecorder::new(clock))
+            .clone()
+    }
     pub fn spawn<F>(&mut self, future: F) -> &mut Self
     where
         F: Future<Output = ()> + 'static,
@@ -91,8 +135,14 @@ impl SingleThreadedRuntime {
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
-        self.enter(|executor| executor.run())
-            .map_err(|source| Error::CurrentThreadRun { source })
+        self.enter(|executor| {
+            while !executor.is_idle() {
+                executor
+                    .turn(None)
+                    .map_err(|source| Error::CurrentThreadTurn { source })?;
+            }
+            Ok(())
+        })
     }
 
     pub fn block_on<F>(&mut self, f: F) -> F::Output
@@ -106,18 +156,48 @@ impl SingleThreadedRuntime {
     where
         F: FnOnce(&mut current_thread::CurrentThread<timer::Timer<Reactor>>) -> R,
     {
+        let _ambient = crate::ambient::set_default(&self.handle());
         let SingleThreadedRuntime {
             ref reactor_handle,
             ref timer_handle,
             ref clock,
             ref mut executor,
+            ..
         } = *self;
-        let _reactor = tokio_net::driver::set_default(&reactor_handle);
-        let clock = clock;
-        tokio_timer::clock::with_default(&clock, || {
-            let _timer = tokio_timer::timer::set_default(&timer_handle);
+        let _reactor = tokio_net::driver::set_default(reactor_handle);
+        tokio_timer::clock::with_default(clock, || {
+            let _timer = tokio_timer::timer::set_default(timer_handle);
             let mut default_executor = tokio_executor::current_thread::TaskExecutor::current();
             tokio_executor::with_default(&mut default_executor, || f(executor))
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    /// Test that `run` returns once every spawned task, and every task they spawn, completes.
+    fn run_spawned_tasks() {
+        let mut runtime = SingleThreadedRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let completed = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let (env, completed) = (handle.clone(), completed.clone());
+            runtime.spawn(async move {
+                env.delay_from(time::Duration::from_millis(5)).await;
+                env.spawn(async move {
+                    completed.fetch_add(1, Ordering::SeqCst);
+                });
+            });
+        }
+        runtime.run().unwrap();
+        assert_eq!(completed.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,4 +1,4 @@
-use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
 use std::{io, net};
 use tokio::net::{TcpListener, TcpStream};
 
@@ -14,12 +14,15 @@ impl crate::TcpStream for TcpStream {
     }
 }
 
-#[async_trait]
 impl crate::TcpListener for TcpListener {
     type Stream = tokio::net::TcpStream;
+    type Incoming = BoxStream<'static, io::Result<Self::Stream>>;
     async fn accept(&mut self) -> Result<(Self::Stream, net::SocketAddr), io::Error> {
         tokio::net::TcpListener::accept(self).await
     }
+    fn incoming(self) -> Self::Incoming {
+        tokio::net::TcpListener::incoming(self).boxed()
+    }
     fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
         tokio::net::TcpListener::local_addr(self)
     }
@@ -0,0 +1,312 @@
+//! Fault-injecting proxy for real TCP connections.
+//!
+//! A `ChaosProxy` listens on a local address and forwards every connection it accepts to an
+//! upstream address, such as a database started by an integration test. Faults are drawn from
+//! a `FaultConfig` and a seed, so dependencies which can not run inside the simulation still see
+//! the delays and disconnects of the deterministic network:
+//!
+//! * `listener_connection_delay` delays connecting to the upstream.
+//! * `socket_read_delay` delays data sent from the client to the upstream.
+//! * `socket_write_delay` delays data sent from the upstream to the client.
+//! * `disconnect_prob` resets both connections before forwarding a chunk of data.
+//!
+//! Each connection draws from a stream derived from the seed and the order it was accepted in,
+//! so a connection sees the same faults whatever the timing of other connections. Real IO
+//! still decides how data is split into chunks, so runs are not reproducible exactly.
+use super::SingleThreadedRuntimeHandle;
+use crate::{
+    deterministic::{rng::Algorithm, FaultConfig, SmallRngAlgorithm},
+    Environment, RngHandle,
+};
+use futures::future;
+use rand::Rng;
+use std::{io, net, ops, time};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Size of the chunks data is forwarded in.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Configuration of the faults injected by a `ChaosProxy`.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Seed the faults of every connection are drawn from.
+    pub seed: u64,
+    /// Faults injected into proxied connections.
+    pub faults: FaultConfig,
+    /// The number of bytes per second forwarded in each direction of a connection, or `None`
+    /// to forward data as fast as it arrives.
+    pub throttle: Option<u64>,
+}
+
+impl ProxyConfig {
+    /// Returns a configuration drawing the default faults from `seed`, without throttling.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            faults: FaultConfig::default(),
+            throttle: None,
+        }
+    }
+}
+
+/// A proxy forwarding connections to an upstream address, injecting faults drawn from a seed.
+#[derive(Debug)]
+pub struct ChaosProxy {
+    handle: SingleThreadedRuntimeHandle,
+    listener: TcpListener,
+    upstream: net::SocketAddr,
+    config: ProxyConfig,
+    rng: RngHandle,
+}
+
+/// How a proxied connection ended.
+enum Outcome {
+    /// Both ends closed their side of the connection.
+    Closed,
+    /// A disconnect was injected.
+    Reset,
+}
+
+impl ChaosProxy {
+    /// Returns a proxy listening on `addr` and forwarding connections to `upstream`. Connections
+    /// are only accepted once the future returned by `run` is spawned.
+    pub async fn bind(
+        handle: SingleThreadedRuntimeHandle,
+        addr: net::SocketAddr,
+        upstream: net::SocketAddr,
+        config: ProxyConfig,
+    ) -> Result<ChaosProxy, io::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let rng = RngHandle::derived(
+            Algorithm::new(SmallRngAlgorithm),
+            config.seed,
+            &("proxy", config.seed),
+        );
+        Ok(ChaosProxy {
+            handle,
+            listener,
+            upstream,
+            config,
+            rng,
+        })
+    }
+
+    /// Returns the address clients connect to in place of the upstream.
+    pub fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until accepting fails, spawning a task forwarding each of them to
+    /// the upstream.
+    pub async fn run(mut self) -> Result<(), io::Error> {
+        let mut accepted = 0u64;
+        loop {
+            let (client, peer) = self.listener.accept().await?;
+            let rng = self.rng.fork(&format!("connection-{}", accepted));
+            accepted += 1;
+            let connection = Connection {
+                handle: self.handle.clone(),
+                upstream: self.upstream,
+                config: self.config.clone(),
+                rng,
+            };
+            self.handle.spawn(async move {
+                if let Err(error) = connection.forward(client).await {
+                    log::debug!("proxied connection from {} failed: {}", peer, error);
+                }
+            });
+        }
+    }
+}
+
+/// A connection accepted by a `ChaosProxy`.
+struct Connection {
+    handle: SingleThreadedRuntimeHandle,
+    upstream: net::SocketAddr,
+    config: ProxyConfig,
+    rng: RngHandle,
+}
+
+impl Connection {
+    async fn forward(mut self, mut client: TcpStream) -> Result<(), io::Error> {
+        let faults = self.config.faults.clone();
+        if let Some(delay) = draw_delay(
+            &mut self.rng,
+            &faults.listener_connection_delay,
+            faults.listener_connection_delay_prob,
+        ) {
+            self.handle.delay_from(delay).await;
+        }
+        let mut upstream = TcpStream::connect(self.upstream).await?;
+        let outcome = {
+            let (client_read, client_write) = client.split();
+            let (upstream_read, upstream_write) = upstream.split();
+            let requests = Pump {
+                handle: self.handle.clone(),
+                rng: self.rng.fork("requests"),
+                delay: faults.socket_read_delay.clone(),
+                delay_prob: faults.socket_read_delay_prob,
+                disconnect_prob: faults.disconnect_prob,
+                throttle: self.config.throttle,
+            };
+            let responses = Pump {
+                handle: self.handle.clone(),
+                rng: self.rng.fork("responses"),
+                delay: faults.socket_write_delay.clone(),
+                delay_prob: faults.socket_write_delay_prob,
+                disconnect_prob: faults.disconnect_prob,
+                throttle: self.config.throttle,
+            };
+            future::try_join(
+                requests.run(client_read, upstream_write),
+                responses.run(upstream_read, client_write),
+            )
+            .await
+        };
+        match outcome {
+            Ok(_) => Ok(()),
+            Err(Outcome::Reset) => {
+                // a zero linger makes closing the sockets send a reset rather than a FIN.
+                client.set_linger(Some(time::Duration::from_secs(0)))?;
+                upstream.set_linger(Some(time::Duration::from_secs(0)))?;
+                Ok(())
+            }
+            Err(Outcome::Closed) => Ok(()),
+        }
+    }
+}
+
+/// Forwards one direction of a proxied connection.
+struct Pump {
+    handle: SingleThreadedRuntimeHandle,
+    rng: RngHandle,
+    delay: ops::Range<time::Duration>,
+    delay_prob: f64,
+    disconnect_prob: f64,
+    throttle: Option<u64>,
+}
+
+impl Pump {
+    /// Copies `from` into `to` until `from` is closed, returning `Outcome::Reset` if a
+    /// disconnect is injected. IO errors close the connection without a reset.
+    async fn run<R, W>(mut self, mut from: R, mut to: W) -> Result<(), Outcome>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let read = from.read(&mut buf).await.map_err(|_| Outcome::Closed)?;
+            if read == 0 {
+                let _ = to.shutdown().await;
+                return Ok(());
+            }
+            if self.rng.gen_bool(self.disconnect_prob) {
+                return Err(Outcome::Reset);
+            }
+            if let Some(delay) = draw_delay(&mut self.rng, &self.delay, self.delay_prob) {
+                self.handle.delay_from(delay).await;
+            }
+            if let Some(bytes_per_sec) = self.throttle {
+                let secs = read as f64 / bytes_per_sec.max(1) as f64;
+                self.handle
+                    .delay_from(time::Duration::from_secs_f64(secs))
+                    .await;
+            }
+            to.write_all(&buf[..read])
+                .await
+                .map_err(|_| Outcome::Closed)?;
+        }
+    }
+}
+
+/// Returns a delay drawn from `range` with probability `prob`.
+fn draw_delay(
+    rng: &mut RngHandle,
+    range: &ops::Range<time::Duration>,
+    prob: f64,
+) -> Option<time::Duration> {
+    if rng.gen_bool(prob) {
+        Some(rng.gen_range(range.clone()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::singlethread::SingleThreadedRuntime;
+
+    /// Binds a server echoing every connection, returning its address.
+    async fn echo_server(handle: &SingleThreadedRuntimeHandle) -> net::SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0".parse::<net::SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let spawner = handle.clone();
+        handle.spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                spawner.spawn(async move {
+                    let (mut read, mut write) = socket.split();
+                    let _ = read.copy(&mut write).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn proxy(handle: &SingleThreadedRuntimeHandle, config: ProxyConfig) -> net::SocketAddr {
+        let upstream = echo_server(handle).await;
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let proxy = ChaosProxy::bind(handle.clone(), addr, upstream, config)
+            .await
+            .unwrap();
+        let addr = proxy.local_addr().unwrap();
+        handle.spawn(async move {
+            let _ = proxy.run().await;
+        });
+        addr
+    }
+
+    #[test]
+    fn forwards_without_faults() {
+        let mut runtime = SingleThreadedRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let config = ProxyConfig {
+                faults: FaultConfig::disabled(),
+                ..ProxyConfig::new(1)
+            };
+            let addr = proxy(&handle, config).await;
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn disconnects_drop_data() {
+        let mut runtime = SingleThreadedRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let config = ProxyConfig {
+                faults: FaultConfig {
+                    disconnect_prob: 1.0,
+                    ..FaultConfig::disabled()
+                },
+                ..ProxyConfig::new(1)
+            };
+            let addr = proxy(&handle, config).await;
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            assert!(conn.read_exact(&mut buf).await.is_err());
+        });
+    }
+}
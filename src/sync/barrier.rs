@@ -0,0 +1,173 @@
+//! Barrier for a fixed number of tasks.
+//!
+//! Once every task has reached the barrier, the waiting tasks are woken in an order drawn from
+//! the runtime seed and one of them, also chosen by the seed, is designated the leader. Each
+//! release of the barrier is recorded in the event log of the deterministic runtime.
+use super::wait::WaitQueue;
+use crate::deterministic::{context, SimEvent, TaskId};
+use futures::Poll;
+use rand::Rng;
+use std::{collections::HashMap, fmt, future::Future, pin::Pin, sync, task::Context};
+
+#[derive(Debug)]
+struct State {
+    /// Waiters which have reached the barrier in the current generation, along with their task.
+    arrived: Vec<(u64, Option<TaskId>)>,
+    /// Released waiters which have not yet been polled, and whether they are the leader.
+    released: HashMap<u64, bool>,
+    waiters: WaitQueue,
+}
+
+/// A barrier which releases tasks once `n` of them are waiting on it.
+pub struct Barrier {
+    n: usize,
+    state: sync::Mutex<State>,
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Barrier").field("n", &self.n).finish()
+    }
+}
+
+/// Returned by `Barrier::wait` once the barrier has been released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Returns true for exactly one of the tasks released by the barrier.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl Barrier {
+    /// Creates a barrier releasing tasks once `n` are waiting. A barrier created with `n` of 0
+    /// behaves as if it were created with 1.
+    #[track_caller]
+    pub fn new(n: usize) -> Self {
+        let state = State {
+            arrived: vec![],
+            released: HashMap::new(),
+            waiters: WaitQueue::new(),
+        };
+        Self {
+            n: n.max(1),
+            state: sync::Mutex::new(state),
+        }
+    }
+
+    /// Waits until `n` tasks are waiting on the barrier.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait {
+            barrier: self,
+            id: None,
+        }
+    }
+}
+
+/// Future returned by `Barrier::wait`.
+pub struct Wait<'a> {
+    barrier: &'a Barrier,
+    /// Id of this waiter, assigned when it reaches the barrier.
+    id: Option<u64>,
+}
+
+impl fmt::Debug for Wait<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wait").field("id", &self.id).finish()
+    }
+}
+
+impl Future for Wait<'_> {
+    type Output = BarrierWaitResult;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let barrier = self.barrier;
+        let mut state = barrier.state.lock().unwrap();
+        let id = match self.id {
+            Some(id) => {
+                if let Some(leader) = state.released.remove(&id) {
+                    self.id = None;
+                    return Poll::Ready(BarrierWaitResult(leader));
+                }
+                id
+            }
+            None => {
+                let id = state.waiters.next_id();
+                state.arrived.push((id, context::current_task()));
+                id
+            }
+        };
+        if state.arrived.len() < barrier.n {
+            self.id = Some(id);
+            state.waiters.register(id, cx.waker());
+            return Poll::Pending;
+        }
+        // the last task to arrive is the leader outside of the deterministic runtime.
+        let arrived = std::mem::take(&mut state.arrived);
+        let leader = match state.waiters.rng() {
+            Some(rng) => rng.gen_range(0, arrived.len()),
+            None => arrived.len() - 1,
+        };
+        context::record(SimEvent::BarrierReleased {
+            leader: arrived[leader].1,
+            waiters: arrived.len(),
+        });
+        for (idx, (waiter, _)) in arrived.iter().enumerate() {
+            if *waiter != id {
+                state.released.insert(*waiter, idx == leader);
+            }
+        }
+        state.waiters.wake_all();
+        self.id = None;
+        Poll::Ready(BarrierWaitResult(arrived[leader].0 == id))
+    }
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.barrier.state.lock().unwrap();
+            state.waiters.remove(id);
+            state.arrived.retain(|(waiter, _)| *waiter != id);
+            state.released.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    /// Returns the index of the task chosen as leader.
+    fn leader(seed: u64) -> usize {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let barrier = sync::Arc::new(Barrier::new(4));
+            let leaders = sync::Arc::new(sync::Mutex::new(vec![]));
+            for idx in 0..4 {
+                let barrier = sync::Arc::clone(&barrier);
+                let leaders = sync::Arc::clone(&leaders);
+                handle.spawn(async move {
+                    if barrier.wait().await.is_leader() {
+                        leaders.lock().unwrap().push(idx);
+                    }
+                });
+            }
+            handle.delay_from(Duration::from_millis(1)).await;
+            let leaders = leaders.lock().unwrap().clone();
+            assert_eq!(leaders.len(), 1);
+            leaders[0]
+        })
+    }
+
+    #[test]
+    /// Test that exactly one task is chosen as the leader, depending on the seed.
+    fn seeded_leader() {
+        assert_eq!(leader(3), leader(3));
+        assert!((0..10).any(|seed| leader(seed) != leader(seed + 1)));
+    }
+}
@@ -0,0 +1,229 @@
+//! Multi-producer, multi-consumer channel where every receiver observes every value.
+//!
+//! Receivers waiting for a value are woken in a seeded order, so the order in which receivers
+//! observe a value varies between seeds.
+use super::wait::WaitQueue;
+use futures::Poll;
+use std::{collections::VecDeque, error, fmt, sync};
+
+/// Error returned by `Sender::send` when there are no receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl<T: fmt::Debug> error::Error for SendError<T> {}
+
+/// Error returned by `Receiver::recv`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender has been dropped and all values have been received.
+    Closed,
+    /// The receiver fell behind, missing this many values which were overwritten.
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "channel closed"),
+            RecvError::Lagged(missed) => write!(f, "receiver lagged by {}", missed),
+        }
+    }
+}
+
+impl error::Error for RecvError {}
+
+#[derive(Debug)]
+struct Shared<T> {
+    /// The most recent values, the first of which has position `head`.
+    values: VecDeque<T>,
+    head: u64,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    waiters: WaitQueue,
+}
+
+/// Sending half of a channel.
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+/// Receiving half of a channel.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    id: u64,
+    /// Position of the next value to receive.
+    next: u64,
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+/// Creates a channel retaining up to `capacity` values for receivers which fall behind.
+#[track_caller]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be greater than 0");
+    let mut waiters = WaitQueue::new();
+    let id = waiters.next_id();
+    let shared = Shared {
+        values: VecDeque::with_capacity(capacity),
+        head: 0,
+        capacity,
+        senders: 1,
+        receivers: 1,
+        waiters,
+    };
+    let shared = sync::Arc::new(sync::Mutex::new(shared));
+    let sender = Sender {
+        shared: sync::Arc::clone(&shared),
+    };
+    let receiver = Receiver {
+        id,
+        next: 0,
+        shared,
+    };
+    (sender, receiver)
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` to every receiver, returning the number of receivers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut lock = self.shared.lock().unwrap();
+        if lock.receivers == 0 {
+            return Err(SendError(value));
+        }
+        if lock.values.len() == lock.capacity {
+            lock.values.pop_front();
+            lock.head += 1;
+        }
+        lock.values.push_back(value);
+        lock.waiters.wake_all();
+        Ok(lock.receivers)
+    }
+
+    /// Returns a receiver which observes values sent after this call.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut lock = self.shared.lock().unwrap();
+        lock.receivers += 1;
+        Receiver {
+            id: lock.waiters.next_id(),
+            next: lock.head + lock.values.len() as u64,
+            shared: sync::Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: sync::Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.senders -= 1;
+        if lock.senders == 0 {
+            lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receives the next value.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        futures::future::poll_fn(|cx| {
+            let mut lock = self.shared.lock().unwrap();
+            if self.next < lock.head {
+                let missed = lock.head - self.next;
+                self.next = lock.head;
+                return Poll::Ready(Err(RecvError::Lagged(missed)));
+            }
+            let offset = (self.next - lock.head) as usize;
+            if let Some(value) = lock.values.get(offset).cloned() {
+                self.next += 1;
+                lock.waiters.remove(self.id);
+                return Poll::Ready(Ok(value));
+            }
+            if lock.senders == 0 {
+                return Poll::Ready(Err(RecvError::Closed));
+            }
+            lock.waiters.register(self.id, cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.receivers -= 1;
+        lock.waiters.remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+
+    /// Returns the order in which receivers observe a single value.
+    fn observation_order(seed: u64) -> Vec<usize> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let (tx, rx) = channel(4);
+            let (order_tx, mut order_rx) = crate::sync::mpsc::unbounded_channel();
+            for idx in 0..6 {
+                let mut rx = tx.subscribe();
+                let mut order_tx = order_tx.clone();
+                handle.spawn(async move {
+                    rx.recv().await.unwrap();
+                    order_tx.try_send(idx).unwrap();
+                });
+            }
+            drop((rx, order_tx));
+            // let every receiver start waiting before sending.
+            handle.delay_from(std::time::Duration::from_millis(1)).await;
+            tx.send(()).unwrap();
+            let mut order = vec![];
+            while let Some(idx) = order_rx.recv().await {
+                order.push(idx);
+            }
+            order
+        })
+    }
+
+    #[test]
+    /// Test that the order in which receivers are woken depends on the seed.
+    fn seeded_wake_order() {
+        assert_eq!(observation_order(7), observation_order(7));
+        assert!((0..10).any(|seed| observation_order(seed) != observation_order(seed + 1)));
+    }
+
+    #[test]
+    /// Test that receivers which fall behind are told how many values they missed.
+    fn lagged() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {
+            let (tx, mut rx) = channel(2);
+            for idx in 0..5 {
+                tx.send(idx).unwrap();
+            }
+            assert_eq!(rx.recv().await, Err(RecvError::Lagged(3)));
+            assert_eq!(rx.recv().await, Ok(3));
+            assert_eq!(rx.recv().await, Ok(4));
+            drop(tx);
+            assert_eq!(rx.recv().await, Err(RecvError::Closed));
+        });
+    }
+}
@@ -0,0 +1,17 @@
+//! Synchronization primitives whose wake ordering is controlled by the runtime seed.
+//!
+//! When created within a `DeterministicRuntime`, tasks waiting on a primitive are woken in an
+//! order drawn from an RNG derived from the seed and the location the primitive was created at,
+//! so code which depends on a particular wake order fails on some seeds. Outside of the
+//! deterministic runtime, waiting tasks are woken in FIFO order.
+pub mod barrier;
+pub mod broadcast;
+pub mod mpsc;
+pub mod mutex;
+pub mod notify;
+pub mod oneshot;
+pub mod queue;
+pub mod rwlock;
+pub mod semaphore;
+pub(crate) mod wait;
+pub mod watch;
@@ -0,0 +1,333 @@
+//! Multi-producer, single-consumer channel.
+//!
+//! When the channel is full, senders waiting for capacity are woken in a seeded order once a
+//! value is received, so the order in which values from different senders are accepted varies
+//! between seeds. A delivery delay can be configured to hold each value back for a seeded
+//! duration before it can be received.
+use super::wait::WaitQueue;
+use crate::deterministic::{context, QueueKind};
+use futures::{FutureExt, Poll, Stream};
+use rand::Rng;
+use std::{
+    collections::VecDeque, error, fmt, ops, panic::Location, pin::Pin, sync, task::Context, time,
+};
+
+/// Error returned by `Sender::send` when the receiver has been dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl<T: fmt::Debug> error::Error for SendError<T> {}
+
+/// Error returned by `Sender::try_send`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// The receiver has been dropped.
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> error::Error for TrySendError<T> {}
+
+#[derive(Debug)]
+struct Shared<T> {
+    /// Buffered values along with the instant they may be received at.
+    queue: VecDeque<(T, Option<time::Instant>)>,
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    send_waiters: WaitQueue,
+    recv_waiter: WaitQueue,
+    delivery_delay: Option<ops::Range<time::Duration>>,
+    /// Where the channel was created, identifying it in watermark alerts.
+    location: &'static Location<'static>,
+}
+
+impl<T> Shared<T> {
+    fn is_full(&self) -> bool {
+        self.capacity
+            .map(|capacity| self.queue.len() >= capacity)
+            .unwrap_or(false)
+    }
+
+    /// Buffers `value`, returning the location of the channel and the number of values
+    /// buffered, to be checked against the channel watermark once the lock is released.
+    fn push(&mut self, value: T) -> (&'static Location<'static>, usize) {
+        let ready_at = match (&self.delivery_delay, self.send_waiters.rng()) {
+            (Some(range), Some(rng)) => {
+                let ready_at = tokio_timer::clock::now() + rng.gen_range(range.start, range.end);
+                // values are delivered in order, so a value is never ready before the previous.
+                let prev = self.queue.back().and_then(|(_, ready_at)| *ready_at);
+                Some(prev.map_or(ready_at, |prev| prev.max(ready_at)))
+            }
+            _ => None,
+        };
+        self.queue.push_back((value, ready_at));
+        self.recv_waiter.wake_all();
+        (self.location, self.queue.len())
+    }
+}
+
+/// Sending half of a channel.
+pub struct Sender<T> {
+    id: u64,
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").field("id", &self.id).finish()
+    }
+}
+
+/// Receiving half of a channel.
+pub struct Receiver<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+    delay: Option<tokio_timer::Delay>,
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish()
+    }
+}
+
+#[track_caller]
+fn new<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let mut send_waiters = WaitQueue::new();
+    let id = send_waiters.next_id();
+    let shared = Shared {
+        queue: VecDeque::new(),
+        capacity,
+        senders: 1,
+        receiver_alive: true,
+        send_waiters,
+        recv_waiter: WaitQueue::new(),
+        delivery_delay: None,
+        location: Location::caller(),
+    };
+    let shared = sync::Arc::new(sync::Mutex::new(shared));
+    let sender = Sender {
+        id,
+        shared: sync::Arc::clone(&shared),
+    };
+    let receiver = Receiver {
+        shared,
+        delay: None,
+    };
+    (sender, receiver)
+}
+
+/// Creates a channel buffering up to `capacity` values.
+#[track_caller]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be greater than 0");
+    new(Some(capacity))
+}
+
+/// Creates a channel with an unbounded buffer.
+#[track_caller]
+pub fn unbounded_channel<T>() -> (Sender<T>, Receiver<T>) {
+    new(None)
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for capacity if the channel is full.
+    pub async fn send(&mut self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        futures::future::poll_fn(|cx| {
+            let mut lock = self.shared.lock().unwrap();
+            if !lock.receiver_alive {
+                lock.send_waiters.remove(self.id);
+                return Poll::Ready(Err(SendError(value.take().unwrap())));
+            }
+            if lock.is_full() {
+                lock.send_waiters.register(self.id, cx.waker());
+                return Poll::Pending;
+            }
+            lock.send_waiters.remove(self.id);
+            let (location, depth) = lock.push(value.take().unwrap());
+            drop(lock);
+            context::watermark(QueueKind::Channel, location, depth - 1, depth);
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Sends `value` if the channel has capacity.
+    pub fn try_send(&mut self, value: T) -> Result<(), TrySendError<T>> {
+        let mut lock = self.shared.lock().unwrap();
+        if !lock.receiver_alive {
+            Err(TrySendError::Closed(value))
+        } else if lock.is_full() {
+            Err(TrySendError::Full(value))
+        } else {
+            let (location, depth) = lock.push(value);
+            drop(lock);
+            context::watermark(QueueKind::Channel, location, depth - 1, depth);
+            Ok(())
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let mut lock = self.shared.lock().unwrap();
+        lock.senders += 1;
+        Sender {
+            id: lock.send_waiters.next_id(),
+            shared: sync::Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.senders -= 1;
+        lock.send_waiters.remove(self.id);
+        if lock.senders == 0 {
+            lock.recv_waiter.wake_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Holds back each value sent after this call for a duration drawn from `delay`. Delays
+    /// are only injected under the deterministic runtime.
+    pub fn set_delivery_delay(&mut self, delay: ops::Range<time::Duration>) {
+        self.shared.lock().unwrap().delivery_delay = Some(delay);
+    }
+
+    /// Receives the next value, returning `None` once every sender has been dropped and the
+    /// buffer is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Closes the channel, further sends will fail. Buffered values can still be received.
+    pub fn close(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.receiver_alive = false;
+        lock.send_waiters.wake_all();
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(delay) = &mut self.delay {
+            futures::ready!(delay.poll_unpin(cx));
+            self.delay = None;
+        }
+        let mut lock = self.shared.lock().unwrap();
+        match lock.queue.front() {
+            Some((_, Some(ready_at))) if *ready_at > tokio_timer::clock::now() => {
+                let mut delay = tokio_timer::delay(*ready_at);
+                drop(lock);
+                if delay.poll_unpin(cx).is_pending() {
+                    self.delay = Some(delay);
+                    return Poll::Pending;
+                }
+                self.poll_recv(cx)
+            }
+            Some(_) => {
+                let (value, _) = lock.queue.pop_front().unwrap();
+                lock.send_waiters.wake_all();
+                Poll::Ready(Some(value))
+            }
+            None if lock.senders == 0 => Poll::Ready(None),
+            None => {
+                lock.recv_waiter.register(0, cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Unpin for Receiver<T> {}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    /// Returns the order in which values from contending senders are received.
+    fn contended(seed: u64) -> Vec<usize> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let (tx, mut rx) = channel(1);
+            for idx in 0..8 {
+                let mut tx = tx.clone();
+                handle.spawn(async move {
+                    tx.send(idx).await.unwrap();
+                });
+            }
+            drop(tx);
+            let mut received = vec![];
+            while let Some(value) = rx.recv().await {
+                received.push(value);
+            }
+            received
+        })
+    }
+
+    #[test]
+    /// Test that contending senders are accepted in an order which depends on the seed.
+    fn seeded_contention() {
+        assert_eq!(contended(1), contended(1));
+        assert!((0..10).any(|seed| contended(seed) != contended(seed + 1)));
+        let mut received = contended(1);
+        received.sort();
+        assert_eq!(received, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    /// Test that delivery delays hold values back while preserving their order.
+    fn delivery_delay() {
+        let mut runtime = DeterministicRuntime::new_with_seed(3).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let (mut tx, mut rx) = unbounded_channel();
+            rx.set_delivery_delay(Duration::from_millis(10)..Duration::from_millis(100));
+            let start = handle.now();
+            for idx in 0..5 {
+                tx.try_send(idx).unwrap();
+            }
+            drop(tx);
+            let mut received = vec![];
+            while let Some(value) = rx.recv().await {
+                received.push(value);
+            }
+            assert_eq!(received, vec![0, 1, 2, 3, 4]);
+            assert!(handle.now() >= start + Duration::from_millis(10));
+        });
+    }
+}
@@ -0,0 +1,222 @@
+//! Asynchronous mutual exclusion lock.
+//!
+//! When the lock is released, it is granted to one of the waiting tasks chosen by the runtime
+//! seed rather than to the task which has waited longest, exposing code which assumes locks are
+//! fair.
+use super::wait::WaitQueue;
+use futures::Poll;
+use std::{fmt, future::Future, ops, pin::Pin, sync, task::Context};
+
+#[derive(Debug)]
+struct State {
+    locked: bool,
+    /// Waiter the lock has been granted to, which has not yet been polled.
+    granted: Option<u64>,
+    waiters: WaitQueue,
+}
+
+impl State {
+    /// Releases the lock, granting it to a waiter if there are any.
+    fn release(&mut self) {
+        match self.waiters.wake_one() {
+            Some(id) => self.granted = Some(id),
+            None => self.locked = false,
+        }
+    }
+}
+
+/// An asynchronous lock protecting a value of type `T`.
+pub struct Mutex<T> {
+    state: sync::Mutex<State>,
+    /// The protected value, which is moved into the guard while the lock is held.
+    value: sync::Mutex<Option<T>>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("locked", &self.state.lock().unwrap().locked)
+            .finish()
+    }
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub fn new(value: T) -> Self {
+        let state = State {
+            locked: false,
+            granted: None,
+            waiters: WaitQueue::new(),
+        };
+        Self {
+            state: sync::Mutex::new(state),
+            value: sync::Mutex::new(Some(value)),
+        }
+    }
+
+    /// Acquires the lock, waiting until it is granted to this task.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            id: None,
+        }
+    }
+
+    /// Acquires the lock if it is free and no other tasks are waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock().unwrap();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        drop(state);
+        Some(self.guard())
+    }
+
+    /// Consumes the lock, returning the protected value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner().unwrap().expect("value missing")
+    }
+
+    fn guard(&self) -> MutexGuard<'_, T> {
+        let value = self.value.lock().unwrap().take();
+        MutexGuard { mutex: self, value }
+    }
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// Id of this waiter, assigned when it first waits.
+    id: Option<u64>,
+}
+
+impl<T> fmt::Debug for Lock<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lock").field("id", &self.id).finish()
+    }
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock().unwrap();
+        let granted = self.id.is_some() && state.granted == self.id;
+        let free = !state.locked && state.waiters.is_empty();
+        if granted || free {
+            state.granted = None;
+            state.locked = true;
+            self.id = None;
+            drop(state);
+            return Poll::Ready(mutex.guard());
+        }
+        let id = match self.id {
+            Some(id) => id,
+            None => state.waiters.next_id(),
+        };
+        self.id = Some(id);
+        state.waiters.register(id, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.mutex.state.lock().unwrap();
+            state.waiters.remove(id);
+            // pass the lock on if it was granted to this waiter.
+            if state.granted == Some(id) {
+                state.granted = None;
+                state.release();
+            }
+        }
+    }
+}
+
+/// Guard giving access to the value protected by a `Mutex`, releasing the lock when dropped.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    value: Option<T>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> ops::Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value.as_ref().expect("value missing")
+    }
+}
+
+impl<T> ops::DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().expect("value missing")
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.mutex.value.lock().unwrap() = self.value.take();
+        self.mutex.state.lock().unwrap().release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    /// Returns the order in which contending tasks are granted the lock.
+    fn grant_order(seed: u64) -> Vec<usize> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let mutex = sync::Arc::new(Mutex::new(vec![]));
+            let guard = mutex.lock().await;
+            for idx in 0..8 {
+                let mutex = sync::Arc::clone(&mutex);
+                handle.spawn(async move {
+                    mutex.lock().await.push(idx);
+                });
+            }
+            handle.delay_from(Duration::from_millis(1)).await;
+            drop(guard);
+            handle.delay_from(Duration::from_millis(1)).await;
+            let order = mutex.lock().await.clone();
+            order
+        })
+    }
+
+    #[test]
+    /// Test that the lock is granted to waiters in an order which depends on the seed.
+    fn seeded_grant_order() {
+        assert_eq!(grant_order(2), grant_order(2));
+        assert!((0..10).any(|seed| grant_order(seed) != grant_order(seed + 1)));
+        assert_eq!(grant_order(2).len(), 8);
+    }
+
+    #[test]
+    /// Test that a lock granted to a waiter which is dropped is passed on.
+    fn dropped_waiter() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {
+            let mutex = Mutex::new(0);
+            let guard = mutex.lock().await;
+            let mut waiter = Box::pin(mutex.lock());
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            assert!(waiter.as_mut().poll(&mut cx).is_pending());
+            drop(guard);
+            drop(waiter);
+            *mutex.lock().await += 1;
+            assert_eq!(mutex.into_inner(), 1);
+        });
+    }
+}
@@ -0,0 +1,230 @@
+//! Notification of a single task, or of every waiting task.
+//!
+//! A notification sent with no task waiting is normally stored and consumed by the next task
+//! to wait. With `set_lost_wakeup_probability`, the deterministic runtime may instead drop
+//! such a notification as an injected fault, simulating the race in which a task checks a
+//! condition, the condition changes and is notified, and only then does the task start
+//! waiting. Code which creates the `Notified` future before checking the condition is not
+//! affected, as a notification sent after the future was created is always delivered to it.
+use super::wait::WaitQueue;
+use crate::deterministic::{context, FaultKind};
+use futures::Poll;
+use rand::Rng;
+use std::{
+    collections::HashMap, fmt, future::Future, panic::Location, pin::Pin, sync, task::Context,
+};
+
+#[derive(Debug)]
+struct State {
+    /// Whether a notification is stored for the next task to wait.
+    permit: bool,
+    /// Waiters which have not yet been notified, in the order they were created.
+    waiting: Vec<u64>,
+    /// Notified waiters which have not yet completed, and whether their notification should
+    /// be passed on if they are dropped.
+    notified: HashMap<u64, bool>,
+    waiters: WaitQueue,
+    /// Identifies this primitive in the fault schedule.
+    callsite: Option<u64>,
+    lost_wakeup_prob: f64,
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        if self.waiting.is_empty() {
+            let lost = match self.callsite {
+                Some(callsite) => {
+                    context::fault(callsite, self.lost_wakeup_prob, FaultKind::LostWakeup)
+                }
+                None => false,
+            };
+            self.permit = !lost;
+            return;
+        }
+        let idx = match self.waiters.rng() {
+            Some(rng) => rng.gen_range(0, self.waiting.len()),
+            None => 0,
+        };
+        let id = self.waiting.remove(idx);
+        self.notified.insert(id, true);
+        self.waiters.wake_matching(|waiter| waiter == id);
+    }
+}
+
+/// Notifies waiting tasks of an event.
+pub struct Notify {
+    state: sync::Mutex<State>,
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Notify")
+            .field("permit", &state.permit)
+            .field("waiting", &state.waiting.len())
+            .finish()
+    }
+}
+
+impl Notify {
+    #[track_caller]
+    pub fn new() -> Self {
+        let state = State {
+            permit: false,
+            waiting: vec![],
+            notified: HashMap::new(),
+            waiters: WaitQueue::new(),
+            callsite: context::callsite_id(Location::caller()),
+            lost_wakeup_prob: 0.0,
+        };
+        Self {
+            state: sync::Mutex::new(state),
+        }
+    }
+
+    /// Sets the probability, 0..1, with which a notification sent while no task is waiting is
+    /// dropped. Notifications are only dropped under the deterministic runtime.
+    pub fn set_lost_wakeup_probability(&self, probability: f64) {
+        self.state.lock().unwrap().lost_wakeup_prob = probability;
+    }
+
+    /// Returns a future which completes once this task is notified. The task is waiting from
+    /// the moment the future is created, so it should be created before checking whatever
+    /// condition the notification signals.
+    pub fn notified(&self) -> Notified<'_> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.waiters.next_id();
+        if state.permit {
+            state.permit = false;
+            state.notified.insert(id, false);
+        } else {
+            state.waiting.push(id);
+        }
+        Notified {
+            notify: self,
+            id: Some(id),
+        }
+    }
+
+    /// Notifies one waiting task, chosen by the runtime seed. If no task is waiting, the
+    /// notification is stored for the next task to wait.
+    pub fn notify_one(&self) {
+        self.state.lock().unwrap().notify_one();
+    }
+
+    /// Notifies every waiting task, waking them in an order drawn from the runtime seed. No
+    /// notification is stored if no task is waiting.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock().unwrap();
+        for id in std::mem::take(&mut state.waiting) {
+            state.notified.insert(id, false);
+        }
+        state.waiters.wake_all();
+    }
+}
+
+impl Default for Notify {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Id of this waiter, until it has been notified.
+    id: Option<u64>,
+}
+
+impl fmt::Debug for Notified<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notified").field("id", &self.id).finish()
+    }
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let id = match self.id {
+            Some(id) => id,
+            None => return Poll::Ready(()),
+        };
+        let mut state = self.notify.state.lock().unwrap();
+        if state.notified.remove(&id).is_some() {
+            drop(state);
+            self.id = None;
+            return Poll::Ready(());
+        }
+        state.waiters.register(id, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.notify.state.lock().unwrap();
+            state.waiters.remove(id);
+            state.waiting.retain(|waiter| *waiter != id);
+            // pass on a notification sent to this waiter by `notify_one`.
+            if state.notified.remove(&id) == Some(true) {
+                state.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that a notification sent before waiting is dropped when the fault is injected.
+    fn lost_wakeup() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let notify = Notify::new();
+            notify.set_lost_wakeup_probability(1.0);
+            notify.notify_one();
+            let wait = handle.timeout(notify.notified(), Duration::from_secs(1));
+            assert!(wait.await.is_err());
+        });
+        let faults = runtime.faults();
+        assert_eq!(faults.len(), 1);
+        assert_eq!(faults[0].kind, FaultKind::LostWakeup);
+    }
+
+    #[test]
+    /// Test that waiting before checking the condition tolerates lost wakeups.
+    fn loss_tolerant_loop() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let notify = sync::Arc::new(Notify::new());
+            notify.set_lost_wakeup_probability(1.0);
+            let ready = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+            {
+                let notify = sync::Arc::clone(&notify);
+                let ready = sync::Arc::clone(&ready);
+                let delay = handle.delay_from(Duration::from_millis(1));
+                handle.spawn(async move {
+                    delay.await;
+                    ready.store(true, sync::atomic::Ordering::SeqCst);
+                    notify.notify_one();
+                });
+            }
+            loop {
+                let notified = notify.notified();
+                if ready.load(sync::atomic::Ordering::SeqCst) {
+                    break;
+                }
+                notified.await;
+            }
+        });
+        assert!(runtime.faults().is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+//! Channel for sending a single value between tasks.
+use futures::Poll;
+use std::{error, fmt, future::Future, pin::Pin, sync, task::Context};
+
+use super::wait::WaitQueue;
+
+/// Error returned by `Receiver` when the sender is dropped without sending a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecvError(());
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl error::Error for RecvError {}
+
+#[derive(Debug)]
+struct Shared<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    waiter: WaitQueue,
+}
+
+/// Sending half of a channel.
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+/// Receiving half of a channel, which resolves to the value sent.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+/// Creates a channel for sending a single value.
+#[track_caller]
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Shared {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        waiter: WaitQueue::new(),
+    };
+    let shared = sync::Arc::new(sync::Mutex::new(shared));
+    let sender = Sender {
+        shared: sync::Arc::clone(&shared),
+    };
+    (sender, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, returning it if the receiver has been dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut lock = self.shared.lock().unwrap();
+        if !lock.receiver_alive {
+            return Err(value);
+        }
+        lock.value = Some(value);
+        lock.waiter.wake_all();
+        Ok(())
+    }
+
+    /// Returns true if the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        !self.shared.lock().unwrap().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.sender_alive = false;
+        lock.waiter.wake_all();
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut lock = self.shared.lock().unwrap();
+        if let Some(value) = lock.value.take() {
+            Poll::Ready(Ok(value))
+        } else if !lock.sender_alive {
+            Poll::Ready(Err(RecvError(())))
+        } else {
+            lock.waiter.register(0, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().receiver_alive = false;
+    }
+}
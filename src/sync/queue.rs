@@ -0,0 +1,283 @@
+//! Bounded queue with backpressure faults.
+//!
+//! Under the deterministic runtime, the capacity of the queue is drawn from a range using the
+//! runtime seed, values may be held back for a seeded duration before they are enqueued, and
+//! `try_push` may report the queue as full when it has capacity. This exercises load shedding
+//! and retry logic at boundaries within an application, rather than only at the network.
+use super::wait::WaitQueue;
+use crate::deterministic::{context, FaultKind};
+use futures::Poll;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, error, fmt, ops, panic::Location, sync, time};
+
+/// Configuration for the capacity and faults of a `Queue`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueConfig {
+    /// The range the capacity of the queue is drawn from. Outside of the deterministic runtime,
+    /// the queue has the largest capacity in the range.
+    pub capacity: ops::Range<usize>,
+    /// The range of duration for which a value can be held back before it is enqueued.
+    pub enqueue_delay: ops::Range<time::Duration>,
+    /// The probability of an enqueue delay being injected, 0..1.
+    pub enqueue_delay_prob: f64,
+    /// The probability of `try_push` spuriously reporting the queue as full, 0..1.
+    pub spurious_full_prob: f64,
+}
+
+impl QueueConfig {
+    /// Returns a configuration for a queue of exactly `capacity` which never injects faults.
+    pub fn fixed(capacity: usize) -> Self {
+        Self {
+            capacity: capacity..capacity + 1,
+            enqueue_delay: time::Duration::from_millis(0)..time::Duration::from_millis(1),
+            enqueue_delay_prob: 0.0,
+            spurious_full_prob: 0.0,
+        }
+    }
+}
+
+/// Error returned by `Queue::try_push` when the queue is full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Full<T>(pub T);
+
+impl<T> fmt::Display for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queue full")
+    }
+}
+
+impl<T: fmt::Debug> error::Error for Full<T> {}
+
+#[derive(Debug)]
+struct State<T> {
+    values: VecDeque<T>,
+    capacity: usize,
+    pushers: WaitQueue,
+    poppers: WaitQueue,
+}
+
+impl<T> State<T> {
+    fn push(&mut self, value: T) {
+        self.values.push_back(value);
+        self.poppers.wake_one();
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let value = self.values.pop_front()?;
+        self.pushers.wake_one();
+        Some(value)
+    }
+}
+
+/// A bounded first-in first-out queue shared between tasks.
+pub struct Queue<T> {
+    config: QueueConfig,
+    /// Identifies this queue in the fault schedule.
+    callsite: Option<u64>,
+    state: sync::Mutex<State<T>>,
+}
+
+impl<T> fmt::Debug for Queue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Queue")
+            .field("len", &state.values.len())
+            .field("capacity", &state.capacity)
+            .finish()
+    }
+}
+
+impl<T> Queue<T> {
+    /// Creates a queue holding up to `capacity` values, without faults.
+    #[track_caller]
+    pub fn new(capacity: usize) -> Self {
+        Self::with_config(QueueConfig::fixed(capacity))
+    }
+
+    /// Creates a queue with a capacity and faults according to `config`.
+    #[track_caller]
+    pub fn with_config(config: QueueConfig) -> Self {
+        assert!(
+            !config.capacity.is_empty() && config.capacity.start > 0,
+            "capacity must be a non-empty range greater than 0"
+        );
+        let mut pushers = WaitQueue::new();
+        let capacity = match pushers.rng() {
+            Some(rng) => rng.gen_range(config.capacity.start, config.capacity.end),
+            None => config.capacity.end - 1,
+        };
+        let state = State {
+            values: VecDeque::new(),
+            capacity,
+            pushers,
+            poppers: WaitQueue::new(),
+        };
+        Self {
+            callsite: context::callsite_id(Location::caller()),
+            config,
+            state: sync::Mutex::new(state),
+        }
+    }
+
+    /// Returns the capacity of the queue.
+    pub fn capacity(&self) -> usize {
+        self.state.lock().unwrap().capacity
+    }
+
+    /// Returns the number of values in the queue.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds `value` to the queue, waiting for capacity if the queue is full.
+    pub async fn push(&self, value: T) {
+        if let Some(callsite) = self.callsite {
+            let delay = context::delay_fault(
+                callsite,
+                self.config.enqueue_delay_prob,
+                self.config.enqueue_delay.clone(),
+                FaultKind::EnqueueDelay,
+            );
+            if let Some(delay) = delay {
+                delay.await;
+            }
+        }
+        let mut value = Some(value);
+        let waiting = Waiting::new(&self.state, |state| &mut state.pushers);
+        futures::future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.values.len() >= state.capacity {
+                state.pushers.register(waiting.id, cx.waker());
+                return Poll::Pending;
+            }
+            state.push(value.take().unwrap());
+            Poll::Ready(())
+        })
+        .await
+    }
+
+    /// Adds `value` to the queue if it has capacity.
+    pub fn try_push(&self, value: T) -> Result<(), Full<T>> {
+        let spurious = self.callsite.is_some_and(|callsite| {
+            context::fault(
+                callsite,
+                self.config.spurious_full_prob,
+                FaultKind::SpuriousFull,
+            )
+        });
+        let mut state = self.state.lock().unwrap();
+        if spurious || state.values.len() >= state.capacity {
+            return Err(Full(value));
+        }
+        state.push(value);
+        Ok(())
+    }
+
+    /// Removes the value at the front of the queue, waiting for one if the queue is empty.
+    pub async fn pop(&self) -> T {
+        let waiting = Waiting::new(&self.state, |state| &mut state.poppers);
+        futures::future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            match state.pop() {
+                Some(value) => Poll::Ready(value),
+                None => {
+                    state.poppers.register(waiting.id, cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Removes the value at the front of the queue, if any.
+    pub fn try_pop(&self) -> Option<T> {
+        self.state.lock().unwrap().pop()
+    }
+}
+
+/// Removes a waiter from its wait queue when a push or pop is completed or dropped.
+struct Waiting<'a, T> {
+    state: &'a sync::Mutex<State<T>>,
+    queue: fn(&mut State<T>) -> &mut WaitQueue,
+    id: u64,
+}
+
+impl<'a, T> Waiting<'a, T> {
+    fn new(state: &'a sync::Mutex<State<T>>, queue: fn(&mut State<T>) -> &mut WaitQueue) -> Self {
+        let id = queue(&mut state.lock().unwrap()).next_id();
+        Self { state, queue, id }
+    }
+}
+
+impl<T> Drop for Waiting<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        (self.queue)(&mut state).remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    fn config() -> QueueConfig {
+        QueueConfig {
+            capacity: 1..16,
+            enqueue_delay: Duration::from_millis(1)..Duration::from_millis(50),
+            enqueue_delay_prob: 0.5,
+            spurious_full_prob: 0.2,
+        }
+    }
+
+    #[test]
+    /// Test that the capacity and spurious full results depend on the seed.
+    fn seeded_backpressure() {
+        let run = |seed| {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            let accepted = runtime.block_on(async {
+                let queue = Queue::with_config(config());
+                let accepted = (0..32).filter(|idx| queue.try_push(*idx).is_ok()).count();
+                assert!(accepted <= queue.capacity());
+                (queue.capacity(), accepted)
+            });
+            (accepted, runtime.faults())
+        };
+        assert_eq!(run(1), run(1));
+        assert!((0..10).any(|seed| run(seed).0 != run(seed + 1).0));
+        assert!((0..10).any(|seed| !run(seed).1.is_empty()));
+    }
+
+    #[test]
+    /// Test that pushing waits for capacity and that values are popped in order.
+    fn push_waits_for_capacity() {
+        let mut runtime = DeterministicRuntime::new_with_seed(2).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let queue = sync::Arc::new(Queue::with_config(QueueConfig {
+                capacity: 1..3,
+                ..config()
+            }));
+            {
+                let queue = sync::Arc::clone(&queue);
+                handle.spawn(async move {
+                    for idx in 0..8 {
+                        queue.push(idx).await;
+                    }
+                });
+            }
+            let mut popped = vec![];
+            for _ in 0..8 {
+                assert!(queue.len() <= queue.capacity());
+                popped.push(queue.pop().await);
+            }
+            assert_eq!(popped, (0..8).collect::<Vec<_>>());
+        });
+    }
+}
@@ -0,0 +1,262 @@
+//! Asynchronous reader-writer lock.
+//!
+//! When the lock becomes free, it is granted to a waiting task chosen by the runtime seed. If
+//! a reader is chosen, every other waiting reader is granted the lock along with it.
+use super::wait::WaitQueue;
+use futures::Poll;
+use std::{collections::HashSet, fmt, future::Future, ops, pin::Pin, sync, task::Context};
+
+#[derive(Debug)]
+struct State {
+    readers: usize,
+    writer: bool,
+    /// Waiters the lock has been granted to, which have not yet been polled.
+    granted: HashSet<u64>,
+    /// Ids of the waiters waiting to write.
+    writers_waiting: HashSet<u64>,
+    waiters: WaitQueue,
+}
+
+impl State {
+    fn is_free(&self) -> bool {
+        self.readers == 0 && !self.writer && self.granted.is_empty()
+    }
+
+    /// Grants the lock to the next waiter if it is free.
+    fn grant_next(&mut self) {
+        if !self.is_free() {
+            return;
+        }
+        if let Some(id) = self.waiters.wake_one() {
+            self.granted.insert(id);
+            if !self.writers_waiting.contains(&id) {
+                let writers = &self.writers_waiting;
+                let readers = self.waiters.wake_matching(|id| !writers.contains(&id));
+                self.granted.extend(readers);
+            }
+        }
+    }
+
+    /// Removes a waiter which is no longer waiting, passing on the lock if it was granted to
+    /// the waiter.
+    fn remove(&mut self, id: u64) {
+        self.waiters.remove(id);
+        self.writers_waiting.remove(&id);
+        if self.granted.remove(&id) {
+            self.grant_next();
+        }
+    }
+}
+
+/// An asynchronous lock allowing either many readers or a single writer.
+pub struct RwLock<T> {
+    state: sync::Mutex<State>,
+    /// The protected value. Readers share the value, a writer takes it while the lock is held.
+    value: sync::Mutex<Option<sync::Arc<T>>>,
+}
+
+impl<T> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("RwLock")
+            .field("readers", &state.readers)
+            .field("writer", &state.writer)
+            .finish()
+    }
+}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub fn new(value: T) -> Self {
+        let state = State {
+            readers: 0,
+            writer: false,
+            granted: HashSet::new(),
+            writers_waiting: HashSet::new(),
+            waiters: WaitQueue::new(),
+        };
+        Self {
+            state: sync::Mutex::new(state),
+            value: sync::Mutex::new(Some(sync::Arc::new(value))),
+        }
+    }
+
+    /// Acquires shared read access.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        Acquire {
+            lock: self,
+            write: false,
+            id: None,
+        }
+        .await;
+        let value = self.value.lock().unwrap().clone().expect("value missing");
+        RwLockReadGuard {
+            lock: self,
+            value: Some(value),
+        }
+    }
+
+    /// Acquires exclusive write access.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        Acquire {
+            lock: self,
+            write: true,
+            id: None,
+        }
+        .await;
+        let value = self.value.lock().unwrap().take().expect("value missing");
+        let value = sync::Arc::try_unwrap(value).unwrap_or_else(|_| unreachable!());
+        RwLockWriteGuard {
+            lock: self,
+            value: Some(value),
+        }
+    }
+}
+
+/// Future waiting for the lock to be granted.
+struct Acquire<'a, T> {
+    lock: &'a RwLock<T>,
+    write: bool,
+    id: Option<u64>,
+}
+
+impl<T> Future for Acquire<'_, T> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let lock = self.lock;
+        let mut state = lock.state.lock().unwrap();
+        let granted = self.id.is_some_and(|id| state.granted.remove(&id));
+        let free = if self.write {
+            state.is_free()
+        } else {
+            !state.writer && state.granted.is_empty()
+        };
+        if granted || (free && state.waiters.is_empty()) {
+            if self.write {
+                state.writer = true;
+            } else {
+                state.readers += 1;
+            }
+            if let Some(id) = self.id.take() {
+                state.writers_waiting.remove(&id);
+            }
+            return Poll::Ready(());
+        }
+        let id = match self.id {
+            Some(id) => id,
+            None => state.waiters.next_id(),
+        };
+        self.id = Some(id);
+        if self.write {
+            state.writers_waiting.insert(id);
+        }
+        state.waiters.register(id, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Acquire<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.lock.state.lock().unwrap().remove(id);
+        }
+    }
+}
+
+/// Guard giving shared access to the value protected by a `RwLock`.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    value: Option<sync::Arc<T>>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> ops::Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value.as_ref().expect("value missing")
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // the shared value must be dropped before a writer can take it.
+        self.value.take();
+        let mut state = self.lock.state.lock().unwrap();
+        state.readers -= 1;
+        state.grant_next();
+    }
+}
+
+/// Guard giving exclusive access to the value protected by a `RwLock`.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    value: Option<T>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> ops::Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value.as_ref().expect("value missing")
+    }
+}
+
+impl<T> ops::DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().expect("value missing")
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let value = self.value.take().map(sync::Arc::new);
+        *self.lock.value.lock().unwrap() = value;
+        let mut state = self.lock.state.lock().unwrap();
+        state.writer = false;
+        state.grant_next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that readers share the lock and writers are excluded while it is read.
+    fn readers_and_writers() {
+        let mut runtime = DeterministicRuntime::new_with_seed(5).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let lock = sync::Arc::new(RwLock::new(vec![]));
+            let first = lock.read().await;
+            let second = lock.read().await;
+            assert!(first.is_empty() && second.is_empty());
+            for idx in 0..4 {
+                let lock = sync::Arc::clone(&lock);
+                handle.spawn(async move {
+                    lock.write().await.push(idx);
+                    let _ = lock.read().await;
+                });
+            }
+            handle.delay_from(Duration::from_millis(1)).await;
+            assert!(lock.value.lock().unwrap().is_some());
+            drop((first, second));
+            handle.delay_from(Duration::from_millis(1)).await;
+            let mut written = lock.read().await.clone();
+            written.sort();
+            assert_eq!(written, vec![0, 1, 2, 3]);
+        });
+    }
+}
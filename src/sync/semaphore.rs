@@ -0,0 +1,277 @@
+//! Counting semaphore.
+//!
+//! When permits are released, they are granted to waiting tasks chosen by the runtime seed
+//! rather than in the order the tasks started waiting. Acquiring and releasing permits is
+//! recorded in the event log of the deterministic runtime.
+use super::wait::WaitQueue;
+use crate::deterministic::{context, SimEvent};
+use futures::Poll;
+use std::{collections::HashMap, fmt, future::Future, pin::Pin, sync, task::Context};
+
+#[derive(Debug)]
+struct State {
+    permits: usize,
+    /// Number of permits wanted by each waiter.
+    wanted: HashMap<u64, usize>,
+    /// Waiters which have been granted their permits but not yet polled.
+    granted: HashMap<u64, usize>,
+    waiters: WaitQueue,
+}
+
+impl State {
+    /// Grants available permits to waiters, chosen by the seed among those whose request can be
+    /// satisfied.
+    fn grant(&mut self) {
+        loop {
+            let permits = self.permits;
+            let wanted = &self.wanted;
+            let chosen = self
+                .waiters
+                .wake_one_matching(|id| wanted.get(&id).is_some_and(|n| *n <= permits));
+            match chosen {
+                Some(id) => {
+                    let n = self.wanted.remove(&id).unwrap();
+                    self.permits -= n;
+                    self.granted.insert(id, n);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn release(&mut self, permits: usize) {
+        self.permits += permits;
+        self.grant();
+    }
+}
+
+/// A semaphore limiting the number of tasks which may proceed concurrently.
+pub struct Semaphore {
+    state: sync::Mutex<State>,
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.state.lock().unwrap().permits)
+            .finish()
+    }
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` initially available.
+    #[track_caller]
+    pub fn new(permits: usize) -> Self {
+        let state = State {
+            permits,
+            wanted: HashMap::new(),
+            granted: HashMap::new(),
+            waiters: WaitQueue::new(),
+        };
+        Self {
+            state: sync::Mutex::new(state),
+        }
+    }
+
+    /// Returns the number of permits currently available.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().permits
+    }
+
+    /// Adds `permits` to the semaphore, granting them to waiting tasks.
+    pub fn add_permits(&self, permits: usize) {
+        self.state.lock().unwrap().release(permits);
+    }
+
+    /// Acquires a single permit, waiting until one is granted to this task.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Acquires `permits` permits at once, waiting until they are granted to this task.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+    }
+
+    /// Acquires a single permit if one is available and no other tasks are waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.permits == 0 || !state.waiters.is_empty() {
+            return None;
+        }
+        state.permits -= 1;
+        drop(state);
+        Some(self.permit(1))
+    }
+
+    fn permit(&self, permits: usize) -> SemaphorePermit<'_> {
+        context::record(SimEvent::PermitsAcquired {
+            task: context::current_task(),
+            permits,
+        });
+        SemaphorePermit {
+            semaphore: self,
+            permits,
+        }
+    }
+}
+
+/// Future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Id of this waiter, assigned when it first waits.
+    id: Option<u64>,
+}
+
+impl fmt::Debug for Acquire<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Acquire")
+            .field("permits", &self.permits)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+        let mut state = semaphore.state.lock().unwrap();
+        if let Some(id) = self.id {
+            if state.granted.remove(&id).is_some() {
+                self.id = None;
+                drop(state);
+                return Poll::Ready(semaphore.permit(permits));
+            }
+        } else if state.waiters.is_empty() && state.permits >= permits {
+            state.permits -= permits;
+            drop(state);
+            return Poll::Ready(semaphore.permit(permits));
+        }
+        let id = match self.id {
+            Some(id) => id,
+            None => state.waiters.next_id(),
+        };
+        self.id = Some(id);
+        state.wanted.insert(id, permits);
+        state.waiters.register(id, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.semaphore.state.lock().unwrap();
+            state.waiters.remove(id);
+            state.wanted.remove(&id);
+            // return the permits if they were granted to this waiter.
+            if let Some(permits) = state.granted.remove(&id) {
+                state.release(permits);
+            }
+        }
+    }
+}
+
+/// Permits acquired from a `Semaphore`, which are released when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Consumes the permit without releasing it back to the semaphore.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits == 0 {
+            return;
+        }
+        context::record(SimEvent::PermitsReleased {
+            task: context::current_task(),
+            permits: self.permits,
+        });
+        self.semaphore.state.lock().unwrap().release(self.permits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    /// Returns the order in which tasks contending for a single permit acquire it.
+    fn acquire_order(seed: u64) -> Vec<usize> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let semaphore = sync::Arc::new(Semaphore::new(1));
+            let order = sync::Arc::new(sync::Mutex::new(vec![]));
+            for idx in 0..8 {
+                let semaphore = sync::Arc::clone(&semaphore);
+                let order = sync::Arc::clone(&order);
+                let handle = handle.clone();
+                handle.clone().spawn(async move {
+                    let _permit = semaphore.acquire().await;
+                    order.lock().unwrap().push(idx);
+                    handle.delay_from(Duration::from_millis(1)).await;
+                });
+            }
+            handle.delay_from(Duration::from_millis(100)).await;
+            let order = order.lock().unwrap().clone();
+            order
+        })
+    }
+
+    #[test]
+    /// Test that permits are granted to waiters in an order which depends on the seed.
+    fn seeded_acquire_order() {
+        assert_eq!(acquire_order(5), acquire_order(5));
+        assert!((0..10).any(|seed| acquire_order(seed) != acquire_order(seed + 1)));
+        assert_eq!(acquire_order(5).len(), 8);
+    }
+
+    #[test]
+    /// Test that acquiring and releasing permits is recorded in the event log.
+    fn events() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let semaphore = Semaphore::new(3);
+            let permit = semaphore.acquire_many(2).await;
+            assert_eq!(semaphore.available_permits(), 1);
+            assert!(semaphore.try_acquire().is_some());
+            drop(permit);
+            assert_eq!(semaphore.available_permits(), 3);
+        });
+        let permits = handle
+            .events()
+            .into_iter()
+            .filter_map(|event| match event.event {
+                SimEvent::PermitsAcquired { permits, .. } => Some(permits as isize),
+                SimEvent::PermitsReleased { permits, .. } => Some(-(permits as isize)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(permits, vec![2, 1, -1, -2]);
+    }
+}
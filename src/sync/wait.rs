@@ -0,0 +1,121 @@
+//! Queue of tasks waiting on a primitive, woken in an order drawn from the runtime seed.
+use crate::deterministic::context;
+use crate::deterministic::rng::SimRng;
+use rand::{seq::SliceRandom, Rng};
+use std::{panic::Location, task::Waker};
+
+#[derive(Debug)]
+pub(crate) struct WaitQueue {
+    /// Waiters in the order they first registered.
+    waiters: Vec<(u64, Waker)>,
+    next_id: u64,
+    rng: Option<SimRng>,
+}
+
+impl WaitQueue {
+    /// Returns a new queue. Under the deterministic runtime waiters are woken in an order drawn
+    /// from an RNG keyed by the caller's location, otherwise they are woken in FIFO order.
+    #[track_caller]
+    pub(crate) fn new() -> Self {
+        Self {
+            waiters: vec![],
+            next_id: 0,
+            rng: context::callsite_rng(Location::caller()),
+        }
+    }
+
+    /// Allocates an id identifying a waiter.
+    pub(crate) fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Registers `waker` to be woken on behalf of `id`, replacing any previous waker.
+    pub(crate) fn register(&mut self, id: u64, waker: &Waker) {
+        match self.waiters.iter_mut().find(|(waiter, _)| *waiter == id) {
+            Some((_, existing)) => {
+                if !existing.will_wake(waker) {
+                    *existing = waker.clone()
+                }
+            }
+            None => self.waiters.push((id, waker.clone())),
+        }
+    }
+
+    /// Removes the waiter identified by `id`.
+    pub(crate) fn remove(&mut self, id: u64) {
+        self.waiters.retain(|(waiter, _)| *waiter != id);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    /// Wakes a single waiter, returning its id.
+    pub(crate) fn wake_one(&mut self) -> Option<u64> {
+        self.wake_one_matching(|_| true)
+    }
+
+    /// Wakes a single waiter for which `f` returns true, returning its id.
+    pub(crate) fn wake_one_matching<F>(&mut self, f: F) -> Option<u64>
+    where
+        F: Fn(u64) -> bool,
+    {
+        let matching = self
+            .waiters
+            .iter()
+            .enumerate()
+            .filter(|(_, (id, _))| f(*id))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            return None;
+        }
+        let idx = match &mut self.rng {
+            Some(rng) => matching[rng.gen_range(0, matching.len())],
+            None => matching[0],
+        };
+        let (id, waker) = self.waiters.remove(idx);
+        waker.wake();
+        Some(id)
+    }
+
+    /// Wakes every waiter. The order in which they are woken determines the order in which
+    /// the executor polls them.
+    pub(crate) fn wake_all(&mut self) {
+        let mut waiters = std::mem::take(&mut self.waiters);
+        if let Some(rng) = &mut self.rng {
+            waiters.shuffle(rng);
+        }
+        for (_, waker) in waiters {
+            waker.wake();
+        }
+    }
+
+    /// Wakes every waiter for which `f` returns true, returning their ids in the order they
+    /// were woken.
+    pub(crate) fn wake_matching<F>(&mut self, f: F) -> Vec<u64>
+    where
+        F: Fn(u64) -> bool,
+    {
+        let (mut matching, rest) = std::mem::take(&mut self.waiters)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(id, _)| f(*id));
+        self.waiters = rest;
+        if let Some(rng) = &mut self.rng {
+            matching.shuffle(rng);
+        }
+        matching
+            .into_iter()
+            .map(|(id, waker)| {
+                waker.wake();
+                id
+            })
+            .collect()
+    }
+
+    /// Returns the RNG of this queue, if running under the deterministic runtime.
+    pub(crate) fn rng(&mut self) -> Option<&mut SimRng> {
+        self.rng.as_mut()
+    }
+}
@@ -0,0 +1,153 @@
+//! Single-producer, multi-consumer channel retaining only the latest value.
+//!
+//! Receivers waiting for a new value are woken in a seeded order, so which receiver observes
+//! an update first varies between seeds.
+use super::wait::WaitQueue;
+use std::{error, fmt, ops, sync};
+
+/// Error returned by `Sender::broadcast` when every receiver has been dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl<T: fmt::Debug> error::Error for SendError<T> {}
+
+#[derive(Debug)]
+struct Shared<T> {
+    value: T,
+    /// Incremented each time a value is broadcast.
+    version: u64,
+    sender_alive: bool,
+    receivers: usize,
+    waiters: WaitQueue,
+}
+
+/// Sending half of a channel.
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+/// Receiving half of a channel.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    id: u64,
+    /// Version of the last value returned by `recv`.
+    version: Option<u64>,
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+/// Reference to the latest value of a channel. The channel cannot be updated while this is
+/// held.
+#[derive(Debug)]
+pub struct Ref<'a, T> {
+    lock: sync::MutexGuard<'a, Shared<T>>,
+}
+
+impl<T> ops::Deref for Ref<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.lock.value
+    }
+}
+
+/// Creates a channel with an initial value of `init`.
+#[track_caller]
+pub fn channel<T>(init: T) -> (Sender<T>, Receiver<T>) {
+    let mut waiters = WaitQueue::new();
+    let id = waiters.next_id();
+    let shared = Shared {
+        value: init,
+        version: 0,
+        sender_alive: true,
+        receivers: 1,
+        waiters,
+    };
+    let shared = sync::Arc::new(sync::Mutex::new(shared));
+    let sender = Sender {
+        shared: sync::Arc::clone(&shared),
+    };
+    let receiver = Receiver {
+        id,
+        version: None,
+        shared,
+    };
+    (sender, receiver)
+}
+
+impl<T> Sender<T> {
+    /// Replaces the value of the channel, notifying every receiver.
+    pub fn broadcast(&self, value: T) -> Result<(), SendError<T>> {
+        let mut lock = self.shared.lock().unwrap();
+        if lock.receivers == 0 {
+            return Err(SendError(value));
+        }
+        lock.value = value;
+        lock.version += 1;
+        lock.waiters.wake_all();
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.sender_alive = false;
+        lock.waiters.wake_all();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Returns a reference to the latest value.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            lock: self.shared.lock().unwrap(),
+        }
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Waits for a value which has not yet been returned by this receiver, returning `None`
+    /// once the sender has been dropped. The first call returns the initial value.
+    pub async fn recv(&mut self) -> Option<T> {
+        futures::future::poll_fn(|cx| {
+            let mut lock = self.shared.lock().unwrap();
+            if self.version.is_none_or(|version| lock.version > version) {
+                self.version = Some(lock.version);
+                lock.waiters.remove(self.id);
+                return futures::Poll::Ready(Some(lock.value.clone()));
+            }
+            if !lock.sender_alive {
+                return futures::Poll::Ready(None);
+            }
+            lock.waiters.register(self.id, cx.waker());
+            futures::Poll::Pending
+        })
+        .await
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        let mut lock = self.shared.lock().unwrap();
+        lock.receivers += 1;
+        Receiver {
+            id: lock.waiters.next_id(),
+            version: self.version,
+            shared: sync::Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.receivers -= 1;
+        lock.waiters.remove(self.id);
+    }
+}
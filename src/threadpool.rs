@@ -0,0 +1,131 @@
+//! An `Environment` backed by the multi-threaded Tokio runtime.
+//!
+//! Tasks are spawned onto Tokio's thread pool, time is read from the system clock and network
+//! operations use `tokio::net`, so application code generic over `Environment` can be run in
+//! production exactly as it was tested under the `DeterministicRuntime`.
+use crate::Error;
+use futures::Future;
+use std::{io, net::SocketAddr, time};
+use tokio::runtime::{self, TaskExecutor};
+
+#[derive(Debug, Clone)]
+pub struct ThreadPoolRuntimeHandle {
+    executor: TaskExecutor,
+}
+
+impl crate::Environment for ThreadPoolRuntimeHandle {
+    type TcpStream = tokio::net::TcpStream;
+    type TcpListener = tokio::net::TcpListener;
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let future = crate::ambient::scope(self.clone(), future);
+        tokio_executor::Executor::spawn(&mut self.executor.clone(), Box::pin(future))
+            .map_err(|source| Error::Spawn { source })
+    }
+    fn now(&self) -> time::Instant {
+        tokio_timer::clock::now()
+    }
+    fn delay(&self, deadline: time::Instant) -> tokio::timer::Delay {
+        tokio_timer::delay(deadline)
+    }
+    fn timeout<T>(&self, value: T, timeout: time::Duration) -> crate::Timeout<T> {
+        crate::Timeout::new(tokio_timer::Timeout::new(value, timeout))
+    }
+    fn rng(&self) -> crate::RngHandle {
+        crate::RngHandle::from_entropy()
+    }
+    fn rng_for(&self, _name: &str) -> crate::RngHandle {
+        crate::RngHandle::from_entropy()
+    }
+    async fn bind<A>(&self, addr: A) -> Result<Self::TcpListener, io::Error>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        tokio::net::TcpListener::bind(addr.into()).await
+    }
+    async fn connect<A>(&self, addr: A) -> Result<Self::TcpStream, io::Error>
+    where
+        A: crate::ToSocketAddrs + Send + Sync,
+    {
+        let addrs = addr.to_socket_addrs()?;
+        crate::connect::connect_any(self, addrs, tokio::net::TcpStream::connect).await
+    }
+}
+
+/// A runtime executing tasks on Tokio's thread pool.
+#[derive(Debug)]
+pub struct ThreadPoolRuntime {
+    runtime: runtime::Runtime,
+}
+
+impl ThreadPoolRuntime {
+    pub fn new() -> Result<Self, Error> {
+        let runtime = runtime::Runtime::new().map_err(|source| Error::RuntimeBuild { source })?;
+        Ok(ThreadPoolRuntime { runtime })
+    }
+
+    pub fn handle(&self) -> ThreadPoolRuntimeHandle {
+        ThreadPoolRuntimeHandle {
+            executor: self.runtime.executor(),
+        }
+    }
+
+    pub fn spawn<F>(&mut self, future: F) -> &mut Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.runtime
+            .spawn(crate::ambient::scope(self.handle(), future));
+        self
+    }
+
+    pub fn block_on<F>(&mut self, f: F) -> F::Output
+    where
+        F: Future,
+    {
+        let _ambient = crate::ambient::set_default(&self.handle());
+        self.runtime.block_on(f)
+    }
+
+    /// Waits for every spawned task to complete, then shuts the runtime down.
+    pub fn shutdown_on_idle(self) {
+        self.runtime.shutdown_on_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that tasks spawned on the thread pool can use timers and the real network.
+    fn echo() {
+        let mut runtime = ThreadPoolRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let env = handle.clone();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                env.delay_from(time::Duration::from_millis(10)).await;
+                let mut buf = [0; 4];
+                socket.read_exact(&mut buf).await.unwrap();
+                socket.write_all(&buf).await.unwrap();
+            });
+            let start = handle.now();
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            assert!(handle.now() - start >= time::Duration::from_millis(10));
+        });
+    }
+}
@@ -0,0 +1,91 @@
+//! Timeouts returned by `Environment::timeout`, independent of the timer of the runtime.
+use futures::Poll;
+use pin_project::pin_project;
+use std::{error, fmt, future::Future, io, pin::Pin, task::Context};
+
+/// Error returned by a `Timeout` whose duration elapsed before its future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl error::Error for Elapsed {}
+
+impl From<Elapsed> for io::Error {
+    fn from(elapsed: Elapsed) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, elapsed)
+    }
+}
+
+/// Future requiring a future to complete before a duration has elapsed, returned by
+/// `Environment::timeout`. Resolves to the output of the future, or `Elapsed` if the
+/// duration elapsed first, so an error of the future is never confused with a timeout.
+#[pin_project]
+#[derive(Debug)]
+pub struct Timeout<T> {
+    #[pin]
+    inner: tokio_timer::Timeout<T>,
+}
+
+impl<T> Timeout<T> {
+    pub(crate) fn new(inner: tokio_timer::Timeout<T>) -> Self {
+        Self { inner }
+    }
+
+    /// Returns a reference to the future.
+    pub fn get_ref(&self) -> &T {
+        self.inner.get_ref()
+    }
+
+    /// Returns a mutable reference to the future.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Returns the future, dropping the timer.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Future> Future for Timeout<T> {
+    type Output = Result<T::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project()
+            .inner
+            .poll(cx)
+            .map(|result| result.map_err(|_| Elapsed(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that an elapsed timeout is told apart from an error of its future.
+    fn elapsed_or_inner_error() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let failing = async { Err::<(), _>(io::Error::from(io::ErrorKind::BrokenPipe)) };
+            match handle.timeout(failing, Duration::from_secs(1)).await {
+                Ok(Err(error)) => assert_eq!(error.kind(), io::ErrorKind::BrokenPipe),
+                other => panic!("unexpected result {:?}", other),
+            }
+            let slow = handle.delay_from(Duration::from_secs(2));
+            let elapsed = handle
+                .timeout(slow, Duration::from_secs(1))
+                .await
+                .unwrap_err();
+            assert_eq!(io::Error::from(elapsed).kind(), io::ErrorKind::TimedOut);
+        });
+    }
+}
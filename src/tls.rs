@@ -0,0 +1,549 @@
+//! A TLS-like handshake over the connections of an `Environment`.
+//!
+//! Code which reconnects after a failed handshake, or rotates certificates before they expire,
+//! is hard to exercise against a real TLS stack, as certificates are checked against the real
+//! wall clock. `TlsConnector` and `TlsAcceptor` stand in for a TLS stack: the client checks the
+//! certificate presented by the server against the name it connected to and against
+//! `Environment::system_time`, which under the `DeterministicRuntime` advances with simulated
+//! time, so a certificate expires in simulated time. Handshakes may be delayed or fail, drawn
+//! from `Environment::rng_for`, so the same seed delays and fails the same handshakes.
+//!
+//! No encryption takes place: once the handshake completes, bytes are passed through to the
+//! underlying connection unchanged.
+//!
+//! ```
+//! # use simulation::{deterministic::{DeterministicRuntime, FaultConfig}, Environment, TcpListener};
+//! use simulation::tls::{Certificate, TlsAcceptor, TlsConnector};
+//! # use std::time::Duration;
+//! # use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//! let mut runtime = DeterministicRuntime::builder()
+//!     .fault_config(FaultConfig::disabled())
+//!     .build()
+//!     .unwrap();
+//! let handle = runtime.handle();
+//! let addr: std::net::SocketAddr = "127.0.0.1:8443".parse().unwrap();
+//! runtime.block_on(async {
+//!     let now = handle.system_time();
+//!     let certificate = Certificate::new("db.local", now, now + Duration::from_secs(3600));
+//!     let acceptor = TlsAcceptor::new(handle.clone(), certificate);
+//!     let mut listener = handle.bind(addr).await.unwrap();
+//!     let (client, server) = futures::join!(handle.connect(addr), listener.accept());
+//!     let connector = TlsConnector::new(handle.clone());
+//!     let (client, server) = futures::join!(
+//!         connector.connect("db.local", client.unwrap()),
+//!         acceptor.accept(server.unwrap().0),
+//!     );
+//!     let (mut client, mut server) = (client.unwrap(), server.unwrap());
+//!     server.write_all(b"hello").await.unwrap();
+//!     let mut greeting = [0; 5];
+//!     client.read_exact(&mut greeting).await.unwrap();
+//!     assert_eq!(&greeting, b"hello");
+//!     assert_eq!(client.peer_certificate().unwrap().subject, "db.local");
+//! });
+//! ```
+use crate::{Environment, RngHandle};
+use futures::Poll;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    error, fmt, io, net, ops,
+    pin::Pin,
+    sync,
+    task::Context,
+    time::{self, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest handshake message accepted, after which the handshake fails.
+const MAX_MESSAGE: usize = 64 * 1024;
+
+/// A certificate presented by a `TlsAcceptor`, valid for `subject` between `not_before` and
+/// `not_after`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Certificate {
+    pub subject: String,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+}
+
+impl Certificate {
+    pub fn new(subject: &str, not_before: SystemTime, not_after: SystemTime) -> Self {
+        Self {
+            subject: subject.to_string(),
+            not_before,
+            not_after,
+        }
+    }
+
+    /// Checks that the certificate is valid for `server_name` at `now`.
+    fn verify(&self, server_name: &str, now: SystemTime) -> Result<(), TlsError> {
+        if self.subject != server_name {
+            return Err(TlsError::NameMismatch {
+                expected: server_name.to_string(),
+                presented: self.subject.clone(),
+            });
+        }
+        if now < self.not_before {
+            return Err(TlsError::CertificateNotYetValid {
+                not_before: self.not_before,
+                now,
+            });
+        }
+        if now > self.not_after {
+            return Err(TlsError::CertificateExpired {
+                not_after: self.not_after,
+                now,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Faults injected into the handshakes of a `TlsConnector` or `TlsAcceptor`. Every fault is
+/// disabled by default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFaults {
+    /// The range of duration a handshake can be delayed by.
+    pub handshake_delay: ops::Range<time::Duration>,
+    /// The probability of a handshake being delayed, 0..1.
+    pub handshake_delay_prob: f64,
+    /// The probability of a handshake failing, 0..1.
+    pub handshake_failure_prob: f64,
+}
+
+impl Default for TlsFaults {
+    fn default() -> Self {
+        Self {
+            handshake_delay: time::Duration::from_millis(0)..time::Duration::from_millis(1000),
+            handshake_delay_prob: 0.0,
+            handshake_failure_prob: 0.0,
+        }
+    }
+}
+
+impl TlsFaults {
+    /// Delays the handshake if drawn to, returning whether it should fail.
+    async fn inject<E: Environment>(&self, env: &E, rng: &mut RngHandle) -> bool {
+        if rng.gen_bool(self.handshake_delay_prob) {
+            let delay = rng.gen_range(self.handshake_delay.clone());
+            env.delay_from(delay).await;
+        }
+        rng.gen_bool(self.handshake_failure_prob)
+    }
+}
+
+/// Error returned by a failed handshake.
+#[derive(Debug)]
+pub enum TlsError {
+    /// The connection failed or was closed mid-handshake, or a malformed message was received.
+    Io { source: io::Error },
+    /// The handshake was aborted by the peer or by an injected fault.
+    HandshakeFailed { alert: String },
+    /// The certificate presented by the server expired before `now`.
+    CertificateExpired {
+        not_after: SystemTime,
+        now: SystemTime,
+    },
+    /// The certificate presented by the server is not valid until after `now`.
+    CertificateNotYetValid {
+        not_before: SystemTime,
+        now: SystemTime,
+    },
+    /// The certificate presented by the server is not for the name connected to.
+    NameMismatch { expected: String, presented: String },
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io { source } => write!(f, "tls connection failed: {}", source),
+            TlsError::HandshakeFailed { alert } => write!(f, "tls handshake failed: {}", alert),
+            TlsError::CertificateExpired { not_after, now } => write!(
+                f,
+                "certificate expired at {:?}, it is now {:?}",
+                not_after, now
+            ),
+            TlsError::CertificateNotYetValid { not_before, now } => write!(
+                f,
+                "certificate is not valid until {:?}, it is now {:?}",
+                not_before, now
+            ),
+            TlsError::NameMismatch {
+                expected,
+                presented,
+            } => write!(f, "certificate is for {}, expected {}", presented, expected),
+        }
+    }
+}
+
+impl error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TlsError::Io { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(source: io::Error) -> Self {
+        TlsError::Io { source }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    ClientHello { server_name: String },
+    ServerHello { certificate: Certificate },
+    Finished,
+    Alert { description: String },
+}
+
+/// Writes `message` to `socket`, prefixed with its length. Messages are read exactly, so no
+/// bytes written after the handshake are consumed by it.
+async fn write_message<S>(socket: &mut S, message: &Message) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bytes = serde_json::to_vec(message)?;
+    socket
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .await?;
+    socket.write_all(&bytes).await
+}
+
+async fn read_message<S>(socket: &mut S) -> io::Result<Message>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut length = [0; 4];
+    socket.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "handshake message too large",
+        ));
+    }
+    let mut bytes = vec![0; length];
+    socket.read_exact(&mut bytes).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+fn unexpected(message: Message) -> TlsError {
+    let message = format!("unexpected handshake message {:?}", message);
+    io::Error::new(io::ErrorKind::InvalidData, message).into()
+}
+
+/// Sends an alert aborting the handshake, returning the error of the handshake.
+async fn abort<S>(socket: &mut S, error: TlsError) -> TlsError
+where
+    S: AsyncWrite + Unpin,
+{
+    let alert = Message::Alert {
+        description: error.to_string(),
+    };
+    if let Err(e) = write_message(socket, &alert).await {
+        log::debug!("failed to send tls alert: {}", e);
+    }
+    error
+}
+
+/// Client side of the handshake, checking the certificate presented by the server.
+#[derive(Debug, Clone)]
+pub struct TlsConnector<E> {
+    env: E,
+    faults: TlsFaults,
+}
+
+impl<E: Environment> TlsConnector<E> {
+    pub fn new(env: E) -> Self {
+        Self {
+            env,
+            faults: TlsFaults::default(),
+        }
+    }
+
+    /// Sets the faults injected into handshakes, drawn from the stream of `Environment::rng_for`
+    /// named after the server name.
+    pub fn faults(mut self, faults: TlsFaults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Performs a handshake over `socket` with a server presenting a certificate for
+    /// `server_name`, which must be valid at `Environment::system_time`.
+    pub async fn connect<S>(
+        &self,
+        server_name: &str,
+        mut socket: S,
+    ) -> Result<TlsStream<S>, TlsError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut rng = self.env.rng_for(&format!("tls:connect:{}", server_name));
+        let hello = Message::ClientHello {
+            server_name: server_name.to_string(),
+        };
+        write_message(&mut socket, &hello).await?;
+        let certificate = match read_message(&mut socket).await? {
+            Message::ServerHello { certificate } => certificate,
+            Message::Alert { description } => {
+                return Err(TlsError::HandshakeFailed { alert: description })
+            }
+            message => return Err(unexpected(message)),
+        };
+        if self.faults.inject(&self.env, &mut rng).await {
+            let error = TlsError::HandshakeFailed {
+                alert: "injected fault".to_string(),
+            };
+            return Err(abort(&mut socket, error).await);
+        }
+        if let Err(error) = certificate.verify(server_name, self.env.system_time()) {
+            return Err(abort(&mut socket, error).await);
+        }
+        write_message(&mut socket, &Message::Finished).await?;
+        Ok(TlsStream {
+            inner: socket,
+            peer_certificate: Some(certificate),
+        })
+    }
+}
+
+/// Server side of the handshake, presenting a certificate. Clones share the certificate, so it
+/// can be replaced with `set_certificate` while accepting connections.
+#[derive(Debug, Clone)]
+pub struct TlsAcceptor<E> {
+    env: E,
+    certificate: sync::Arc<sync::Mutex<Certificate>>,
+    faults: TlsFaults,
+}
+
+impl<E: Environment> TlsAcceptor<E> {
+    pub fn new(env: E, certificate: Certificate) -> Self {
+        Self {
+            env,
+            certificate: sync::Arc::new(sync::Mutex::new(certificate)),
+            faults: TlsFaults::default(),
+        }
+    }
+
+    /// Sets the faults injected into handshakes, drawn from the stream of `Environment::rng_for`
+    /// named after the subject of the certificate.
+    pub fn faults(mut self, faults: TlsFaults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Replaces the certificate presented by later handshakes, such as to renew it.
+    pub fn set_certificate(&self, certificate: Certificate) {
+        *self.certificate.lock().unwrap() = certificate;
+    }
+
+    pub fn certificate(&self) -> Certificate {
+        self.certificate.lock().unwrap().clone()
+    }
+
+    /// Performs a handshake over `socket` with a client, failing if the client rejects the
+    /// certificate.
+    pub async fn accept<S>(&self, mut socket: S) -> Result<TlsStream<S>, TlsError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let certificate = self.certificate();
+        let mut rng = self
+            .env
+            .rng_for(&format!("tls:accept:{}", certificate.subject));
+        match read_message(&mut socket).await? {
+            Message::ClientHello { .. } => {}
+            message => return Err(unexpected(message)),
+        }
+        if self.faults.inject(&self.env, &mut rng).await {
+            let error = TlsError::HandshakeFailed {
+                alert: "injected fault".to_string(),
+            };
+            return Err(abort(&mut socket, error).await);
+        }
+        write_message(&mut socket, &Message::ServerHello { certificate }).await?;
+        match read_message(&mut socket).await? {
+            Message::Finished => Ok(TlsStream {
+                inner: socket,
+                peer_certificate: None,
+            }),
+            Message::Alert { description } => Err(TlsError::HandshakeFailed { alert: description }),
+            message => Err(unexpected(message)),
+        }
+    }
+}
+
+/// A connection which completed a handshake. Reads and writes pass through to the connection
+/// unchanged.
+#[derive(Debug)]
+pub struct TlsStream<S> {
+    inner: S,
+    peer_certificate: Option<Certificate>,
+}
+
+impl<S> TlsStream<S> {
+    /// Returns the certificate presented by the server, if this is the client end.
+    pub fn peer_certificate(&self) -> Option<&Certificate> {
+        self.peer_certificate.as_ref()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: crate::TcpStream> crate::TcpStream for TlsStream<S> {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, DeterministicRuntimeHandle, FaultConfig},
+        TcpListener,
+    };
+    use std::time::Duration;
+
+    /// Accepts connections on `addr` until the runtime completes, ignoring failed handshakes.
+    async fn serve(
+        handle: &DeterministicRuntimeHandle,
+        addr: net::SocketAddr,
+        acceptor: TlsAcceptor<DeterministicRuntimeHandle>,
+    ) {
+        let mut listener = handle.bind(addr).await.unwrap();
+        handle.spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                if let Ok(mut socket) = acceptor.accept(socket).await {
+                    let _ = socket.write_all(b"ok").await;
+                }
+            }
+        });
+    }
+
+    #[test]
+    /// Test that certificates are checked against the simulated wall clock and the name
+    /// connected to, and that a renewed certificate is presented to later handshakes.
+    fn expiry() {
+        let start = time::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut runtime = DeterministicRuntime::builder()
+            .fault_config(FaultConfig::disabled())
+            .start_time(start)
+            .build()
+            .unwrap();
+        let handle = runtime.handle();
+        let addr: net::SocketAddr = "127.0.0.1:8443".parse().unwrap();
+        runtime.block_on(async {
+            assert_eq!(handle.system_time(), start);
+            let hour = Duration::from_secs(3600);
+            let acceptor = TlsAcceptor::new(
+                handle.clone(),
+                Certificate::new("db.local", start, start + hour),
+            );
+            serve(&handle, addr, acceptor.clone()).await;
+            let connector = TlsConnector::new(handle.clone());
+            let connect = |name: &'static str| {
+                let (handle, connector) = (handle.clone(), connector.clone());
+                async move {
+                    let socket = handle.connect(addr).await.unwrap();
+                    connector.connect(name, socket).await
+                }
+            };
+            let mut socket = connect("db.local").await.unwrap();
+            assert_eq!(socket.peer_certificate().unwrap().subject, "db.local");
+            let mut reply = [0; 2];
+            socket.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"ok");
+            assert!(matches!(
+                connect("cache.local").await,
+                Err(TlsError::NameMismatch { .. })
+            ));
+            handle.delay_from(2 * hour).await;
+            assert_eq!(handle.system_time(), start + 2 * hour);
+            match connect("db.local").await {
+                Err(TlsError::CertificateExpired { not_after, now }) => {
+                    assert_eq!((not_after, now), (start + hour, start + 2 * hour))
+                }
+                other => panic!("expected an expired certificate, got {:?}", other),
+            }
+            let now = handle.system_time();
+            acceptor.set_certificate(Certificate::new("db.local", now, now + hour));
+            assert!(connect("db.local").await.is_ok());
+        });
+    }
+
+    #[test]
+    /// Test that injected handshake delays and failures are the same for a seed.
+    fn faults() {
+        let run = |seed| {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            let handle = runtime.handle().new_cluster(FaultConfig::disabled());
+            let addr: net::SocketAddr = "127.0.0.1:8443".parse().unwrap();
+            runtime.block_on(async {
+                let now = handle.system_time();
+                let certificate =
+                    Certificate::new("db.local", now, now + Duration::from_secs(3600));
+                let faults = TlsFaults {
+                    handshake_delay_prob: 0.5,
+                    handshake_failure_prob: 0.2,
+                    ..TlsFaults::default()
+                };
+                let acceptor = TlsAcceptor::new(handle.clone(), certificate).faults(faults.clone());
+                serve(&handle, addr, acceptor).await;
+                let connector = TlsConnector::new(handle.clone()).faults(faults);
+                let (start, mut results) = (handle.now(), vec![]);
+                for _ in 0..20 {
+                    let socket = handle.connect(addr).await.unwrap();
+                    let result = connector.connect("db.local", socket).await;
+                    results.push((result.is_ok(), handle.now() - start));
+                }
+                results
+            })
+        };
+        let results = run(1);
+        assert_eq!(results, run(1));
+        assert!(results.iter().any(|r| r.0));
+        assert!(results.iter().any(|r| !r.0));
+    }
+}
@@ -0,0 +1,74 @@
+//! Identifiers generated from the environment's randomness.
+//!
+//! Systems which stamp records with random UUIDs produce different logs on every run. Ids
+//! generated through `Environment::next_id` and `Environment::new_uuid` are instead drawn from
+//! a stream derived from the seed under the `DeterministicRuntime`, so runs of the same seed
+//! generate the same ids.
+use std::fmt;
+
+/// A version 4 (random) UUID, as described in RFC 4122.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Uuid([u8; 16]);
+
+impl Uuid {
+    /// Returns a version 4 UUID built from 16 random bytes, overwriting the version and variant
+    /// bits.
+    pub fn from_random_bytes(mut bytes: [u8; 16]) -> Self {
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Uuid(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn as_u128(&self) -> u128 {
+        u128::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, byte) in self.0.iter().enumerate() {
+            if idx == 4 || idx == 6 || idx == 8 || idx == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uuid({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment};
+
+    fn ids(seed: u64) -> (Vec<u64>, Vec<String>) {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.handle();
+        runtime.block_on(async {
+            let ids = (0..4).map(|_| handle.next_id()).collect();
+            let uuids = (0..4).map(|_| handle.new_uuid().to_string()).collect();
+            (ids, uuids)
+        })
+    }
+
+    #[test]
+    /// Test that ids are reproduced by the seed and that UUIDs are well formed version 4 UUIDs.
+    fn seeded_ids() {
+        assert_eq!(ids(1), ids(1));
+        assert_ne!(ids(1), ids(2));
+        for uuid in ids(1).1 {
+            assert_eq!(uuid.len(), 36);
+            assert_eq!(&uuid[14..15], "4");
+            assert!("89ab".contains(&uuid[19..20]));
+        }
+    }
+}